@@ -1,6 +1,8 @@
 use anyhow::Result;
+use opendal::Configurator;
 use opendal::Operator;
 use opendal::Scheme;
+use pgrx::guc::{GucContext, GucFlags, GucRegistry, GucSetting};
 use pgrx::prelude::*;
 use pgrx::JsonB;
 use serde_json::Value;
@@ -11,274 +13,11922 @@ use futures::stream::TryStreamExt;
 
 pgrx::pg_module_magic!();
 
-async fn do_read_async(op: Operator, path: &str) -> Result<String, String> {
-    match op.read(path).await {
-        Ok(data) => String::from_utf8(data.to_vec())
+static PATH_PREFIX: GucSetting<Option<&'static std::ffi::CStr>> =
+    GucSetting::<Option<&'static std::ffi::CStr>>::new(None);
+static MAX_CONCURRENCY: GucSetting<i32> = GucSetting::<i32>::new(16);
+
+#[allow(non_snake_case)]
+#[pg_guard]
+pub extern "C-unwind" fn _PG_init() {
+    GucRegistry::define_string_guc(
+        "pg_opendal.path_prefix",
+        "Prefix prepended to every relative path argument.",
+        "Paths starting with '/' are used as given.",
+        &PATH_PREFIX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pg_opendal.max_concurrency",
+        "Maximum number of storage requests a bulk operation runs at once.",
+        "Applies to operations that act on many files, such as pg_opendal_tier.",
+        &MAX_CONCURRENCY,
+        1,
+        1024,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+}
+
+/// Joins `prefix` and `path` with exactly one slash between them. Absolute paths
+/// (starting with `/`), an empty prefix and paths already under the prefix, such as
+/// those returned by a listing, leave `path` untouched.
+fn apply_path_prefix(prefix: &str, path: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() || path.starts_with('/') {
+        return path.to_string();
+    }
+    let under_prefix = path
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+    if under_prefix {
+        return path.to_string();
+    }
+    format!("{}/{}", prefix, path)
+}
+
+/// Resolves a path argument against the `pg_opendal.path_prefix` setting.
+fn resolve_path(path: &str) -> String {
+    match PATH_PREFIX.get() {
+        Some(prefix) => apply_path_prefix(&prefix.to_string_lossy(), path),
+        None => path.to_string(),
+    }
+}
+
+fn resolve_paths(paths: Vec<String>) -> Vec<String> {
+    paths.iter().map(|path| resolve_path(path)).collect()
+}
+
+/// How text reads treat bytes that aren't valid UTF-8.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Utf8Mode {
+    /// Fail the read.
+    Strict,
+    /// Replace each invalid sequence with U+FFFD.
+    Lossy,
+    /// Drop invalid bytes.
+    Skip,
+}
+
+impl FromStr for Utf8Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" => Ok(Utf8Mode::Strict),
+            "lossy" => Ok(Utf8Mode::Lossy),
+            "skip" => Ok(Utf8Mode::Skip),
+            other => Err(format!(
+                "Invalid mode '{}', expected one of 'strict', 'lossy' or 'skip'",
+                other
+            )),
+        }
+    }
+}
+
+fn decode_utf8(data: Vec<u8>, mode: Utf8Mode) -> Result<String, String> {
+    match mode {
+        Utf8Mode::Strict => String::from_utf8(data)
             .map_err(|e| format!("Failed to convert data to UTF-8: {}", e)),
+        Utf8Mode::Lossy => Ok(String::from_utf8_lossy(&data).into_owned()),
+        Utf8Mode::Skip => Ok(data.utf8_chunks().map(|chunk| chunk.valid()).collect()),
+    }
+}
+
+async fn do_read_async(op: Operator, path: &str, mode: Utf8Mode) -> Result<String, String> {
+    match op.read(path).await {
+        Ok(data) => decode_utf8(data.to_vec(), mode),
         Err(e) => Err(format!("Failed to read file '{}': {}", path, e)),
     }
 }
 
 #[pg_extern]
-fn pg_opendal_read(service: &str, path: &str, config: JsonB) -> Result<String, String> {
+fn pg_opendal_read(
+    service: &str,
+    path: &str,
+    config: JsonB,
+    mode: default!(&str, "'strict'"),
+) -> Result<String, String> {
+    let mode: Utf8Mode = mode.parse()?;
     let config_map = jsonb_to_hashmap(config.0)
         .map_err(|e| format!("Failed to parse config: {}", e))?;
     let op = create_operator(service, config_map)
         .map_err(|e| format!("Failed to create operator: {}", e))?;
     
     let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
-    rt.block_on(do_read_async(op, path))
+    rt.block_on(do_read_async(op, &resolve_path(path), mode))
 }
 
-async fn do_write_async(op: Operator, path: &str, content: &[u8]) -> Result<bool, String> {
-    op.write(path, content.to_owned())
-        .await
-        .map(|_| true)
-        .map_err(|e| format!("Failed to write to '{}': {}", path, e))
+/// Reads a file that may not exist: `None` when it's missing, and an empty string when it
+/// exists but is empty. Existence comes from a stat rather than a failed read, and an empty
+/// file isn't read at all.
+async fn do_try_read_async(op: Operator, path: &str) -> Result<Option<String>, String> {
+    let metadata = match op.stat(path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to get metadata for '{}': {}", path, e)),
+    };
+    if metadata.is_dir() {
+        return Err(format!("'{}' is a directory", path));
+    }
+    if metadata.content_length() == 0 {
+        return Ok(Some(String::new()));
+    }
+    match op.read(path).await {
+        Ok(data) => String::from_utf8(data.to_vec())
+            .map(Some)
+            .map_err(|e| format!("Failed to convert data to UTF-8: {}", e)),
+        // Deleted since the stat.
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("Failed to read file '{}': {}", path, e)),
+    }
 }
 
 #[pg_extern]
-fn pg_opendal_write(service: &str, path: &str, content: &str, config: JsonB) -> Result<bool, String> {
+fn pg_opendal_try_read(service: &str, path: &str, config: JsonB) -> Result<Option<String>, String> {
     let config_map = jsonb_to_hashmap(config.0)
         .map_err(|e| format!("Failed to parse config: {}", e))?;
     let op = create_operator(service, config_map)
         .map_err(|e| format!("Failed to create operator: {}", e))?;
 
     let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
-    rt.block_on(do_write_async(op, path, content.as_bytes()))
+    rt.block_on(do_try_read_async(op, &resolve_path(path)))
 }
 
-async fn do_exists_async(op: Operator, path: &str) -> Result<bool, String> {
-    match op.stat(path).await {
-        Ok(_) => Ok(true),
-        Err(e) => {
-            if e.kind() == opendal::ErrorKind::NotFound {
-                Ok(false)
-            } else {
-                Err(format!("Failed to check existence of '{}': {}", path, e))
-            }
+/// Text decoded from bytes of unknown encoding.
+struct DecodedText {
+    text: String,
+    encoding: &'static str,
+    lossy: bool,
+}
+
+/// Decodes text by its byte order mark if it has one, as UTF-8 if it's valid UTF-8,
+/// and otherwise by the encoding chardetng guesses. Text the guess can't decode cleanly
+/// falls back to UTF-8 with invalid sequences replaced.
+fn decode_text_auto(content: &[u8]) -> DecodedText {
+    let decoded = |encoding: &'static encoding_rs::Encoding, bytes: &[u8]| {
+        let (text, had_errors) = encoding.decode_without_bom_handling(bytes);
+        (!had_errors).then(|| DecodedText {
+            text: text.into_owned(),
+            encoding: encoding.name(),
+            lossy: false,
+        })
+    };
+
+    if let Some((encoding, bom_len)) = encoding_rs::Encoding::for_bom(content) {
+        if let Some(text) = decoded(encoding, &content[bom_len..]) {
+            return text;
         }
     }
+    if let Some(text) = decoded(encoding_rs::UTF_8, content) {
+        return text;
+    }
+    let mut detector = chardetng::EncodingDetector::new();
+    detector.feed(content, true);
+    decoded(detector.guess(None, true), content).unwrap_or_else(|| DecodedText {
+        text: String::from_utf8_lossy(content).into_owned(),
+        encoding: encoding_rs::UTF_8.name(),
+        lossy: true,
+    })
 }
 
-#[pg_extern]
-fn pg_opendal_exists(service: &str, path: &str, config: JsonB) -> Result<bool, String> {
+fn read_text_auto(service: &str, path: &str, config: JsonB) -> Result<DecodedText, String> {
     let config_map = jsonb_to_hashmap(config.0)
         .map_err(|e| format!("Failed to parse config: {}", e))?;
     let op = create_operator(service, config_map)
         .map_err(|e| format!("Failed to create operator: {}", e))?;
 
+    let path = resolve_path(path);
     let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
-    rt.block_on(do_exists_async(op, path))
+    let content = rt
+        .block_on(op.read(&path))
+        .map_err(|e| format!("Failed to read file '{}': {}", path, e))?;
+    Ok(decode_text_auto(&content.to_vec()))
 }
 
-async fn do_delete_async(op: Operator, path: &str) -> Result<bool, String> {
-    op.delete(path)
-        .await
-        .map(|_| true)
-        .map_err(|e| format!("Failed to delete '{}': {}", path, e))
+#[pg_extern]
+fn pg_opendal_read_text_auto(service: &str, path: &str, config: JsonB) -> Result<String, String> {
+    read_text_auto(service, path, config).map(|decoded| decoded.text)
 }
 
 #[pg_extern]
-fn pg_opendal_delete(service: &str, path: &str, config: JsonB) -> Result<bool, String> {
+fn pg_opendal_read_text_auto_jsonb(service: &str, path: &str, config: JsonB) -> Result<JsonB, String> {
+    let decoded = read_text_auto(service, path, config)?;
+    let mut result = serde_json::Map::new();
+    result.insert("encoding".to_string(), Value::String(decoded.encoding.to_string()));
+    result.insert("lossy".to_string(), Value::Bool(decoded.lossy));
+    result.insert("text".to_string(), Value::String(decoded.text));
+    Ok(JsonB(Value::Object(result)))
+}
+
+extension_sql!(
+    r#"
+CREATE TABLE pg_opendal_read_cache (
+    path text NOT NULL,
+    service text NOT NULL,
+    config_fingerprint text NOT NULL,
+    content text NOT NULL,
+    etag text,
+    cached_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (service, config_fingerprint, path)
+);
+"#,
+    name = "create_read_cache",
+);
+
+/// Substrings of the config keys that hold credentials rather than say where objects live.
+const SECRET_CONFIG_KEYS: [&str; 5] = ["secret", "password", "token", "credential", "account_key"];
+
+/// Identifies the storage a config points at, so cache entries for the same path in
+/// different buckets or endpoints don't collide: a hash of its non-secret settings in
+/// key order. Rotating credentials keeps the fingerprint, and with it the cached entries.
+fn config_fingerprint(config: &HashMap<String, String>) -> String {
+    use sha2::Digest;
+    let settings: std::collections::BTreeMap<&str, &str> = config
+        .iter()
+        .filter(|(key, _)| {
+            let key = key.to_ascii_lowercase();
+            !SECRET_CONFIG_KEYS.iter().any(|secret| key.contains(secret))
+        })
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    let mut hasher = sha2::Sha256::new();
+    for (key, value) in settings {
+        hasher.update(key.as_bytes());
+        hasher.update([0u8]);
+        hasher.update(value.as_bytes());
+        hasher.update([0u8]);
+    }
+    hex::encode(hasher.finalize())[..16].to_string()
+}
+
+/// A row of `pg_opendal_read_cache`; `fresh` is whether it's still within the caller's TTL.
+struct CacheEntry {
+    content: String,
+    etag: Option<String>,
+    fresh: bool,
+}
+
+fn cache_lookup(service: &str, fingerprint: &str, path: &str, ttl_seconds: i32) -> Result<Option<CacheEntry>, String> {
+    Spi::connect(|client| {
+        let rows = client
+            .select(
+                "SELECT content, etag, cached_at > now() - make_interval(secs => $4) \
+                 FROM pg_opendal_read_cache WHERE service = $1 AND config_fingerprint = $2 AND path = $3",
+                Some(1),
+                &[service.into(), fingerprint.into(), path.into(), ttl_seconds.into()],
+            )
+            .map_err(|e| format!("Failed to look up the read cache: {}", e))?;
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        let row = rows.first();
+        let content: Option<String> = row.get(1).map_err(|e| e.to_string())?;
+        let etag: Option<String> = row.get(2).map_err(|e| e.to_string())?;
+        let fresh: Option<bool> = row.get(3).map_err(|e| e.to_string())?;
+        Ok(Some(CacheEntry {
+            content: content.unwrap_or_default(),
+            etag,
+            fresh: fresh.unwrap_or(false),
+        }))
+    })
+}
+
+/// Reads `path` unless its ETag still matches `cached_etag`, in which case `None` is returned.
+/// The check is a stat with If-None-Match where the service supports it. The ETag is taken
+/// before the read, so a concurrent overwrite costs an extra read later rather than a stale hit.
+async fn fetch_if_changed_async(
+    op: &Operator,
+    path: &str,
+    cached_etag: Option<&str>,
+) -> Result<Option<(String, Option<String>)>, String> {
+    let stat = match cached_etag {
+        Some(etag) if op.info().full_capability().stat_with_if_none_match => {
+            op.stat_with(path).if_none_match(etag).await
+        }
+        _ => op.stat(path).await,
+    };
+    let meta = match stat {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == opendal::ErrorKind::ConditionNotMatch => return Ok(None),
+        Err(e) => return Err(format!("Failed to stat '{}': {}", path, e)),
+    };
+    let etag = meta.etag().map(str::to_string);
+    if cached_etag.is_some() && etag.as_deref() == cached_etag {
+        return Ok(None);
+    }
+    let content = do_read_async(op.clone(), path, Utf8Mode::Strict).await?;
+    Ok(Some((content, etag)))
+}
+
+#[pg_extern]
+fn pg_opendal_read_cached(
+    service: &str,
+    path: &str,
+    config: JsonB,
+    ttl_seconds: default!(i32, 300),
+) -> Result<String, String> {
+    if ttl_seconds < 0 {
+        return Err(format!("Invalid ttl_seconds: {}", ttl_seconds));
+    }
+    let path = resolve_path(path);
     let config_map = jsonb_to_hashmap(config.0)
         .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let fingerprint = config_fingerprint(&config_map);
+    let cached = cache_lookup(service, &fingerprint, &path, ttl_seconds)?;
+    if let Some(CacheEntry { content, fresh: true, .. }) = cached {
+        return Ok(content);
+    }
+
     let op = create_operator(service, config_map)
         .map_err(|e| format!("Failed to create operator: {}", e))?;
 
     let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
-    rt.block_on(do_delete_async(op, path))
+    let cached_etag = cached.as_ref().and_then(|entry| entry.etag.as_deref());
+    let fetched = rt.block_on(fetch_if_changed_async(&op, &path, cached_etag))?;
+
+    Spi::connect_mut(|client| match (fetched, cached) {
+        (Some((content, etag)), _) => {
+            client
+                .update(
+                    "INSERT INTO pg_opendal_read_cache (service, config_fingerprint, path, content, etag, cached_at) \
+                     VALUES ($1, $2, $3, $4, $5, now()) \
+                     ON CONFLICT (service, config_fingerprint, path) DO UPDATE \
+                     SET content = EXCLUDED.content, etag = EXCLUDED.etag, cached_at = EXCLUDED.cached_at",
+                    None,
+                    &[
+                        service.into(),
+                        fingerprint.as_str().into(),
+                        path.as_str().into(),
+                        content.as_str().into(),
+                        etag.into(),
+                    ],
+                )
+                .map_err(|e| format!("Failed to update the read cache: {}", e))?;
+            Ok(content)
+        }
+        (None, Some(entry)) => {
+            client
+                .update(
+                    "UPDATE pg_opendal_read_cache SET cached_at = now() \
+                     WHERE service = $1 AND config_fingerprint = $2 AND path = $3",
+                    None,
+                    &[service.into(), fingerprint.as_str().into(), path.as_str().into()],
+                )
+                .map_err(|e| format!("Failed to update the read cache: {}", e))?;
+            Ok(entry.content)
+        }
+        (None, None) => Err(format!("No cached copy of '{}' to revalidate", path)),
+    })
 }
 
-async fn do_stat_async(op: Operator, path: &str) -> Result<JsonB, String> {
-    match op.stat(path).await {
-        Ok(metadata) => {
-            let mut stat_info = serde_json::Map::new();
-            stat_info.insert(
-                "content_length".to_string(),
-                Value::Number(serde_json::Number::from(metadata.content_length())),
-            );
-            stat_info.insert("is_file".to_string(), Value::Bool(metadata.is_file()));
-            stat_info.insert("is_dir".to_string(), Value::Bool(metadata.is_dir()));
+/// Whether `etag` matches an If-None-Match value: `*`, or a comma-separated list of
+/// ETags compared weakly, ignoring any `W/` prefix, as HTTP does for GET.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match.trim() == "*" || if_none_match.split(',').any(|tag| weak(tag) == weak(etag))
+}
 
-            if let Some(last_modified) = metadata.last_modified() {
-                stat_info.insert(
-                    "last_modified".to_string(),
-                    Value::String(last_modified.to_rfc3339()),
-                );
-            }
+/// HTTP revalidation: If-None-Match decides when given, otherwise If-Modified-Since is
+/// compared at the one-second precision of HTTP dates. Without a validator to compare
+/// against, the object counts as modified.
+fn is_not_modified(
+    metadata: &opendal::Metadata,
+    if_none_match: Option<&str>,
+    if_modified_since: Option<chrono::DateTime<chrono::Utc>>,
+) -> bool {
+    if let Some(if_none_match) = if_none_match {
+        return metadata.etag().is_some_and(|etag| etag_matches(if_none_match, etag));
+    }
+    match (if_modified_since, metadata.last_modified()) {
+        (Some(since), Some(last_modified)) => last_modified.timestamp() <= since.timestamp(),
+        _ => false,
+    }
+}
 
-            Ok(JsonB(Value::Object(stat_info)))
+/// A conditional GET of `path`: `{"status": 304}` when the caller's validators still
+/// hold, otherwise the content with the validators for the next request. Services that
+/// take the validator on a read evaluate it themselves, and the validators for a 200 are
+/// then stated after the read. Elsewhere they are compared against a stat taken before
+/// the read, so a concurrent overwrite costs an extra fetch later rather than a stale hit.
+async fn do_conditional_fetch_async(
+    op: &Operator,
+    path: &str,
+    if_none_match: Option<&str>,
+    if_modified_since: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<Value, String> {
+    let capability = op.info().full_capability();
+    let mut read = op.read_with(path);
+    let conditional = match (if_none_match, if_modified_since) {
+        (Some(etag), _) if capability.read_with_if_none_match => {
+            read = read.if_none_match(etag);
+            true
         }
-        Err(e) => Err(format!("Failed to get stat for '{}': {}", path, e)),
-    }
+        (None, Some(since)) if capability.read_with_if_modified_since => {
+            read = read.if_modified_since(since);
+            true
+        }
+        _ => false,
+    };
+
+    let (content, metadata) = if conditional {
+        let content = match read.await {
+            Ok(content) => content,
+            Err(e) if e.kind() == opendal::ErrorKind::ConditionNotMatch => {
+                return Ok(serde_json::json!({ "status": 304 }));
+            }
+            Err(e) => return Err(format!("Failed to read file '{}': {}", path, e)),
+        };
+        let metadata = op.stat(path).await
+            .map_err(|e| format!("Failed to get stat for '{}': {}", path, e))?;
+        (content, metadata)
+    } else {
+        let metadata = op.stat(path).await
+            .map_err(|e| format!("Failed to get stat for '{}': {}", path, e))?;
+        if is_not_modified(&metadata, if_none_match, if_modified_since) {
+            return Ok(serde_json::json!({ "status": 304 }));
+        }
+        let content = read.await
+            .map_err(|e| format!("Failed to read file '{}': {}", path, e))?;
+        (content, metadata)
+    };
+    Ok(serde_json::json!({
+        "status": 200,
+        "etag": metadata.etag(),
+        "last_modified": metadata.last_modified().map(|t| t.to_rfc3339()),
+        "content": base64::Engine::encode(&base64::engine::general_purpose::STANDARD, content.to_vec()),
+    }))
 }
 
 #[pg_extern]
-fn pg_opendal_stat(service: &str, path: &str, config: JsonB) -> Result<JsonB, String> {
+fn pg_opendal_conditional_fetch(
+    service: &str,
+    path: &str,
+    if_none_match: Option<&str>,
+    if_modified_since: Option<TimestampWithTimeZone>,
+    config: JsonB,
+) -> Result<JsonB, String> {
+    let if_modified_since =
+        if_modified_since.map(|since| postgres_micros_to_utc(pg_sys::TimestampTz::from(since)));
     let config_map = jsonb_to_hashmap(config.0)
         .map_err(|e| format!("Failed to parse config: {}", e))?;
     let op = create_operator(service, config_map)
         .map_err(|e| format!("Failed to create operator: {}", e))?;
-    
+
     let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
-    rt.block_on(do_stat_async(op, path))
+    rt.block_on(do_conditional_fetch_async(&op, &resolve_path(path), if_none_match, if_modified_since))
+        .map(JsonB)
 }
 
-async fn do_create_dir_async(op: Operator, path: &str) -> Result<bool, String> {
-    op.create_dir(path)
-        .await
-        .map(|_| true)
-        .map_err(|e| format!("Failed to create directory '{}': {}", path, e))
+#[pg_extern]
+fn pg_opendal_cache_invalidate(
+    service: &str,
+    path: &str,
+    config: default!(Option<JsonB>, "NULL"),
+) -> Result<bool, String> {
+    let path = resolve_path(path);
+    // Without a config, the path is invalidated for every storage it was cached from.
+    let fingerprint = config
+        .map(|config| jsonb_to_hashmap(config.0).map(|config| config_fingerprint(&config)))
+        .transpose()
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    Spi::connect_mut(|client| {
+        client
+            .update(
+                "DELETE FROM pg_opendal_read_cache \
+                 WHERE service = $1 AND path = $2 AND config_fingerprint = coalesce($3, config_fingerprint) \
+                 RETURNING 1",
+                None,
+                &[service.into(), path.as_str().into(), fingerprint.into()],
+            )
+            .map(|deleted| !deleted.is_empty())
+            .map_err(|e| format!("Failed to invalidate the read cache: {}", e))
+    })
 }
 
 #[pg_extern]
-fn pg_opendal_create_dir(service: &str, path: &str, config: JsonB) -> Result<bool, String> {
-    let config_map = jsonb_to_hashmap(config.0)
+fn pg_opendal_read_resilient(
+    service: &str,
+    path: &str,
+    config: JsonB,
+    timeout_secs: default!(i32, 30),
+    max_retries: default!(i32, 3),
+) -> Result<String, String> {
+    let timeout = u64::try_from(timeout_secs)
+        .ok()
+        .filter(|&secs| secs > 0)
+        .map(std::time::Duration::from_secs)
+        .ok_or_else(|| format!("Invalid timeout_secs: {}", timeout_secs))?;
+    let max_retries =
+        usize::try_from(max_retries).map_err(|_| format!("Invalid max_retries: {}", max_retries))?;
+
+    let mut config_map = jsonb_to_hashmap(config.0)
         .map_err(|e| format!("Failed to parse config: {}", e))?;
+    // The explicit arguments override any config-level timeout and retry settings.
+    take_resilience_config(&mut config_map).map_err(|e| format!("Failed to parse config: {}", e))?;
     let op = create_operator(service, config_map)
         .map_err(|e| format!("Failed to create operator: {}", e))?;
 
+    // Retries are collected and reported afterwards, from the backend's own thread.
+    let retries = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let notify = {
+        let retries = retries.clone();
+        move |err: &opendal::Error, delay: std::time::Duration| {
+            let mut retries = retries.lock().unwrap_or_else(|e| e.into_inner());
+            let attempt = retries.len() + 1;
+            retries.push(format!(
+                "Retry attempt {} after {:.1}s: {}",
+                attempt,
+                delay.as_secs_f64(),
+                err
+            ));
+        }
+    };
+    let op = with_resilience_layers(op, Some(timeout), Some(max_retries), notify);
+
+    let path = resolve_path(path);
     let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
-    rt.block_on(do_create_dir_async(op, path))
+    let result = rt.block_on(do_read_async(op, &path, Utf8Mode::Strict));
+    for retry in retries.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        pgrx::warning!("Reading '{}': {}", path, retry);
+    }
+    result
 }
 
-async fn do_copy_async(op: Operator, source: &str, target: &str) -> Result<bool, String> {
-    op.copy(source, target)
+/// Which end of an object a partial read takes its bytes from.
+#[derive(Clone, Copy)]
+enum ReadEnd {
+    First,
+    Last,
+}
+
+async fn do_read_end_bytes_async(
+    op: Operator,
+    path: &str,
+    end: ReadEnd,
+    n_bytes: u64,
+    strict: bool,
+) -> Result<Vec<u8>, String> {
+    let content_length = op.stat(path).await
+        .map_err(|e| format!("Failed to get stat for '{}': {}", path, e))?
+        .content_length();
+    if strict && n_bytes > content_length {
+        return Err(format!(
+            "Requested {} bytes of '{}', which is only {} bytes long",
+            n_bytes, path, content_length
+        ));
+    }
+
+    let n_bytes = n_bytes.min(content_length);
+    if n_bytes == 0 {
+        return Ok(Vec::new());
+    }
+    let range = match end {
+        ReadEnd::First => 0..n_bytes,
+        ReadEnd::Last => content_length - n_bytes..content_length,
+    };
+    op.read_with(path)
+        .range(range)
         .await
-        .map(|_| true)
-        .map_err(|e| format!("Failed to copy from '{}' to '{}': {}", source, target, e))
+        .map(|data| data.to_vec())
+        .map_err(|e| format!("Failed to read file '{}': {}", path, e))
 }
 
-#[pg_extern]
-fn pg_opendal_copy(service: &str, source: &str, target: &str, config: JsonB) -> Result<bool, String> {
+fn read_end_bytes(
+    service: &str,
+    path: &str,
+    end: ReadEnd,
+    n_bytes: i32,
+    config: JsonB,
+    strict: bool,
+) -> Result<Vec<u8>, String> {
+    let n_bytes = u64::try_from(n_bytes).map_err(|_| format!("Invalid n_bytes: {}", n_bytes))?;
     let config_map = jsonb_to_hashmap(config.0)
         .map_err(|e| format!("Failed to parse config: {}", e))?;
     let op = create_operator(service, config_map)
         .map_err(|e| format!("Failed to create operator: {}", e))?;
 
     let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
-    rt.block_on(do_copy_async(op, source, target))
+    rt.block_on(do_read_end_bytes_async(op, &resolve_path(path), end, n_bytes, strict))
 }
 
-async fn do_rename_async(op: Operator, source: &str, target: &str) -> Result<bool, String> {
-    op.rename(source, target)
-        .await
-        .map(|_| true)
-        .map_err(|e| format!("Failed to rename from '{}' to '{}': {}", source, target, e))
+#[pg_extern]
+fn pg_opendal_read_first_bytes(
+    service: &str,
+    path: &str,
+    n_bytes: i32,
+    config: JsonB,
+    strict: default!(bool, false),
+) -> Result<Vec<u8>, String> {
+    read_end_bytes(service, path, ReadEnd::First, n_bytes, config, strict)
 }
 
 #[pg_extern]
-fn pg_opendal_rename(service: &str, source: &str, target: &str, config: JsonB) -> Result<bool, String> {
+fn pg_opendal_read_last_bytes(
+    service: &str,
+    path: &str,
+    n_bytes: i32,
+    config: JsonB,
+    strict: default!(bool, false),
+) -> Result<Vec<u8>, String> {
+    read_end_bytes(service, path, ReadEnd::Last, n_bytes, config, strict)
+}
+
+/// Bytes per line assumed when sizing the first read of `pg_opendal_read_head_lines` and
+/// `pg_opendal_read_tail_lines`. Each further read is twice as large as the one before.
+const ESTIMATED_LINE_LENGTH: u64 = 256;
+
+/// Splits `data` into lines the way `LineStream` does: a final newline doesn't start an
+/// empty line, and a `\r` before a newline is dropped.
+fn split_lines(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let data = data.strip_suffix(b"\n").unwrap_or(data);
+    data.split(|&b| b == b'\n')
+        .filter(move |_| !data.is_empty())
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+/// The first or last `n` lines of a file. Reads start at `n * ESTIMATED_LINE_LENGTH` bytes
+/// from that end and double until `n` whole lines are in, so only that end is fetched.
+async fn do_read_end_lines_async(op: Operator, path: &str, end: ReadEnd, n: usize) -> Result<Vec<String>, String> {
+    let content_length = op.stat(path).await
+        .map_err(|e| format!("Failed to get stat for '{}': {}", path, e))?
+        .content_length();
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+
+    // `start..stop` is the part of the file read into `data` so far.
+    let (mut start, mut stop) = match end {
+        ReadEnd::First => (0, 0),
+        ReadEnd::Last => (content_length, content_length),
+    };
+    let mut data = Vec::new();
+    let mut window = (n as u64).saturating_mul(ESTIMATED_LINE_LENGTH);
+    loop {
+        let range = match end {
+            ReadEnd::First => stop..stop.saturating_add(window).min(content_length),
+            ReadEnd::Last => start.saturating_sub(window)..start,
+        };
+        if !range.is_empty() {
+            let chunk = op.read_with(path)
+                .range(range.clone())
+                .await
+                .map_err(|e| format!("Failed to read file '{}': {}", path, e))?
+                .to_vec();
+            match end {
+                ReadEnd::First => data.extend_from_slice(&chunk),
+                ReadEnd::Last => drop(data.splice(0..0, chunk)),
+            }
+        }
+        (start, stop) = (start.min(range.start), stop.max(range.end));
+
+        // Enough newlines to know `n` lines are complete, not counting the file's final one.
+        let newlines = data.strip_suffix(b"\n").unwrap_or(&data).iter().filter(|&&b| b == b'\n').count();
+        let whole_file = start == 0 && stop == content_length;
+        if newlines >= n || whole_file {
+            break;
+        }
+        window = window.saturating_mul(2);
+    }
+
+    let mut lines: Vec<&[u8]> = split_lines(&data).collect();
+    // Unless the whole file was read, the line at the far end of `data` may be cut off;
+    // there are more than `n` lines in that case, so it's never kept.
+    match end {
+        ReadEnd::First => lines.truncate(n),
+        ReadEnd::Last => drop(lines.drain(..lines.len().saturating_sub(n))),
+    }
+    lines
+        .into_iter()
+        .map(|line| {
+            String::from_utf8(line.to_vec())
+                .map_err(|e| format!("Failed to convert a line of '{}' to UTF-8: {}", path, e))
+        })
+        .collect()
+}
+
+fn read_end_lines(
+    service: &str,
+    path: &str,
+    end: ReadEnd,
+    n: i32,
+    config: JsonB,
+) -> Result<SetOfIterator<'static, String>, String> {
+    let n = usize::try_from(n).map_err(|_| format!("Invalid n: {}", n))?;
     let config_map = jsonb_to_hashmap(config.0)
         .map_err(|e| format!("Failed to parse config: {}", e))?;
     let op = create_operator(service, config_map)
         .map_err(|e| format!("Failed to create operator: {}", e))?;
 
     let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
-    rt.block_on(do_rename_async(op, source, target))
+    let lines = rt.block_on(do_read_end_lines_async(op, &resolve_path(path), end, n))?;
+    Ok(SetOfIterator::new(lines))
 }
 
-async fn do_list_async(op: Operator, path: &str) -> Result<Vec<JsonB>, String> {
-    let mut lister = op.lister(path).await // op.lister() is async for the OpenDAL version in use
-        .map_err(|e| format!("Failed to get lister for '{}': {}", path, e))?;
-    
-    let mut results = Vec::new();
-    
-    while let Some(entry_result) = lister.try_next().await
-        .map_err(|e| format!("Failed to list contents of '{}': {}", path, e))? {
-        // entry_result is an opendal::Entry
-        let entry = entry_result; // Assuming entry_result is the Entry itself after try_next handles Result
-        let mut entry_info = serde_json::Map::new();
-        entry_info.insert("name".to_string(), Value::String(entry.name().to_string()));
-        entry_info.insert("path".to_string(), Value::String(entry.path().to_string()));
-
-        // Fetch metadata for each entry asynchronously
-        let metadata = op.stat(entry.path()).await
-            .map_err(|e| format!("Failed to get metadata for entry '{}': {}", entry.path(), e))?;
-        
-        entry_info.insert("is_file".to_string(), Value::Bool(metadata.is_file()));
-        entry_info.insert("is_dir".to_string(), Value::Bool(metadata.is_dir()));
-        entry_info.insert(
-            "content_length".to_string(),
-            Value::Number(serde_json::Number::from(metadata.content_length())),
-        );
+#[pg_extern]
+fn pg_opendal_read_head_lines(
+    service: &str,
+    path: &str,
+    n: i32,
+    config: JsonB,
+) -> Result<SetOfIterator<'static, String>, String> {
+    read_end_lines(service, path, ReadEnd::First, n, config)
+}
 
+#[pg_extern]
+fn pg_opendal_read_tail_lines(
+    service: &str,
+    path: &str,
+    n: i32,
+    config: JsonB,
+) -> Result<SetOfIterator<'static, String>, String> {
+    read_end_lines(service, path, ReadEnd::Last, n, config)
+}
 
-        if let Some(last_modified) = metadata.last_modified() {
-            entry_info.insert(
-                "last_modified".to_string(),
-                Value::String(last_modified.to_rfc3339()),
-            );
-        }
-        results.push(JsonB(Value::Object(entry_info)));
+/// Reads bytes `start..end` of a file as text. `end` is clamped to the file's size.
+async fn do_read_range_text_async(op: Operator, path: &str, start: u64, end: u64) -> Result<String, String> {
+    if start > end {
+        return Err(format!("start_byte {} is after end_byte {}", start, end));
     }
-    Ok(results)
+    let content_length = op.stat(path).await
+        .map_err(|e| format!("Failed to get metadata for '{}': {}", path, e))?
+        .content_length();
+    let end = end.min(content_length);
+    if start >= end {
+        return Ok(String::new());
+    }
+    let data = op.read_with(path)
+        .range(start..end)
+        .await
+        .map_err(|e| format!("Failed to read file '{}': {}", path, e))?;
+    String::from_utf8(data.to_vec()).map_err(|e| {
+        format!(
+            "Bytes {}..{} of '{}' are not valid UTF-8; the range may split a character: {}",
+            start, end, path, e
+        )
+    })
 }
 
 #[pg_extern]
-fn pg_opendal_list(service: &str, path: &str, config: JsonB) -> Result<Vec<JsonB>, String> {
+fn pg_opendal_read_range_text(
+    service: &str,
+    path: &str,
+    start_byte: i64,
+    end_byte: i64,
+    config: JsonB,
+) -> Result<String, String> {
+    let start = u64::try_from(start_byte).map_err(|_| format!("Invalid start_byte: {}", start_byte))?;
+    let end = u64::try_from(end_byte).map_err(|_| format!("Invalid end_byte: {}", end_byte))?;
     let config_map = jsonb_to_hashmap(config.0)
         .map_err(|e| format!("Failed to parse config: {}", e))?;
     let op = create_operator(service, config_map)
         .map_err(|e| format!("Failed to create operator: {}", e))?;
-    
+
     let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
-    rt.block_on(do_list_async(op, path))
+    rt.block_on(do_read_range_text_async(op, &resolve_path(path), start, end))
+}
+
+/// The text between the first `start_marker` in `content` and the next `end_marker`,
+/// without the markers. `None` when `start_marker` doesn't occur.
+fn between_markers<'a>(content: &'a str, start_marker: &str, end_marker: &str) -> Result<Option<&'a str>, String> {
+    if start_marker.is_empty() || end_marker.is_empty() {
+        return Err("Markers must not be empty".to_string());
+    }
+    let Some(start) = content.find(start_marker).map(|i| i + start_marker.len()) else {
+        return Ok(None);
+    };
+    match content[start..].find(end_marker) {
+        Some(len) => Ok(Some(&content[start..start + len])),
+        None => Err(format!("Found '{}' but no '{}' after it", start_marker, end_marker)),
+    }
 }
 
 #[pg_extern]
-fn pg_opendal_capability(service: &str, config: JsonB) -> Result<JsonB, String> {
+fn pg_opendal_read_between_markers(
+    service: &str,
+    path: &str,
+    start_marker: &str,
+    end_marker: &str,
+    config: JsonB,
+) -> Result<Option<String>, String> {
     let config_map = jsonb_to_hashmap(config.0)
         .map_err(|e| format!("Failed to parse config: {}", e))?;
     let op = create_operator(service, config_map)
         .map_err(|e| format!("Failed to create operator: {}", e))?;
 
-    let capability = op.info().full_capability();
-    let mut cap_info = serde_json::Map::new();
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let content = rt.block_on(do_read_async(op, &resolve_path(path), Utf8Mode::Strict))?;
+    between_markers(&content, start_marker, end_marker).map(|section| section.map(str::to_string))
+}
 
-    cap_info.insert("read".to_string(), Value::Bool(capability.read));
-    cap_info.insert("write".to_string(), Value::Bool(capability.write));
-    cap_info.insert("list".to_string(), Value::Bool(capability.list));
-    cap_info.insert("stat".to_string(), Value::Bool(capability.stat));
-    cap_info.insert("delete".to_string(), Value::Bool(capability.delete));
-    cap_info.insert("copy".to_string(), Value::Bool(capability.copy));
-    cap_info.insert("rename".to_string(), Value::Bool(capability.rename));
-    cap_info.insert("create_dir".to_string(), Value::Bool(capability.create_dir));
+/// Chunk size used when streaming reads, so large objects are never held in memory whole.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
 
-    Ok(JsonB(Value::Object(cap_info)))
+/// Reads one or more objects back to back, `READ_CHUNK_SIZE` bytes at a time. Owns
+/// its runtime so it can back a lazily evaluated set-returning function.
+struct ChunkStream {
+    rt: Runtime,
+    op: Operator,
+    remaining: std::collections::VecDeque<String>,
+    current: Option<(String, opendal::BufferStream)>,
 }
 
-fn jsonb_to_hashmap(value: Value) -> Result<HashMap<String, String>> {
-    let mut map = HashMap::new();
-    if let Value::Object(obj) = value {
-        for (k, v) in obj {
-            if let Value::String(s) = v {
-                map.insert(k, s);
-            } else {
-                return Err(anyhow::anyhow!("Config values must be strings"));
+impl ChunkStream {
+    fn open(rt: Runtime, op: Operator, paths: Vec<String>) -> Self {
+        ChunkStream { rt, op, remaining: paths.into(), current: None }
+    }
+
+    /// The next chunk, moving on to the next object when the current one is exhausted.
+    fn next_chunk(&mut self) -> Result<Option<opendal::Buffer>, String> {
+        loop {
+            let Some((path, stream)) = &mut self.current else {
+                let Some(path) = self.remaining.pop_front() else {
+                    return Ok(None);
+                };
+                let op = &self.op;
+                let stream = self.rt.block_on(async {
+                    op.reader_with(&path)
+                        .chunk(READ_CHUNK_SIZE)
+                        .await
+                        .map_err(|e| format!("Failed to open reader for '{}': {}", path, e))?
+                        .into_stream(..)
+                        .await
+                        .map_err(|e| format!("Failed to read file '{}': {}", path, e))
+                })?;
+                self.current = Some((path, stream));
+                continue;
+            };
+            match self.rt.block_on(stream.try_next()) {
+                Ok(Some(chunk)) => return Ok(Some(chunk)),
+                Ok(None) => self.current = None,
+                Err(e) => return Err(format!("Failed to read file '{}': {}", path, e)),
             }
         }
-        Ok(map)
-    } else {
-        Err(anyhow::anyhow!("Config must be a JSON object"))
     }
 }
 
-fn create_operator(service: &str, config: HashMap<String, String>) -> Result<Operator> {
-    let scheme = Scheme::from_str(service)
-        .map_err(|e| anyhow::anyhow!("Invalid service type '{}': {}", service, e))?;
-    opendal::Operator::via_iter(scheme, config).map_err(|e| anyhow::anyhow!(e))
+/// Yields the lines of one or more objects read back to back, without their `\n` /
+/// `\r\n` terminators. A line may span the end of one object and the start of the next.
+struct LineStream {
+    chunks: ChunkStream,
+    pending: Vec<u8>,
+    start: usize,
+    /// How far past `start` there is known to be no `\n`, so a long line isn't searched
+    /// again from its start each time a chunk arrives.
+    searched: usize,
+    eof: bool,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl LineStream {
+    fn open(rt: Runtime, op: Operator, path: &str) -> Result<Self, String> {
+        Self::open_many(rt, op, vec![path.to_string()])
+    }
 
-    #[test]
-    fn test_json_conversion() {
-        let json = serde_json::json!({ "bucket": "my-bucket" });
-        let map = jsonb_to_hashmap(json).unwrap();
-        assert_eq!(map.get("bucket"), Some(&"my-bucket".to_string()));
+    fn open_many(rt: Runtime, op: Operator, paths: Vec<String>) -> Result<Self, String> {
+        let mut chunks = ChunkStream::open(rt, op, paths);
+        // Fetch the first chunk eagerly so a missing object fails the call up front.
+        let pending = chunks.next_chunk()?.map(|chunk| chunk.to_vec());
+        Ok(LineStream {
+            chunks,
+            eof: pending.is_none(),
+            pending: pending.unwrap_or_default(),
+            start: 0,
+            searched: 0,
+        })
+    }
+}
+
+impl Iterator for LineStream {
+    type Item = Result<Vec<u8>, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let from = self.start + self.searched;
+            if let Some(pos) = self.pending[from..].iter().position(|&b| b == b'\n') {
+                let end = from + pos;
+                let mut line = self.pending[self.start..end].to_vec();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                self.start = end + 1;
+                self.searched = 0;
+                return Some(Ok(line));
+            }
+            self.searched = self.pending.len() - self.start;
+            if self.eof {
+                if self.start == self.pending.len() {
+                    return None;
+                }
+                let line = self.pending[self.start..].to_vec();
+                self.start = self.pending.len();
+                self.searched = 0;
+                return Some(Ok(line));
+            }
+
+            // Drop consumed bytes before buffering the next chunk.
+            self.pending.drain(..self.start);
+            self.start = 0;
+            match self.chunks.next_chunk() {
+                Ok(Some(chunk)) => self.pending.extend(chunk.to_vec()),
+                Ok(None) => self.eof = true,
+                Err(e) => {
+                    self.eof = true;
+                    self.pending.clear();
+                    self.searched = 0;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Yields the records of an object separated by an arbitrary delimiter, which may span
+/// chunk boundaries. Like `LineStream`, a final delimiter doesn't start an empty record.
+struct RecordStream {
+    chunks: ChunkStream,
+    delimiter: Vec<u8>,
+    pending: Vec<u8>,
+    start: usize,
+    /// How far past `start` the delimiter is known not to begin, so a long record isn't
+    /// searched again from its start each time a chunk arrives.
+    searched: usize,
+    eof: bool,
+}
+
+impl RecordStream {
+    fn open(rt: Runtime, op: Operator, path: &str, delimiter: &[u8]) -> Result<Self, String> {
+        if delimiter.is_empty() {
+            return Err("Delimiter must not be empty".to_string());
+        }
+        let mut chunks = ChunkStream::open(rt, op, vec![path.to_string()]);
+        // Fetch the first chunk eagerly so a missing object fails the call up front.
+        let pending = chunks.next_chunk()?.map(|chunk| chunk.to_vec());
+        Ok(RecordStream {
+            chunks,
+            delimiter: delimiter.to_vec(),
+            eof: pending.is_none(),
+            pending: pending.unwrap_or_default(),
+            start: 0,
+            searched: 0,
+        })
+    }
+}
+
+impl Iterator for RecordStream {
+    type Item = Result<Vec<u8>, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let unread = &self.pending[self.start..];
+            let from = self.searched.min(unread.len());
+            if let Some(pos) = unread[from..].windows(self.delimiter.len()).position(|w| w == self.delimiter) {
+                let record = unread[..from + pos].to_vec();
+                self.start += from + pos + self.delimiter.len();
+                self.searched = 0;
+                return Some(Ok(record));
+            }
+            self.searched = unread.len().saturating_sub(self.delimiter.len() - 1);
+            if self.eof {
+                if self.start == self.pending.len() {
+                    return None;
+                }
+                let record = self.pending[self.start..].to_vec();
+                self.start = self.pending.len();
+                return Some(Ok(record));
+            }
+
+            // Drop consumed bytes before buffering the next chunk.
+            self.pending.drain(..self.start);
+            self.start = 0;
+            match self.chunks.next_chunk() {
+                Ok(Some(chunk)) => self.pending.extend(chunk.to_vec()),
+                Ok(None) => self.eof = true,
+                Err(e) => {
+                    self.eof = true;
+                    self.pending.clear();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Drops empty records at the end of a stream, such as those left by blank lines at the
+/// end of a file, keeping empty records that are followed by others.
+fn drop_trailing_empty(
+    records: impl Iterator<Item = Result<Vec<u8>, String>>,
+) -> impl Iterator<Item = Result<Vec<u8>, String>> {
+    let mut held_empty = 0usize;
+    records.flat_map(move |record| match record {
+        Ok(record) if record.is_empty() => {
+            held_empty += 1;
+            Vec::new()
+        }
+        other => {
+            let mut out: Vec<_> = std::iter::repeat_with(|| Ok(Vec::new())).take(held_empty).collect();
+            held_empty = 0;
+            out.push(other);
+            out
+        }
+    })
+}
+
+/// Counts lines like `LineStream` yields them: each `\n`, plus a final line without one.
+fn count_lines(chunks: &mut ChunkStream) -> Result<i64, String> {
+    let mut lines = 0i64;
+    let mut last_byte = None;
+    while let Some(chunk) = chunks.next_chunk()? {
+        for bytes in chunk {
+            lines += bytes.iter().filter(|&&b| b == b'\n').count() as i64;
+            last_byte = bytes.last().copied().or(last_byte);
+        }
+    }
+    if last_byte.is_some_and(|b| b != b'\n') {
+        lines += 1;
+    }
+    Ok(lines)
+}
+
+#[pg_extern]
+fn pg_opendal_count_lines(service: &str, path: &str, config: JsonB) -> Result<i64, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    count_lines(&mut ChunkStream::open(rt, op, vec![resolve_path(path)]))
+}
+
+#[pg_extern]
+fn pg_opendal_count_bytes(service: &str, path: &str, config: JsonB) -> Result<i64, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let path = resolve_path(path);
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(op.stat(&path))
+        .map(|metadata| metadata.content_length() as i64)
+        .map_err(|e| format!("Failed to get stat for '{}': {}", path, e))
+}
+
+/// The hex digest and length of an object, streamed through `algorithm`.
+async fn digest_object_async(op: &Operator, path: &str, algorithm: ChecksumAlgorithm) -> Result<(String, u64), String> {
+    let reader = op.reader_with(path).chunk(READ_CHUNK_SIZE).await
+        .map_err(|e| format!("Failed to open reader for '{}': {}", path, e))?;
+    let mut stream = reader.into_stream(..).await
+        .map_err(|e| format!("Failed to read file '{}': {}", path, e))?;
+    let mut hasher = ChecksumHasher::new(algorithm);
+    let mut size = 0u64;
+    while let Some(chunk) = stream.try_next().await
+        .map_err(|e| format!("Failed to read file '{}': {}", path, e))? {
+        for bytes in chunk {
+            hasher.update(&bytes);
+            size += bytes.len() as u64;
+        }
+    }
+    Ok((hasher.finalize_hex(), size))
+}
+
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn pg_opendal_integrity_check(
+    service: &str,
+    path: &str,
+    expected_sha256: &str,
+    config: JsonB,
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(path, String),
+            name!(matches, bool),
+            name!(actual_sha256, String),
+            name!(file_size, i64),
+        ),
+    >,
+    String,
+> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let path = resolve_path(path);
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let (actual, size) = rt.block_on(digest_object_async(&op, &path, ChecksumAlgorithm::Sha256))?;
+    let matches = actual.eq_ignore_ascii_case(expected_sha256.trim());
+    Ok(TableIterator::once((path, matches, actual, size as i64)))
+}
+
+/// Yields an object in chunks of exactly `size` bytes, the last one possibly smaller.
+struct FixedChunks {
+    chunks: ChunkStream,
+    size: usize,
+    pending: Vec<u8>,
+    start: usize,
+    eof: bool,
+}
+
+impl FixedChunks {
+    fn open(rt: Runtime, op: Operator, path: &str, size: usize) -> Result<Self, String> {
+        let mut chunks = ChunkStream::open(rt, op, vec![path.to_string()]);
+        // Fetch the first chunk eagerly so a missing object fails the call up front.
+        let pending = chunks.next_chunk()?.map(|chunk| chunk.to_vec());
+        Ok(FixedChunks {
+            chunks,
+            size,
+            eof: pending.is_none(),
+            pending: pending.unwrap_or_default(),
+            start: 0,
+        })
+    }
+}
+
+impl Iterator for FixedChunks {
+    type Item = Result<Vec<u8>, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.eof && self.pending.len() - self.start < self.size {
+            // Drop consumed bytes before buffering more.
+            self.pending.drain(..self.start);
+            self.start = 0;
+        }
+        while !self.eof && self.pending.len() < self.size {
+            match self.chunks.next_chunk() {
+                Ok(Some(chunk)) => self.pending.extend(chunk.to_vec()),
+                Ok(None) => self.eof = true,
+                Err(e) => {
+                    self.eof = true;
+                    self.pending.clear();
+                    return Some(Err(e));
+                }
+            }
+        }
+        if self.start == self.pending.len() {
+            return None;
+        }
+        let end = self.pending.len().min(self.start + self.size);
+        let chunk = self.pending[self.start..end].to_vec();
+        self.start = end;
+        Some(Ok(chunk))
+    }
+}
+
+/// Largest chunk `pg_opendal_read_chunks` accepts, safely below the 1 GB field size limit.
+const MAX_READ_CHUNK_SIZE: usize = 512 * 1024 * 1024;
+
+#[pg_extern]
+fn pg_opendal_read_chunks(
+    service: &str,
+    path: &str,
+    chunk_size: i32,
+    config: JsonB,
+) -> Result<SetOfIterator<'static, Vec<u8>>, String> {
+    let chunk_size = usize::try_from(chunk_size)
+        .ok()
+        .filter(|size| (1..=MAX_READ_CHUNK_SIZE).contains(size))
+        .ok_or_else(|| {
+            format!("chunk_size must be between 1 and {}, got {}", MAX_READ_CHUNK_SIZE, chunk_size)
+        })?;
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let chunks = FixedChunks::open(rt, op, &resolve_path(path), chunk_size)?;
+    Ok(SetOfIterator::new(
+        chunks.map(|chunk| chunk.unwrap_or_else(|e| pgrx::error!("{}", e))),
+    ))
+}
+
+#[pg_extern]
+fn pg_opendal_read_concat(service: &str, paths: Vec<String>, config: JsonB) -> Result<Vec<u8>, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let mut chunks = ChunkStream::open(rt, op, resolve_paths(paths));
+    let mut content = Vec::new();
+    while let Some(chunk) = chunks.next_chunk()? {
+        for bytes in chunk {
+            content.extend_from_slice(&bytes);
+        }
+    }
+    Ok(content)
+}
+
+#[pg_extern]
+fn pg_opendal_read_concat_lines(
+    service: &str,
+    paths: Vec<String>,
+    config: JsonB,
+) -> Result<SetOfIterator<'static, String>, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let lines = LineStream::open_many(rt, op, resolve_paths(paths))?;
+    Ok(SetOfIterator::new(lines.map(|line| {
+        let line = line.unwrap_or_else(|e| pgrx::error!("{}", e));
+        String::from_utf8(line)
+            .unwrap_or_else(|e| pgrx::error!("Failed to convert data to UTF-8: {}", e))
+    })))
+}
+
+/// Splits `line` into columns of the given character widths. Columns starting past
+/// the end of a short line are `None`; a column cut short keeps what is there.
+fn slice_fixed_width(line: &str, widths: &[usize], trim: bool) -> Vec<Option<String>> {
+    let mut chars = line.chars();
+    widths
+        .iter()
+        .map(|&width| {
+            let column: String = chars.by_ref().take(width).collect();
+            if column.is_empty() {
+                None
+            } else if trim {
+                Some(column.trim().to_string())
+            } else {
+                Some(column)
+            }
+        })
+        .collect()
+}
+
+#[pg_extern]
+fn pg_opendal_read_fixed(
+    service: &str,
+    path: &str,
+    widths: Vec<i32>,
+    config: JsonB,
+    trim: default!(bool, true),
+) -> Result<SetOfIterator<'static, Vec<Option<String>>>, String> {
+    let widths = widths
+        .into_iter()
+        .map(|w| usize::try_from(w).ok().filter(|&w| w > 0).ok_or(format!("Invalid width: {}", w)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let path = resolve_path(path);
+    let lines = LineStream::open(rt, op, &path)?;
+    Ok(SetOfIterator::new(lines.enumerate().map(move |(i, line)| {
+        let line = line.unwrap_or_else(|e| pgrx::error!("{}", e));
+        let line = String::from_utf8(line).unwrap_or_else(|e| {
+            pgrx::error!("Failed to convert line {} of '{}' to UTF-8: {}", i + 1, path, e)
+        });
+        slice_fixed_width(&line, &widths, trim)
+    })))
+}
+
+/// Lines `start_line..start_line + line_count` of `lines`, counting from 1. Lines past
+/// the end of the selection are never read.
+fn lines_range(
+    lines: impl Iterator<Item = Result<Vec<u8>, String>>,
+    start_line: i64,
+    line_count: i64,
+) -> Result<impl Iterator<Item = (usize, Result<Vec<u8>, String>)>, String> {
+    let skip = start_line
+        .checked_sub(1)
+        .and_then(|skip| usize::try_from(skip).ok())
+        .ok_or_else(|| format!("Invalid start_line: {}, lines are numbered from 1", start_line))?;
+    let take = usize::try_from(line_count).map_err(|_| format!("Invalid line_count: {}", line_count))?;
+    Ok(lines.enumerate().skip(skip).take(take))
+}
+
+#[pg_extern]
+fn pg_opendal_read_lines_range(
+    service: &str,
+    path: &str,
+    start_line: i64,
+    line_count: i64,
+    config: JsonB,
+) -> Result<SetOfIterator<'static, String>, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let path = resolve_path(path);
+    let lines = lines_range(LineStream::open(rt, op, &path)?, start_line, line_count)?;
+    Ok(SetOfIterator::new(lines.map(move |(i, line)| {
+        let line = line.unwrap_or_else(|e| pgrx::error!("{}", e));
+        String::from_utf8(line).unwrap_or_else(|e| {
+            pgrx::error!("Failed to convert line {} of '{}' to UTF-8: {}", i + 1, path, e)
+        })
+    })))
+}
+
+/// `pg_opendal_read_lines_range` taking `line_count` as an int.
+#[pg_extern]
+fn pg_opendal_read_line_range(
+    service: &str,
+    path: &str,
+    start_line: i64,
+    line_count: i32,
+    config: JsonB,
+) -> Result<SetOfIterator<'static, String>, String> {
+    pg_opendal_read_lines_range(service, path, start_line, line_count.into(), config)
+}
+
+#[pg_extern]
+fn pg_opendal_read_table(
+    service: &str,
+    path: &str,
+    row_delim: &str,
+    col_delim: &str,
+    config: JsonB,
+) -> Result<SetOfIterator<'static, Vec<String>>, String> {
+    if col_delim.is_empty() {
+        return Err("Column delimiter must not be empty".to_string());
+    }
+    let col_delim = col_delim.to_string();
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let path = resolve_path(path);
+    let rows = RecordStream::open(rt, op, &path, row_delim.as_bytes())?;
+    Ok(SetOfIterator::new(drop_trailing_empty(rows).enumerate().map(move |(i, row)| {
+        let row = row.unwrap_or_else(|e| pgrx::error!("{}", e));
+        let row = String::from_utf8(row).unwrap_or_else(|e| {
+            pgrx::error!("Failed to convert row {} of '{}' to UTF-8: {}", i + 1, path, e)
+        });
+        row.split(col_delim.as_str()).map(str::to_string).collect()
+    })))
+}
+
+/// How `pg_opendal_read_csv_jsonb` converts a column's fields to JSON.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CsvType {
+    /// Booleans and JSON numbers become typed values, anything else a string.
+    Infer,
+    Text,
+    Number,
+    Integer,
+    Boolean,
+}
+
+impl FromStr for CsvType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "infer" => Ok(CsvType::Infer),
+            "text" => Ok(CsvType::Text),
+            "number" => Ok(CsvType::Number),
+            "integer" => Ok(CsvType::Integer),
+            "boolean" => Ok(CsvType::Boolean),
+            other => Err(format!(
+                "Invalid type '{}', expected one of 'infer', 'text', 'number', 'integer' or 'boolean'",
+                other
+            )),
+        }
+    }
+}
+
+struct CsvOptions {
+    columns: Option<Vec<String>>,
+    header: bool,
+    delimiter: u8,
+    null: String,
+    types: HashMap<String, CsvType>,
+    skip_bad_rows: bool,
+}
+
+fn parse_csv_options(options: Option<Value>) -> Result<CsvOptions, String> {
+    let options = match options {
+        None | Some(Value::Null) => serde_json::Map::new(),
+        Some(Value::Object(options)) => options,
+        Some(_) => return Err("options must be a JSON object".to_string()),
+    };
+    let mut parsed = CsvOptions {
+        columns: None,
+        header: true,
+        delimiter: b',',
+        null: String::new(),
+        types: HashMap::new(),
+        skip_bad_rows: false,
+    };
+    let mut header = None;
+    for (key, value) in options {
+        match (key.as_str(), value) {
+            ("columns", Value::Array(names)) => {
+                let names = names
+                    .into_iter()
+                    .map(|name| match name {
+                        Value::String(name) => Ok(name),
+                        _ => Err("columns must be an array of strings".to_string()),
+                    })
+                    .collect::<Result<_, _>>()?;
+                parsed.columns = Some(names);
+            }
+            ("header", Value::Bool(value)) => header = Some(value),
+            ("delimiter", Value::String(delimiter)) if delimiter.len() == 1 => {
+                parsed.delimiter = delimiter.as_bytes()[0];
+            }
+            ("null", Value::String(null)) => parsed.null = null,
+            ("types", Value::Object(types)) => {
+                for (column, ty) in types {
+                    let ty = ty.as_str().ok_or("types must map column names to type names")?.parse()?;
+                    parsed.types.insert(column, ty);
+                }
+            }
+            ("on_error", Value::String(on_error)) if on_error == "error" || on_error == "skip" => {
+                parsed.skip_bad_rows = on_error == "skip";
+            }
+            ("columns" | "header" | "delimiter" | "null" | "types" | "on_error", value) => {
+                return Err(format!("Invalid value for option '{}': {}", key, value));
+            }
+            _ => return Err(format!("Unknown option '{}'", key)),
+        }
+    }
+    // Given column names replace the header, so by default there's none to skip.
+    parsed.header = header.unwrap_or(parsed.columns.is_none());
+    Ok(parsed)
+}
+
+fn csv_value(field: &str, ty: CsvType, null: &str) -> Result<Value, String> {
+    if field == null {
+        return Ok(Value::Null);
+    }
+    let parse_bool = |field: &str| match field.to_ascii_lowercase().as_str() {
+        "true" | "t" | "yes" | "y" | "1" => Some(true),
+        "false" | "f" | "no" | "n" | "0" => Some(false),
+        _ => None,
+    };
+    match ty {
+        CsvType::Infer => Ok(match field {
+            "true" | "TRUE" => Value::Bool(true),
+            "false" | "FALSE" => Value::Bool(false),
+            // Only JSON's number syntax, so values like "007" or "1e" stay strings.
+            _ => serde_json::from_str::<serde_json::Number>(field)
+                .map_or_else(|_| Value::String(field.to_string()), Value::Number),
+        }),
+        CsvType::Text => Ok(Value::String(field.to_string())),
+        CsvType::Number => field
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(|number| match field.trim().parse::<i64>() {
+                Ok(integer) => Value::Number(integer.into()),
+                Err(_) => Value::Number(number),
+            })
+            .ok_or_else(|| format!("'{}' is not a number", field)),
+        CsvType::Integer => field
+            .trim()
+            .parse::<i64>()
+            .map(|integer| Value::Number(integer.into()))
+            .map_err(|_| format!("'{}' is not an integer", field)),
+        CsvType::Boolean => parse_bool(field.trim())
+            .map(Value::Bool)
+            .ok_or_else(|| format!("'{}' is not a boolean", field)),
+    }
+}
+
+/// Converts CSV records to JSON objects keyed by column name. Each item is a fatal error,
+/// or a row that is either converted or bad; bad rows are described with their line number.
+struct CsvRows<R: std::io::Read> {
+    reader: csv::Reader<R>,
+    columns: Vec<String>,
+    types: Vec<CsvType>,
+    null: String,
+    record: csv::StringRecord,
+}
+
+impl<R: std::io::Read> CsvRows<R> {
+    fn new(input: R, options: CsvOptions) -> Result<Self, String> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(options.header)
+            .delimiter(options.delimiter)
+            .flexible(true)
+            .from_reader(input);
+        let columns = match options.columns {
+            Some(columns) => columns,
+            None => reader
+                .headers()
+                .map_err(|e| format!("Failed to read header: {}", e))?
+                .iter()
+                .map(str::to_string)
+                .collect(),
+        };
+        if columns.is_empty() {
+            return Err("No columns: the file is empty and no column names were given".to_string());
+        }
+        let mut seen = std::collections::HashSet::new();
+        if let Some(duplicate) = columns.iter().find(|column| !seen.insert(column.as_str())) {
+            return Err(format!("Duplicate column '{}'", duplicate));
+        }
+        if let Some(unknown) = options.types.keys().find(|column| !columns.contains(column)) {
+            return Err(format!("types names unknown column '{}'", unknown));
+        }
+        let types = columns
+            .iter()
+            .map(|column| options.types.get(column).copied().unwrap_or(CsvType::Infer))
+            .collect();
+        Ok(CsvRows { reader, columns, types, null: options.null, record: csv::StringRecord::new() })
+    }
+}
+
+impl<R: std::io::Read> Iterator for CsvRows<R> {
+    type Item = Result<Result<Value, String>, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let error_line = |e: &csv::Error| e.position().map_or(0, csv::Position::line);
+        match self.reader.read_record(&mut self.record) {
+            Ok(false) => return None,
+            Ok(true) => {}
+            Err(e) => {
+                return Some(match e.kind() {
+                    csv::ErrorKind::Io(_) => Err(e.to_string()),
+                    _ => Ok(Err(format!("Line {}: {}", error_line(&e), e))),
+                });
+            }
+        }
+        let line = self.record.position().map_or(0, csv::Position::line);
+        if self.record.len() != self.columns.len() {
+            return Some(Ok(Err(format!(
+                "Line {}: found {} fields, expected {}",
+                line,
+                self.record.len(),
+                self.columns.len()
+            ))));
+        }
+        let mut row = serde_json::Map::with_capacity(self.columns.len());
+        for ((column, ty), field) in self.columns.iter().zip(&self.types).zip(self.record.iter()) {
+            match csv_value(field, *ty, &self.null) {
+                Ok(value) => {
+                    row.insert(column.clone(), value);
+                }
+                Err(e) => return Some(Ok(Err(format!("Line {}, column '{}': {}", line, column, e)))),
+            }
+        }
+        Some(Ok(Ok(Value::Object(row))))
+    }
+}
+
+#[pg_extern]
+fn pg_opendal_read_csv_jsonb(
+    service: &str,
+    path: &str,
+    config: JsonB,
+    options: default!(Option<JsonB>, "NULL"),
+) -> Result<SetOfIterator<'static, JsonB>, String> {
+    let options = parse_csv_options(options.map(|options| options.0))?;
+    let skip_bad_rows = options.skip_bad_rows;
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let path = resolve_path(path);
+    let input = ChunkReader::new(ChunkStream::open(rt, op, vec![path.clone()]));
+    let rows = CsvRows::new(input, options).map_err(|e| format!("Failed to parse '{}': {}", path, e))?;
+    Ok(SetOfIterator::new(rows.filter_map(move |row| {
+        match row.unwrap_or_else(|e| pgrx::error!("Failed to read '{}': {}", path, e)) {
+            Ok(row) => Some(JsonB(row)),
+            Err(e) if skip_bad_rows => {
+                pgrx::warning!("Skipping bad row of '{}': {}", path, e);
+                None
+            }
+            Err(e) => pgrx::error!("Bad row in '{}': {}", path, e),
+        }
+    })))
+}
+
+/// Splits an RFC 6901 JSON Pointer into its unescaped reference tokens. The empty
+/// pointer refers to the whole document.
+fn parse_json_pointer(pointer: &str) -> Result<Vec<String>, String> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(rest) = pointer.strip_prefix('/') else {
+        return Err(format!("Invalid JSON pointer '{}': it must be empty or start with '/'", pointer));
+    };
+    rest.split('/')
+        .map(|token| {
+            let mut unescaped = String::with_capacity(token.len());
+            let mut chars = token.chars();
+            while let Some(c) = chars.next() {
+                if c != '~' {
+                    unescaped.push(c);
+                    continue;
+                }
+                match chars.next() {
+                    Some('0') => unescaped.push('~'),
+                    Some('1') => unescaped.push('/'),
+                    _ => return Err(format!("Invalid JSON pointer '{}': '~' must be followed by '0' or '1'", pointer)),
+                }
+            }
+            Ok(unescaped)
+        })
+        .collect()
+}
+
+/// Walks a JSON document towards the value at `tokens`, skipping everything else without
+/// building it. The value found is stored in `found`, and parsing is then cut short with
+/// an error so the rest of the document is never read.
+struct JsonPointerSeed<'a> {
+    tokens: &'a [String],
+    found: &'a std::cell::RefCell<Option<Value>>,
+}
+
+impl<'de> serde::de::DeserializeSeed<'de> for JsonPointerSeed<'_> {
+    type Value = ();
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        if self.tokens.is_empty() {
+            *self.found.borrow_mut() = Some(serde::Deserialize::deserialize(deserializer)?);
+            return Err(serde::de::Error::custom("value found"));
+        }
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for JsonPointerSeed<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a JSON value")
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            if key == self.tokens[0] {
+                map.next_value_seed(JsonPointerSeed { tokens: &self.tokens[1..], found: self.found })?;
+            } else {
+                map.next_value::<serde::de::IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        // Array indexes are plain decimal numbers, without leading zeros.
+        let token = &self.tokens[0];
+        let index = match token.parse::<usize>() {
+            Ok(index) if token.bytes().all(|b| b.is_ascii_digit()) && (token == "0" || !token.starts_with('0')) => {
+                Some(index)
+            }
+            _ => None,
+        };
+        let mut position = 0;
+        loop {
+            if Some(position) == index {
+                let seed = JsonPointerSeed { tokens: &self.tokens[1..], found: self.found };
+                if seq.next_element_seed(seed)?.is_none() {
+                    return Ok(());
+                }
+            } else if seq.next_element::<serde::de::IgnoredAny>()?.is_none() {
+                return Ok(());
+            }
+            position += 1;
+        }
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_unit<E>(self) -> Result<(), E> {
+        Ok(())
+    }
+}
+
+/// The value at `pointer` in the JSON document read from `input`, or `None` when the
+/// pointer doesn't resolve. Reading stops once the value has been parsed.
+fn read_json_pointer<R: std::io::Read>(input: R, pointer: &str) -> Result<Option<Value>, String> {
+    let tokens = parse_json_pointer(pointer)?;
+    let found = std::cell::RefCell::new(None);
+    let mut deserializer = serde_json::Deserializer::from_reader(input);
+    let result = serde::de::DeserializeSeed::deserialize(
+        JsonPointerSeed { tokens: &tokens, found: &found },
+        &mut deserializer,
+    );
+    if let Some(value) = found.into_inner() {
+        return Ok(Some(value));
+    }
+    result.and_then(|()| deserializer.end()).map_err(|e| format!("Invalid JSON: {}", e))?;
+    Ok(None)
+}
+
+#[pg_extern]
+fn pg_opendal_read_json_pointer(service: &str, path: &str, pointer: &str, config: JsonB) -> Result<JsonB, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let path = resolve_path(path);
+    let input = ChunkReader::new(ChunkStream::open(rt, op, vec![path.clone()]));
+    match read_json_pointer(input, pointer).map_err(|e| format!("Failed to read '{}': {}", path, e))? {
+        Some(value) => Ok(JsonB(value)),
+        None => Err(format!("JSON pointer '{}' does not resolve in '{}'", pointer, path)),
+    }
+}
+
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// An insertable column of an import's target table. `required` columns are NOT NULL
+/// with no default, so a row can't be inserted without them.
+struct TargetColumn {
+    name: String,
+    required: bool,
+}
+
+fn target_columns(client: &pgrx::spi::SpiClient<'_>, table: pg_sys::Oid) -> Result<Vec<TargetColumn>, String> {
+    let rows = client
+        .select(
+            "SELECT attname::text, attnotnull AND NOT atthasdef AND attidentity = '' \
+             FROM pg_attribute \
+             WHERE attrelid = $1 AND attnum > 0 AND NOT attisdropped AND attgenerated = '' \
+             ORDER BY attnum",
+            None,
+            &[table.into()],
+        )
+        .map_err(|e| format!("Failed to look up columns of the target table: {}", e))?;
+    rows.map(|row| {
+        let name: Option<String> = row.get(1).map_err(|e| e.to_string())?;
+        let required: Option<bool> = row.get(2).map_err(|e| e.to_string())?;
+        Ok(TargetColumn { name: name.unwrap_or_default(), required: required.unwrap_or(false) })
+    })
+    .collect()
+}
+
+/// Renames a JSON object's fields to target columns, dropping fields that don't map to
+/// a column. Without a mapping fields keep their names. Fails with the names of any
+/// required columns left missing or null.
+fn map_json_fields(
+    object: serde_json::Map<String, Value>,
+    mapping: Option<&HashMap<String, String>>,
+    columns: &[TargetColumn],
+) -> Result<serde_json::Map<String, Value>, Vec<String>> {
+    let mut row = serde_json::Map::new();
+    for (field, value) in object {
+        let column = match mapping {
+            Some(mapping) => match mapping.get(&field) {
+                Some(column) => column.clone(),
+                None => continue,
+            },
+            None => field,
+        };
+        if columns.iter().any(|c| c.name == column) {
+            row.insert(column, value);
+        }
+    }
+
+    let missing: Vec<String> = columns
+        .iter()
+        .filter(|c| c.required && matches!(row.get(&c.name), None | Some(Value::Null)))
+        .map(|c| c.name.clone())
+        .collect();
+    if missing.is_empty() {
+        Ok(row)
+    } else {
+        Err(missing)
+    }
+}
+
+#[pg_extern]
+fn pg_opendal_import_json_lines(
+    service: &str,
+    path: &str,
+    target_table: pgrx::PgRelation,
+    config: JsonB,
+    column_mapping: default!(Option<JsonB>, "NULL"),
+) -> Result<i64, String> {
+    let mapping = column_mapping
+        .map(|mapping| {
+            jsonb_to_hashmap(mapping.0).map_err(|e| format!("Failed to parse column_mapping: {}", e))
+        })
+        .transpose()?;
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let path = resolve_path(path);
+    let table = format!(
+        "{}.{}",
+        quote_ident(target_table.namespace()),
+        quote_ident(target_table.name())
+    );
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let lines = LineStream::open(rt, op, &path)?;
+
+    Spi::connect_mut(|client| {
+        let columns = target_columns(client, target_table.oid())?;
+        let mut inserted = 0i64;
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let object = match serde_json::from_slice(&line) {
+                Ok(Value::Object(object)) => object,
+                Ok(_) => return Err(format!("Line {} of '{}' is not a JSON object", i + 1, path)),
+                Err(e) => return Err(format!("Failed to parse line {} of '{}': {}", i + 1, path, e)),
+            };
+            let row = match map_json_fields(object, mapping.as_ref(), &columns) {
+                Ok(row) => row,
+                Err(missing) => {
+                    pgrx::warning!(
+                        "Skipping line {} of '{}': missing required fields {}",
+                        i + 1, path, missing.join(", ")
+                    );
+                    continue;
+                }
+            };
+            if row.is_empty() {
+                pgrx::warning!("Skipping line {} of '{}': no fields match columns of {}", i + 1, path, table);
+                continue;
+            }
+
+            let names = row.keys().map(|name| quote_ident(name)).collect::<Vec<_>>().join(", ");
+            let query = format!(
+                "INSERT INTO {table} ({names}) SELECT {names} FROM jsonb_populate_record(NULL::{table}, $1)"
+            );
+            client
+                .update(&query, None, &[JsonB(Value::Object(row)).into()])
+                .map_err(|e| format!("Failed to insert line {} of '{}': {}", i + 1, path, e))?;
+            inserted += 1;
+        }
+        Ok(inserted)
+    })
+}
+
+async fn do_write_async(op: Operator, path: &str, content: &[u8]) -> Result<bool, String> {
+    op.write(path, content.to_owned())
+        .await
+        .map(|_| true)
+        .map_err(|e| format!("Failed to write to '{}': {}", path, e))
+}
+
+/// User metadata key recording the idempotency key a file was written with.
+const IDEMPOTENCY_KEY_METADATA: &str = "pg-opendal-idempotency-key";
+
+/// Whether `op` can store an idempotency key with a file and read it back.
+fn supports_idempotency_key(op: &Operator) -> bool {
+    let capability = op.info().full_capability();
+    capability.write_with_user_metadata && capability.stat_has_user_metadata
+}
+
+/// Whether `op` can make a write conditional on the file not having changed since it was
+/// checked, so concurrent writes with the same idempotency key can't both happen.
+fn supports_conditional_write(op: &Operator) -> bool {
+    let capability = op.info().full_capability();
+    capability.write_with_if_not_exists && capability.write_with_if_match
+}
+
+/// Attempts made by an idempotent write whose file keeps changing between check and write.
+const IDEMPOTENT_WRITE_MAX_ATTEMPTS: u32 = 5;
+
+/// Writes `content` to `path` tagged with `key`, unless the file there already carries
+/// `key`, i.e. this write was already done. Returns whether it wrote. Where the service
+/// supports it, the write only succeeds if the file is still as checked; otherwise it is
+/// checked again, so of two concurrent writes with the same key only one goes through.
+async fn do_write_idempotent_async(op: &Operator, path: &str, content: &[u8], key: &str) -> Result<bool, String> {
+    let conditional = supports_conditional_write(op);
+    for _ in 0..IDEMPOTENT_WRITE_MAX_ATTEMPTS {
+        let mut write = op
+            .write_with(path, content.to_owned())
+            .user_metadata([(IDEMPOTENCY_KEY_METADATA.to_string(), key.to_string())]);
+        match op.stat(path).await {
+            Ok(metadata) => {
+                let written_with = metadata.user_metadata().and_then(|meta| meta.get(IDEMPOTENCY_KEY_METADATA));
+                if written_with.is_some_and(|written_with| written_with == key) {
+                    return Ok(false);
+                }
+                if let (true, Some(etag)) = (conditional, metadata.etag()) {
+                    write = write.if_match(etag);
+                }
+            }
+            Err(e) if e.kind() == opendal::ErrorKind::NotFound => {
+                write = write.if_not_exists(conditional);
+            }
+            Err(e) => return Err(format!("Failed to get stat for '{}': {}", path, e)),
+        }
+        match write.await {
+            Ok(_) => return Ok(true),
+            Err(e) if e.kind() == opendal::ErrorKind::ConditionNotMatch => continue,
+            Err(e) => return Err(format!("Failed to write to '{}': {}", path, e)),
+        }
+    }
+    Err(format!(
+        "Failed to write to '{}': it changed between check and write {} times",
+        path, IDEMPOTENT_WRITE_MAX_ATTEMPTS
+    ))
+}
+
+#[pg_extern]
+fn pg_opendal_write(
+    service: &str,
+    path: &str,
+    content: &str,
+    config: JsonB,
+    idempotency_key: default!(Option<&str>, "NULL"),
+) -> Result<bool, String> {
+    if idempotency_key == Some("") {
+        return Err("idempotency_key must not be empty".to_string());
+    }
+    let mut config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let sidecar = take_checksum_sidecar_config(&mut config_map)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let path = resolve_path(path);
+    let written = match idempotency_key {
+        Some(key) if supports_idempotency_key(&op) => {
+            if !supports_conditional_write(&op) {
+                pgrx::warning!(
+                    "Service '{}' can't write conditionally; concurrent writes of '{}' with the same idempotency key may both go through",
+                    op.info().scheme(),
+                    path
+                );
+            }
+            let written = rt.block_on(do_write_idempotent_async(&op, &path, content.as_bytes(), key))?;
+            if !written {
+                pgrx::notice!("Skipped writing '{}': already written with idempotency key '{}'", path, key);
+            }
+            written
+        }
+        Some(_) => {
+            pgrx::warning!(
+                "Service '{}' does not store user metadata; writing '{}' without an idempotency check",
+                op.info().scheme(),
+                path
+            );
+            rt.block_on(do_write_async(op.clone(), &path, content.as_bytes()))?
+        }
+        None => rt.block_on(do_write_async(op.clone(), &path, content.as_bytes()))?,
+    };
+    if let (true, Some(sidecar)) = (written, &sidecar) {
+        let digest = ChecksumHasher::digest_hex(sidecar.algorithm, content.as_bytes());
+        rt.block_on(write_checksum_sidecar_async(&op, sidecar, &path, &digest))?;
+    }
+    Ok(written)
+}
+
+/// Matches a `{{key}}` template placeholder, allowing spaces inside the braces.
+static TEMPLATE_PLACEHOLDER: std::sync::LazyLock<regex::Regex> =
+    std::sync::LazyLock::new(|| regex::Regex::new(r"\{\{\s*([^{}\s]+)\s*\}\}").unwrap());
+
+/// Replaces `{{key}}` placeholders in `template` with the values of `params`. Strings are
+/// inserted as they are, null as nothing, and other values as JSON. Substituted text is
+/// never expanded further. Placeholders without a key in `params` are left as they are,
+/// and returned, in order of first appearance.
+fn render_template(template: &str, params: &Value) -> Result<(String, Vec<String>), String> {
+    let Value::Object(params) = params else {
+        return Err("params must be a JSON object".to_string());
+    };
+    let mut missing: Vec<String> = Vec::new();
+    let rendered = TEMPLATE_PLACEHOLDER.replace_all(template, |caps: &regex::Captures| {
+        let key = &caps[1];
+        match params.get(key) {
+            Some(Value::String(value)) => value.clone(),
+            Some(Value::Null) => String::new(),
+            Some(value) => value.to_string(),
+            None => {
+                if !missing.iter().any(|m| m == key) {
+                    missing.push(key.to_string());
+                }
+                caps[0].to_string()
+            }
+        }
+    });
+    Ok((rendered.into_owned(), missing))
+}
+
+#[pg_extern]
+fn pg_opendal_write_formatted(
+    service: &str,
+    path: &str,
+    template: &str,
+    params: JsonB,
+    config: JsonB,
+) -> Result<bool, String> {
+    let (content, missing) = render_template(template, &params.0)?;
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let path = resolve_path(path);
+    for key in &missing {
+        pgrx::warning!("No value for placeholder '{{{{{}}}}}' in template for '{}'; leaving it as is", key, path);
+    }
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_write_async(op, &path, content.as_bytes()))
+}
+
+/// Name of the sequence numbering files written under `prefix`: a hash of the service, the
+/// storage the config points at (see `config_fingerprint`) and the prefix, so it's stable
+/// across calls, separate per bucket, and within PostgreSQL's identifier limit.
+fn write_sequence_name(service: &str, fingerprint: &str, prefix: &str) -> String {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    for part in [service, fingerprint, prefix] {
+        hasher.update(part.as_bytes());
+        hasher.update([0u8]);
+    }
+    format!("pg_opendal_seq_{}", &hex::encode(hasher.finalize())[..16])
+}
+
+fn write_sequence_path(prefix: &str, number: i64, padding: i32) -> Result<String, String> {
+    let width = usize::try_from(padding)
+        .ok()
+        .filter(|&width| width <= 19)
+        .ok_or_else(|| format!("Invalid padding: {}, expected 0 to 19", padding))?;
+    Ok(format!("{}{:0width$}.txt", prefix, number, width = width))
+}
+
+// Write sequences live in the extension's schema whatever the caller's search_path, and
+// are created by the extension owner, so callers need no CREATE privilege. The creation is
+// serialized with an advisory lock, so concurrent first calls don't race, and skipped once
+// the sequence exists, so later calls raise no "already exists" notice.
+extension_sql!(
+    r#"
+CREATE FUNCTION pg_opendal_write_sequence_next(sequence_name text) RETURNS bigint
+    LANGUAGE plpgsql SECURITY DEFINER SET search_path = pg_catalog, pg_temp AS $$
+DECLARE
+    qualified text;
+BEGIN
+    IF sequence_name !~ '^pg_opendal_seq_[0-9a-f]{16}$' THEN
+        RAISE EXCEPTION 'Invalid write sequence name %', sequence_name;
+    END IF;
+    qualified := format('@extschema@.%I', sequence_name);
+    IF to_regclass(qualified) IS NULL THEN
+        PERFORM pg_advisory_xact_lock(hashtext(qualified));
+        IF to_regclass(qualified) IS NULL THEN
+            EXECUTE format('CREATE SEQUENCE %s', qualified);
+            EXECUTE format('GRANT SELECT ON SEQUENCE %s TO PUBLIC', qualified);
+        END IF;
+    END IF;
+    RETURN nextval(qualified::regclass);
+END
+$$;
+"#,
+    name = "create_write_sequence_next",
+);
+
+/// The next number of the prefix's sequence, creating the sequence on first use.
+fn next_write_sequence(sequence: &str) -> Result<i64, String> {
+    Spi::get_one_with_args::<i64>("SELECT pg_opendal_write_sequence_next($1)", &[sequence.into()])
+        .map_err(|e| format!("Failed to get the next value of sequence '{}': {}", sequence, e))?
+        .ok_or_else(|| format!("Sequence '{}' returned no value", sequence))
+}
+
+#[pg_extern]
+fn pg_opendal_write_sequence(
+    service: &str,
+    prefix: &str,
+    content: &str,
+    config: JsonB,
+    padding: default!(i32, 6),
+) -> Result<String, String> {
+    let prefix = resolve_path(prefix);
+    write_sequence_path(&prefix, 0, padding)?;
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let sequence = write_sequence_name(service, &config_fingerprint(&config_map), &prefix);
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let number = next_write_sequence(&sequence)?;
+    let path = write_sequence_path(&prefix, number, padding)?;
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_write_async(op, &path, content.as_bytes()))?;
+    Ok(path)
+}
+
+/// Splits `content` into parts of at most `max_part_bytes`, only after a `\n`, so no line
+/// is broken across parts. A line longer than the limit is an error.
+fn split_at_lines(content: &str, max_part_bytes: usize) -> Result<Vec<&str>, String> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut end = 0;
+    for line in content.split_inclusive('\n') {
+        if line.len() > max_part_bytes {
+            return Err(format!(
+                "Line at byte {} is {} bytes, longer than max_part_bytes {}",
+                end,
+                line.len(),
+                max_part_bytes
+            ));
+        }
+        if end + line.len() - start > max_part_bytes {
+            parts.push(&content[start..end]);
+            start = end;
+        }
+        end += line.len();
+    }
+    if end > start {
+        parts.push(&content[start..end]);
+    }
+    Ok(parts)
+}
+
+/// The path of part `part`: `{part}` in the template replaced with its zero-padded number.
+fn part_path(path_template: &str, part: usize) -> String {
+    path_template.replace("{part}", &format!("{:04}", part))
+}
+
+async fn do_write_parted_async(
+    op: &Operator,
+    path_template: &str,
+    content: &str,
+    max_part_bytes: usize,
+) -> Result<Vec<(String, i64)>, String> {
+    if !path_template.contains("{part}") {
+        return Err(format!("Path template '{}' has no {{part}} placeholder", path_template));
+    }
+    let mut rows = Vec::new();
+    for (i, part) in split_at_lines(content, max_part_bytes)?.into_iter().enumerate() {
+        let path = part_path(path_template, i);
+        do_write_async(op.clone(), &path, part.as_bytes()).await?;
+        rows.push((path, part.len() as i64));
+    }
+    Ok(rows)
+}
+
+#[pg_extern]
+fn pg_opendal_write_parted(
+    service: &str,
+    path_template: &str,
+    content: &str,
+    max_part_bytes: i64,
+    config: JsonB,
+) -> Result<TableIterator<'static, (name!(part_path, String), name!(bytes_written, i64))>, String> {
+    let max_part_bytes = usize::try_from(max_part_bytes)
+        .ok()
+        .filter(|&max| max > 0)
+        .ok_or_else(|| format!("Invalid max_part_bytes: {}", max_part_bytes))?;
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let rows = rt.block_on(do_write_parted_async(&op, &resolve_path(path_template), content, max_part_bytes))?;
+    Ok(TableIterator::new(rows))
+}
+
+/// Terminates each line with `line_ending`, so appended lines continue the file cleanly.
+fn join_lines(lines: &[Option<String>], line_ending: &str) -> Result<Vec<u8>, String> {
+    if !matches!(line_ending, "\n" | "\r\n" | "\r") {
+        return Err(format!("Invalid line_ending {:?}, expected E'\\n', E'\\r\\n' or E'\\r'", line_ending));
+    }
+    let mut content = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        let line = line.as_deref().ok_or_else(|| format!("Line {} is NULL", i + 1))?;
+        content.extend_from_slice(line.as_bytes());
+        content.extend_from_slice(line_ending.as_bytes());
+    }
+    Ok(content)
+}
+
+/// Appends natively where the service supports it. Elsewhere the file is read and
+/// rewritten, which isn't atomic with respect to concurrent writers.
+async fn do_append_async(op: Operator, path: &str, content: &[u8]) -> Result<(), String> {
+    if op.info().full_capability().write_can_append {
+        return op.write_with(path, content.to_owned())
+            .append(true)
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("Failed to append to '{}': {}", path, e));
+    }
+    let mut existing = match op.read(path).await {
+        Ok(buffer) => buffer.to_vec(),
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(format!("Failed to read from '{}': {}", path, e)),
+    };
+    existing.extend_from_slice(content);
+    op.write(path, existing)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Failed to write to '{}': {}", path, e))
+}
+
+#[pg_extern]
+fn pg_opendal_write_lines(
+    service: &str,
+    path: &str,
+    lines: Vec<Option<String>>,
+    config: JsonB,
+    line_ending: default!(&str, "E'\\n'"),
+) -> Result<i64, String> {
+    let content = join_lines(&lines, line_ending)?;
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_write_async(op, &resolve_path(path), &content))?;
+    Ok(content.len() as i64)
+}
+
+#[pg_extern]
+fn pg_opendal_write_lines_append(
+    service: &str,
+    path: &str,
+    lines: Vec<Option<String>>,
+    config: JsonB,
+    line_ending: default!(&str, "E'\\n'"),
+) -> Result<i64, String> {
+    let content = join_lines(&lines, line_ending)?;
+    if content.is_empty() {
+        return Ok(0);
+    }
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_append_async(op, &resolve_path(path), &content))?;
+    Ok(content.len() as i64)
+}
+
+async fn do_write_atomic_async(
+    op: Operator,
+    final_path: &str,
+    content: &[u8],
+    tmp_suffix: &str,
+) -> Result<bool, String> {
+    if !op.info().full_capability().rename {
+        return Err(format!(
+            "Service '{}' does not support rename, which atomic writes require",
+            op.info().scheme()
+        ));
+    }
+    if tmp_suffix.is_empty() {
+        return Err("tmp_suffix must not be empty".to_string());
+    }
+
+    let tmp_path = format!("{}{}", final_path, tmp_suffix);
+    if let Err(e) = op.write(&tmp_path, content.to_owned()).await {
+        let _ = op.delete(&tmp_path).await;
+        return Err(format!("Failed to write to '{}': {}", tmp_path, e));
+    }
+    if let Err(e) = op.rename(&tmp_path, final_path).await {
+        let _ = op.delete(&tmp_path).await;
+        return Err(format!("Failed to rename from '{}' to '{}': {}", tmp_path, final_path, e));
+    }
+    Ok(true)
+}
+
+#[pg_extern]
+fn pg_opendal_write_atomic(
+    service: &str,
+    final_path: &str,
+    content: &str,
+    config: JsonB,
+    tmp_suffix: default!(&str, "'.tmp'"),
+) -> Result<bool, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_write_atomic_async(op, &resolve_path(final_path), content.as_bytes(), tmp_suffix))
+}
+
+/// Chunk size used when streaming writes whose total length isn't known up front.
+/// Kept above the 5 MiB minimum part size required by S3-compatible multipart uploads.
+const WRITE_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Opens a writer that uploads in `WRITE_CHUNK_SIZE` parts on backends supporting
+/// multipart uploads, so callers don't need to know the total length up front. A
+/// `pg_opendal_multipart_threshold` in the config raises the size up to which a write still
+/// goes up in a single request (see `MultipartThresholdLayer`).
+async fn open_writer_async(op: &Operator, path: &str) -> Result<opendal::Writer, String> {
+    if op.info().full_capability().write_can_multi {
+        op.writer_with(path).chunk(WRITE_CHUNK_SIZE).await
+    } else {
+        op.writer(path).await
+    }
+    .map_err(|e| format!("Failed to open writer for '{}': {}", path, e))
+}
+
+/// Streams an object from one operator to another without buffering it whole.
+/// Returns the number of bytes copied.
+async fn stream_copy_async(
+    src: &Operator,
+    src_path: &str,
+    dst: &Operator,
+    dst_path: &str,
+) -> Result<u64, String> {
+    let writer = open_writer_async(dst, dst_path).await?;
+    stream_to_writer_async(src, src_path, writer, dst_path).await
+}
+
+/// Streams an object into `writer`, which writes to `dst_path`, and closes it. The writer
+/// is aborted if anything fails. Returns the number of bytes copied.
+async fn stream_to_writer_async(
+    src: &Operator,
+    src_path: &str,
+    mut writer: opendal::Writer,
+    dst_path: &str,
+) -> Result<u64, String> {
+    let opened = match src.reader(src_path).await {
+        Ok(reader) => reader.into_stream(..).await,
+        Err(e) => Err(e),
+    };
+    let mut stream = match opened {
+        Ok(stream) => stream,
+        Err(e) => {
+            let _ = writer.abort().await;
+            return Err(format!("Failed to read '{}': {}", src_path, e));
+        }
+    };
+
+    let mut copied = 0u64;
+    loop {
+        let chunk = match stream.try_next().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => {
+                let _ = writer.abort().await;
+                return Err(format!("Failed to read '{}': {}", src_path, e));
+            }
+        };
+        copied += chunk.len() as u64;
+        if let Err(e) = writer.write(chunk).await {
+            let _ = writer.abort().await;
+            return Err(format!("Failed to write to '{}': {}", dst_path, e));
+        }
+    }
+    writer.close().await
+        .map_err(|e| format!("Failed to finish writing '{}': {}", dst_path, e))?;
+    Ok(copied)
+}
+
+/// Compression formats, for streaming conversions, whole values and compressed archives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Codec {
+    None,
+    Gzip,
+    Zstd,
+    Lz4,
+    Brotli,
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "none" => Ok(Codec::None),
+            "gzip" | "gz" => Ok(Codec::Gzip),
+            "zstd" | "zst" => Ok(Codec::Zstd),
+            "lz4" => Ok(Codec::Lz4),
+            "brotli" | "br" => Ok(Codec::Brotli),
+            other => Err(format!(
+                "Unknown codec '{}', expected one of 'none', 'gzip', 'zstd', 'lz4' or 'brotli'",
+                other
+            )),
+        }
+    }
+}
+
+/// Parses a `from->to` codec conversion. A single codec means compressing
+/// uncompressed source data with it.
+fn parse_codec_conversion(codec: &str) -> Result<(Codec, Codec), String> {
+    match codec.split_once("->") {
+        Some((from, to)) => Ok((from.parse()?, to.parse()?)),
+        None => Ok((Codec::None, codec.parse()?)),
+    }
+}
+
+/// Shared output buffer for a `Transcoder`, drained as compressed data is produced.
+#[derive(Clone, Default)]
+struct OutputBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+impl std::io::Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Encoder {
+    None(OutputBuffer),
+    Gzip(flate2::write::GzEncoder<OutputBuffer>),
+    Zstd(zstd::stream::write::Encoder<'static, OutputBuffer>),
+    Lz4(lz4_flex::frame::FrameEncoder<OutputBuffer>),
+    Brotli(Box<brotli::CompressorWriter<OutputBuffer>>),
+}
+
+impl std::io::Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::None(w) => w.write(buf),
+            Encoder::Gzip(w) => w.write(buf),
+            Encoder::Zstd(w) => w.write(buf),
+            Encoder::Lz4(w) => w.write(buf),
+            Encoder::Brotli(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::None(w) => w.flush(),
+            Encoder::Gzip(w) => w.flush(),
+            Encoder::Zstd(w) => w.flush(),
+            Encoder::Lz4(w) => w.flush(),
+            Encoder::Brotli(w) => w.flush(),
+        }
+    }
+}
+
+/// Input of an `Lz4Decoder`'s frame decoder, holding only whole headers and blocks.
+#[derive(Clone, Default)]
+struct InputBuffer(std::rc::Rc<std::cell::RefCell<std::collections::VecDeque<u8>>>);
+
+impl std::io::Read for InputBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        std::io::Read::read(&mut *self.0.borrow_mut(), buf)
+    }
+}
+
+const LZ4_FRAME_MAGIC: u32 = 0x184D_2204;
+const LZ4_MAX_BLOCK_SIZE: usize = 4 << 20;
+
+/// Where the unread part of an lz4 stream starts in the frame format.
+#[derive(Clone, Copy)]
+enum Lz4Position {
+    FrameStart,
+    Blocks { block_checksums: bool, content_checksum: bool },
+}
+
+/// The length of the frame header, block or end mark starting `input`, with the position
+/// after it, or `None` until all of it has arrived.
+fn lz4_unit(input: &[u8], position: Lz4Position) -> std::io::Result<Option<(usize, Lz4Position)>> {
+    let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string());
+    let Some(word) = input.get(..4) else {
+        return Ok(None);
+    };
+    let word = u32::from_le_bytes(word.try_into().unwrap());
+    let (len, next) = match position {
+        Lz4Position::FrameStart if word == LZ4_FRAME_MAGIC => {
+            let Some(&flags) = input.get(4) else {
+                return Ok(None);
+            };
+            let len = 7 + if flags & 0x08 != 0 { 8 } else { 0 } + if flags & 0x01 != 0 { 4 } else { 0 };
+            let next = Lz4Position::Blocks {
+                block_checksums: flags & 0x10 != 0,
+                content_checksum: flags & 0x04 != 0,
+            };
+            (len, next)
+        }
+        Lz4Position::FrameStart => return Err(invalid("not an lz4 frame")),
+        Lz4Position::Blocks { content_checksum, .. } if word == 0 => {
+            (if content_checksum { 8 } else { 4 }, Lz4Position::FrameStart)
+        }
+        Lz4Position::Blocks { block_checksums, .. } => {
+            let size = (word & 0x7FFF_FFFF) as usize;
+            if size > LZ4_MAX_BLOCK_SIZE {
+                return Err(invalid("lz4 block too large"));
+            }
+            (4 + size + if block_checksums { 4 } else { 0 }, position)
+        }
+    };
+    Ok((input.len() >= len).then_some((len, next)))
+}
+
+/// Decodes an lz4 stream as it arrives. lz4_flex only decodes frames from a reader and
+/// can't resume a read that runs out of input, so its decoder is only handed whole
+/// blocks, and a frame header together with what follows it. At most one block waits
+/// for the rest of its bytes.
+struct Lz4Decoder {
+    decoder: lz4_flex::frame::FrameDecoder<InputBuffer>,
+    input: InputBuffer,
+    pending: Vec<u8>,
+    position: Lz4Position,
+    encoder: Encoder,
+}
+
+impl Lz4Decoder {
+    fn new(encoder: Encoder) -> Self {
+        let input = InputBuffer::default();
+        Lz4Decoder {
+            decoder: lz4_flex::frame::FrameDecoder::new(input.clone()),
+            input,
+            pending: Vec::new(),
+            position: Lz4Position::FrameStart,
+            encoder,
+        }
+    }
+
+    fn write(&mut self, chunk: &[u8]) -> std::io::Result<()> {
+        use std::io::{Read, Write};
+        self.pending.extend_from_slice(chunk);
+        let (mut offset, mut position) = (0, self.position);
+        let mut ready = (0, position);
+        while let Some((len, next)) = lz4_unit(&self.pending[offset..], position)? {
+            offset += len;
+            if !matches!(position, Lz4Position::FrameStart) {
+                ready = (offset, next);
+            }
+            position = next;
+        }
+        let (ready, position) = ready;
+        self.input.0.borrow_mut().extend(self.pending.drain(..ready));
+        self.position = position;
+
+        // Decoded data of the last block handed over stays in the decoder until more
+        // input arrives or the stream ends.
+        let mut buf = vec![0; 64 * 1024];
+        while !self.input.0.borrow().is_empty() {
+            let n = self.decoder.read(&mut buf)?;
+            self.encoder.write_all(&buf[..n])?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> std::io::Result<Encoder> {
+        if !self.pending.is_empty() || matches!(self.position, Lz4Position::Blocks { .. }) {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "truncated lz4 stream"));
+        }
+        std::io::copy(&mut self.decoder, &mut self.encoder)?;
+        Ok(self.encoder)
+    }
+}
+
+enum Decoder {
+    None(Encoder),
+    Gzip(flate2::write::MultiGzDecoder<Encoder>),
+    Zstd(zstd::stream::zio::Writer<Encoder, zstd::stream::raw::Decoder<'static>>),
+    Lz4(Box<Lz4Decoder>),
+    Brotli(Box<brotli::DecompressorWriter<Encoder>>),
+}
+
+/// Decompresses and recompresses data incrementally, one chunk at a time.
+struct Transcoder {
+    decoder: Decoder,
+    output: OutputBuffer,
+}
+
+impl Transcoder {
+    fn new(from: Codec, to: Codec) -> std::io::Result<Self> {
+        let output = OutputBuffer::default();
+        let encoder = match to {
+            Codec::None => Encoder::None(output.clone()),
+            Codec::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                output.clone(),
+                flate2::Compression::default(),
+            )),
+            Codec::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(output.clone(), 0)?),
+            Codec::Lz4 => Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(output.clone())),
+            Codec::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                output.clone(),
+                4096,
+                BROTLI_QUALITY as u32,
+                BROTLI_WINDOW,
+            ))),
+        };
+        let decoder = match from {
+            Codec::None => Decoder::None(encoder),
+            Codec::Gzip => Decoder::Gzip(flate2::write::MultiGzDecoder::new(encoder)),
+            Codec::Zstd => Decoder::Zstd(zstd::stream::zio::Writer::new(encoder, zstd::stream::raw::Decoder::new()?)),
+            Codec::Lz4 => Decoder::Lz4(Box::new(Lz4Decoder::new(encoder))),
+            Codec::Brotli => Decoder::Brotli(Box::new(brotli::DecompressorWriter::new(encoder, 4096))),
+        };
+        Ok(Transcoder { decoder, output })
+    }
+
+    fn write(&mut self, input: &[u8]) -> std::io::Result<()> {
+        use std::io::Write;
+        match &mut self.decoder {
+            Decoder::None(w) => w.write_all(input),
+            Decoder::Gzip(w) => w.write_all(input),
+            Decoder::Zstd(w) => w.write_all(input),
+            Decoder::Lz4(decoder) => decoder.write(input),
+            Decoder::Brotli(w) => w.write_all(input),
+        }
+    }
+
+    /// Feeds a chunk of input through and returns the output produced so far.
+    fn transcode(&mut self, chunk: opendal::Buffer) -> std::io::Result<Vec<u8>> {
+        for bytes in chunk {
+            self.write(&bytes)?;
+        }
+        Ok(std::mem::take(&mut *self.output.0.borrow_mut()))
+    }
+
+    /// Flushes the end of the stream through both codecs and returns the remaining output.
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        let encoder = match self.decoder {
+            Decoder::None(encoder) => encoder,
+            Decoder::Gzip(decoder) => decoder.finish()?,
+            // Fails on a frame cut short, where flushing would accept it.
+            Decoder::Zstd(mut decoder) => {
+                decoder.finish()?;
+                decoder.into_inner().0
+            }
+            Decoder::Lz4(decoder) => decoder.finish()?,
+            Decoder::Brotli(decoder) => decoder.into_inner().map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "truncated brotli stream")
+            })?,
+        };
+        match encoder {
+            Encoder::None(_) => {}
+            Encoder::Gzip(encoder) => {
+                encoder.finish()?;
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.finish()?;
+            }
+            Encoder::Lz4(encoder) => {
+                encoder.finish()?;
+            }
+            Encoder::Brotli(encoder) => {
+                // Finishes the stream.
+                encoder.into_inner();
+            }
+        }
+        Ok(self.output.0.take())
+    }
+}
+
+/// Streams an object from one operator to another, converting its compression on the
+/// way. Returns the number of bytes written.
+async fn recompress_async(
+    src: &Operator,
+    src_path: &str,
+    dst: &Operator,
+    dst_path: &str,
+    from: Codec,
+    to: Codec,
+) -> Result<u64, String> {
+    let mut transcoder = Transcoder::new(from, to)
+        .map_err(|e| format!("Failed to set up compression: {}", e))?;
+    let reader = src.reader_with(src_path).chunk(READ_CHUNK_SIZE).await
+        .map_err(|e| format!("Failed to open reader for '{}': {}", src_path, e))?;
+    let mut stream = reader.into_stream(..).await
+        .map_err(|e| format!("Failed to read '{}': {}", src_path, e))?;
+    let mut writer = open_writer_async(dst, dst_path).await?;
+
+    let mut written = 0u64;
+    loop {
+        let output = match stream.try_next().await {
+            Ok(Some(chunk)) => transcoder
+                .transcode(chunk)
+                .map_err(|e| format!("Failed to convert '{}': {}", src_path, e)),
+            Ok(None) => break,
+            Err(e) => Err(format!("Failed to read '{}': {}", src_path, e)),
+        };
+        let output = match output {
+            Ok(output) => output,
+            Err(e) => {
+                let _ = writer.abort().await;
+                return Err(e);
+            }
+        };
+        if output.is_empty() {
+            continue;
+        }
+        written += output.len() as u64;
+        if let Err(e) = writer.write(output).await {
+            let _ = writer.abort().await;
+            return Err(format!("Failed to write to '{}': {}", dst_path, e));
+        }
+    }
+
+    let output = match transcoder.finish() {
+        Ok(output) => output,
+        Err(e) => {
+            let _ = writer.abort().await;
+            return Err(format!("Failed to convert '{}': {}", src_path, e));
+        }
+    };
+    written += output.len() as u64;
+    if let Err(e) = writer.write(output).await {
+        let _ = writer.abort().await;
+        return Err(format!("Failed to write to '{}': {}", dst_path, e));
+    }
+    writer.close().await
+        .map_err(|e| format!("Failed to finish writing '{}': {}", dst_path, e))?;
+    Ok(written)
+}
+
+#[pg_extern]
+fn pg_opendal_recompress(
+    src_service: &str,
+    src_path: &str,
+    src_config: JsonB,
+    dst_service: &str,
+    dst_path: &str,
+    dst_config: JsonB,
+    codec: &str,
+) -> Result<i64, String> {
+    let (from, to) = parse_codec_conversion(codec)?;
+    let src_config = jsonb_to_hashmap(src_config.0)
+        .map_err(|e| format!("Failed to parse source config: {}", e))?;
+    let dst_config = jsonb_to_hashmap(dst_config.0)
+        .map_err(|e| format!("Failed to parse destination config: {}", e))?;
+    let src = create_operator(src_service, src_config)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+    let dst = create_operator(dst_service, dst_config)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(recompress_async(
+        &src,
+        &resolve_path(src_path),
+        &dst,
+        &resolve_path(dst_path),
+        from,
+        to,
+    ))
+    .map(|written| written as i64)
+}
+
+fn gzip_compress(data: &[u8], level: i32) -> Result<Vec<u8>, String> {
+    let level = u32::try_from(level)
+        .ok()
+        .filter(|&level| level <= 9)
+        .ok_or_else(|| format!("Invalid gzip level {}, expected 0 to 9", level))?;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(level));
+    std::io::Write::write_all(&mut encoder, data)
+        .and_then(|_| encoder.finish())
+        .map_err(|e| format!("Failed to compress: {}", e))
+}
+
+/// Decompresses every concatenated gzip member, as `gunzip` does.
+fn gzip_decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decompressed = Vec::new();
+    std::io::Read::read_to_end(&mut flate2::read::MultiGzDecoder::new(data), &mut decompressed)
+        .map_err(|e| format!("Failed to decompress gzip data: {}", e))?;
+    Ok(decompressed)
+}
+
+fn zstd_compress(data: &[u8], level: i32) -> Result<Vec<u8>, String> {
+    let levels = zstd::compression_level_range();
+    if !levels.contains(&level) {
+        return Err(format!(
+            "Invalid zstd level {}, expected {} to {}",
+            level,
+            levels.start(),
+            levels.end()
+        ));
+    }
+    zstd::encode_all(data, level).map_err(|e| format!("Failed to compress: {}", e))
+}
+
+fn zstd_decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    zstd::decode_all(data).map_err(|e| format!("Failed to decompress zstd data: {}", e))
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn pg_opendal_gzip_compress(data: &[u8], level: default!(i32, 6)) -> Result<Vec<u8>, String> {
+    gzip_compress(data, level)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn pg_opendal_gzip_decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    gzip_decompress(data)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn pg_opendal_zstd_compress(data: &[u8], level: default!(i32, 3)) -> Result<Vec<u8>, String> {
+    zstd_compress(data, level)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn pg_opendal_zstd_decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    zstd_decompress(data)
+}
+
+/// Brotli quality used when compressing; the maximum, 11, is far slower for little gain.
+const BROTLI_QUALITY: i32 = 6;
+
+/// Brotli window size, as a power of two; 22 is the encoder's default.
+const BROTLI_WINDOW: u32 = 22;
+
+impl Codec {
+    /// The codec whose magic number `bytes` start with, `None` if there's no match. Brotli
+    /// has no magic number, so it is never returned.
+    fn from_magic(bytes: &[u8]) -> Codec {
+        match bytes {
+            [0x1f, 0x8b, ..] => Codec::Gzip,
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Codec::Zstd,
+            [0x04, 0x22, 0x4d, 0x18, ..] => Codec::Lz4,
+            _ => Codec::None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Codec::None => "",
+            Codec::Gzip => ".gz",
+            Codec::Zstd => ".zst",
+            Codec::Lz4 => ".lz4",
+            Codec::Brotli => ".br",
+        }
+    }
+
+    /// The format of compressed `data` read from `path`, from its magic number, or for
+    /// brotli from a `.br` extension.
+    fn detect(data: &[u8], path: &str) -> Codec {
+        match Codec::from_magic(data) {
+            Codec::None if path.ends_with(Codec::Brotli.extension()) => Codec::Brotli,
+            codec => codec,
+        }
+    }
+
+    /// Wraps `input` in a reader that decompresses it as it is read.
+    fn reader<'a>(self, input: impl std::io::Read + 'a) -> Result<Box<dyn std::io::Read + 'a>, String> {
+        Ok(match self {
+            Codec::None => Box::new(input),
+            Codec::Gzip => Box::new(flate2::read::MultiGzDecoder::new(input)),
+            Codec::Zstd => Box::new(
+                zstd::stream::read::Decoder::new(input).map_err(|e| format!("Failed to create zstd decoder: {}", e))?,
+            ),
+            Codec::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(input)),
+            Codec::Brotli => Box::new(brotli::Decompressor::new(input, 4096)),
+        })
+    }
+
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Gzip => gzip_compress(data, 6),
+            Codec::Zstd => zstd_compress(data, 3),
+            Codec::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+                std::io::Write::write_all(&mut encoder, data)
+                    .map_err(|e| format!("Failed to compress: {}", e))?;
+                encoder.finish().map_err(|e| format!("Failed to compress: {}", e))
+            }
+            Codec::Brotli => {
+                let mut compressed = Vec::new();
+                let params = brotli::enc::BrotliEncoderParams {
+                    quality: BROTLI_QUALITY,
+                    lgwin: BROTLI_WINDOW as i32,
+                    ..Default::default()
+                };
+                brotli::BrotliCompress(&mut &data[..], &mut compressed, &params)
+                    .map_err(|e| format!("Failed to compress: {}", e))?;
+                Ok(compressed)
+            }
+        }
+    }
+
+    fn decompress(self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let name = match self {
+            Codec::None => return Ok(data.to_vec()),
+            Codec::Gzip => return gzip_decompress(data),
+            Codec::Zstd => return zstd_decompress(data),
+            Codec::Lz4 => "lz4",
+            Codec::Brotli => "brotli",
+        };
+        let mut decompressed = Vec::new();
+        std::io::Read::read_to_end(&mut self.reader(data)?, &mut decompressed)
+            .map_err(|e| format!("Failed to decompress {} data: {}", name, e))?;
+        Ok(decompressed)
+    }
+}
+
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn pg_opendal_compress_write(
+    service: &str,
+    path: &str,
+    content: &[u8],
+    config: JsonB,
+    algorithm: default!(&str, "'gzip'"),
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(path, String),
+            name!(original_bytes, i64),
+            name!(compressed_bytes, i64),
+            name!(ratio, f64),
+        ),
+    >,
+    String,
+> {
+    let algorithm: Codec = algorithm.parse()?;
+    if algorithm == Codec::None {
+        return Err("Invalid algorithm 'none', expected one of 'gzip', 'zstd', 'lz4' or 'brotli'".to_string());
+    }
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let mut path = resolve_path(path);
+    if !path.ends_with(algorithm.extension()) {
+        path.push_str(algorithm.extension());
+    }
+    let compressed = algorithm.compress(content)?;
+    let compressed_bytes = compressed.len();
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    // A single write, so readers never see a partial object.
+    rt.block_on(op.write(&path, compressed))
+        .map_err(|e| format!("Failed to write to '{}': {}", path, e))?;
+    let ratio = content.len() as f64 / compressed_bytes as f64;
+    Ok(TableIterator::once((path, content.len() as i64, compressed_bytes as i64, ratio)))
+}
+
+#[pg_extern]
+fn pg_opendal_read_decompress(service: &str, path: &str, config: JsonB) -> Result<Vec<u8>, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let path = resolve_path(path);
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let data = rt.block_on(op.read(&path))
+        .map_err(|e| format!("Failed to read '{}': {}", path, e))?
+        .to_vec();
+    match Codec::detect(&data, &path) {
+        Codec::None => Err(format!("'{}' is not gzip, zstd, lz4 or brotli compressed", path)),
+        codec => codec.decompress(&data),
+    }
+}
+
+/// Minimum size of each ranged request made by `RangeReader`, so runs of small reads,
+/// like consecutive archive headers, don't each cost a round trip.
+const RANGE_READ_AHEAD: u64 = 32 * 1024;
+
+/// Reads an object through ranged requests, so archive readers can seek past the parts
+/// they don't need instead of downloading them.
+struct RangeReader<'a> {
+    rt: &'a Runtime,
+    op: &'a Operator,
+    path: &'a str,
+    pos: u64,
+    size: u64,
+    buffer: Vec<u8>,
+    buffer_start: u64,
+}
+
+impl<'a> RangeReader<'a> {
+    fn open(rt: &'a Runtime, op: &'a Operator, path: &'a str) -> Result<Self, String> {
+        let size = rt
+            .block_on(op.stat(path))
+            .map_err(|e| format!("Failed to get stat for '{}': {}", path, e))?
+            .content_length();
+        Ok(RangeReader { rt, op, path, pos: 0, size, buffer: Vec::new(), buffer_start: 0 })
+    }
+
+    fn read_range(&self, range: std::ops::Range<u64>) -> Result<Vec<u8>, String> {
+        self.rt
+            .block_on(async { self.op.read_with(self.path).range(range).await })
+            .map(|data| data.to_vec())
+            .map_err(|e| format!("Failed to read file '{}': {}", self.path, e))
+    }
+}
+
+impl std::io::Read for RangeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let buffer_end = self.buffer_start + self.buffer.len() as u64;
+        if !(self.buffer_start..buffer_end).contains(&self.pos) {
+            let end = self.size.min(self.pos + RANGE_READ_AHEAD.max(buf.len() as u64));
+            self.buffer = self.read_range(self.pos..end).map_err(std::io::Error::other)?;
+            self.buffer_start = self.pos;
+        }
+        let available = &self.buffer[(self.pos - self.buffer_start) as usize..];
+        let n = buf.len().min(available.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl std::io::Seek for RangeReader<'_> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            std::io::SeekFrom::Start(offset) => Some(offset),
+            std::io::SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            std::io::SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before the start of the object")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// Adapts a `ChunkStream` to `std::io::Read`, for readers that only need to go forward.
+struct ChunkReader {
+    chunks: ChunkStream,
+    current: Vec<u8>,
+    pos: usize,
+}
+
+impl ChunkReader {
+    fn new(chunks: ChunkStream) -> Self {
+        ChunkReader { chunks, current: Vec::new(), pos: 0 }
+    }
+}
+
+impl std::io::Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.current.len() {
+            match self.chunks.next_chunk().map_err(std::io::Error::other)? {
+                Some(chunk) => {
+                    self.current = chunk.to_vec();
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len() - self.pos);
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Whether a tar entry is `member`, ignoring a leading `./` on either name.
+fn is_tar_member<R: std::io::Read>(entry: &tar::Entry<'_, R>, member: &str) -> bool {
+    let name = entry.path_bytes();
+    name.strip_prefix(b"./").unwrap_or(&name) == member.strip_prefix("./").unwrap_or(member).as_bytes()
+}
+
+fn tar_member_file_check<R: std::io::Read>(entry: &tar::Entry<'_, R>, member: &str) -> Result<(), String> {
+    if entry.header().entry_type().is_file() {
+        Ok(())
+    } else {
+        Err(format!("Archive member '{}' is not a regular file", member))
+    }
+}
+
+/// The offset and size of `member`'s data in an uncompressed tar. Entries are walked
+/// header to header, seeking over file data, so only the headers are read.
+fn tar_member_location<R: std::io::Read + std::io::Seek>(
+    reader: R,
+    member: &str,
+) -> Result<Option<(u64, u64)>, String> {
+    let mut archive = tar::Archive::new(reader);
+    let entries = archive.entries_with_seek().map_err(|e| format!("Failed to read tar archive: {}", e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Failed to read tar archive: {}", e))?;
+        if is_tar_member(&entry, member) {
+            tar_member_file_check(&entry, member)?;
+            return Ok(Some((entry.raw_file_position(), entry.size())));
+        }
+    }
+    Ok(None)
+}
+
+/// `member`'s contents, scanning a tar archive front to back.
+fn tar_member_streamed<R: std::io::Read>(reader: R, member: &str) -> Result<Option<Vec<u8>>, String> {
+    let mut archive = tar::Archive::new(reader);
+    let entries = archive.entries().map_err(|e| format!("Failed to read tar archive: {}", e))?;
+    for entry in entries {
+        let mut entry = entry.map_err(|e| format!("Failed to read tar archive: {}", e))?;
+        if is_tar_member(&entry, member) {
+            tar_member_file_check(&entry, member)?;
+            let mut content = Vec::with_capacity(entry.size() as usize);
+            std::io::Read::read_to_end(&mut entry, &mut content)
+                .map_err(|e| format!("Failed to read archive member '{}': {}", member, e))?;
+            return Ok(Some(content));
+        }
+    }
+    Ok(None)
+}
+
+/// Extracts `member` from the tar archive at `path`. Plain archives are read with ranged
+/// requests for the headers and the member alone. Compressed ones can't be seeked, so they
+/// are streamed through a decoder up to the member.
+fn read_tar_member(rt: Runtime, op: Operator, path: &str, member: &str) -> Result<Option<Vec<u8>>, String> {
+    let codec = {
+        let reader = RangeReader::open(&rt, &op, path)?;
+        let codec = Codec::from_magic(&reader.read_range(0..reader.size.min(4))?);
+        if codec == Codec::None {
+            return match tar_member_location(reader, member)? {
+                Some((offset, size)) => {
+                    let reader = RangeReader::open(&rt, &op, path)?;
+                    reader.read_range(offset..offset + size).map(Some)
+                }
+                None => Ok(None),
+            };
+        }
+        codec
+    };
+
+    let chunks = ChunkReader::new(ChunkStream::open(rt, op, vec![path.to_string()]));
+    tar_member_streamed(codec.reader(chunks)?, member)
+}
+
+#[pg_extern]
+fn pg_opendal_read_tar_member(
+    service: &str,
+    archive_path: &str,
+    member: &str,
+    config: JsonB,
+) -> Result<Vec<u8>, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let archive_path = resolve_path(archive_path);
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    read_tar_member(rt, op, &archive_path, member)?
+        .ok_or_else(|| format!("Member '{}' not found in archive '{}'", member, archive_path))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ArchiveKind {
+    Tar(Codec),
+    Zip,
+}
+
+/// Tells a zip from a (possibly compressed) tar by the first bytes of the archive,
+/// falling back to the extension for old tars without the `ustar` magic.
+fn detect_archive_kind(path: &str, head: &[u8]) -> Result<ArchiveKind, String> {
+    if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+        return Ok(ArchiveKind::Zip);
+    }
+    match Codec::from_magic(head) {
+        Codec::None => {}
+        codec => return Ok(ArchiveKind::Tar(codec)),
+    }
+    let extension = path.rsplit_once('.').map(|(_, ext)| ext.to_ascii_lowercase());
+    if head.get(257..262) == Some(b"ustar") || extension.as_deref() == Some("tar") {
+        Ok(ArchiveKind::Tar(Codec::None))
+    } else if extension.as_deref() == Some("zip") {
+        Ok(ArchiveKind::Zip)
+    } else {
+        Err(format!("Can't tell whether '{}' is a tar or zip archive", path))
+    }
+}
+
+/// `(name, size, offset)` of an archive member.
+type ArchiveMemberRow = (String, i64, Option<i64>);
+
+/// Lists tar entries. Offsets are only meaningful when the archive is read as stored.
+fn tar_members<R: std::io::Read>(entries: tar::Entries<'_, R>, with_offsets: bool) -> Result<Vec<ArchiveMemberRow>, String> {
+    entries
+        .map(|entry| {
+            let entry = entry.map_err(|e| format!("Failed to read tar archive: {}", e))?;
+            let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+            let offset = with_offsets.then(|| entry.raw_file_position() as i64);
+            Ok((name, entry.size() as i64, offset))
+        })
+        .collect()
+}
+
+/// Lists zip entries from the central directory, plus each entry's local header, which
+/// gives where its data starts.
+fn zip_members<R: std::io::Read + std::io::Seek>(reader: R) -> Result<Vec<ArchiveMemberRow>, String> {
+    let mut archive = zip::ZipArchive::new(reader).map_err(|e| format!("Failed to read zip archive: {}", e))?;
+    (0..archive.len())
+        .map(|i| {
+            let file = archive.by_index_raw(i).map_err(|e| format!("Failed to read zip archive: {}", e))?;
+            Ok((file.name().to_string(), file.size() as i64, Some(file.data_start() as i64)))
+        })
+        .collect()
+}
+
+fn list_archive(rt: Runtime, op: Operator, path: &str) -> Result<Vec<ArchiveMemberRow>, String> {
+    let codec = {
+        let reader = RangeReader::open(&rt, &op, path)?;
+        match detect_archive_kind(path, &reader.read_range(0..reader.size.min(512))?)? {
+            ArchiveKind::Zip => return zip_members(reader),
+            ArchiveKind::Tar(Codec::None) => {
+                let mut archive = tar::Archive::new(reader);
+                let entries = archive.entries_with_seek().map_err(|e| format!("Failed to read tar archive: {}", e))?;
+                return tar_members(entries, true);
+            }
+            ArchiveKind::Tar(codec) => codec,
+        }
+    };
+
+    let chunks = ChunkReader::new(ChunkStream::open(rt, op, vec![path.to_string()]));
+    let mut archive = tar::Archive::new(codec.reader(chunks)?);
+    let entries = archive.entries().map_err(|e| format!("Failed to read tar archive: {}", e))?;
+    tar_members(entries, false)
+}
+
+#[pg_extern]
+fn pg_opendal_list_archive(
+    service: &str,
+    archive_path: &str,
+    config: JsonB,
+) -> Result<TableIterator<'static, (name!(name, String), name!(size, i64), name!(offset, Option<i64>))>, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let members = list_archive(rt, op, &resolve_path(archive_path))?;
+    Ok(TableIterator::new(members))
+}
+
+async fn do_write_bytea_async(
+    op: Operator,
+    path: &str,
+    content: &[u8],
+    content_length: Option<u64>,
+) -> Result<i64, String> {
+    let actual = content.len() as u64;
+    match content_length {
+        Some(expected) if expected != actual => Err(format!(
+            "Content length mismatch for '{}': expected {} bytes, got {}",
+            path, expected, actual
+        )),
+        // Known length: a single-shot PUT, the Content-Length header comes from the buffer.
+        Some(_) => op
+            .write(path, content.to_owned())
+            .await
+            .map(|_| actual as i64)
+            .map_err(|e| format!("Failed to write to '{}': {}", path, e)),
+        // Unknown length: stream through a chunked writer, which backends supporting
+        // multipart uploads turn into a multipart upload instead of a sized PUT.
+        None => {
+            let mut writer = open_writer_async(&op, path).await?;
+
+            for chunk in content.chunks(WRITE_CHUNK_SIZE) {
+                writer
+                    .write(chunk.to_owned())
+                    .await
+                    .map_err(|e| format!("Failed to write to '{}': {}", path, e))?;
+            }
+            writer
+                .close()
+                .await
+                .map(|_| actual as i64)
+                .map_err(|e| format!("Failed to finish writing '{}': {}", path, e))
+        }
+    }
+}
+
+#[pg_extern]
+fn pg_opendal_write_bytea(
+    service: &str,
+    path: &str,
+    content: &[u8],
+    config: JsonB,
+    content_length: default!(Option<i64>, "NULL"),
+) -> Result<i64, String> {
+    let content_length = content_length
+        .map(|len| u64::try_from(len).map_err(|_| format!("Invalid content_length: {}", len)))
+        .transpose()?;
+    let mut config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let sidecar = take_checksum_sidecar_config(&mut config_map)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let path = resolve_path(path);
+    let written = rt.block_on(do_write_bytea_async(op.clone(), &path, content, content_length))?;
+    if let Some(sidecar) = &sidecar {
+        let digest = ChecksumHasher::digest_hex(sidecar.algorithm, content);
+        rt.block_on(write_checksum_sidecar_async(&op, sidecar, &path, &digest))?;
+    }
+    Ok(written)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ExportFormat {
+    Csv,
+    Tsv,
+    JsonLines,
+    CopyCsv,
+    CopyText,
+    CopyBinary,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "tsv" => Ok(ExportFormat::Tsv),
+            "jsonl" => Ok(ExportFormat::JsonLines),
+            "copy_text" => Ok(ExportFormat::CopyText),
+            "copy_binary" => Ok(ExportFormat::CopyBinary),
+            other => Err(format!(
+                "Invalid format '{}', expected one of 'csv', 'tsv', 'jsonl', 'copy_text' or 'copy_binary'",
+                other
+            )),
+        }
+    }
+}
+
+impl ExportFormat {
+    /// Parses a format named as for `COPY ... (FORMAT ...)`.
+    fn from_copy_format(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::CopyCsv),
+            "text" => Ok(ExportFormat::CopyText),
+            "binary" => Ok(ExportFormat::CopyBinary),
+            other => Err(format!(
+                "Invalid format '{}', expected one of 'csv', 'text' or 'binary'",
+                other
+            )),
+        }
+    }
+}
+
+/// Signature starting a file in PostgreSQL's binary COPY format.
+const COPY_BINARY_SIGNATURE: &[u8] = b"PGCOPY\n\xff\r\n\0";
+
+/// Rows fetched from the query's cursor at a time.
+const EXPORT_BATCH_ROWS: i64 = 1000;
+
+/// A CSV field as `COPY ... CSV` writes it: NULL is empty, and values containing
+/// delimiters, quotes or line breaks, or that are empty, are quoted.
+fn csv_field(value: Option<&[u8]>, out: &mut Vec<u8>) {
+    let Some(value) = value else {
+        return;
+    };
+    if value.is_empty() || value.iter().any(|b| matches!(b, b',' | b'"' | b'\n' | b'\r')) {
+        out.push(b'"');
+        for &b in value {
+            if b == b'"' {
+                out.push(b'"');
+            }
+            out.push(b);
+        }
+        out.push(b'"');
+    } else {
+        out.extend_from_slice(value);
+    }
+}
+
+/// A field in `COPY` text format: NULL is `\N`, and backslashes, tabs and line breaks
+/// are escaped.
+fn copy_text_field(value: Option<&[u8]>, out: &mut Vec<u8>) {
+    let Some(value) = value else {
+        out.extend_from_slice(b"\\N");
+        return;
+    };
+    for &b in value {
+        match b {
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            _ => out.push(b),
+        }
+    }
+}
+
+/// Appends one row to `out`. Text formats take each column's text output; binary
+/// COPY takes its binary send output.
+fn encode_export_row(format: ExportFormat, values: &[Option<Vec<u8>>], out: &mut Vec<u8>) {
+    match format {
+        ExportFormat::Csv | ExportFormat::Tsv | ExportFormat::CopyCsv | ExportFormat::CopyText => {
+            for (i, value) in values.iter().enumerate() {
+                match format {
+                    ExportFormat::Csv | ExportFormat::CopyCsv => {
+                        if i > 0 {
+                            out.push(b',');
+                        }
+                        csv_field(value.as_deref(), out);
+                    }
+                    _ => {
+                        if i > 0 {
+                            out.push(b'\t');
+                        }
+                        copy_text_field(value.as_deref(), out);
+                    }
+                }
+            }
+            out.push(b'\n');
+        }
+        ExportFormat::JsonLines => {
+            for value in values.iter().flatten() {
+                out.extend_from_slice(value);
+            }
+            out.push(b'\n');
+        }
+        ExportFormat::CopyBinary => {
+            out.extend_from_slice(&(values.len() as i16).to_be_bytes());
+            for value in values {
+                match value {
+                    Some(value) => {
+                        out.extend_from_slice(&(value.len() as i32).to_be_bytes());
+                        out.extend_from_slice(value);
+                    }
+                    None => out.extend_from_slice(&(-1i32).to_be_bytes()),
+                }
+            }
+        }
+    }
+}
+
+/// What precedes the rows: a header line of column names for CSV and TSV, the
+/// signature, flags and header extension length for binary COPY.
+fn export_header(format: ExportFormat, names: &[String]) -> Vec<u8> {
+    let mut out = Vec::new();
+    match format {
+        ExportFormat::Csv | ExportFormat::Tsv => {
+            let names: Vec<Option<Vec<u8>>> =
+                names.iter().map(|name| Some(name.as_bytes().to_vec())).collect();
+            encode_export_row(format, &names, &mut out);
+        }
+        ExportFormat::CopyBinary => {
+            out.extend_from_slice(COPY_BINARY_SIGNATURE);
+            out.extend_from_slice(&0i32.to_be_bytes());
+            out.extend_from_slice(&0i32.to_be_bytes());
+        }
+        ExportFormat::JsonLines | ExportFormat::CopyCsv | ExportFormat::CopyText => {}
+    }
+    out
+}
+
+/// Runs `query` through an SPI cursor and feeds the encoded output to `write` a batch
+/// at a time. Returns the number of rows exported.
+fn export_query(
+    client: &pgrx::spi::SpiClient<'_>,
+    query: &str,
+    format: ExportFormat,
+    mut write: impl FnMut(Vec<u8>) -> Result<(), String>,
+) -> Result<i64, String> {
+    let query = query.trim().trim_end_matches(';');
+    let described = client
+        .select(&format!("SELECT * FROM ({}) AS q LIMIT 0", query), None, &[])
+        .map_err(|e| format!("Failed to run query: {}", e))?;
+    let column_count = described.columns().map_err(|e| e.to_string())?;
+    let mut names = Vec::with_capacity(column_count);
+    let mut types = Vec::with_capacity(column_count);
+    for i in 1..=column_count {
+        names.push(described.column_name(i).map_err(|e| e.to_string())?);
+        types.push(described.column_type_oid(i).map_err(|e| e.to_string())?.value());
+    }
+
+    // Columns are renamed positionally, so duplicate or unnamed columns can be referenced.
+    let aliases: Vec<String> = (1..=column_count).map(|i| format!("c{}", i)).collect();
+    let projection = match format {
+        ExportFormat::JsonLines => "row_to_json(q)::text".to_string(),
+        ExportFormat::CopyBinary => {
+            let mut sends = Vec::with_capacity(column_count);
+            for (alias, type_oid) in aliases.iter().zip(&types) {
+                let send: String = client
+                    .select("SELECT typsend::regproc::text FROM pg_type WHERE oid = $1", None, &[(*type_oid).into()])
+                    .and_then(|rows| rows.first().get_one())
+                    .map_err(|e| format!("Failed to look up binary output function: {}", e))?
+                    .ok_or_else(|| format!("Column '{}' has no binary output function", alias))?;
+                sends.push(format!("{}(q.{})", send, alias));
+            }
+            sends.join(", ")
+        }
+        _ => aliases.iter().map(|alias| format!("q.{}::text", alias)).collect::<Vec<_>>().join(", "),
+    };
+    let from = match format {
+        ExportFormat::JsonLines => format!("({}) AS q", query),
+        _ => format!("({}) AS q({})", query, aliases.join(", ")),
+    };
+
+    write(export_header(format, &names))?;
+    let mut cursor = client
+        .try_open_cursor(&format!("SELECT {} FROM {}", projection, from), &[])
+        .map_err(|e| format!("Failed to run query: {}", e))?;
+    let output_columns = if format == ExportFormat::JsonLines { 1 } else { column_count };
+    let mut rows = 0i64;
+    loop {
+        let batch = cursor.fetch(EXPORT_BATCH_ROWS).map_err(|e| format!("Failed to fetch rows: {}", e))?;
+        if batch.is_empty() {
+            break;
+        }
+        let mut out = Vec::new();
+        for row in batch {
+            let values = (1..=output_columns)
+                .map(|i| match format {
+                    ExportFormat::CopyBinary => row.get::<Vec<u8>>(i),
+                    _ => row.get::<String>(i).map(|v| v.map(String::into_bytes)),
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Failed to read row {}: {}", rows + 1, e))?;
+            encode_export_row(format, &values, &mut out);
+            rows += 1;
+        }
+        write(out)?;
+    }
+    if format == ExportFormat::CopyBinary {
+        write((-1i16).to_be_bytes().to_vec())?;
+    }
+    Ok(rows)
+}
+
+/// Streams the output of `query` to `path`, aborting the upload if the export fails.
+fn write_export(
+    query: &str,
+    service: &str,
+    path: &str,
+    format: ExportFormat,
+    config: JsonB,
+) -> Result<i64, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let path = resolve_path(path);
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let mut writer = rt.block_on(open_writer_async(&op, &path))?;
+    let exported = Spi::connect(|client| {
+        export_query(client, query, format, |buf| {
+            rt.block_on(writer.write(buf))
+                .map_err(|e| format!("Failed to write to '{}': {}", path, e))
+        })
+    });
+    match exported {
+        Ok(rows) => rt
+            .block_on(writer.close())
+            .map(|_| rows)
+            .map_err(|e| format!("Failed to finish writing '{}': {}", path, e)),
+        Err(e) => {
+            let _ = rt.block_on(writer.abort());
+            Err(e)
+        }
+    }
+}
+
+#[pg_extern]
+fn pg_opendal_write_from_query(
+    query: &str,
+    service: &str,
+    path: &str,
+    format: &str,
+    config: JsonB,
+) -> Result<i64, String> {
+    write_export(query, service, path, format.parse()?, config)
+}
+
+#[pg_extern]
+fn pg_opendal_write_from_table(
+    table_name: pgrx::PgRelation,
+    service: &str,
+    path: &str,
+    format: &str,
+    config: JsonB,
+) -> Result<i64, String> {
+    let format = ExportFormat::from_copy_format(format)?;
+    let partitioned = Spi::get_one_with_args::<bool>(
+        "SELECT relkind = 'p' FROM pg_class WHERE oid = $1",
+        &[table_name.oid().into()],
+    )
+    .map_err(|e| format!("Failed to look up table '{}': {}", table_name.name(), e))?;
+    if partitioned == Some(true) {
+        return Err(format!(
+            "Cannot export partitioned table '{}'; export its partitions, or use pg_opendal_write_from_query",
+            table_name.name()
+        ));
+    }
+    // ONLY, because `COPY table TO` leaves out the rows of inheritance children.
+    let query = format!(
+        "SELECT * FROM ONLY {}.{}",
+        quote_ident(table_name.namespace()),
+        quote_ident(table_name.name())
+    );
+    write_export(&query, service, path, format, config)
+}
+
+/// Number of leading bytes inspected when sniffing a content type.
+const MAGIC_BYTES_LEN: usize = 16;
+
+/// Guesses a MIME type from the content's magic bytes.
+fn detect_content_type(content: &[u8]) -> &'static str {
+    let magic = &content[..content.len().min(MAGIC_BYTES_LEN)];
+    infer::get(magic).map_or("application/octet-stream", |kind| kind.mime_type())
+}
+
+async fn do_write_with_content_type_async(
+    op: Operator,
+    path: &str,
+    content: &[u8],
+    content_type: &str,
+) -> Result<bool, String> {
+    op.write_with(path, content.to_owned())
+        .content_type(content_type)
+        .await
+        .map(|_| true)
+        .map_err(|e| format!("Failed to write to '{}': {}", path, e))
+}
+
+#[pg_extern]
+fn pg_opendal_write_autodetect(
+    service: &str,
+    path: &str,
+    content: &[u8],
+    config: JsonB,
+) -> Result<bool, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let path = resolve_path(path);
+    let content_type = detect_content_type(content);
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let written = rt.block_on(do_write_with_content_type_async(op, &path, content, content_type))?;
+    pgrx::notice!("Wrote '{}' with content type {}", path, content_type);
+    Ok(written)
+}
+
+/// A writer kept open across calls by `pg_opendal_writer_open`. It owns its runtime,
+/// since in-flight multipart uploads must outlive the call that started them.
+struct OpenWriter {
+    rt: Runtime,
+    op: Operator,
+    writer: opendal::Writer,
+    path: String,
+    written: u64,
+    /// The checksum sidecar to write on commit, and the hash of what was written so far.
+    checksum: Option<(ChecksumSidecar, ChecksumHasher)>,
+}
+
+/// Writers opened in this backend, by handle. Handles are not visible to other sessions.
+static OPEN_WRITERS: std::sync::Mutex<std::collections::BTreeMap<String, OpenWriter>> =
+    std::sync::Mutex::new(std::collections::BTreeMap::new());
+static NEXT_WRITER_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+
+fn open_writers(
+) -> std::sync::MutexGuard<'static, std::collections::BTreeMap<String, OpenWriter>> {
+    OPEN_WRITERS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn take_open_writer(handle: &str) -> Result<OpenWriter, String> {
+    open_writers()
+        .remove(handle)
+        .ok_or_else(|| format!("Unknown writer handle '{}'", handle))
+}
+
+/// How long aborting a writer left open by a failed transaction or an exiting backend may
+/// take, so an unreachable service can't hang either.
+const WRITER_ABORT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Aborts `writers`, ignoring failures and giving up after `WRITER_ABORT_TIMEOUT` each:
+/// this runs while the transaction or backend is going away, where there is no one left
+/// to report them to.
+fn abort_writers(writers: impl IntoIterator<Item = OpenWriter>) {
+    for mut entry in writers {
+        let _ = entry
+            .rt
+            .block_on(async { tokio::time::timeout(WRITER_ABORT_TIMEOUT, entry.writer.abort()).await });
+    }
+}
+
+fn abort_open_writers() {
+    let writers = std::mem::take(&mut *open_writers());
+    abort_writers(writers.into_values());
+}
+
+/// Whether the current transaction has callbacks registered to clean up open writers.
+static WRITER_XACT_CALLBACKS: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+static WRITER_EXIT_CALLBACK: std::sync::Once = std::sync::Once::new();
+
+#[pg_guard]
+unsafe extern "C-unwind" fn abort_open_writers_on_exit(_code: std::ffi::c_int, _arg: pg_sys::Datum) {
+    abort_open_writers();
+}
+
+/// Makes sure open writers are aborted, and their multipart uploads cleaned up, if the
+/// transaction using them fails or the backend exits. Transaction callbacks only last
+/// one transaction, so every call that uses a writer registers them again.
+fn register_writer_cleanup() {
+    use std::sync::atomic::Ordering;
+
+    WRITER_EXIT_CALLBACK.call_once(|| unsafe {
+        pg_sys::on_proc_exit(Some(abort_open_writers_on_exit), pg_sys::Datum::from(0usize));
+    });
+    if WRITER_XACT_CALLBACKS.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    pgrx::register_xact_callback(pgrx::PgXactCallbackEvent::Abort, || {
+        WRITER_XACT_CALLBACKS.store(false, Ordering::Relaxed);
+        abort_open_writers();
+    });
+    pgrx::register_xact_callback(pgrx::PgXactCallbackEvent::Commit, || {
+        WRITER_XACT_CALLBACKS.store(false, Ordering::Relaxed);
+    });
+}
+
+fn writer_open(op: Operator, path: &str, sidecar: Option<ChecksumSidecar>) -> Result<String, String> {
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let writer = rt.block_on(open_writer_async(&op, path))?;
+
+    let id = NEXT_WRITER_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let handle = format!("writer-{}-{}", std::process::id(), id);
+    let checksum = sidecar.map(|sidecar| {
+        let hasher = ChecksumHasher::new(sidecar.algorithm);
+        (sidecar, hasher)
+    });
+    open_writers().insert(
+        handle.clone(),
+        OpenWriter { rt, op, writer, path: path.to_string(), written: 0, checksum },
+    );
+    Ok(handle)
+}
+
+fn writer_write(handle: &str, content: &[u8]) -> Result<i64, String> {
+    let mut entry = take_open_writer(handle)?;
+    match entry.rt.block_on(entry.writer.write(content.to_owned())) {
+        Ok(()) => {
+            entry.written += content.len() as u64;
+            if let Some((_, hasher)) = &mut entry.checksum {
+                hasher.update(content);
+            }
+            let written = entry.written as i64;
+            open_writers().insert(handle.to_string(), entry);
+            Ok(written)
+        }
+        Err(e) => {
+            // The upload can't continue; abort it so no orphaned parts are left behind.
+            let _ = entry.rt.block_on(entry.writer.abort());
+            Err(format!("Failed to write to '{}', upload aborted: {}", entry.path, e))
+        }
+    }
+}
+
+fn writer_commit(handle: &str) -> Result<Value, String> {
+    let mut entry = take_open_writer(handle)?;
+    let metadata = match entry.rt.block_on(entry.writer.close()) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            // As for a failed write, don't leave the parts uploaded so far behind.
+            let _ = entry.rt.block_on(entry.writer.abort());
+            return Err(format!("Failed to finish writing '{}', upload aborted: {}", entry.path, e));
+        }
+    };
+    let checksum = match entry.checksum {
+        Some((sidecar, hasher)) => {
+            let digest = hasher.finalize_hex();
+            entry.rt.block_on(write_checksum_sidecar_async(&entry.op, &sidecar, &entry.path, &digest))?;
+            Some(digest)
+        }
+        None => None,
+    };
+
+    let mut commit_info = serde_json::Map::new();
+    commit_info.insert("path".to_string(), Value::String(entry.path));
+    commit_info.insert("content_length".to_string(), Value::Number(entry.written.into()));
+    commit_info.insert(
+        "etag".to_string(),
+        metadata.etag().map_or(Value::Null, |etag| Value::String(etag.to_string())),
+    );
+    if let Some(checksum) = checksum {
+        commit_info.insert("checksum".to_string(), Value::String(checksum));
+    }
+    Ok(Value::Object(commit_info))
+}
+
+/// Returns false for an unknown handle, so error handlers can call it unconditionally
+/// even after a failed write already released the writer.
+fn writer_abort(handle: &str) -> Result<bool, String> {
+    let Some(mut entry) = open_writers().remove(handle) else {
+        return Ok(false);
+    };
+    entry
+        .rt
+        .block_on(entry.writer.abort())
+        .map(|_| true)
+        .map_err(|e| format!("Failed to abort writing '{}': {}", entry.path, e))
+}
+
+#[pg_extern]
+fn pg_opendal_writer_open(service: &str, path: &str, config: JsonB) -> Result<String, String> {
+    let mut config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let sidecar = take_checksum_sidecar_config(&mut config_map)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    register_writer_cleanup();
+    writer_open(op, &resolve_path(path), sidecar)
+}
+
+#[pg_extern]
+fn pg_opendal_writer_write(handle: &str, content: &[u8]) -> Result<i64, String> {
+    register_writer_cleanup();
+    writer_write(handle, content)
+}
+
+#[pg_extern]
+fn pg_opendal_writer_commit(handle: &str) -> Result<JsonB, String> {
+    register_writer_cleanup();
+    writer_commit(handle).map(JsonB)
+}
+
+#[pg_extern]
+fn pg_opendal_writer_abort(handle: &str) -> Result<bool, String> {
+    writer_abort(handle)
+}
+
+// Functions can't read a COPY stream, but COPY can target a table whose BEFORE INSERT
+// trigger forwards each row to an open writer and discards it. Clients stream content
+// with `COPY pg_opendal_upload (handle, content) FROM STDIN`, one chunk per row.
+extension_sql!(
+    r#"
+CREATE TABLE pg_opendal_upload (
+    handle text NOT NULL,
+    content bytea NOT NULL
+);
+
+CREATE FUNCTION pg_opendal_upload_forward() RETURNS trigger LANGUAGE plpgsql AS $$
+BEGIN
+    PERFORM pg_opendal_writer_write(NEW.handle, NEW.content);
+    RETURN NULL;
+END
+$$;
+
+CREATE TRIGGER pg_opendal_upload_forward
+    BEFORE INSERT ON pg_opendal_upload
+    FOR EACH ROW EXECUTE FUNCTION pg_opendal_upload_forward();
+"#,
+    name = "create_upload_sink",
+    requires = [pg_opendal_writer_write],
+);
+
+/// Attempts made by `pg_opendal_increment` before giving up on a contended counter.
+const INCREMENT_MAX_ATTEMPTS: u32 = 10;
+
+fn parse_counter(path: &str, content: &[u8]) -> Result<i64, String> {
+    std::str::from_utf8(content)
+        .ok()
+        .and_then(|text| text.trim().parse().ok())
+        .ok_or_else(|| format!("Counter '{}' does not hold an integer", path))
+}
+
+/// One read-modify-write of a counter. Returns `Ok(None)` when another writer got in
+/// first, so the caller can retry.
+async fn try_increment_async(op: &Operator, path: &str, delta: i64) -> Result<Option<i64>, String> {
+    let (current, etag) = match op.stat(path).await {
+        Ok(metadata) => {
+            let etag = metadata.etag().map(str::to_string).ok_or_else(|| {
+                format!("Service '{}' reports no ETag for '{}'", op.info().scheme(), path)
+            })?;
+            let content = match op.read_with(path).if_match(&etag).await {
+                Ok(content) => content,
+                Err(e) if e.kind() == opendal::ErrorKind::ConditionNotMatch => return Ok(None),
+                Err(e) => return Err(format!("Failed to read file '{}': {}", path, e)),
+            };
+            (parse_counter(path, &content.to_vec())?, Some(etag))
+        }
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => (0, None),
+        Err(e) => return Err(format!("Failed to get stat for '{}': {}", path, e)),
+    };
+
+    let next = current
+        .checked_add(delta)
+        .ok_or_else(|| format!("Counter '{}' overflows adding {} to {}", path, delta, current))?;
+    let write = op.write_with(path, next.to_string());
+    let write = match &etag {
+        Some(etag) => write.if_match(etag),
+        None => write.if_not_exists(true),
+    };
+    match write.await {
+        Ok(_) => Ok(Some(next)),
+        Err(e) if e.kind() == opendal::ErrorKind::ConditionNotMatch => Ok(None),
+        Err(e) => Err(format!("Failed to write to '{}': {}", path, e)),
+    }
+}
+
+async fn do_increment_async(op: Operator, path: &str, delta: i64) -> Result<i64, String> {
+    let capability = op.info().full_capability();
+    if !(capability.write_with_if_match && capability.write_with_if_not_exists) {
+        return Err(format!(
+            "Service '{}' does not support the conditional writes counters require",
+            op.info().scheme()
+        ));
+    }
+
+    for attempt in 0..INCREMENT_MAX_ATTEMPTS {
+        if let Some(value) = try_increment_async(&op, path, delta).await? {
+            return Ok(value);
+        }
+        // Back off a little longer after each conflict.
+        tokio::time::sleep(std::time::Duration::from_millis(10 << attempt)).await;
+    }
+    Err(format!(
+        "Counter '{}' is still contended after {} attempts",
+        path, INCREMENT_MAX_ATTEMPTS
+    ))
+}
+
+#[pg_extern]
+fn pg_opendal_increment(service: &str, path: &str, delta: i64, config: JsonB) -> Result<i64, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_increment_async(op, &resolve_path(path), delta))
+}
+
+/// `path` with exactly one trailing slash, the form OpenDAL uses for directories.
+fn as_dir_path(path: &str) -> String {
+    format!("{}/", path.trim_end_matches('/'))
+}
+
+/// Stats `path`, retrying as a directory when a path without a trailing slash isn't found.
+/// Object stores only know a directory as `dir/`, so a bare `dir` would otherwise be missing.
+async fn stat_either_async(op: &Operator, path: &str) -> opendal::Result<opendal::Metadata> {
+    match op.stat(path).await {
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound && !path.is_empty() && !path.ends_with('/') => {
+            match op.stat(&as_dir_path(path)).await {
+                Err(dir_err) if dir_err.kind() == opendal::ErrorKind::NotFound => Err(e),
+                result => result,
+            }
+        }
+        result => result,
+    }
+}
+
+/// The path to list for `path`. A trailing slash lists inside it, as does a bare name that
+/// turns out to be a directory; anything else is listed as a name prefix.
+async fn list_path_async(op: &Operator, path: &str) -> Result<String, String> {
+    if path.is_empty() || path.ends_with('/') {
+        return Ok(path.to_string());
+    }
+    match stat_either_async(op, path).await {
+        Ok(metadata) if metadata.is_dir() => Ok(as_dir_path(path)),
+        Ok(_) => Ok(path.to_string()),
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => Ok(path.to_string()),
+        Err(e) => Err(format!("Failed to get stat for '{}': {}", path, e)),
+    }
+}
+
+async fn do_exists_async(op: Operator, path: &str) -> Result<bool, String> {
+    match stat_either_async(&op, path).await {
+        Ok(_) => Ok(true),
+        Err(e) => {
+            if e.kind() == opendal::ErrorKind::NotFound {
+                Ok(false)
+            } else {
+                Err(format!("Failed to check existence of '{}': {}", path, e))
+            }
+        }
+    }
+}
+
+#[pg_extern]
+fn pg_opendal_exists(service: &str, path: &str, config: JsonB) -> Result<bool, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_exists_async(op, &resolve_path(path)))
+}
+
+async fn do_delete_async(op: Operator, path: &str) -> Result<bool, String> {
+    op.delete(path)
+        .await
+        .map(|_| true)
+        .map_err(|e| format!("Failed to delete '{}': {}", path, e))
+}
+
+#[pg_extern]
+fn pg_opendal_delete(service: &str, path: &str, config: JsonB) -> Result<bool, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_delete_async(op, &resolve_path(path)))
+}
+
+/// The JSON object `pg_opendal_stat` describes metadata with.
+fn stat_json(metadata: &opendal::Metadata) -> JsonB {
+    let mut stat_info = serde_json::Map::new();
+    stat_info.insert(
+        "content_length".to_string(),
+        Value::Number(serde_json::Number::from(metadata.content_length())),
+    );
+    stat_info.insert("is_file".to_string(), Value::Bool(metadata.is_file()));
+    stat_info.insert("is_dir".to_string(), Value::Bool(metadata.is_dir()));
+
+    if let Some(last_modified) = metadata.last_modified() {
+        stat_info.insert(
+            "last_modified".to_string(),
+            Value::String(last_modified.to_rfc3339()),
+        );
+    }
+
+    JsonB(Value::Object(stat_info))
+}
+
+async fn do_stat_async(op: Operator, path: &str) -> Result<JsonB, String> {
+    match stat_either_async(&op, path).await {
+        Ok(metadata) => Ok(stat_json(&metadata)),
+        Err(e) => Err(format!("Failed to get stat for '{}': {}", path, e)),
+    }
+}
+
+#[pg_extern]
+fn pg_opendal_stat(service: &str, path: &str, config: JsonB) -> Result<JsonB, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+    
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_stat_async(op, &resolve_path(path)))
+}
+
+/// Like `do_stat_async`, but `None` when nothing exists at `path`.
+async fn do_stat_if_exists_async(op: Operator, path: &str) -> Result<Option<JsonB>, String> {
+    match stat_either_async(&op, path).await {
+        Ok(metadata) => Ok(Some(stat_json(&metadata))),
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("Failed to get stat for '{}': {}", path, e)),
+    }
+}
+
+#[pg_extern]
+fn pg_opendal_stat_if_exists(service: &str, path: &str, config: JsonB) -> Result<Option<JsonB>, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_stat_if_exists_async(op, &resolve_path(path)))
+}
+
+type StatRow = (String, Option<JsonB>, Option<String>);
+
+/// Stats `paths`, `concurrency` at a time, in the order given. Missing paths get no
+/// metadata; other failures, including a panicked task, are reported in the row rather
+/// than failing the batch.
+fn stat_batch(rt: &Runtime, op: &Operator, paths: Vec<String>, concurrency: usize) -> Vec<StatRow> {
+    let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(concurrency));
+    let mut tasks = tokio::task::JoinSet::new();
+    let mut pending = HashMap::with_capacity(paths.len());
+    for (i, path) in paths.into_iter().enumerate() {
+        let (op, task_path, semaphore) = (op.clone(), path.clone(), semaphore.clone());
+        let task = tasks.spawn_on(
+            async move {
+                let _permit = semaphore.acquire_owned().await.map_err(|e| e.to_string())?;
+                do_stat_if_exists_async(op, &task_path).await
+            },
+            rt.handle(),
+        );
+        pending.insert(task.id(), (i, path));
+    }
+
+    let mut rows = Vec::with_capacity(tasks.len());
+    while let Some(joined) = rt.block_on(tasks.join_next_with_id()) {
+        pgrx::check_for_interrupts!();
+        let (id, result) = match joined {
+            Ok((id, result)) => (id, result),
+            Err(e) => (e.id(), Err(format!("Stat task failed: {}", e))),
+        };
+        let Some((i, path)) = pending.remove(&id) else {
+            continue;
+        };
+        match result {
+            Ok(metadata) => rows.push((i, (path, metadata, None))),
+            Err(e) => rows.push((i, (path, None, Some(e)))),
+        }
+    }
+    rows.sort_by_key(|(i, _)| *i);
+    rows.into_iter().map(|(_, row)| row).collect()
+}
+
+/// Number of paths fetched from the query and stat'ed at a time.
+const STAT_BATCH_ROWS: i64 = 1000;
+
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn pg_opendal_stat_from_list(
+    service: &str,
+    paths_query: &str,
+    config: JsonB,
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(path, String),
+            name!(metadata, Option<JsonB>),
+            name!(error, Option<String>),
+        ),
+    >,
+    String,
+> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let concurrency = MAX_CONCURRENCY.get().max(1) as usize;
+    let query = paths_query.trim().trim_end_matches(';');
+    let rows = Spi::connect(|client| {
+        let mut cursor = client
+            .try_open_cursor(&format!("SELECT q.path::text FROM ({}) AS q(path)", query), &[])
+            .map_err(|e| format!("Failed to run paths_query: {}", e))?;
+        let mut rows = Vec::new();
+        loop {
+            let batch = cursor.fetch(STAT_BATCH_ROWS).map_err(|e| format!("Failed to fetch paths: {}", e))?;
+            if batch.is_empty() {
+                break;
+            }
+            let mut paths = Vec::new();
+            for row in batch {
+                match row.get::<String>(1).map_err(|e| format!("Failed to read path: {}", e))? {
+                    Some(path) => paths.push(resolve_path(&path)),
+                    None => pgrx::warning!("Skipping a NULL path returned by paths_query"),
+                }
+            }
+            rows.extend(stat_batch(&rt, &op, paths, concurrency));
+        }
+        Ok::<_, String>(rows)
+    })?;
+    Ok(TableIterator::new(rows))
+}
+
+/// Entries listed, and inventory rows inserted per statement, at a time.
+const INVENTORY_BATCH_ROWS: usize = 1000;
+
+/// The inventory row for `entry`, with metadata from the listing when the service
+/// includes every field, otherwise from a stat.
+async fn inventory_row_async(op: &Operator, entry: opendal::Entry) -> Result<Value, String> {
+    let capability = op.info().full_capability();
+    let metadata = if capability.list_has_content_length
+        && capability.list_has_last_modified
+        && capability.list_has_etag
+        && capability.list_has_content_type
+    {
+        entry.metadata().clone()
+    } else {
+        op.stat(entry.path())
+            .await
+            .map_err(|e| format!("Failed to get metadata for entry '{}': {}", entry.path(), e))?
+    };
+    Ok(serde_json::json!({
+        "path": entry.path(),
+        "is_file": metadata.is_file(),
+        "content_length": metadata.content_length(),
+        "last_modified": metadata.last_modified().map(|t| t.to_rfc3339()),
+        "etag": metadata.etag(),
+        "content_type": metadata.content_type(),
+    }))
+}
+
+/// Lists everything below `path` recursively and passes the inventory rows to `insert`
+/// a batch at a time, fetching metadata `concurrency` entries at a time. Returns the
+/// number of rows.
+fn generate_inventory(
+    rt: &Runtime,
+    op: &Operator,
+    path: &str,
+    concurrency: usize,
+    mut insert: impl FnMut(Vec<Value>) -> Result<(), String>,
+) -> Result<i64, String> {
+    use futures::StreamExt;
+
+    let path = rt.block_on(list_path_async(op, path))?;
+    let mut lister = rt.block_on(lister_after_async(op, &path, true, None))?;
+    let mut total = 0i64;
+    loop {
+        let mut entries = Vec::with_capacity(INVENTORY_BATCH_ROWS);
+        while entries.len() < INVENTORY_BATCH_ROWS {
+            match rt.block_on(lister.try_next())
+                .map_err(|e| format!("Failed to list contents of '{}': {}", path, e))? {
+                // Some backends include the listed directory itself.
+                Some(entry) if entry.path() == path => {}
+                Some(entry) => entries.push(entry),
+                None => break,
+            }
+        }
+        if entries.is_empty() {
+            return Ok(total);
+        }
+
+        let mut pending = futures::stream::iter(entries)
+            .map(|entry| inventory_row_async(op, entry))
+            .buffer_unordered(concurrency);
+        let mut rows = Vec::with_capacity(INVENTORY_BATCH_ROWS);
+        while let Some(row) = rt.block_on(pending.next()) {
+            pgrx::check_for_interrupts!();
+            rows.push(row?);
+        }
+        drop(pending);
+        total += rows.len() as i64;
+        insert(rows)?;
+    }
+}
+
+#[pg_extern]
+fn pg_opendal_generate_inventory(
+    service: &str,
+    path: &str,
+    target_table: &str,
+    config: JsonB,
+) -> Result<i64, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    // Accepts a qualified and quoted name, as a regclass would.
+    let table: String = Spi::get_one_with_args(
+        "SELECT array_to_string(ARRAY(SELECT quote_ident(part) FROM unnest(parse_ident($1)) AS part), '.')",
+        &[target_table.into()],
+    )
+    .map_err(|e| format!("Invalid target_table '{}': {}", target_table, e))?
+    .ok_or_else(|| format!("Invalid target_table '{}'", target_table))?;
+    Spi::run(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (path text, is_file bool, content_length bigint, \
+         last_modified timestamptz, etag text, content_type text)",
+        table
+    ))
+    .map_err(|e| format!("Failed to create {}: {}", table, e))?;
+    // An existing table is left as it is, so check it can take the rows before listing.
+    let missing: Option<String> = Spi::get_one_with_args(
+        "SELECT string_agg(format('%s %s', c.name, c.type), ', ' ORDER BY c.n) \
+         FROM (VALUES (1, 'path', 'text'), (2, 'is_file', 'boolean'), (3, 'content_length', 'bigint'), \
+         (4, 'last_modified', 'timestamp with time zone'), (5, 'etag', 'text'), (6, 'content_type', 'text')) \
+         AS c(n, name, type) \
+         WHERE NOT EXISTS (SELECT FROM pg_attribute a WHERE a.attrelid = $1::regclass \
+         AND a.attname = c.name AND a.attnum > 0 AND NOT a.attisdropped \
+         AND format_type(a.atttypid, NULL) = c.type)",
+        &[table.as_str().into()],
+    )
+    .map_err(|e| format!("Failed to look up columns of {}: {}", table, e))?;
+    if let Some(missing) = missing {
+        return Err(format!(
+            "Table {} already exists without the inventory columns {}",
+            table, missing
+        ));
+    }
+    let insert = format!(
+        "INSERT INTO {} (path, is_file, content_length, last_modified, etag, content_type) \
+         SELECT * FROM jsonb_to_recordset($1) AS r(path text, is_file bool, content_length bigint, \
+         last_modified timestamptz, etag text, content_type text)",
+        table
+    );
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let concurrency = MAX_CONCURRENCY.get().max(1) as usize;
+    generate_inventory(&rt, &op, &resolve_path(path), concurrency, |rows| {
+        Spi::run_with_args(&insert, &[JsonB(Value::Array(rows)).into()])
+            .map_err(|e| format!("Failed to insert into {}: {}", table, e))
+    })
+}
+
+async fn do_create_dir_async(op: Operator, path: &str) -> Result<bool, String> {
+    let path = as_dir_path(path);
+    op.create_dir(&path)
+        .await
+        .map(|_| true)
+        .map_err(|e| format!("Failed to create directory '{}': {}", path, e))
+}
+
+#[pg_extern]
+fn pg_opendal_create_dir(service: &str, path: &str, config: JsonB) -> Result<bool, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_create_dir_async(op, &resolve_path(path)))
+}
+
+/// Every directory prefix of `path`, from the outermost to `path` itself,
+/// each with a trailing slash: `a/b/c` yields `a/`, `a/b/`, `a/b/c/`.
+fn dir_prefixes(path: &str) -> Vec<String> {
+    let mut prefixes = Vec::new();
+    let mut prefix = if path.starts_with('/') { "/".to_string() } else { String::new() };
+    for part in path.split('/').filter(|p| !p.is_empty()) {
+        prefix.push_str(part);
+        prefix.push('/');
+        prefixes.push(prefix.clone());
+    }
+    prefixes
+}
+
+async fn do_create_dir_all_async(op: Operator, path: &str) -> Result<bool, String> {
+    // Directories are virtual on object storage; there is nothing to create.
+    if !op.info().full_capability().create_dir {
+        return Ok(true);
+    }
+
+    for prefix in dir_prefixes(path) {
+        match op.create_dir(&prefix).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == opendal::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(format!("Failed to create directory '{}': {}", prefix, e)),
+        }
+    }
+    Ok(true)
+}
+
+#[pg_extern]
+fn pg_opendal_create_dir_all(service: &str, path: &str, config: JsonB) -> Result<bool, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_create_dir_all_async(op, &resolve_path(path)))
+}
+
+/// Whether `target` is missing or older than `source`. When either side has no
+/// last-modified time the copy goes ahead, since staleness can't be ruled out.
+async fn target_is_stale_async(op: &Operator, source: &str, target: &str) -> Result<bool, String> {
+    let source_meta = op.stat(source).await
+        .map_err(|e| format!("Failed to get stat for '{}': {}", source, e))?;
+    let target_meta = match op.stat(target).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(format!("Failed to get stat for '{}': {}", target, e)),
+    };
+
+    match (source_meta.last_modified(), target_meta.last_modified()) {
+        (Some(src), Some(dst)) => Ok(dst < src),
+        _ => Ok(true),
+    }
+}
+
+async fn do_copy_async(
+    op: Operator,
+    source: &str,
+    target: &str,
+    copy_if_newer: bool,
+) -> Result<bool, String> {
+    if copy_if_newer && !target_is_stale_async(&op, source, target).await? {
+        return Ok(false);
+    }
+
+    op.copy(source, target)
+        .await
+        .map(|_| true)
+        .map_err(|e| format!("Failed to copy from '{}' to '{}': {}", source, target, e))
+}
+
+#[pg_extern]
+fn pg_opendal_copy(
+    service: &str,
+    source: &str,
+    target: &str,
+    config: JsonB,
+    copy_if_newer: default!(bool, false),
+) -> Result<bool, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_copy_async(op, &resolve_path(source), &resolve_path(target), copy_if_newer))
+}
+
+/// Opens a writer for `path` that gives the new object `metadata`'s content headers and
+/// user metadata, as far as the service can store them.
+async fn open_writer_with_metadata_async(
+    op: &Operator,
+    path: &str,
+    metadata: &opendal::Metadata,
+) -> Result<opendal::Writer, String> {
+    let capability = op.info().full_capability();
+    let mut writer = op.writer_with(path);
+    if capability.write_can_multi {
+        writer = writer.chunk(WRITE_CHUNK_SIZE);
+    }
+    if let (true, Some(content_type)) = (capability.write_with_content_type, metadata.content_type()) {
+        writer = writer.content_type(content_type);
+    }
+    if let (true, Some(disposition)) = (capability.write_with_content_disposition, metadata.content_disposition()) {
+        writer = writer.content_disposition(disposition);
+    }
+    if let (true, Some(encoding)) = (capability.write_with_content_encoding, metadata.content_encoding()) {
+        writer = writer.content_encoding(encoding);
+    }
+    if let (true, Some(cache_control)) = (capability.write_with_cache_control, metadata.cache_control()) {
+        writer = writer.cache_control(cache_control);
+    }
+    if let (true, Some(user_metadata)) = (capability.write_with_user_metadata, metadata.user_metadata()) {
+        writer = writer.user_metadata(user_metadata.clone());
+    }
+    writer.await.map_err(|e| format!("Failed to open writer for '{}': {}", path, e))
+}
+
+/// Copies `source` to `target`, server-side where the service supports it, and returns
+/// the new object's ETag and version. A server-side copy keeps the source's metadata, so
+/// without `preserve_metadata` the object is streamed instead. OpenDAL can't read or write
+/// tags, so they can only be kept by S3's server-side copy, which copies them by default.
+async fn do_clone_object_async(
+    op: &Operator,
+    source: &str,
+    target: &str,
+    preserve_metadata: bool,
+    preserve_tags: bool,
+) -> Result<(Option<String>, Option<String>), String> {
+    let server_side = preserve_metadata && op.info().full_capability().copy;
+    if preserve_tags && !(server_side && op.info().scheme() == Scheme::S3) {
+        return Err(format!(
+            "Service '{}' can't preserve tags: that needs a server-side copy on S3 with preserve_metadata",
+            op.info().scheme()
+        ));
+    }
+
+    if server_side {
+        op.copy(source, target)
+            .await
+            .map_err(|e| format!("Failed to copy from '{}' to '{}': {}", source, target, e))?;
+    } else {
+        let writer = if preserve_metadata {
+            let metadata = op.stat(source).await
+                .map_err(|e| format!("Failed to get stat for '{}': {}", source, e))?;
+            open_writer_with_metadata_async(op, target, &metadata).await?
+        } else {
+            open_writer_async(op, target).await?
+        };
+        stream_to_writer_async(op, source, writer, target).await?;
+    }
+
+    let metadata = op.stat(target).await
+        .map_err(|e| format!("Failed to get stat for '{}': {}", target, e))?;
+    Ok((metadata.etag().map(str::to_string), metadata.version().map(str::to_string)))
+}
+
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn pg_opendal_clone_object(
+    service: &str,
+    source: &str,
+    target: &str,
+    config: JsonB,
+    preserve_metadata: default!(bool, true),
+    preserve_tags: default!(bool, false),
+) -> Result<
+    TableIterator<
+        'static,
+        (name!(success, bool), name!(etag, Option<String>), name!(version_id, Option<String>)),
+    >,
+    String,
+> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let (etag, version_id) = rt.block_on(do_clone_object_async(
+        &op,
+        &resolve_path(source),
+        &resolve_path(target),
+        preserve_metadata,
+        preserve_tags,
+    ))?;
+    Ok(TableIterator::once((true, etag, version_id)))
+}
+
+struct CrossServiceCopy {
+    service_src: String,
+    src: String,
+    service_dst: String,
+    dst: String,
+}
+
+fn parse_cross_service_copies(operations: Value) -> Result<Vec<CrossServiceCopy>, String> {
+    let Value::Array(items) = operations else {
+        return Err("Operations must be a JSON array".to_string());
+    };
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let field = |name: &str| {
+                item.get(name)
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+                    .ok_or_else(|| format!("Operation {} is missing string field '{}'", i, name))
+            };
+            Ok(CrossServiceCopy {
+                service_src: field("service_src")?,
+                src: field("src")?,
+                service_dst: field("service_dst")?,
+                dst: field("dst")?,
+            })
+        })
+        .collect()
+}
+
+/// Builds one operator per distinct service name, so each is created only once per batch.
+fn operator_cache<'a>(
+    services: impl Iterator<Item = &'a str>,
+    config: &HashMap<String, String>,
+) -> HashMap<String, Result<Operator, String>> {
+    let mut cache = HashMap::new();
+    for service in services {
+        cache.entry(service.to_string()).or_insert_with(|| {
+            create_operator(service, config.clone())
+                .map_err(|e| format!("Failed to create operator: {}", e))
+        });
+    }
+    cache
+}
+
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn pg_opendal_copy_batch_across_services(
+    operations: JsonB,
+    config_src: JsonB,
+    config_dst: JsonB,
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(src_path, String),
+            name!(dst_path, String),
+            name!(bytes_copied, i64),
+            name!(success, bool),
+            name!(error, Option<String>),
+        ),
+    >,
+    String,
+> {
+    let mut copies = parse_cross_service_copies(operations.0)?;
+    for copy in &mut copies {
+        copy.src = resolve_path(&copy.src);
+        copy.dst = resolve_path(&copy.dst);
+    }
+    let config_src = jsonb_to_hashmap(config_src.0)
+        .map_err(|e| format!("Failed to parse source config: {}", e))?;
+    let config_dst = jsonb_to_hashmap(config_dst.0)
+        .map_err(|e| format!("Failed to parse destination config: {}", e))?;
+
+    let copies = cross_service_copies(copies, &config_src, &config_dst);
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let concurrency = MAX_CONCURRENCY.get().max(1) as usize;
+    let rows = copy_cross_service_batch(&rt, copies, concurrency, |_, _| {});
+    Ok(TableIterator::new(rows))
+}
+
+/// Resolves each copy's services to operators, building each service once per side.
+fn cross_service_copies(
+    copies: Vec<CrossServiceCopy>,
+    config_src: &HashMap<String, String>,
+    config_dst: &HashMap<String, String>,
+) -> Vec<BatchCopy> {
+    let src_ops = operator_cache(copies.iter().map(|c| c.service_src.as_str()), config_src);
+    let dst_ops = operator_cache(copies.iter().map(|c| c.service_dst.as_str()), config_dst);
+    copies
+        .into_iter()
+        .map(|copy| BatchCopy {
+            src_op: src_ops[&copy.service_src].clone(),
+            src: copy.src,
+            dst_op: dst_ops[&copy.service_dst].clone(),
+            dst: copy.dst,
+        })
+        .collect()
+}
+
+/// Parses an array of `{"src": ..., "dst": ...}` objects into `(src, dst)` pairs.
+fn parse_copy_pairs(operations: Value) -> Result<Vec<(String, String)>, String> {
+    let Value::Array(items) = operations else {
+        return Err("Operations must be a JSON array".to_string());
+    };
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let field = |name: &str| {
+                item.get(name)
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+                    .ok_or_else(|| format!("Operation {} is missing string field '{}'", i, name))
+            };
+            Ok((field("src")?, field("dst")?))
+        })
+        .collect()
+}
+
+/// One file of a batch copied between services. An operator that couldn't be built
+/// fails only the copies that need it.
+struct BatchCopy {
+    src_op: Result<Operator, String>,
+    src: String,
+    dst_op: Result<Operator, String>,
+    dst: String,
+}
+
+/// Pairs each `(src, dst)` path with the same source and destination operators.
+fn batch_copies(src_op: &Operator, dst_op: &Operator, pairs: Vec<(String, String)>) -> Vec<BatchCopy> {
+    pairs
+        .into_iter()
+        .map(|(src, dst)| BatchCopy { src_op: Ok(src_op.clone()), src, dst_op: Ok(dst_op.clone()), dst })
+        .collect()
+}
+
+type CopyResultRow = (String, String, i64, bool, Option<String>);
+
+/// Streams each copy, at most `concurrency` at a time, and returns a row per copy in the
+/// order given. A failed copy, even one whose task panicked, is reported in its row
+/// without stopping the others. `progress` is called with the number of copies finished
+/// after each tenth of them.
+fn copy_cross_service_batch(
+    rt: &Runtime,
+    copies: Vec<BatchCopy>,
+    concurrency: usize,
+    mut progress: impl FnMut(usize, usize),
+) -> Vec<CopyResultRow> {
+    let total = copies.len();
+    let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(concurrency));
+    let mut tasks = tokio::task::JoinSet::new();
+    let mut pending = HashMap::with_capacity(total);
+    for (i, BatchCopy { src_op, src, dst_op, dst }) in copies.into_iter().enumerate() {
+        let (task_src, task_dst, semaphore) = (src.clone(), dst.clone(), semaphore.clone());
+        let task = tasks.spawn_on(
+            async move {
+                let _permit = semaphore.acquire_owned().await.map_err(|e| e.to_string())?;
+                match (src_op, dst_op) {
+                    (Ok(src_op), Ok(dst_op)) => stream_copy_async(&src_op, &task_src, &dst_op, &task_dst).await,
+                    (Err(e), _) | (_, Err(e)) => Err(e),
+                }
+            },
+            rt.handle(),
+        );
+        pending.insert(task.id(), (i, src, dst));
+    }
+
+    let step = total.div_ceil(10).max(1);
+    let mut rows = Vec::with_capacity(total);
+    while let Some(joined) = rt.block_on(tasks.join_next_with_id()) {
+        pgrx::check_for_interrupts!();
+        let (id, result) = match joined {
+            Ok((id, result)) => (id, result),
+            Err(e) => (e.id(), Err(format!("Copy task failed: {}", e))),
+        };
+        let Some((i, src, dst)) = pending.remove(&id) else {
+            continue;
+        };
+        match result {
+            Ok(bytes) => rows.push((i, (src, dst, bytes as i64, true, None))),
+            Err(e) => rows.push((i, (src, dst, 0, false, Some(e)))),
+        }
+        if rows.len() % step == 0 || rows.len() == total {
+            progress(rows.len(), total);
+        }
+    }
+    rows.sort_by_key(|(i, _)| *i);
+    rows.into_iter().map(|(_, row)| row).collect()
+}
+
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn pg_opendal_copy_cross_service_batch(
+    src_service: &str,
+    src_config: JsonB,
+    dst_service: &str,
+    dst_config: JsonB,
+    operations: JsonB,
+    max_concurrency: default!(i32, 16),
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(src_path, String),
+            name!(dst_path, String),
+            name!(bytes, i64),
+            name!(success, bool),
+            name!(error, Option<String>),
+        ),
+    >,
+    String,
+> {
+    let concurrency = usize::try_from(max_concurrency)
+        .ok()
+        .filter(|&concurrency| (1..=1024).contains(&concurrency))
+        .ok_or_else(|| format!("Invalid max_concurrency: {}, expected 1 to 1024", max_concurrency))?;
+    let copies: Vec<(String, String)> = parse_copy_pairs(operations.0)?
+        .into_iter()
+        .map(|(src, dst)| (resolve_path(&src), resolve_path(&dst)))
+        .collect();
+    let src_config = jsonb_to_hashmap(src_config.0)
+        .map_err(|e| format!("Failed to parse source config: {}", e))?;
+    let dst_config = jsonb_to_hashmap(dst_config.0)
+        .map_err(|e| format!("Failed to parse destination config: {}", e))?;
+    let src_op = create_operator(src_service, src_config)
+        .map_err(|e| format!("Failed to create source operator: {}", e))?;
+    let dst_op = create_operator(dst_service, dst_config)
+        .map_err(|e| format!("Failed to create destination operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let rows = copy_cross_service_batch(&rt, batch_copies(&src_op, &dst_op, copies), concurrency, |done, total| {
+        pgrx::warning!("Copied {} of {} files ({}%)", done, total, done * 100 / total);
+    });
+    Ok(TableIterator::new(rows))
+}
+
+extension_sql!(
+    r#"
+CREATE TABLE pg_opendal_job (
+    job_id bigserial PRIMARY KEY,
+    operation text NOT NULL,
+    params jsonb NOT NULL,
+    status text NOT NULL DEFAULT 'queued'
+        CHECK (status IN ('queued', 'running', 'succeeded', 'failed')),
+    progress jsonb,
+    result jsonb,
+    error text,
+    worker_pid int,
+    submitted_by name NOT NULL DEFAULT current_user,
+    submitted_at timestamptz NOT NULL DEFAULT now(),
+    started_at timestamptz,
+    finished_at timestamptz
+);
+
+-- Jobs are visible only to the user who submitted them. Rows are only written by the
+-- extension's own functions, as the table's owner (see `as_job_table_owner`), so users
+-- can't forge a job's status or queue jobs that no worker runs.
+ALTER TABLE pg_opendal_job ENABLE ROW LEVEL SECURITY;
+CREATE POLICY pg_opendal_job_owner ON pg_opendal_job FOR SELECT
+    USING (submitted_by = current_user);
+GRANT SELECT ON pg_opendal_job TO PUBLIC;
+"#,
+    name = "create_job_table",
+);
+
+/// First key of the advisory lock a submitting transaction holds on its job until it
+/// ends; the second is derived from the job id by `job_lock_key`.
+const JOB_LOCK_CLASS: i32 = 0x6f64_616c;
+
+fn job_lock_key(job_id: i64) -> i32 {
+    (job_id & 0x7fff_ffff) as i32
+}
+
+/// Runs `f` as the owner of `pg_opendal_job`, the only role that may write to it, and
+/// switches back afterwards, even if `f` fails.
+fn as_job_table_owner<R, E: std::fmt::Display>(f: impl FnOnce() -> Result<R, E>) -> Result<R, String> {
+    struct RestoreUser(pg_sys::Oid, std::ffi::c_int);
+    impl Drop for RestoreUser {
+        fn drop(&mut self) {
+            unsafe { pg_sys::SetUserIdAndSecContext(self.0, self.1) };
+        }
+    }
+
+    let owner = Spi::get_one::<pg_sys::Oid>("SELECT relowner FROM pg_class WHERE oid = 'pg_opendal_job'::regclass")
+        .map_err(|e| format!("Failed to look up the job table: {}", e))?
+        .ok_or("Failed to look up the job table")?;
+    let (mut user, mut context) = (pg_sys::Oid::INVALID, 0);
+    let _restore = unsafe {
+        pg_sys::GetUserIdAndSecContext(&mut user, &mut context);
+        pg_sys::SetUserIdAndSecContext(owner, context | pg_sys::SECURITY_LOCAL_USERID_CHANGE as std::ffi::c_int);
+        RestoreUser(user, context)
+    };
+    f().map_err(|e| e.to_string())
+}
+
+/// A recursive copy between services run by a background worker, as stored in
+/// `pg_opendal_job.params`. Paths are already resolved against `pg_opendal.path_prefix`.
+/// The configs hold credentials, so they are left out of `params` and handed to the
+/// worker through `share_job_configs` instead.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct TransferJob {
+    src_service: String,
+    src_path: String,
+    #[serde(skip)]
+    src_config: HashMap<String, String>,
+    dst_service: String,
+    dst_path: String,
+    #[serde(skip)]
+    dst_config: HashMap<String, String>,
+    concurrency: usize,
+}
+
+type JobConfigs = (HashMap<String, String>, HashMap<String, String>);
+
+/// Copies `configs` into a pinned dynamic shared memory segment, which outlives this
+/// backend until `take_job_configs` unpins it, and returns the segment's handle.
+fn share_job_configs(configs: &JobConfigs) -> Result<pg_sys::dsm_handle, String> {
+    let payload = serde_json::to_vec(configs).map_err(|e| e.to_string())?;
+    let len = payload.len().to_le_bytes();
+    unsafe {
+        let segment = pg_sys::dsm_create(len.len() + payload.len(), 0);
+        let address = pg_sys::dsm_segment_address(segment) as *mut u8;
+        std::ptr::copy_nonoverlapping(len.as_ptr(), address, len.len());
+        std::ptr::copy_nonoverlapping(payload.as_ptr(), address.add(len.len()), payload.len());
+        pg_sys::dsm_pin_segment(segment);
+        let handle = pg_sys::dsm_segment_handle(segment);
+        pg_sys::dsm_detach(segment);
+        Ok(handle)
+    }
+}
+
+/// Reads the configs left by `share_job_configs` and releases their segment.
+fn take_job_configs(handle: pg_sys::dsm_handle) -> Result<JobConfigs, String> {
+    let payload = unsafe {
+        let segment = pg_sys::dsm_attach(handle);
+        if segment.is_null() {
+            return Err("the job's configs are no longer in shared memory".to_string());
+        }
+        let address = pg_sys::dsm_segment_address(segment) as *const u8;
+        let mut len = [0u8; std::mem::size_of::<usize>()];
+        std::ptr::copy_nonoverlapping(address, len.as_mut_ptr(), len.len());
+        let payload = std::slice::from_raw_parts(address.add(len.len()), usize::from_le_bytes(len)).to_vec();
+        pg_sys::dsm_unpin_segment(handle);
+        pg_sys::dsm_detach(segment);
+        payload
+    };
+    serde_json::from_slice(&payload).map_err(|e| format!("Invalid job configs: {}", e))
+}
+
+/// Copies every file under the job's source path to the same relative path under its
+/// destination. A failed copy is recorded in the result without stopping the others.
+fn run_transfer_job(job: &TransferJob, progress: impl FnMut(usize, usize)) -> Result<Value, String> {
+    let src_op = create_operator(&job.src_service, job.src_config.clone())
+        .map_err(|e| format!("Failed to create source operator: {}", e))?;
+    let dst_op = create_operator(&job.dst_service, job.dst_config.clone())
+        .map_err(|e| format!("Failed to create destination operator: {}", e))?;
+    let src_path = as_dir_path(&job.src_path);
+    let dst_path = as_dir_path(&job.dst_path);
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let copies: Vec<(String, String)> = rt
+        .block_on(list_files_recursive_async(&src_op, &src_path, None))?
+        .into_iter()
+        .map(|entry| {
+            let dst = format!("{}{}", dst_path, &entry.path()[src_path.len()..]);
+            (entry.path().to_string(), dst)
+        })
+        .collect();
+    let rows = copy_cross_service_batch(&rt, batch_copies(&src_op, &dst_op, copies), job.concurrency, progress);
+
+    let bytes: i64 = rows.iter().map(|(_, _, bytes, _, _)| bytes).sum();
+    let failed: Vec<Value> = rows
+        .iter()
+        .filter_map(|(src, _, _, _, error)| {
+            error.as_ref().map(|error| serde_json::json!({ "path": src, "error": error }))
+        })
+        .collect();
+    Ok(serde_json::json!({
+        "files": rows.len(),
+        "files_copied": rows.len() - failed.len(),
+        "bytes_copied": bytes,
+        "failed": failed,
+    }))
+}
+
+#[pg_extern]
+fn pg_opendal_submit_transfer(
+    src_service: &str,
+    src_path: &str,
+    src_config: JsonB,
+    dst_service: &str,
+    dst_path: &str,
+    dst_config: JsonB,
+) -> Result<i64, String> {
+    let job = TransferJob {
+        src_service: src_service.to_string(),
+        src_path: resolve_path(src_path),
+        src_config: jsonb_to_hashmap(src_config.0)
+            .map_err(|e| format!("Failed to parse source config: {}", e))?,
+        dst_service: dst_service.to_string(),
+        dst_path: resolve_path(dst_path),
+        dst_config: jsonb_to_hashmap(dst_config.0)
+            .map_err(|e| format!("Failed to parse destination config: {}", e))?,
+        concurrency: MAX_CONCURRENCY.get().max(1) as usize,
+    };
+    // Fail here rather than in the worker on an unusable config.
+    create_operator(&job.src_service, job.src_config.clone())
+        .map_err(|e| format!("Failed to create source operator: {}", e))?;
+    create_operator(&job.dst_service, job.dst_config.clone())
+        .map_err(|e| format!("Failed to create destination operator: {}", e))?;
+    let params = serde_json::to_value(&job).map_err(|e| e.to_string())?;
+
+    let submitted_by = Spi::get_one::<String>("SELECT current_user::text")
+        .map_err(|e| format!("Failed to look up the current user: {}", e))?
+        .ok_or("Failed to look up the current user")?;
+
+    let configs = share_job_configs(&(job.src_config.clone(), job.dst_config.clone()))?;
+
+    let job_id = as_job_table_owner(|| Spi::connect_mut(|client| {
+        let job_id: i64 = client
+            .update(
+                "INSERT INTO pg_opendal_job (operation, params, submitted_by) \
+                 VALUES ('transfer', $1, $2) RETURNING job_id",
+                None,
+                &[JsonB(params).into(), submitted_by.as_str().into()],
+            )
+            .and_then(|rows| rows.first().get_one())
+            .map_err(|e| format!("Failed to record job: {}", e))?
+            .ok_or("Failed to record job")?;
+        // The worker waits on this lock, so it only looks for the job once it is committed.
+        client
+            .update(
+                "SELECT pg_advisory_xact_lock($1, $2)",
+                None,
+                &[JOB_LOCK_CLASS.into(), job_lock_key(job_id).into()],
+            )
+            .map_err(|e| format!("Failed to lock job: {}", e))?;
+        Ok::<_, String>(job_id)
+    }))
+    .inspect_err(|_| unsafe { pg_sys::dsm_unpin_segment(configs) })?;
+
+    let (database, user, backend) = unsafe { (pg_sys::MyDatabaseId, pg_sys::GetUserId(), pg_sys::MyProcPid) };
+    let worker = pgrx::bgworkers::BackgroundWorkerBuilder::new(&format!("pg_opendal job {}", job_id))
+        .set_type("pg_opendal job")
+        .set_library("pg_opendal")
+        .set_function("pg_opendal_job_worker_main")
+        .set_argument(Some(pg_sys::Datum::from(job_id)))
+        .set_extra(&format!("{} {} {}", database.to_u32(), user.to_u32(), configs))
+        .set_notify_pid(backend)
+        .enable_spi_access()
+        .set_start_time(pgrx::bgworkers::BgWorkerStartTime::RecoveryFinished)
+        .load_dynamic()
+        .map_err(|_| {
+            unsafe { pg_sys::dsm_unpin_segment(configs) };
+            "Failed to start a background worker for the job; check max_worker_processes".to_string()
+        })?;
+    // Recorded now so a job whose worker dies later is reported as lost. A worker that
+    // didn't start never takes its configs, which it only does once this transaction ends.
+    match worker.wait_for_startup() {
+        Ok(worker_pid) => as_job_table_owner(|| {
+            Spi::run_with_args(
+                "UPDATE pg_opendal_job SET worker_pid = $2 WHERE job_id = $1",
+                &[job_id.into(), worker_pid.into()],
+            )
+        }),
+        Err(status) => {
+            unsafe { pg_sys::dsm_unpin_segment(configs) };
+            let error = format!("The background worker for the job failed to start ({:?})", status);
+            pgrx::warning!("Job {}: {}", job_id, error);
+            as_job_table_owner(|| {
+                Spi::run_with_args(
+                    "UPDATE pg_opendal_job SET status = 'failed', error = $2, finished_at = now() WHERE job_id = $1",
+                    &[job_id.into(), error.into()],
+                )
+            })
+        }
+    }
+    .map_err(|e| format!("Failed to record job: {}", e))?;
+    Ok(job_id)
+}
+
+/// Runs one submitted job in its own background worker process, as the submitting user,
+/// so it outlives the client session. The argument is the job id; the extra data holds the
+/// database, the user and the handle of the segment holding the job's configs.
+#[pg_guard]
+#[no_mangle]
+pub extern "C-unwind" fn pg_opendal_job_worker_main(arg: pg_sys::Datum) {
+    use pgrx::bgworkers::BackgroundWorker;
+
+    let job_id = arg.value() as i64;
+    let extra: Vec<u32> = BackgroundWorker::get_extra()
+        .split(' ')
+        .map_while(|value| value.parse().ok())
+        .collect();
+    let &[database, user, configs] = extra.as_slice() else {
+        pgrx::error!("Job {} was started without a database, user and configs", job_id);
+    };
+    unsafe {
+        // Exit at the next interrupt check on shutdown, as regular backends do.
+        pg_sys::pqsignal(pg_sys::SIGTERM as i32, Some(pg_sys::die));
+        pg_sys::BackgroundWorkerUnblockSignals();
+        pg_sys::BackgroundWorkerInitializeConnectionByOid(database.into(), user.into(), 0);
+    }
+    // Once the submitting transaction ends, the job row is either committed or gone.
+    BackgroundWorker::transaction(|| {
+        Spi::run_with_args(
+            "SELECT pg_advisory_xact_lock($1, $2)",
+            &[JOB_LOCK_CLASS.into(), job_lock_key(job_id).into()],
+        )
+    })
+    .unwrap_or_else(|e| pgrx::error!("Failed to wait for job {}: {}", job_id, e));
+    // Released before anything else, so the segment doesn't outlive a rolled back job. Not
+    // before the lock, as until then the submitting backend may still release it itself.
+    let configs = take_job_configs(configs);
+
+    let params = BackgroundWorker::transaction(|| {
+        as_job_table_owner(|| Spi::connect_mut(|client| {
+            let rows = client.update(
+                "UPDATE pg_opendal_job SET status = 'running', started_at = now(), worker_pid = pg_backend_pid() \
+                 WHERE job_id = $1 AND status = 'queued' RETURNING params",
+                None,
+                &[job_id.into()],
+            )?;
+            if rows.is_empty() {
+                return Ok(None);
+            }
+            rows.first().get_one::<JsonB>()
+        }))
+    })
+    .unwrap_or_else(|e| pgrx::error!("Failed to start job {}: {}", job_id, e));
+    let Some(JsonB(params)) = params else {
+        // The submitting transaction rolled back.
+        return;
+    };
+
+    let outcome = serde_json::from_value::<TransferJob>(params)
+        .map_err(|e| format!("Invalid job parameters: {}", e))
+        .and_then(|job| {
+            let (src_config, dst_config) = configs?;
+            Ok(TransferJob { src_config, dst_config, ..job })
+        })
+        .and_then(|job| {
+            run_transfer_job(&job, |done, total| {
+                let progress = JsonB(serde_json::json!({ "files_done": done, "files_total": total }));
+                BackgroundWorker::transaction(|| {
+                    as_job_table_owner(|| {
+                        Spi::run_with_args(
+                            "UPDATE pg_opendal_job SET progress = $2 WHERE job_id = $1",
+                            &[job_id.into(), progress.into()],
+                        )
+                    })
+                })
+                .unwrap_or_else(|e| pgrx::warning!("Failed to record progress of job {}: {}", job_id, e));
+            })
+        });
+    let (status, result, error) = match outcome {
+        Ok(result) => match result["failed"].as_array().map_or(0, Vec::len) {
+            0 => ("succeeded", Some(JsonB(result)), None),
+            failed => ("failed", Some(JsonB(result)), Some(format!("{} files failed to copy", failed))),
+        },
+        Err(e) => ("failed", None, Some(e)),
+    };
+    BackgroundWorker::transaction(|| {
+        as_job_table_owner(|| {
+            Spi::run_with_args(
+                "UPDATE pg_opendal_job SET status = $2, result = $3, error = $4, finished_at = now() \
+                 WHERE job_id = $1",
+                &[job_id.into(), status.into(), result.into(), error.into()],
+            )
+        })
+    })
+    .unwrap_or_else(|e| pgrx::error!("Failed to record the outcome of job {}: {}", job_id, e));
+}
+
+#[pg_extern]
+fn pg_opendal_job_status(job_id: i64) -> Result<JsonB, String> {
+    // A queued or running job whose worker is gone was killed before it could record an outcome.
+    Spi::get_one_with_args::<JsonB>(
+        "SELECT (SELECT jsonb_build_object( \
+             'job_id', job_id, \
+             'operation', operation, \
+             'status', CASE WHEN status IN ('queued', 'running') AND NOT EXISTS \
+                 (SELECT 1 FROM pg_stat_activity a WHERE a.pid = j.worker_pid) THEN 'lost' ELSE status END, \
+             'progress', progress, \
+             'result', result, \
+             'error', error, \
+             'submitted_by', submitted_by, \
+             'submitted_at', submitted_at, \
+             'started_at', started_at, \
+             'finished_at', finished_at) \
+         FROM pg_opendal_job j WHERE job_id = $1)",
+        &[job_id.into()],
+    )
+    .map_err(|e| format!("Failed to look up job {}: {}", job_id, e))?
+    .ok_or_else(|| format!("Job {} not found", job_id))
+}
+
+/// Builds the GET request for `url`, which must be an http or https URL of a file. The
+/// URL is sent as given, query string included, so presigned URLs work.
+fn download_request(url: &str) -> Result<reqwest::Request, String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| format!("Invalid URL '{}': {}", url, e))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("Invalid URL '{}': expected an http or https URL", url));
+    }
+    if parsed.path().ends_with('/') {
+        return Err(format!("Invalid URL '{}': expected a file, not a directory", url));
+    }
+    Ok(reqwest::Request::new(reqwest::Method::GET, parsed))
+}
+
+/// Streams `url` into `dst_path`, returning the number of bytes written. The shared client
+/// follows redirects, so moved objects are still fetched. A failed request is reported by
+/// its HTTP status, and the writer is aborted if the download fails partway.
+async fn do_ingest_url_async(url: &str, dst: &Operator, dst_path: &str) -> Result<u64, String> {
+    let request = download_request(url)?;
+    let transport = HttpTransport::from_config(&HashMap::new())?;
+    let failed = |e: String| format!("Failed to download '{}': {}", url, e);
+    let mut response = transport.send(request).await.map_err(failed)?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(failed(format!(
+            "HTTP {} {}",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or("Unknown")
+        )));
+    }
+
+    let mut writer = open_writer_async(dst, dst_path).await?;
+    let mut copied = 0u64;
+    loop {
+        let chunk = match transport.within_timeout(response.chunk()).await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => {
+                let _ = writer.abort().await;
+                return Err(failed(e));
+            }
+        };
+        copied += chunk.len() as u64;
+        if let Err(e) = writer.write(chunk).await {
+            let _ = writer.abort().await;
+            return Err(format!("Failed to write to '{}': {}", dst_path, e));
+        }
+    }
+    writer.close().await
+        .map_err(|e| format!("Failed to finish writing '{}': {}", dst_path, e))?;
+    Ok(copied)
+}
+
+#[pg_extern]
+fn pg_opendal_ingest_url(
+    url: &str,
+    dst_service: &str,
+    dst_path: &str,
+    dst_config: JsonB,
+) -> Result<i64, String> {
+    let config_map = jsonb_to_hashmap(dst_config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(dst_service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_ingest_url_async(url, &op, &resolve_path(dst_path)))
+        .map(|bytes| bytes as i64)
+}
+
+async fn do_rename_async(op: Operator, source: &str, target: &str) -> Result<bool, String> {
+    op.rename(source, target)
+        .await
+        .map(|_| true)
+        .map_err(|e| format!("Failed to rename from '{}' to '{}': {}", source, target, e))
+}
+
+#[pg_extern]
+fn pg_opendal_rename(service: &str, source: &str, target: &str, config: JsonB) -> Result<bool, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_rename_async(op, &resolve_path(source), &resolve_path(target)))
+}
+
+/// Copies a file within one service, natively where supported and by streaming otherwise.
+async fn copy_within_async(op: &Operator, source: &str, target: &str) -> Result<(), String> {
+    if op.info().full_capability().copy {
+        op.copy(source, target)
+            .await
+            .map_err(|e| format!("Failed to copy from '{}' to '{}': {}", source, target, e))
+    } else {
+        stream_copy_async(op, source, op, target).await.map(|_| ())
+    }
+}
+
+/// Files moved between progress warnings from `pg_opendal_move_recursive`.
+const MOVE_PROGRESS_INTERVAL: usize = 100;
+
+/// Moves every file under `source` to the same relative path under `target`, as
+/// `move_files` does.
+fn move_recursive(
+    rt: &Runtime,
+    op: &Operator,
+    source: &str,
+    target: &str,
+    progress: impl FnMut(usize, usize),
+) -> Result<i64, String> {
+    let source = as_dir_path(source);
+    let target = as_dir_path(target);
+    if source == "/" {
+        return Err("Refusing to move everything: source must not be empty or '/'".to_string());
+    }
+    if source.starts_with(&target) || target.starts_with(&source) {
+        return Err(format!("Can't move '{}' to '{}': one contains the other", source, target));
+    }
+
+    let moves: Vec<(String, String)> = rt
+        .block_on(list_files_recursive_async(op, &source, None))?
+        .into_iter()
+        .map(|entry| {
+            let destination = format!("{}{}", target, &entry.path()[source.len()..]);
+            (entry.path().to_string(), destination)
+        })
+        .collect();
+    move_files(rt, op, &moves, progress)
+}
+
+/// Moves each `(source, destination)` file, copying them all before deleting any
+/// originals. Refuses to start if a destination already exists, so a rollback never
+/// deletes a file this call didn't create: if a copy fails the copies made so far are
+/// deleted, leaving the sources intact. `progress` is called with the number of files
+/// copied so far and the total.
+fn move_files(
+    rt: &Runtime,
+    op: &Operator,
+    moves: &[(String, String)],
+    mut progress: impl FnMut(usize, usize),
+) -> Result<i64, String> {
+    for (source, destination) in moves {
+        let exists = rt.block_on(op.exists(destination))
+            .map_err(|e| format!("Failed to check whether '{}' exists: {}", destination, e))?;
+        if exists {
+            return Err(format!("Can't move '{}' to '{}': the destination already exists", source, destination));
+        }
+    }
+
+    let mut copied = Vec::with_capacity(moves.len());
+    for (source, destination) in moves {
+        if let Err(e) = rt.block_on(copy_within_async(op, source, destination)) {
+            let rollback = rt.block_on(op.delete_iter(copied));
+            return Err(match rollback {
+                Ok(()) => format!("{}; copies made so far were deleted", e),
+                Err(rollback) => format!("{}; deleting the copies made so far also failed: {}", e, rollback),
+            });
+        }
+        copied.push(destination.clone());
+        if copied.len() % MOVE_PROGRESS_INTERVAL == 0 {
+            progress(copied.len(), moves.len());
+        }
+    }
+
+    rt.block_on(op.delete_iter(moves.iter().map(|(source, _)| source.clone())))
+        .map_err(|e| format!("Copied all files but failed to delete the originals: {}", e))?;
+    Ok(moves.len() as i64)
+}
+
+#[pg_extern]
+fn pg_opendal_move_recursive(service: &str, src_path: &str, dst_path: &str, config: JsonB) -> Result<i64, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let moved = move_recursive(&rt, &op, &resolve_path(src_path), &resolve_path(dst_path), |done, total| {
+        pgrx::warning!("Copied {} of {} files", done, total);
+    })?;
+    pgrx::warning!("Moved {} files from '{}' to '{}'", moved, src_path, dst_path);
+    Ok(moved)
+}
+
+/// Maps each path matching `pattern` to its first match replaced by `template`, which
+/// may refer to capture groups as `$1` or `${name}`. Paths that don't match or map to
+/// themselves are left out. Fails if two paths would map to the same destination, or a
+/// destination is one of `paths`: files are copied one at a time, so even a destination
+/// that is renamed away too would be overwritten before it is copied. Destinations
+/// outside `paths` are checked by `check_rename_destinations_async`.
+fn rename_plan(
+    paths: &[String],
+    pattern: &regex::Regex,
+    template: &str,
+) -> Result<Vec<(String, String)>, String> {
+    let mut plan = Vec::new();
+    let mut sources_by_destination: HashMap<String, &str> = HashMap::new();
+    for path in paths {
+        if !pattern.is_match(path) {
+            continue;
+        }
+        let destination = pattern.replace(path, template).into_owned();
+        if destination == *path {
+            continue;
+        }
+        if destination.is_empty() || destination.ends_with('/') {
+            return Err(format!("'{}' would be renamed to '{}', which is not a file path", path, destination));
+        }
+        if let Some(other) = sources_by_destination.insert(destination.clone(), path) {
+            return Err(format!(
+                "'{}' and '{}' would both be renamed to '{}'",
+                other, path, destination
+            ));
+        }
+        plan.push((path.clone(), destination));
+    }
+
+    let existing: std::collections::HashSet<&str> = paths.iter().map(String::as_str).collect();
+    if let Some((source, destination)) = plan.iter().find(|(_, destination)| existing.contains(destination.as_str())) {
+        return Err(format!("'{}' would be renamed to '{}', which already exists", source, destination));
+    }
+    Ok(plan)
+}
+
+/// Fails if any destination in `plan` exists, for dry runs; `move_files` checks again
+/// before a real rename. `rename_plan` only knows the listed paths, so the others, e.g.
+/// outside the prefix, are checked with a stat each.
+async fn check_rename_destinations_async(
+    op: &Operator,
+    plan: &[(String, String)],
+    listed: &[String],
+) -> Result<(), String> {
+    let listed: std::collections::HashSet<&str> = listed.iter().map(String::as_str).collect();
+    for (source, destination) in plan {
+        if listed.contains(destination.as_str()) {
+            continue;
+        }
+        let exists = op.exists(destination).await
+            .map_err(|e| format!("Failed to check whether '{}' exists: {}", destination, e))?;
+        if exists {
+            return Err(format!("'{}' would be renamed to '{}', which already exists", source, destination));
+        }
+    }
+    Ok(())
+}
+
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn pg_opendal_rename_pattern(
+    service: &str,
+    prefix: &str,
+    match_regex: &str,
+    replace_template: &str,
+    config: JsonB,
+    dry_run: default!(bool, true),
+) -> Result<TableIterator<'static, (name!(old_path, String), name!(new_path, String))>, String> {
+    let pattern = regex::Regex::new(match_regex)
+        .map_err(|e| format!("Invalid match_regex '{}': {}", match_regex, e))?;
+    let prefix = resolve_path(prefix);
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let paths: Vec<String> = rt
+        .block_on(list_files_recursive_async(&op, &prefix, None))?
+        .into_iter()
+        .map(|entry| entry.path().to_string())
+        .collect();
+    let plan = rename_plan(&paths, &pattern, replace_template)?;
+
+    if dry_run {
+        rt.block_on(check_rename_destinations_async(&op, &plan, &paths))?;
+        pgrx::warning!(
+            "{} files under '{}' match '{}' (dry run, nothing renamed)",
+            plan.len(), prefix, match_regex
+        );
+        return Ok(TableIterator::new(plan));
+    }
+
+    move_files(&rt, &op, &plan, |done, total| {
+        pgrx::warning!("Copied {} of {} files", done, total);
+    })?;
+    pgrx::warning!("Renamed {} files under '{}'", plan.len(), prefix);
+    Ok(TableIterator::new(plan))
+}
+
+/// Converts a timestamptz, in microseconds since 2000-01-01 UTC, to a chrono time.
+/// `-infinity` and `infinity` become the earliest and latest representable times.
+fn postgres_micros_to_utc(micros: i64) -> chrono::DateTime<chrono::Utc> {
+    const POSTGRES_EPOCH_MICROS: i64 = 946_684_800_000_000;
+    micros
+        .checked_add(POSTGRES_EPOCH_MICROS)
+        .and_then(chrono::DateTime::from_timestamp_micros)
+        .unwrap_or(if micros < 0 { chrono::DateTime::<chrono::Utc>::MIN_UTC } else { chrono::DateTime::<chrono::Utc>::MAX_UTC })
+}
+
+/// When a listed file was last modified, from the listing if the service includes it
+/// there and from a stat otherwise.
+async fn entry_last_modified_async(
+    op: &Operator,
+    entry: &opendal::Entry,
+) -> Result<Option<chrono::DateTime<chrono::Utc>>, String> {
+    if op.info().full_capability().list_has_last_modified {
+        return Ok(entry.metadata().last_modified());
+    }
+    op.stat(entry.path())
+        .await
+        .map(|metadata| metadata.last_modified())
+        .map_err(|e| format!("Failed to get metadata for entry '{}': {}", entry.path(), e))
+}
+
+/// Moves `entry` from under `source` to the same relative path under `target` if it was
+/// last modified before `cutoff`. Returns whether it was moved; files whose service
+/// reports no modification time are left alone.
+async fn tier_file_async(
+    op: &Operator,
+    entry: opendal::Entry,
+    source: &str,
+    target: &str,
+    cutoff: chrono::DateTime<chrono::Utc>,
+) -> Result<bool, String> {
+    let modified = entry_last_modified_async(op, &entry).await?;
+    if modified.is_none_or(|modified| modified >= cutoff) {
+        return Ok(false);
+    }
+    let destination = format!("{}{}", target, &entry.path()[source.len()..]);
+    copy_within_async(op, entry.path(), &destination).await?;
+    op.delete(entry.path()).await
+        .map_err(|e| format!("Copied '{}' to '{}' but failed to delete the original: {}", entry.path(), destination, e))?;
+    Ok(true)
+}
+
+/// Moves the files under `source` last modified before `cutoff` to the same relative
+/// paths under `target`, `concurrency` at a time. Each file is copied before its original
+/// is deleted, so a failure or cancellation never loses one. Stops at the first failure.
+fn tier(
+    rt: &Runtime,
+    op: &Operator,
+    source: &str,
+    target: &str,
+    cutoff: chrono::DateTime<chrono::Utc>,
+    concurrency: usize,
+) -> Result<i64, String> {
+    use futures::StreamExt;
+
+    let source = as_dir_path(source);
+    let target = as_dir_path(target);
+    if source.starts_with(&target) || target.starts_with(&source) {
+        return Err(format!("Can't tier '{}' to '{}': one contains the other", source, target));
+    }
+
+    let files = rt.block_on(list_files_recursive_async(op, &source, None))?;
+    let mut moves = futures::stream::iter(files)
+        .map(|entry| tier_file_async(op, entry, &source, &target, cutoff))
+        .buffer_unordered(concurrency);
+    let mut moved = 0i64;
+    while let Some(result) = rt.block_on(moves.next()) {
+        pgrx::check_for_interrupts!();
+        match result {
+            Ok(true) => moved += 1,
+            Ok(false) => {}
+            Err(e) => return Err(format!("{}; {} files were moved before the failure", e, moved)),
+        }
+    }
+    Ok(moved)
+}
+
+#[pg_extern]
+fn pg_opendal_tier(
+    service: &str,
+    src_prefix: &str,
+    dst_prefix: &str,
+    older_than: TimestampWithTimeZone,
+    config: JsonB,
+) -> Result<i64, String> {
+    let cutoff = postgres_micros_to_utc(pg_sys::TimestampTz::from(older_than));
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let concurrency = MAX_CONCURRENCY.get().max(1) as usize;
+    tier(&rt, &op, &resolve_path(src_prefix), &resolve_path(dst_prefix), cutoff, concurrency)
+}
+
+/// Whether the local file at `local` already matches a remote file of `size` bytes last
+/// modified at `modified`: same size, and modified no earlier than the remote copy.
+fn local_copy_is_current(local: &std::path::Path, size: u64, modified: Option<chrono::DateTime<chrono::Utc>>) -> bool {
+    let Ok(metadata) = std::fs::metadata(local) else {
+        return false;
+    };
+    match (metadata.modified(), modified) {
+        (Ok(local_modified), Some(modified)) => {
+            metadata.is_file() && metadata.len() == size && chrono::DateTime::<chrono::Utc>::from(local_modified) >= modified
+        }
+        _ => false,
+    }
+}
+
+/// The local path mirroring `relative`, refusing names that would escape `root`.
+fn mirror_local_path(root: &std::path::Path, relative: &str) -> Result<std::path::PathBuf, String> {
+    let relative_path = std::path::Path::new(relative);
+    if relative.is_empty() || !relative_path.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+        return Err(format!("Refusing to mirror '{}' outside of '{}'", relative, root.display()));
+    }
+    Ok(root.join(relative_path))
+}
+
+/// Downloads `path` to `local`, through a temporary file next to it that's renamed into
+/// place, so an interrupted download never leaves a partial file looking current.
+async fn download_to_local_async(op: &Operator, path: &str, local: &std::path::Path) -> Result<(), String> {
+    use std::io::Write;
+
+    if let Some(parent) = local.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create directory '{}': {}", parent.display(), e))?;
+    }
+    let mut tmp_name = local.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".pg_opendal_tmp");
+    let tmp = local.with_file_name(tmp_name);
+    let result = async {
+        let mut file = std::fs::File::create(&tmp)
+            .map_err(|e| format!("Failed to create '{}': {}", tmp.display(), e))?;
+        let reader = op.reader_with(path).chunk(READ_CHUNK_SIZE).await
+            .map_err(|e| format!("Failed to open reader for '{}': {}", path, e))?;
+        let mut stream = reader.into_stream(..).await
+            .map_err(|e| format!("Failed to read '{}': {}", path, e))?;
+        while let Some(chunk) = stream.try_next().await
+            .map_err(|e| format!("Failed to read '{}': {}", path, e))? {
+            for bytes in chunk {
+                file.write_all(&bytes)
+                    .map_err(|e| format!("Failed to write '{}': {}", tmp.display(), e))?;
+            }
+        }
+        std::fs::rename(&tmp, local)
+            .map_err(|e| format!("Failed to move '{}' into place: {}", local.display(), e))
+    }
+    .await;
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Copies every file below `source` to the same relative path under `local_root`,
+/// skipping files whose local copy is current. Returns the counts of copied, skipped and
+/// failed files, and the failures' errors; only a failure to list stops the mirror.
+fn mirror_to_local(
+    rt: &Runtime,
+    op: &Operator,
+    source: &str,
+    local_root: &std::path::Path,
+) -> Result<(i64, i64, Vec<String>), String> {
+    let source = as_dir_path(source);
+    let files = rt.block_on(list_files_recursive_async(op, &source, None))?;
+    let (mut copied, mut skipped) = (0i64, 0i64);
+    let mut failures = Vec::new();
+    for entry in files {
+        pgrx::check_for_interrupts!();
+        let result = rt.block_on(async {
+            let relative = entry.path().strip_prefix(source.as_str()).unwrap_or(entry.path());
+            let local = mirror_local_path(local_root, relative)?;
+            let metadata = op.stat(entry.path()).await
+                .map_err(|e| format!("Failed to get metadata for '{}': {}", entry.path(), e))?;
+            if local_copy_is_current(&local, metadata.content_length(), metadata.last_modified()) {
+                return Ok(false);
+            }
+            download_to_local_async(op, entry.path(), &local).await.map(|_| true)
+        });
+        match result {
+            Ok(true) => copied += 1,
+            Ok(false) => skipped += 1,
+            Err(e) => failures.push(e),
+        }
+    }
+    Ok((copied, skipped, failures))
+}
+
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn pg_opendal_mirror_to_local(
+    src_service: &str,
+    src_path: &str,
+    src_config: JsonB,
+    local_dst_path: &str,
+) -> Result<TableIterator<'static, (name!(copied, i64), name!(skipped, i64), name!(failed, i64))>, String> {
+    let local_root = std::path::Path::new(local_dst_path);
+    if !local_root.is_absolute() {
+        return Err(format!("local_dst_path '{}' must be an absolute path", local_dst_path));
+    }
+    let config_map = jsonb_to_hashmap(src_config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(src_service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let (copied, skipped, failures) = mirror_to_local(&rt, &op, &resolve_path(src_path), local_root)?;
+    for failure in &failures {
+        pgrx::warning!("{}", failure);
+    }
+    Ok(TableIterator::once((copied, skipped, failures.len() as i64)))
+}
+
+/// Builds the JSON object describing a listed entry, as returned by `pg_opendal_list`.
+async fn entry_info_async(
+    op: &Operator,
+    entry: &opendal::Entry,
+) -> Result<serde_json::Map<String, Value>, String> {
+    // Fetch metadata for each entry asynchronously
+    let metadata = op.stat(entry.path()).await
+        .map_err(|e| format!("Failed to get metadata for entry '{}': {}", entry.path(), e))?;
+    Ok(entry_metadata_info(entry, &metadata))
+}
+
+/// Like `entry_info_async`, but built from the listing's own metadata when the service
+/// returns sizes and modification times inline, saving a stat per entry.
+async fn listed_entry_info_async(
+    op: &Operator,
+    entry: &opendal::Entry,
+) -> Result<serde_json::Map<String, Value>, String> {
+    let capability = op.info().full_capability();
+    if capability.list_has_content_length && capability.list_has_last_modified {
+        Ok(entry_metadata_info(entry, entry.metadata()))
+    } else {
+        entry_info_async(op, entry).await
+    }
+}
+
+fn entry_metadata_info(
+    entry: &opendal::Entry,
+    metadata: &opendal::Metadata,
+) -> serde_json::Map<String, Value> {
+    let mut entry_info = serde_json::Map::new();
+    entry_info.insert("name".to_string(), Value::String(entry.name().to_string()));
+    entry_info.insert("path".to_string(), Value::String(entry.path().to_string()));
+
+    entry_info.insert("is_file".to_string(), Value::Bool(metadata.is_file()));
+    entry_info.insert("is_dir".to_string(), Value::Bool(metadata.is_dir()));
+    entry_info.insert(
+        "content_length".to_string(),
+        Value::Number(serde_json::Number::from(metadata.content_length())),
+    );
+
+    if let Some(last_modified) = metadata.last_modified() {
+        entry_info.insert(
+            "last_modified".to_string(),
+            Value::String(last_modified.to_rfc3339()),
+        );
+    }
+    entry_info
+}
+
+/// Opens a lister that skips entries up to and including `start_after`, natively when
+/// the service supports it. Callers filter with `listed_after` too, which covers the rest.
+async fn lister_after_async(
+    op: &Operator,
+    path: &str,
+    recursive: bool,
+    start_after: Option<&str>,
+) -> Result<opendal::Lister, String> {
+    let path = list_path_async(op, path).await?;
+    let lister = op.lister_with(&path).recursive(recursive);
+    let lister = match start_after {
+        Some(after) if op.info().full_capability().list_with_start_after => lister.start_after(after),
+        _ => lister,
+    };
+    lister.await.map_err(|e| format!("Failed to get lister for '{}': {}", path, e))
+}
+
+/// Whether `entry` sorts after `start_after`, so belongs in a resumed scan.
+fn listed_after(entry: &opendal::Entry, start_after: Option<&str>) -> bool {
+    match start_after {
+        Some(after) => entry.path() > after,
+        None => true,
+    }
+}
+
+/// Services without native start-after support list everything and skip client-side.
+fn warn_if_start_after_unsupported(op: &Operator, start_after: Option<&str>) {
+    if start_after.is_some() && !op.info().full_capability().list_with_start_after {
+        pgrx::warning!(
+            "Service '{}' does not support start_after; listing everything and skipping client-side",
+            op.info().scheme()
+        );
+    }
+}
+
+/// Optional include/exclude regexes matched against listed entry paths. An entry is kept
+/// when it matches `include` (if set) and does not match `exclude` (if set).
+#[derive(Default)]
+struct PathFilter {
+    include: Option<regex::Regex>,
+    exclude: Option<regex::Regex>,
+}
+
+impl PathFilter {
+    fn new(include_regex: Option<&str>, exclude_regex: Option<&str>) -> Result<Self, String> {
+        let compile = |name: &str, pattern: Option<&str>| {
+            pattern
+                .map(|p| regex::Regex::new(p).map_err(|e| format!("Invalid {} '{}': {}", name, p, e)))
+                .transpose()
+        };
+        Ok(Self {
+            include: compile("include_regex", include_regex)?,
+            exclude: compile("exclude_regex", exclude_regex)?,
+        })
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        self.include.as_ref().is_none_or(|re| re.is_match(path))
+            && !self.exclude.as_ref().is_some_and(|re| re.is_match(path))
+    }
+}
+
+async fn do_list_async(
+    op: Operator,
+    path: &str,
+    start_after: Option<&str>,
+    filter: &PathFilter,
+) -> Result<Vec<JsonB>, String> {
+    let mut lister = lister_after_async(&op, path, false, start_after).await?;
+    
+    let mut results = Vec::new();
+    
+    while let Some(entry) = lister.try_next().await
+        .map_err(|e| format!("Failed to list contents of '{}': {}", path, e))? {
+        if !listed_after(&entry, start_after) || !filter.matches(entry.path()) {
+            continue;
+        }
+        let entry_info = entry_info_async(&op, &entry).await?;
+        results.push(JsonB(Value::Object(entry_info)));
+    }
+    Ok(results)
+}
+
+#[pg_extern]
+fn pg_opendal_list(
+    service: &str,
+    path: &str,
+    config: JsonB,
+    start_after: default!(Option<&str>, "NULL"),
+    include_regex: default!(Option<&str>, "NULL"),
+    exclude_regex: default!(Option<&str>, "NULL"),
+) -> Result<Vec<JsonB>, String> {
+    let filter = PathFilter::new(include_regex, exclude_regex)?;
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+    
+    let start_after = start_after.map(resolve_path);
+    warn_if_start_after_unsupported(&op, start_after.as_deref());
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_list_async(op, &resolve_path(path), start_after.as_deref(), &filter))
+}
+
+async fn do_list_recursive_depth_async(
+    op: Operator,
+    path: &str,
+    max_depth: i32,
+    filter: &PathFilter,
+) -> Result<Vec<JsonB>, String> {
+    if max_depth < 1 {
+        return Err(format!("max_depth must be at least 1, got {}", max_depth));
+    }
+
+    let mut results = Vec::new();
+    let mut queue = std::collections::VecDeque::from([(list_path_async(&op, path).await?, 1)]);
+
+    while let Some((dir, depth)) = queue.pop_front() {
+        let mut lister = op.lister(&dir).await
+            .map_err(|e| format!("Failed to get lister for '{}': {}", dir, e))?;
+
+        while let Some(entry) = lister.try_next().await
+            .map_err(|e| format!("Failed to list contents of '{}': {}", dir, e))? {
+            // Some backends include the listed directory itself.
+            if entry.path() == dir {
+                continue;
+            }
+
+            // Filtered-out directories are still descended into.
+            if entry.metadata().is_dir() && depth < max_depth {
+                queue.push_back((entry.path().to_string(), depth + 1));
+            }
+            if !filter.matches(entry.path()) {
+                continue;
+            }
+
+            let mut entry_info = entry_info_async(&op, &entry).await?;
+            entry_info.insert("depth".to_string(), Value::Number(depth.into()));
+            results.push(JsonB(Value::Object(entry_info)));
+        }
+    }
+    Ok(results)
+}
+
+#[pg_extern]
+fn pg_opendal_list_recursive_depth(
+    service: &str,
+    path: &str,
+    max_depth: i32,
+    config: JsonB,
+    include_regex: default!(Option<&str>, "NULL"),
+    exclude_regex: default!(Option<&str>, "NULL"),
+) -> Result<SetOfIterator<'static, JsonB>, String> {
+    let filter = PathFilter::new(include_regex, exclude_regex)?;
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let results = rt.block_on(do_list_recursive_depth_async(op, &resolve_path(path), max_depth, &filter))?;
+    Ok(SetOfIterator::new(results))
+}
+
+/// ETag of a listed entry, from the listing metadata when the service includes it,
+/// otherwise from a separate stat.
+async fn entry_etag_async(op: &Operator, entry: &opendal::Entry) -> Result<Option<String>, String> {
+    if op.info().full_capability().list_has_etag {
+        if let Some(etag) = entry.metadata().etag() {
+            return Ok(Some(etag.to_string()));
+        }
+    }
+    op.stat(entry.path())
+        .await
+        .map(|metadata| metadata.etag().map(str::to_string))
+        .map_err(|e| format!("Failed to get metadata for entry '{}': {}", entry.path(), e))
+}
+
+type ChangeRow = (String, String, Option<String>);
+
+/// Compares current `(path, etag)` pairs against a known `path -> etag` state. Added and
+/// modified files come in listing order, followed by deleted ones sorted by path.
+fn diff_etags(
+    known: &serde_json::Map<String, Value>,
+    current: Vec<(String, String)>,
+) -> Result<Vec<ChangeRow>, String> {
+    let mut known_etags = HashMap::new();
+    for (path, etag) in known {
+        let etag = etag
+            .as_str()
+            .ok_or_else(|| format!("Known ETag for '{}' must be a string", path))?;
+        known_etags.insert(path.as_str(), etag);
+    }
+
+    let mut rows = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+    for (path, etag) in &current {
+        seen.insert(path.as_str());
+        match known_etags.get(path.as_str()) {
+            None => rows.push((path.clone(), "added".to_string(), Some(etag.clone()))),
+            Some(known) if known != etag => {
+                rows.push((path.clone(), "modified".to_string(), Some(etag.clone())))
+            }
+            Some(_) => {}
+        }
+    }
+
+    let mut deleted: Vec<&str> = known_etags.into_keys().filter(|p| !seen.contains(p)).collect();
+    deleted.sort_unstable();
+    rows.extend(deleted.into_iter().map(|p| (p.to_string(), "deleted".to_string(), None)));
+    Ok(rows)
+}
+
+async fn do_list_changed_since_async(
+    op: Operator,
+    path: &str,
+    known_state: Value,
+) -> Result<Vec<ChangeRow>, String> {
+    let Value::Object(known) = known_state else {
+        return Err("known_state must be a JSON object mapping paths to ETags".to_string());
+    };
+
+    let path = list_path_async(&op, path).await?;
+    let mut lister = op.lister(&path).await
+        .map_err(|e| format!("Failed to get lister for '{}': {}", path, e))?;
+    let mut current = Vec::new();
+    while let Some(entry) = lister.try_next().await
+        .map_err(|e| format!("Failed to list contents of '{}': {}", path, e))? {
+        if entry.metadata().is_dir() {
+            continue;
+        }
+        let etag = entry_etag_async(&op, &entry).await?.ok_or_else(|| {
+            format!(
+                "Service '{}' reports no ETag for '{}', so changes can't be detected",
+                op.info().scheme(),
+                entry.path()
+            )
+        })?;
+        current.push((entry.path().to_string(), etag));
+    }
+
+    diff_etags(&known, current)
+}
+
+#[pg_extern]
+fn pg_opendal_list_changed_since(
+    service: &str,
+    path: &str,
+    known_state: JsonB,
+    config: JsonB,
+) -> Result<
+    TableIterator<'static, (name!(path, String), name!(change_type, String), name!(new_etag, Option<String>))>,
+    String,
+> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let rows = rt.block_on(do_list_changed_since_async(op, &resolve_path(path), known_state.0))?;
+    Ok(TableIterator::new(rows))
+}
+
+/// `(etag, paths, total_wasted_bytes)`
+type DuplicateRow = (Option<String>, Vec<String>, i64);
+
+/// Groups same-sized files by `key`, keeping groups of more than one path, largest waste first.
+fn duplicate_groups(files: Vec<(u64, String, String)>, key_is_etag: bool) -> Vec<DuplicateRow> {
+    let mut groups: std::collections::BTreeMap<(u64, String), Vec<String>> = std::collections::BTreeMap::new();
+    for (size, key, path) in files {
+        groups.entry((size, key)).or_default().push(path);
+    }
+    let mut rows: Vec<DuplicateRow> = groups
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|((size, key), mut paths)| {
+            paths.sort();
+            let wasted = size as i64 * (paths.len() as i64 - 1);
+            (key_is_etag.then_some(key), paths, wasted)
+        })
+        .collect();
+    rows.sort_by_key(|row| std::cmp::Reverse(row.2));
+    rows
+}
+
+/// Finds duplicate files under `path`. Only files sharing a size with another are compared,
+/// by ETag, or by a SHA-256 of their content if the service doesn't report ETags.
+async fn do_list_duplicates_async(op: Operator, path: &str) -> Result<Vec<DuplicateRow>, String> {
+    let mut by_size: HashMap<u64, Vec<opendal::Entry>> = HashMap::new();
+    for entry in list_files_recursive_async(&op, path, None).await? {
+        let size = entry_content_length_async(&op, &entry).await?;
+        if size > 0 {
+            by_size.entry(size).or_default().push(entry);
+        }
+    }
+    let candidates: Vec<(u64, opendal::Entry)> = by_size
+        .into_iter()
+        .filter(|(_, entries)| entries.len() > 1)
+        .flat_map(|(size, entries)| entries.into_iter().map(move |entry| (size, entry)))
+        .collect();
+
+    let mut by_etag = Vec::with_capacity(candidates.len());
+    for (size, entry) in &candidates {
+        match entry_etag_async(&op, entry).await? {
+            Some(etag) => by_etag.push((*size, etag, entry.path().to_string())),
+            None => break,
+        }
+    }
+    if by_etag.len() == candidates.len() {
+        return Ok(duplicate_groups(by_etag, true));
+    }
+
+    let mut by_content = Vec::with_capacity(candidates.len());
+    for (size, entry) in candidates {
+        let (digest, _) = digest_object_async(&op, entry.path(), ChecksumAlgorithm::Sha256).await?;
+        by_content.push((size, digest, entry.path().to_string()));
+    }
+    Ok(duplicate_groups(by_content, false))
+}
+
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn pg_opendal_list_duplicates(
+    service: &str,
+    path: &str,
+    config: JsonB,
+) -> Result<
+    TableIterator<'static, (name!(etag, Option<String>), name!(paths, Vec<String>), name!(total_wasted_bytes, i64))>,
+    String,
+> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let rows = rt.block_on(do_list_duplicates_async(op, &resolve_path(path)))?;
+    Ok(TableIterator::new(rows))
+}
+
+/// The content MD5 carried by an S3 ETag. Only objects uploaded in one part have one;
+/// multipart ETags end in `-<parts>`. ETags of encrypted objects look the same but aren't
+/// MD5s, which can only make identical objects look different, never the reverse.
+fn s3_etag_md5(etag: &str) -> Option<String> {
+    let etag = etag.trim_matches('"');
+    (etag.len() == 32 && etag.bytes().all(|b| b.is_ascii_hexdigit())).then(|| etag.to_ascii_lowercase())
+}
+
+
+/// `(kept, removed, hash)`
+type DedupRow = (String, String, String);
+
+/// Finds files under `prefix` with identical content and, unless `dry_run`, deletes all
+/// but the first path of each group. Only same-sized, non-empty files are compared, by
+/// the MD5 in their S3 ETags when every file of that size has one, and otherwise by
+/// streaming them through SHA-256.
+async fn do_dedup_async(op: &Operator, prefix: &str, dry_run: bool) -> Result<Vec<DedupRow>, String> {
+    let mut by_size: std::collections::BTreeMap<u64, Vec<opendal::Entry>> = std::collections::BTreeMap::new();
+    for entry in list_files_recursive_async(op, prefix, None).await? {
+        let size = entry_content_length_async(op, &entry).await?;
+        if size > 0 {
+            by_size.entry(size).or_default().push(entry);
+        }
+    }
+
+    let etags_are_md5 = op.info().scheme() == Scheme::S3;
+    let mut rows = Vec::new();
+    for entries in by_size.into_values().filter(|entries| entries.len() > 1) {
+        let mut md5s = Vec::with_capacity(entries.len());
+        if etags_are_md5 {
+            for entry in &entries {
+                match entry_etag_async(op, entry).await?.as_deref().and_then(s3_etag_md5) {
+                    Some(md5) => md5s.push(format!("md5:{}", md5)),
+                    None => break,
+                }
+            }
+        }
+        let hashes = if md5s.len() == entries.len() {
+            md5s
+        } else {
+            let mut hashes = Vec::with_capacity(entries.len());
+            for entry in &entries {
+                let (digest, _) = digest_object_async(op, entry.path(), ChecksumAlgorithm::Sha256).await?;
+                hashes.push(format!("sha256:{}", digest));
+            }
+            hashes
+        };
+
+        let mut groups: std::collections::BTreeMap<String, Vec<String>> = std::collections::BTreeMap::new();
+        for (hash, entry) in hashes.into_iter().zip(entries) {
+            groups.entry(hash).or_default().push(entry.path().to_string());
+        }
+        for (hash, mut paths) in groups.into_iter().filter(|(_, paths)| paths.len() > 1) {
+            paths.sort();
+            let kept = paths.remove(0);
+            for removed in paths {
+                if !dry_run {
+                    op.delete(&removed).await
+                        .map_err(|e| format!("Failed to delete '{}': {}", removed, e))?;
+                }
+                rows.push((kept.clone(), removed, hash.clone()));
+            }
+        }
+    }
+    Ok(rows)
+}
+
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn pg_opendal_dedup(
+    service: &str,
+    prefix: &str,
+    config: JsonB,
+    dry_run: default!(bool, true),
+) -> Result<
+    TableIterator<'static, (name!(kept, String), name!(removed, String), name!(hash, String))>,
+    String,
+> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let rows = rt.block_on(do_dedup_async(&op, &resolve_path(prefix), dry_run))?;
+    Ok(TableIterator::new(rows))
+}
+
+/// Root of a Merkle tree over `(relative path, identity)` leaves, sorted by path so the
+/// result doesn't depend on listing order. Leaves and interior nodes are hashed with
+/// distinct prefixes, as in RFC 6962, so one can't pass for the other.
+fn merkle_root(mut leaves: Vec<(String, String)>) -> String {
+    use sha2::Digest;
+    leaves.sort();
+    let mut level: Vec<Vec<u8>> = leaves
+        .iter()
+        .map(|(path, identity)| {
+            let mut hasher = sha2::Sha256::new();
+            hasher.update([0u8]);
+            hasher.update(path.as_bytes());
+            hasher.update([0u8]);
+            hasher.update(identity.as_bytes());
+            hasher.finalize().to_vec()
+        })
+        .collect();
+    if level.is_empty() {
+        return hex::encode(sha2::Sha256::digest([]));
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut hasher = sha2::Sha256::new();
+                    hasher.update([1u8]);
+                    hasher.update(left);
+                    hasher.update(right);
+                    hasher.finalize().to_vec()
+                }
+                [single] => single.clone(),
+                _ => unreachable!("chunks(2) yields one or two items"),
+            })
+            .collect();
+    }
+    hex::encode(&level[0])
+}
+
+/// Hashes the files under `prefix` by their paths relative to it and their ETags, or their
+/// sizes where the service has no ETags, so identical subtrees hash alike wherever they are.
+async fn do_tree_hash_async(op: Operator, prefix: &str) -> Result<String, String> {
+    let base = list_path_async(&op, prefix).await?;
+    let mut leaves = Vec::new();
+    for entry in list_files_recursive_async(&op, &base, None).await? {
+        let relative = entry.path().strip_prefix(base.as_str()).unwrap_or(entry.path()).to_string();
+        let identity = match entry_etag_async(&op, &entry).await? {
+            Some(etag) => format!("etag:{}", etag),
+            None => format!("size:{}", entry_content_length_async(&op, &entry).await?),
+        };
+        leaves.push((relative, identity));
+    }
+    Ok(merkle_root(leaves))
+}
+
+#[pg_extern]
+fn pg_opendal_tree_hash(service: &str, prefix: &str, config: JsonB) -> Result<String, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_tree_hash_async(op, &resolve_path(prefix)))
+}
+
+/// Response headers a presigned read URL makes the service send back, set through the
+/// `response-*` query parameters.
+#[derive(Debug, Default, PartialEq)]
+struct PresignOverrides {
+    content_disposition: Option<String>,
+    content_type: Option<String>,
+    cache_control: Option<String>,
+}
+
+impl PresignOverrides {
+    fn is_empty(&self) -> bool {
+        *self == PresignOverrides::default()
+    }
+}
+
+fn parse_presign_options(options: Option<Value>) -> Result<PresignOverrides, String> {
+    let mut overrides = PresignOverrides::default();
+    let Some(options) = options else {
+        return Ok(overrides);
+    };
+    let Value::Object(options) = options else {
+        return Err("options must be a JSON object".to_string());
+    };
+    for (name, value) in options {
+        let Value::String(value) = value else {
+            return Err(format!("Option '{}' must be a string", name));
+        };
+        let slot = match name.as_str() {
+            "response-content-disposition" => &mut overrides.content_disposition,
+            "response-content-type" => &mut overrides.content_type,
+            "response-cache-control" => &mut overrides.cache_control,
+            other => {
+                return Err(format!(
+                    "Unknown option '{}', expected 'response-content-disposition', \
+                     'response-content-type' or 'response-cache-control'",
+                    other
+                ))
+            }
+        };
+        *slot = Some(value);
+    }
+    Ok(overrides)
+}
+
+async fn presign_async(
+    op: &Operator,
+    path: &str,
+    operation: &str,
+    expire: std::time::Duration,
+    overrides: &PresignOverrides,
+) -> Result<opendal::raw::PresignedRequest, String> {
+    let capability = op.info().full_capability();
+    if !capability.presign {
+        return Err(format!("Service '{}' does not support presigning", op.info().scheme()));
+    }
+    if operation != "read" && !overrides.is_empty() {
+        return Err(format!("Response header overrides only apply to 'read' URLs, not '{}'", operation));
+    }
+    let unsupported = [
+        ("response-content-disposition", &overrides.content_disposition, capability.read_with_override_content_disposition),
+        ("response-content-type", &overrides.content_type, capability.read_with_override_content_type),
+        ("response-cache-control", &overrides.cache_control, capability.read_with_override_cache_control),
+    ]
+    .into_iter()
+    .find(|(_, value, supported)| value.is_some() && !supported);
+    if let Some((name, _, _)) = unsupported {
+        return Err(format!("Service '{}' does not support the '{}' option", op.info().scheme(), name));
+    }
+
+    let presigned = match operation {
+        "read" => {
+            let mut presign = op.presign_read_with(path, expire);
+            if let Some(value) = &overrides.content_disposition {
+                presign = presign.override_content_disposition(value);
+            }
+            if let Some(value) = &overrides.content_type {
+                presign = presign.override_content_type(value);
+            }
+            if let Some(value) = &overrides.cache_control {
+                presign = presign.override_cache_control(value);
+            }
+            presign.await
+        }
+        "write" => op.presign_write(path, expire).await,
+        "delete" => op.presign_delete(path, expire).await,
+        other => {
+            return Err(format!(
+                "Invalid operation '{}', expected one of 'read', 'write' or 'delete'",
+                other
+            ))
+        }
+    };
+    presigned.map_err(|e| format!("Failed to presign {} of '{}': {}", operation, path, e))
+}
+
+/// Splits a presigned request into its URL, HTTP method and the headers a client must send.
+fn presigned_request_parts(presigned: &opendal::raw::PresignedRequest) -> (String, String, Value) {
+    let headers = presigned
+        .header()
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes()).to_string();
+            (name.to_string(), Value::String(value))
+        })
+        .collect();
+    (
+        presigned.uri().to_string(),
+        presigned.method().to_string(),
+        Value::Object(headers),
+    )
+}
+
+fn presign_expiry(expires_in_seconds: i32) -> Result<std::time::Duration, String> {
+    u64::try_from(expires_in_seconds)
+        .ok()
+        .filter(|&secs| secs > 0)
+        .map(std::time::Duration::from_secs)
+        .ok_or_else(|| format!("Invalid expiry: {} seconds", expires_in_seconds))
+}
+
+/// Probes a presigned read URL and reports whether it granted access along with the HTTP status.
+///
+/// The probe keeps the signed method and asks for a single byte, since a HEAD would not match
+/// the signature of a GET URL. An empty object answers 416, which still proves access.
+async fn verify_presigned_read_async(
+    transport: &HttpTransport,
+    presigned: &opendal::raw::PresignedRequest,
+) -> Result<(bool, u16), String> {
+    let request = transport
+        .client
+        .request(presigned.method().clone(), presigned.uri().to_string())
+        .headers(presigned.header().clone())
+        .header(reqwest::header::RANGE, "bytes=0-0")
+        .build()
+        .map_err(|e| format!("Failed to verify presigned URL: {}", e))?;
+    let response = transport
+        .send(request)
+        .await
+        .map_err(|e| format!("Failed to verify presigned URL: {}", e))?;
+    let status = response.status();
+    let granted =
+        status.is_success() || status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE;
+    Ok((granted, status.as_u16()))
+}
+
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn pg_opendal_generate_signed_url(
+    service: &str,
+    path: &str,
+    operation: &str,
+    expires_in_seconds: i32,
+    config: JsonB,
+    verify: default!(bool, false),
+    options: default!(Option<JsonB>, "NULL"),
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(url, String),
+            name!(method, String),
+            name!(required_headers, JsonB),
+            name!(verified, Option<bool>),
+            name!(verify_status, Option<i32>),
+        ),
+    >,
+    String,
+> {
+    if verify && operation != "read" {
+        return Err(format!(
+            "Verification is only supported for 'read' URLs, not '{}'",
+            operation
+        ));
+    }
+    let expire = presign_expiry(expires_in_seconds)?;
+    let overrides = parse_presign_options(options.map(|options| options.0))?;
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let transport = HttpTransport::from_config(&config_map)?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let presigned = rt.block_on(presign_async(&op, &resolve_path(path), operation, expire, &overrides))?;
+    let (url, method, headers) = presigned_request_parts(&presigned);
+    let (verified, verify_status) = if verify {
+        match rt.block_on(verify_presigned_read_async(&transport, &presigned)) {
+            Ok((granted, status)) => {
+                if !granted {
+                    pgrx::warning!("Presigned URL for '{}' was rejected with HTTP {}", path, status);
+                }
+                (Some(granted), Some(i32::from(status)))
+            }
+            Err(e) => {
+                pgrx::warning!("{}", e);
+                (Some(false), None)
+            }
+        }
+    } else {
+        (None, None)
+    };
+    Ok(TableIterator::once((url, method, JsonB(headers), verified, verify_status)))
+}
+
+/// Writes `content` to `path` and presigns a read URL for it. Presigning comes after the
+/// write, so on a service that can't presign the write stands and only the URL fails.
+async fn do_write_and_presign_async(
+    op: &Operator,
+    path: &str,
+    content: &[u8],
+    expire: std::time::Duration,
+) -> Result<String, String> {
+    do_write_async(op.clone(), path, content).await?;
+    let presigned = presign_async(op, path, "read", expire, &PresignOverrides::default())
+        .await
+        .map_err(|e| format!("Wrote '{}', but could not create a URL for it: {}", path, e))?;
+    Ok(presigned.uri().to_string())
+}
+
+#[pg_extern]
+fn pg_opendal_write_and_presign(
+    service: &str,
+    path: &str,
+    content: &[u8],
+    expires_seconds: i32,
+    config: JsonB,
+) -> Result<String, String> {
+    let expire = presign_expiry(expires_seconds)?;
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_write_and_presign_async(&op, &resolve_path(path), content, expire))
+}
+
+/// What `pg_opendal_presign_info` extracts from a presigned URL.
+#[derive(Debug, PartialEq)]
+struct PresignInfo {
+    base_url: String,
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    signature: String,
+    headers: Vec<String>,
+    service: &'static str,
+}
+
+/// Parses an S3 or GCS V4 presigned URL, or an Azure SAS URL, without contacting the
+/// service. Query parameter names are matched case-insensitively. `headers` lists the
+/// signed headers a client must send, besides `host`.
+fn parse_presign_info(url: &str) -> Result<PresignInfo, String> {
+    let mut parsed = reqwest::Url::parse(url).map_err(|e| format!("Invalid URL '{}': {}", url, e))?;
+    let params: HashMap<String, String> =
+        parsed.query_pairs().map(|(name, value)| (name.to_ascii_lowercase(), value.into_owned())).collect();
+    parsed.set_query(None);
+    parsed.set_fragment(None);
+    let base_url = parsed.to_string();
+    let param = |name: &str| params.get(name).map(String::as_str);
+
+    // SigV4-style URLs, which GCS uses with its own prefix.
+    for (prefix, service) in [("x-amz-", "s3"), ("x-goog-", "gcs")] {
+        let Some(signature) = param(&format!("{}signature", prefix)) else {
+            continue;
+        };
+        let signed_at = param(&format!("{}date", prefix))
+            .and_then(|date| chrono::NaiveDateTime::parse_from_str(date, "%Y%m%dT%H%M%SZ").ok());
+        let expires = param(&format!("{}expires", prefix)).and_then(|secs| secs.parse::<i64>().ok());
+        let expires_at = match (signed_at, expires) {
+            (Some(signed_at), Some(expires)) => {
+                signed_at.and_utc().checked_add_signed(chrono::Duration::seconds(expires))
+            }
+            _ => None,
+        };
+        let headers = param(&format!("{}signedheaders", prefix))
+            .unwrap_or_default()
+            .split(';')
+            .filter(|name| !name.is_empty() && *name != "host")
+            .map(str::to_string)
+            .collect();
+        return Ok(PresignInfo { base_url, expires_at, signature: signature.to_string(), headers, service });
+    }
+
+    if let Some(signature) = param("sig") {
+        let expires_at = param("se")
+            .and_then(|expiry| chrono::DateTime::parse_from_rfc3339(expiry).ok())
+            .map(|expiry| expiry.to_utc());
+        return Ok(PresignInfo {
+            base_url,
+            expires_at,
+            signature: signature.to_string(),
+            headers: Vec::new(),
+            service: "azure",
+        });
+    }
+
+    Err(format!(
+        "'{}' is not a recognized presigned URL: it has no X-Amz-Signature, X-Goog-Signature or sig parameter",
+        base_url
+    ))
+}
+
+#[pg_extern(immutable, parallel_safe)]
+#[allow(clippy::type_complexity)]
+fn pg_opendal_presign_info(
+    url: &str,
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(base_url, String),
+            name!(expires_at, Option<TimestampWithTimeZone>),
+            name!(signature, String),
+            name!(headers, JsonB),
+            name!(service_detected, String),
+        ),
+    >,
+    String,
+> {
+    let info = parse_presign_info(url)?;
+    Ok(TableIterator::once((
+        info.base_url,
+        info.expires_at.and_then(utc_to_timestamptz),
+        info.signature,
+        JsonB(Value::from(info.headers)),
+        info.service.to_string(),
+    )))
+}
+
+/// The public URL of `path`: `custom_domain` when set, otherwise the service's canonical
+/// endpoint for the bucket or container named in `config`. Keys are resolved under `root`.
+fn object_url(service: &str, config: &HashMap<String, String>, path: &str) -> Result<String, String> {
+    let setting = |key: &str| config.get(key).filter(|value| !value.is_empty());
+    let require = |key: &str| {
+        setting(key).ok_or_else(|| format!("Object URLs for service '{}' require '{}' in config", service, key))
+    };
+    let scheme = Scheme::from_str(service).map_err(|e| format!("Invalid service type '{}': {}", service, e))?;
+    let key = sigv4_encode_key(&s3_object_key(config, path.trim_start_matches('/')));
+
+    if let Some(domain) = setting("custom_domain") {
+        let domain = domain.trim_end_matches('/');
+        if domain.contains("://") {
+            return Ok(format!("{}/{}", domain, key));
+        }
+        return Ok(format!("https://{}/{}", domain, key));
+    }
+    let base = match scheme {
+        Scheme::S3 => {
+            let bucket = require("bucket")?;
+            match setting("endpoint") {
+                Some(_) => s3_bucket_url(config, bucket, setting("region").map_or("", String::as_str)),
+                None => format!("https://{}.s3.{}.amazonaws.com", bucket, require("region")?),
+            }
+        }
+        Scheme::Gcs => format!("https://storage.googleapis.com/{}", require("bucket")?),
+        Scheme::Azblob => {
+            let container = require("container")?;
+            match setting("endpoint") {
+                Some(endpoint) => format!("{}/{}", endpoint.trim_end_matches('/'), container),
+                None => format!("https://{}.blob.core.windows.net/{}", require("account_name")?, container),
+            }
+        }
+        _ => {
+            return Err(format!(
+                "Object URLs are not supported for service '{}', expected one of 's3', 'gcs' or 'azblob'",
+                service
+            ))
+        }
+    };
+    Ok(format!("{}/{}", base, key))
+}
+
+#[pg_extern(stable, parallel_safe)]
+fn pg_opendal_object_url(service: &str, path: &str, config: JsonB) -> Result<String, String> {
+    let mut config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    take_provider_config(service, &mut config_map).map_err(|e| e.to_string())?;
+    object_url(service, &config_map, &resolve_path(path))
+}
+
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    use hmac::Mac;
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Derives the AWS Signature Version 4 signing key for one day, region and service.
+fn sigv4_signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    [date, region, service, "aws4_request"]
+        .iter()
+        .fold(format!("AWS4{}", secret_access_key).into_bytes(), |key, part| hmac_sha256(&key, part))
+}
+
+/// The object key `path` maps to under the config's `root`.
+fn s3_object_key(config: &HashMap<String, String>, path: &str) -> String {
+    let root = config.get("root").filter(|root| !root.is_empty()).map_or("/", String::as_str);
+    opendal::raw::build_abs_path(&opendal::raw::normalize_root(root), path)
+}
+
+/// The config's S3 endpoint, without a trailing slash, or the region's AWS endpoint.
+fn s3_endpoint(config: &HashMap<String, String>, region: &str) -> String {
+    match config.get("endpoint").filter(|endpoint| !endpoint.is_empty()) {
+        Some(endpoint) if endpoint.contains("://") => endpoint.trim_end_matches('/').to_string(),
+        Some(endpoint) => format!("https://{}", endpoint.trim_end_matches('/')),
+        None => format!("https://s3.{}.amazonaws.com", region),
+    }
+}
+
+/// The URL of `bucket`, without a trailing slash, addressed the way the config asks:
+/// path style by default, or virtual-host style with `enable_virtual_host_style`.
+fn s3_bucket_url(config: &HashMap<String, String>, bucket: &str, region: &str) -> String {
+    let endpoint = s3_endpoint(config, region);
+    match endpoint.split_once("://") {
+        Some((scheme, host)) if config.get("enable_virtual_host_style").is_some_and(|v| v == "true") => {
+            format!("{}://{}.{}", scheme, bucket, host)
+        }
+        _ => format!("{}/{}", endpoint, bucket),
+    }
+}
+
+/// Builds the form for a browser upload of `path` to an S3 bucket, signed as a POST policy
+/// with Signature Version 4. `conditions` follow the ones binding the bucket, key and
+/// signature fields. Only static credentials from `config` can sign.
+fn s3_post_policy(
+    config: &HashMap<String, String>,
+    path: &str,
+    expire: std::time::Duration,
+    conditions: Vec<Value>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<Value, String> {
+    let setting = |key: &str| config.get(key).filter(|value| !value.is_empty());
+    let bucket = setting("bucket").ok_or("POST policies require 'bucket' in config")?;
+    let region = setting("region").ok_or("POST policies require 'region' in config")?;
+    let (Some(access_key_id), Some(secret_access_key)) =
+        (setting("access_key_id"), setting("secret_access_key"))
+    else {
+        return Err("POST policies require 'access_key_id' and 'secret_access_key' in config".to_string());
+    };
+
+    let key = s3_object_key(config, path);
+    let url = s3_bucket_url(config, bucket, region);
+
+    let expire = chrono::Duration::from_std(expire).map_err(|e| format!("Invalid expiry: {}", e))?;
+    let date = now.format("%Y%m%d").to_string();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let credential = format!("{}/{}/{}/s3/aws4_request", access_key_id, date, region);
+
+    let mut fields = serde_json::Map::new();
+    fields.insert("key".to_string(), Value::String(key));
+    fields.insert("x-amz-algorithm".to_string(), Value::String("AWS4-HMAC-SHA256".to_string()));
+    fields.insert("x-amz-credential".to_string(), Value::String(credential));
+    fields.insert("x-amz-date".to_string(), Value::String(amz_date));
+    if let Some(token) = setting("session_token") {
+        fields.insert("x-amz-security-token".to_string(), Value::String(token.clone()));
+    }
+
+    let mut policy_conditions = vec![serde_json::json!({ "bucket": bucket })];
+    policy_conditions.extend(fields.iter().map(|(name, value)| serde_json::json!({ name: value })));
+    policy_conditions.extend(conditions);
+    let policy = serde_json::json!({
+        "expiration": (now + expire).to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "conditions": policy_conditions,
+    });
+    let policy = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, policy.to_string());
+
+    let signing_key = sigv4_signing_key(secret_access_key, &date, region, "s3");
+    let signature = hex::encode(hmac_sha256(&signing_key, &policy));
+    fields.insert("policy".to_string(), Value::String(policy));
+    fields.insert("x-amz-signature".to_string(), Value::String(signature));
+
+    Ok(serde_json::json!({ "url": url, "fields": fields }))
+}
+
+#[pg_extern]
+fn pg_opendal_presign_post(
+    service: &str,
+    path: &str,
+    expires_seconds: i32,
+    conditions: JsonB,
+    config: JsonB,
+) -> Result<JsonB, String> {
+    let expire = presign_expiry(expires_seconds)?;
+    let Value::Array(conditions) = conditions.0 else {
+        return Err("conditions must be a JSON array of POST policy conditions".to_string());
+    };
+    if !matches!(Scheme::from_str(service), Ok(Scheme::S3)) {
+        return Err(format!("Service '{}' does not support POST policies", service));
+    }
+    let mut config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    take_provider_config(service, &mut config_map)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+
+    s3_post_policy(&config_map, &resolve_path(path), expire, conditions, chrono::Utc::now()).map(JsonB)
+}
+
+/// Lists the entries directly under `prefix` for `pg_opendal_index`: directories first,
+/// then files, each sorted by name. Files link to a presigned read URL when `presign` is
+/// given, otherwise to their name relative to the index.
+async fn index_entries_async(
+    op: &Operator,
+    prefix: &str,
+    presign: Option<std::time::Duration>,
+) -> Result<Vec<serde_json::Map<String, Value>>, String> {
+    let dir = if prefix.is_empty() { String::new() } else { as_dir_path(prefix) };
+    let mut lister = lister_after_async(op, &dir, false, None).await?;
+    let mut entries = Vec::new();
+    while let Some(entry) = lister.try_next().await
+        .map_err(|e| format!("Failed to list contents of '{}': {}", dir, e))? {
+        if entry.path() == dir || entry.path() == "/" {
+            continue;
+        }
+        let mut info = listed_entry_info_async(op, &entry).await?;
+        let href = match presign {
+            Some(expire) if entry.metadata().is_file() => {
+                presign_async(op, entry.path(), "read", expire, &PresignOverrides::default()).await?.uri().to_string()
+            }
+            _ => sigv4_encode_key(entry.name()),
+        };
+        info.insert("href".to_string(), Value::String(href));
+        entries.push(info);
+    }
+    entries.sort_by_key(|info| (info["is_file"].as_bool(), info["name"].as_str().map(str::to_string)));
+    Ok(entries)
+}
+
+/// Renders a minimal HTML directory index. Names and links are escaped, and links are
+/// either percent-encoded relative paths or presigned URLs, so no name can inject markup
+/// or a `javascript:` link.
+fn render_html_index(prefix: &str, entries: &[serde_json::Map<String, Value>]) -> String {
+    let title = xml_escape(&format!("Index of /{}", prefix.trim_start_matches('/')));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n\
+         <table>\n<tr><th>Name</th><th>Size</th><th>Last modified</th></tr>\n",
+        title
+    );
+    for info in entries {
+        let text = |key: &str| info.get(key).and_then(Value::as_str).unwrap_or_default();
+        let size = match info.get("is_file").and_then(Value::as_bool) {
+            Some(true) => info.get("content_length").map(Value::to_string).unwrap_or_default(),
+            _ => "-".to_string(),
+        };
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            xml_escape(text("href")),
+            xml_escape(text("name")),
+            size,
+            xml_escape(text("last_modified"))
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+#[pg_extern]
+fn pg_opendal_index(
+    service: &str,
+    prefix: &str,
+    format: &str,
+    config: JsonB,
+    presign_expires_seconds: default!(Option<i32>, "NULL"),
+) -> Result<String, String> {
+    if !matches!(format, "html" | "json") {
+        return Err(format!("Invalid format '{}', expected 'html' or 'json'", format));
+    }
+    let presign = presign_expires_seconds.map(presign_expiry).transpose()?;
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let prefix = resolve_path(prefix);
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let entries = rt.block_on(index_entries_async(&op, &prefix, presign))?;
+    Ok(match format {
+        "html" => render_html_index(&prefix, &entries),
+        _ => serde_json::json!({ "prefix": prefix, "entries": entries }).to_string(),
+    })
+}
+
+/// Percent-encodes an object key for a SigV4 canonical URI, which encodes everything but
+/// unreserved characters and `/`.
+fn sigv4_encode_key(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for &b in key.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// The `InputSerialization` of an S3 Select request. `csv` and `json` objects may be
+/// gzip or bzip2 compressed, which is told from the extension; JSON is read as JSON Lines.
+fn s3_select_input(input_format: &str, path: &str) -> Result<String, String> {
+    let format = match input_format.to_ascii_lowercase().as_str() {
+        "csv" => "<CSV/>",
+        "json" => "<JSON><Type>LINES</Type></JSON>",
+        "parquet" => return Ok("<Parquet/>".to_string()),
+        other => {
+            return Err(format!(
+                "Invalid input_format '{}', expected one of 'csv', 'json' or 'parquet'",
+                other
+            ))
+        }
+    };
+    let extension = path.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase());
+    let compression = match extension.as_deref() {
+        Some("gz") => "GZIP",
+        Some("bz2") => "BZIP2",
+        _ => "NONE",
+    };
+    Ok(format!("<CompressionType>{}</CompressionType>{}", compression, format))
+}
+
+/// Percent-encodes a query parameter name or value for a SigV4 canonical query string.
+fn sigv4_encode_query(value: &str) -> String {
+    sigv4_encode_key(value).replace('/', "%2F")
+}
+
+/// Builds a request for the object `key`, or the bucket itself when `key` is empty, signed
+/// with Signature Version 4. Only static credentials from `config` can sign; `action` names
+/// the request in errors about missing settings.
+fn s3_signed_request(
+    config: &HashMap<String, String>,
+    action: &str,
+    method: reqwest::Method,
+    key: &str,
+    query: &[(&str, &str)],
+    body: Vec<u8>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<reqwest::Request, String> {
+    let setting = |key: &str| config.get(key).filter(|value| !value.is_empty());
+    let bucket = setting("bucket").ok_or_else(|| format!("{} requires 'bucket' in config", action))?;
+    let region = setting("region").ok_or_else(|| format!("{} requires 'region' in config", action))?;
+    let url = format!("{}/{}", s3_bucket_url(config, bucket, region), sigv4_encode_key(key));
+    s3_signed_request_to(config, action, method, &url, query, body, now)
+}
+
+/// Builds a request for `url`, which must not have a query string, as `s3_signed_request`
+/// does. Requests that aren't about a bucket, such as ListBuckets, start from here.
+fn s3_signed_request_to(
+    config: &HashMap<String, String>,
+    action: &str,
+    method: reqwest::Method,
+    url: &str,
+    query: &[(&str, &str)],
+    body: Vec<u8>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<reqwest::Request, String> {
+    use sha2::Digest;
+
+    let setting = |key: &str| config.get(key).filter(|value| !value.is_empty());
+    let region = setting("region").ok_or_else(|| format!("{} requires 'region' in config", action))?;
+    let (Some(access_key_id), Some(secret_access_key)) =
+        (setting("access_key_id"), setting("secret_access_key"))
+    else {
+        return Err(format!("{} requires 'access_key_id' and 'secret_access_key' in config", action));
+    };
+
+    let mut query: Vec<(String, String)> =
+        query.iter().map(|(name, value)| (sigv4_encode_query(name), sigv4_encode_query(value))).collect();
+    query.sort();
+    let query_string = query.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("&");
+    let mut url = url.to_string();
+    if !query_string.is_empty() {
+        url = format!("{}?{}", url, query_string);
+    }
+    let url = reqwest::Url::parse(&url).map_err(|e| format!("Invalid S3 URL '{}': {}", url, e))?;
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+
+    let date = now.format("%Y%m%d").to_string();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let payload_hash = hex::encode(sha2::Sha256::digest(&body));
+    let mut headers = vec![
+        ("host", host),
+        ("x-amz-content-sha256", payload_hash.clone()),
+        ("x-amz-date", amz_date.clone()),
+    ];
+    if setting("enable_request_payer").is_some_and(|v| v == "true") {
+        headers.push(("x-amz-request-payer", "requester".to_string()));
+    }
+    if let Some(token) = setting("session_token") {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+    let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+    let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        url.path(),
+        query_string,
+        canonical_headers,
+        signed_headers,
+        payload_hash
+    );
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(sha2::Sha256::digest(canonical_request.as_bytes()))
+    );
+    let signing_key = sigv4_signing_key(secret_access_key, &date, region, "s3");
+    let signature = hex::encode(hmac_sha256(&signing_key, &string_to_sign));
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        access_key_id, scope, signed_headers, signature
+    );
+
+    let mut request = reqwest::Request::new(method, url);
+    headers.push(("authorization", authorization));
+    for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+        let value = reqwest::header::HeaderValue::from_str(&value)
+            .map_err(|e| format!("Failed to build {} request: {}", action, e))?;
+        request.headers_mut().insert(name, value);
+    }
+    *request.body_mut() = Some(body.into());
+    Ok(request)
+}
+
+/// Builds a SelectObjectContent request for `path`. Results are asked for as CSV.
+fn s3_select_request(
+    config: &HashMap<String, String>,
+    path: &str,
+    query: &str,
+    input_format: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<reqwest::Request, String> {
+    let body = format!(
+        "<SelectObjectContentRequest xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+         <Expression>{}</Expression><ExpressionType>SQL</ExpressionType>\
+         <InputSerialization>{}</InputSerialization>\
+         <OutputSerialization><CSV/></OutputSerialization>\
+         </SelectObjectContentRequest>",
+        xml_escape(query),
+        s3_select_input(input_format, path)?
+    );
+    s3_signed_request(
+        config,
+        "S3 Select",
+        reqwest::Method::POST,
+        &s3_object_key(config, path),
+        &[("select", ""), ("select-type", "2")],
+        body.into_bytes(),
+        now,
+    )
+}
+
+/// A message of the AWS event stream encoding, with its string-valued headers.
+struct EventMessage {
+    headers: HashMap<String, String>,
+    payload: Vec<u8>,
+}
+
+/// Decodes the event stream message at the start of `buf`, returning it with its length,
+/// or `None` when `buf` doesn't hold a whole message yet.
+fn decode_event_message(buf: &[u8]) -> Result<Option<(EventMessage, usize)>, String> {
+    if buf.len() < 12 {
+        return Ok(None);
+    }
+    let be_u32 = |at: usize| u32::from_be_bytes(buf[at..at + 4].try_into().unwrap());
+    if crc32fast::hash(&buf[..8]) != be_u32(8) {
+        return Err("Corrupt event stream: prelude checksum mismatch".to_string());
+    }
+    let total_len = be_u32(0) as usize;
+    let headers_len = be_u32(4) as usize;
+    if total_len < 16 + headers_len {
+        return Err("Corrupt event stream: invalid message length".to_string());
+    }
+    if buf.len() < total_len {
+        return Ok(None);
+    }
+    if crc32fast::hash(&buf[..total_len - 4]) != be_u32(total_len - 4) {
+        return Err("Corrupt event stream: message checksum mismatch".to_string());
+    }
+
+    let truncated = || "Corrupt event stream: truncated header".to_string();
+    let mut headers = HashMap::new();
+    let mut rest = &buf[12..12 + headers_len];
+    while let Some((&name_len, tail)) = rest.split_first() {
+        let name = tail.get(..name_len as usize).ok_or_else(truncated)?;
+        let (&value_type, tail) = tail[name_len as usize..].split_first().ok_or_else(truncated)?;
+        let value_len = match value_type {
+            0 | 1 => 0,
+            2 => 1,
+            3 => 2,
+            4 => 4,
+            5 | 8 => 8,
+            9 => 16,
+            6 | 7 => {
+                let len = tail.get(..2).ok_or_else(truncated)?;
+                2 + u16::from_be_bytes([len[0], len[1]]) as usize
+            }
+            other => return Err(format!("Corrupt event stream: unknown header type {}", other)),
+        };
+        let value = tail.get(..value_len).ok_or_else(truncated)?;
+        if value_type == 7 {
+            headers.insert(
+                String::from_utf8_lossy(name).into_owned(),
+                String::from_utf8_lossy(&value[2..]).into_owned(),
+            );
+        }
+        rest = &tail[value_len..];
+    }
+    let payload = buf[12 + headers_len..total_len - 4].to_vec();
+    Ok(Some((EventMessage { headers, payload }, total_len)))
+}
+
+/// Reads the result rows of an S3 Select response, concatenating the payloads of its
+/// `Records` events. Owns its runtime so it can back a lazily evaluated set-returning function.
+/// Each chunk of the response is subject to the config's timeout.
+struct SelectRecords {
+    rt: Runtime,
+    transport: HttpTransport,
+    response: reqwest::Response,
+    raw: Vec<u8>,
+    records: Vec<u8>,
+    pos: usize,
+    ended: bool,
+}
+
+impl SelectRecords {
+    fn next_message(&mut self) -> Result<Option<EventMessage>, String> {
+        loop {
+            if let Some((message, len)) = decode_event_message(&self.raw)? {
+                self.raw.drain(..len);
+                return Ok(Some(message));
+            }
+            pgrx::check_for_interrupts!();
+            match self.rt.block_on(self.transport.within_timeout(self.response.chunk())) {
+                Ok(Some(chunk)) => self.raw.extend_from_slice(&chunk),
+                Ok(None) if self.raw.is_empty() => return Ok(None),
+                Ok(None) => return Err("S3 Select response ended mid-message".to_string()),
+                Err(e) => return Err(format!("Failed to read S3 Select response: {}", e)),
+            }
+        }
+    }
+}
+
+impl std::io::Read for SelectRecords {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.records.len() {
+            if self.ended {
+                return Ok(0);
+            }
+            // Without an End event the result may be incomplete, so it's an error.
+            let message = self
+                .next_message()
+                .and_then(|message| message.ok_or_else(|| "S3 Select response ended before the End event".to_string()))
+                .map_err(std::io::Error::other)?;
+            let header = |name: &str| message.headers.get(name).map(String::as_str);
+            match (header(":message-type"), header(":event-type")) {
+                (Some("error"), _) => {
+                    return Err(std::io::Error::other(format!(
+                        "S3 Select failed: {}: {}",
+                        header(":error-code").unwrap_or("unknown"),
+                        header(":error-message").unwrap_or("")
+                    )))
+                }
+                (_, Some("Records")) => {
+                    self.records = message.payload;
+                    self.pos = 0;
+                }
+                (_, Some("End")) => self.ended = true,
+                _ => {}
+            }
+        }
+        let n = buf.len().min(self.records.len() - self.pos);
+        buf[..n].copy_from_slice(&self.records[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// The text of the first `<tag>` element in an S3 error response.
+fn xml_element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    Some(&xml[start..end])
+}
+
+#[pg_extern]
+fn pg_opendal_s3_select(
+    service: &str,
+    path: &str,
+    query: &str,
+    input_format: &str,
+    config: JsonB,
+) -> Result<SetOfIterator<'static, Vec<String>>, String> {
+    let config_map = s3_direct_config(service, config, "S3 Select")?;
+
+    let path = resolve_path(path);
+    let request = s3_select_request(&config_map, &path, query, input_format, chrono::Utc::now())?;
+    let transport = HttpTransport::from_config(&config_map)?;
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let response = rt
+        .block_on(transport.send(request))
+        .map_err(|e| format!("Failed to run S3 Select on '{}': {}", path, e))?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = rt.block_on(transport.within_timeout(response.text())).unwrap_or_default();
+        return Err(s3_error(&format!("run S3 Select on '{}'", path), status, &body));
+    }
+
+    let records =
+        SelectRecords { rt, transport, response, raw: Vec::new(), records: Vec::new(), pos: 0, ended: false };
+    let rows = csv::ReaderBuilder::new().has_headers(false).flexible(true).from_reader(records);
+    Ok(SetOfIterator::new(rows.into_records().map(move |row| {
+        row.map(|row| row.iter().map(str::to_string).collect())
+            .unwrap_or_else(|e| pgrx::error!("Failed to read S3 Select results for '{}': {}", path, e))
+    })))
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListMultipartUploadsResult {
+    #[serde(default)]
+    is_truncated: bool,
+    next_key_marker: Option<String>,
+    next_upload_id_marker: Option<String>,
+    #[serde(default, rename = "Upload")]
+    uploads: Vec<MultipartUpload>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct MultipartUpload {
+    key: String,
+    upload_id: String,
+    initiated: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListPartsResult {
+    #[serde(default)]
+    is_truncated: bool,
+    next_part_number_marker: Option<String>,
+    #[serde(default, rename = "Part")]
+    parts: Vec<UploadedPart>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct UploadedPart {
+    size: u64,
+}
+
+/// Describes an S3 error response, preferring the message S3 gives in its body.
+fn s3_error(action: &str, status: reqwest::StatusCode, body: &str) -> String {
+    format!(
+        "Failed to {}: HTTP {}: {}",
+        action,
+        status.as_u16(),
+        xml_element(body, "Message").or_else(|| xml_element(body, "Code")).unwrap_or(body)
+    )
+}
+
+/// Sends an S3 request built from `config`, returning the response status and body.
+async fn s3_execute_async(
+    config: &HashMap<String, String>,
+    request: reqwest::Request,
+    action: &str,
+) -> Result<(reqwest::StatusCode, String), String> {
+    let transport = HttpTransport::from_config(config)?;
+    let failed = |e: String| format!("Failed to {}: {}", action, e);
+    let response = transport.send(request).await.map_err(failed)?;
+    let status = response.status();
+    let body = transport.within_timeout(response.text()).await.map_err(failed)?;
+    Ok((status, body))
+}
+
+/// Sends an S3 request built from `config`, returning the response body, or the error S3 reported.
+async fn s3_send_async(config: &HashMap<String, String>, request: reqwest::Request, action: &str) -> Result<String, String> {
+    match s3_execute_async(config, request, action).await? {
+        (status, body) if status.is_success() => Ok(body),
+        (status, body) => Err(s3_error(action, status, &body)),
+    }
+}
+
+fn parse_s3_xml<T: serde::de::DeserializeOwned>(body: &str, action: &str) -> Result<T, String> {
+    quick_xml::de::from_str(body).map_err(|e| format!("Failed to {}: invalid response: {}", action, e))
+}
+
+/// Total size of the parts uploaded so far, following pagination.
+async fn multipart_uploaded_size_async(
+    config: &HashMap<String, String>,
+    key: &str,
+    upload_id: &str,
+) -> Result<u64, String> {
+    let action = format!("list parts of upload '{}'", upload_id);
+    let mut size = 0;
+    let mut marker: Option<String> = None;
+    loop {
+        let mut query = vec![("uploadId", upload_id)];
+        if let Some(marker) = &marker {
+            query.push(("part-number-marker", marker.as_str()));
+        }
+        let request =
+            s3_signed_request(config, "Listing parts", reqwest::Method::GET, key, &query, Vec::new(), chrono::Utc::now())?;
+        let body = s3_send_async(config, request, &action).await?;
+        let page: ListPartsResult = parse_s3_xml(&body, &action)?;
+        size += page.parts.iter().map(|part| part.size).sum::<u64>();
+        match page.next_part_number_marker {
+            Some(next) if page.is_truncated => marker = Some(next),
+            _ => return Ok(size),
+        }
+    }
+}
+
+type MultipartUploadRow = (String, String, Option<chrono::DateTime<chrono::Utc>>, Option<i64>);
+
+/// The in-progress multipart uploads of keys starting with `path`, following pagination.
+async fn list_multipart_uploads_async(
+    config: &HashMap<String, String>,
+    path: &str,
+) -> Result<Vec<MultipartUpload>, String> {
+    let action = "list multipart uploads";
+    let prefix = s3_object_key(config, path);
+    let mut uploads = Vec::new();
+    let mut markers: Option<(String, String)> = None;
+    loop {
+        let mut query = vec![("uploads", ""), ("prefix", prefix.as_str())];
+        if let Some((key_marker, upload_id_marker)) = &markers {
+            query.push(("key-marker", key_marker));
+            query.push(("upload-id-marker", upload_id_marker));
+        }
+        let request = s3_signed_request(
+            config,
+            "Listing multipart uploads",
+            reqwest::Method::GET,
+            "",
+            &query,
+            Vec::new(),
+            chrono::Utc::now(),
+        )?;
+        let body = s3_send_async(config, request, action).await?;
+        let page: ListMultipartUploadsResult = parse_s3_xml(&body, action)?;
+        uploads.extend(page.uploads);
+        match (page.next_key_marker, page.next_upload_id_marker) {
+            (Some(key_marker), Some(upload_id_marker)) if page.is_truncated => {
+                markers = Some((key_marker, upload_id_marker))
+            }
+            _ => return Ok(uploads),
+        }
+    }
+}
+
+/// The in-progress multipart uploads of keys starting with `path`, with paths relative to
+/// the config's `root`. Sizes are `None` when the parts can't be listed, e.g. because the
+/// upload was completed or aborted meanwhile.
+async fn do_multipart_list_async(
+    config: &HashMap<String, String>,
+    path: &str,
+) -> Result<Vec<MultipartUploadRow>, String> {
+    let root_key = s3_object_key(config, "");
+    let uploads = list_multipart_uploads_async(config, path).await?;
+    let mut rows = Vec::with_capacity(uploads.len());
+    for upload in uploads {
+        let size = multipart_uploaded_size_async(config, &upload.key, &upload.upload_id).await.ok();
+        let initiated = chrono::DateTime::parse_from_rfc3339(&upload.initiated).ok().map(|t| t.to_utc());
+        let path = upload.key.strip_prefix(&root_key).unwrap_or(&upload.key).to_string();
+        rows.push((upload.upload_id, path, initiated, size.map(|size| size as i64)));
+    }
+    Ok(rows)
+}
+
+/// Checks that `service` is S3 and parses its config for a request signed by this extension.
+fn s3_direct_config(service: &str, config: JsonB, feature: &str) -> Result<HashMap<String, String>, String> {
+    if !matches!(Scheme::from_str(service), Ok(Scheme::S3)) {
+        return Err(format!("Service '{}' does not support {}", service, feature));
+    }
+    let mut config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    take_provider_config(service, &mut config_map)
+        .and_then(|_| take_request_payer_config(service, &mut config_map))
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    HttpTransport::from_config(&config_map)?;
+    Ok(config_map)
+}
+
+/// Converts a chrono time to a timestamptz, in microseconds since 2000-01-01 UTC.
+fn utc_to_timestamptz(time: chrono::DateTime<chrono::Utc>) -> Option<TimestampWithTimeZone> {
+    const POSTGRES_EPOCH_MICROS: i64 = 946_684_800_000_000;
+    TimestampWithTimeZone::try_from(time.timestamp_micros() - POSTGRES_EPOCH_MICROS).ok()
+}
+
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn pg_opendal_multipart_list(
+    service: &str,
+    path: &str,
+    config: JsonB,
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(upload_id, String),
+            name!(path, String),
+            name!(initiated, Option<TimestampWithTimeZone>),
+            name!(size_uploaded, Option<i64>),
+        ),
+    >,
+    String,
+> {
+    let config_map = s3_direct_config(service, config, "listing multipart uploads")?;
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let rows = rt.block_on(do_multipart_list_async(&config_map, &resolve_path(path)))?;
+    Ok(TableIterator::new(rows.into_iter().map(|(upload_id, path, initiated, size)| {
+        (upload_id, path, initiated.and_then(utc_to_timestamptz), size)
+    })))
+}
+
+/// Aborts one multipart upload of the object `key`. Returns false when S3 no longer knows
+/// the upload.
+async fn abort_multipart_upload_async(
+    config: &HashMap<String, String>,
+    key: &str,
+    upload_id: &str,
+) -> Result<bool, String> {
+    let action = format!("abort upload '{}'", upload_id);
+    let request = s3_signed_request(
+        config,
+        "Aborting uploads",
+        reqwest::Method::DELETE,
+        key,
+        &[("uploadId", upload_id)],
+        Vec::new(),
+        chrono::Utc::now(),
+    )?;
+    match s3_execute_async(config, request, &action).await? {
+        (status, _) if status.is_success() => Ok(true),
+        (reqwest::StatusCode::NOT_FOUND, _) => Ok(false),
+        (status, body) => Err(s3_error(&action, status, &body)),
+    }
+}
+
+async fn do_multipart_abort_async(
+    config: &HashMap<String, String>,
+    path: &str,
+    upload_id: &str,
+) -> Result<bool, String> {
+    abort_multipart_upload_async(config, &s3_object_key(config, path), upload_id).await
+}
+
+#[pg_extern]
+fn pg_opendal_multipart_abort(service: &str, path: &str, upload_id: &str, config: JsonB) -> Result<bool, String> {
+    let config_map = s3_direct_config(service, config, "aborting multipart uploads")?;
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_multipart_abort_async(&config_map, &resolve_path(path), upload_id))
+}
+
+/// Aborts every in-progress multipart upload of keys starting with `prefix`, returning how
+/// many were aborted. Uploads finished or aborted by someone else meanwhile aren't counted.
+fn abort_incomplete_uploads(rt: &Runtime, config: &HashMap<String, String>, prefix: &str) -> Result<i64, String> {
+    let uploads = rt.block_on(list_multipart_uploads_async(config, prefix))?;
+    let mut aborted = 0i64;
+    for upload in uploads {
+        pgrx::check_for_interrupts!();
+        match rt.block_on(abort_multipart_upload_async(config, &upload.key, &upload.upload_id)) {
+            Ok(true) => aborted += 1,
+            Ok(false) => {}
+            Err(e) => return Err(format!("{}; {} uploads were aborted before the failure", e, aborted)),
+        }
+    }
+    Ok(aborted)
+}
+
+#[pg_extern]
+fn pg_opendal_abort_incomplete_uploads(service: &str, prefix: &str, config: JsonB) -> Result<i64, String> {
+    let config_map = s3_direct_config(service, config, "aborting multipart uploads")?;
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    abort_incomplete_uploads(&rt, &config_map, &resolve_path(prefix))
+}
+
+/// Builds a GetBucketPolicy, PutBucketPolicy or DeleteBucketPolicy request, depending on
+/// `method`, for the config's bucket.
+fn s3_bucket_policy_request(
+    config: &HashMap<String, String>,
+    method: reqwest::Method,
+    body: Vec<u8>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<reqwest::Request, String> {
+    s3_signed_request(config, "Managing bucket policies", method, "", &[("policy", "")], body, now)
+}
+
+/// Whether an error response means the bucket has no policy, as opposed to e.g. a missing bucket.
+fn is_no_such_bucket_policy(status: reqwest::StatusCode, body: &str) -> bool {
+    status == reqwest::StatusCode::NOT_FOUND && xml_element(body, "Code") == Some("NoSuchBucketPolicy")
+}
+
+/// Interprets a GetBucketPolicy response: the policy document, or `None` when the bucket has none.
+fn parse_bucket_policy_response(status: reqwest::StatusCode, body: &str) -> Result<Option<Value>, String> {
+    let action = "get bucket policy";
+    if is_no_such_bucket_policy(status, body) {
+        return Ok(None);
+    }
+    if !status.is_success() {
+        return Err(s3_error(action, status, body));
+    }
+    serde_json::from_str(body)
+        .map(Some)
+        .map_err(|e| format!("Failed to {}: invalid response: {}", action, e))
+}
+
+async fn do_get_bucket_policy_async(config: &HashMap<String, String>) -> Result<Option<Value>, String> {
+    let request = s3_bucket_policy_request(config, reqwest::Method::GET, Vec::new(), chrono::Utc::now())?;
+    let (status, body) = s3_execute_async(config, request, "get bucket policy").await?;
+    parse_bucket_policy_response(status, &body)
+}
+
+#[pg_extern]
+fn pg_opendal_s3_get_bucket_policy(service: &str, config: JsonB) -> Result<Option<JsonB>, String> {
+    let config_map = s3_direct_config(service, config, "bucket policies")?;
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    Ok(rt.block_on(do_get_bucket_policy_async(&config_map))?.map(JsonB))
+}
+
+async fn do_set_bucket_policy_async(config: &HashMap<String, String>, policy: &Value) -> Result<bool, String> {
+    if !policy.is_object() {
+        return Err("Bucket policy must be a JSON object".to_string());
+    }
+    let body = serde_json::to_vec(policy).map_err(|e| format!("Failed to serialize bucket policy: {}", e))?;
+    let request = s3_bucket_policy_request(config, reqwest::Method::PUT, body, chrono::Utc::now())?;
+    s3_send_async(config, request, "set bucket policy").await?;
+    Ok(true)
+}
+
+#[pg_extern]
+fn pg_opendal_s3_set_bucket_policy(service: &str, policy: JsonB, config: JsonB) -> Result<bool, String> {
+    let config_map = s3_direct_config(service, config, "bucket policies")?;
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_set_bucket_policy_async(&config_map, &policy.0))
+}
+
+/// Removes the bucket's policy. Returns false when it had none.
+async fn do_delete_bucket_policy_async(config: &HashMap<String, String>) -> Result<bool, String> {
+    let action = "delete bucket policy";
+    let request = s3_bucket_policy_request(config, reqwest::Method::DELETE, Vec::new(), chrono::Utc::now())?;
+    match s3_execute_async(config, request, action).await? {
+        (status, _) if status.is_success() => Ok(true),
+        (status, body) if is_no_such_bucket_policy(status, &body) => Ok(false),
+        (status, body) => Err(s3_error(action, status, &body)),
+    }
+}
+
+#[pg_extern]
+fn pg_opendal_s3_delete_bucket_policy(service: &str, config: JsonB) -> Result<bool, String> {
+    let config_map = s3_direct_config(service, config, "bucket policies")?;
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_delete_bucket_policy_async(&config_map))
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListAllMyBucketsResult {
+    #[serde(default)]
+    buckets: BucketList,
+    continuation_token: Option<String>,
+}
+
+#[derive(Default, serde::Deserialize)]
+struct BucketList {
+    #[serde(default, rename = "Bucket")]
+    buckets: Vec<BucketSummary>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BucketSummary {
+    name: String,
+    creation_date: String,
+    bucket_region: Option<String>,
+}
+
+type BucketRow = (String, Option<chrono::DateTime<chrono::Utc>>, Option<String>);
+
+/// Every bucket the config's credentials can list, following pagination. The region is
+/// only known where S3 reports it.
+async fn do_list_buckets_async(config: &HashMap<String, String>) -> Result<Vec<BucketRow>, String> {
+    let action = "list buckets";
+    let region = config.get("region").map(String::as_str).unwrap_or_default();
+    let url = format!("{}/", s3_endpoint(config, region));
+    let mut rows = Vec::new();
+    let mut token: Option<String> = None;
+    loop {
+        let query: Vec<(&str, &str)> = token.iter().map(|token| ("continuation-token", token.as_str())).collect();
+        let request = s3_signed_request_to(
+            config,
+            "Listing buckets",
+            reqwest::Method::GET,
+            &url,
+            &query,
+            Vec::new(),
+            chrono::Utc::now(),
+        )?;
+        let body = s3_send_async(config, request, action).await?;
+        let page: ListAllMyBucketsResult = parse_s3_xml(&body, action)?;
+        rows.extend(page.buckets.buckets.into_iter().map(|bucket| {
+            let created = chrono::DateTime::parse_from_rfc3339(&bucket.creation_date).ok().map(|t| t.to_utc());
+            (bucket.name, created, bucket.bucket_region)
+        }));
+        match page.continuation_token {
+            Some(next) if !next.is_empty() => token = Some(next),
+            _ => return Ok(rows),
+        }
+    }
+}
+
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn pg_opendal_s3_list_buckets(
+    service: &str,
+    config: JsonB,
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(bucket, String),
+            name!(creation_date, Option<TimestampWithTimeZone>),
+            name!(region, Option<String>),
+        ),
+    >,
+    String,
+> {
+    let config_map = s3_direct_config(service, config, "listing buckets")?;
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let rows = rt.block_on(do_list_buckets_async(&config_map))?;
+    Ok(TableIterator::new(rows.into_iter().map(|(bucket, created, region)| {
+        (bucket, created.and_then(utc_to_timestamptz), region)
+    })))
+}
+
+/// Lists every file below `path`, descending into subdirectories.
+async fn list_files_recursive_async(
+    op: &Operator,
+    path: &str,
+    start_after: Option<&str>,
+) -> Result<Vec<opendal::Entry>, String> {
+    let mut lister = lister_after_async(op, path, true, start_after).await?;
+
+    let mut files = Vec::new();
+    while let Some(entry) = lister.try_next().await
+        .map_err(|e| format!("Failed to list contents of '{}': {}", path, e))? {
+        if entry.metadata().is_file() && listed_after(&entry, start_after) {
+            files.push(entry);
+        }
+    }
+    Ok(files)
+}
+
+/// Size of a listed file, from the listing metadata when the service includes it,
+/// otherwise from a separate stat.
+async fn entry_content_length_async(op: &Operator, entry: &opendal::Entry) -> Result<u64, String> {
+    if op.info().full_capability().list_has_content_length {
+        return Ok(entry.metadata().content_length());
+    }
+    op.stat(entry.path())
+        .await
+        .map(|metadata| metadata.content_length())
+        .map_err(|e| format!("Failed to get metadata for entry '{}': {}", entry.path(), e))
+}
+
+/// Content type of a listed file, from the listing metadata when the service includes
+/// it, otherwise from a separate stat.
+async fn entry_content_type_async(op: &Operator, entry: &opendal::Entry) -> Result<Option<String>, String> {
+    if op.info().full_capability().list_has_content_type {
+        return Ok(entry.metadata().content_type().map(str::to_string));
+    }
+    op.stat(entry.path())
+        .await
+        .map(|metadata| metadata.content_type().map(str::to_string))
+        .map_err(|e| format!("Failed to get metadata for entry '{}': {}", entry.path(), e))
+}
+
+/// Whether `content_type` matches `pattern`, either exactly or as `type/*` or `*/*`.
+/// Parameters such as `; charset=utf-8` are ignored and case doesn't matter.
+fn content_type_matches(pattern: &str, content_type: &str) -> bool {
+    let essence = |value: &str| value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    let (pattern, content_type) = (essence(pattern), essence(content_type));
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(top_level) => content_type.split_once('/').is_some_and(|(ty, _)| ty == top_level),
+        None => pattern == content_type,
+    }
+}
+
+async fn do_list_by_content_type_async(
+    op: Operator,
+    path: &str,
+    content_type: &str,
+) -> Result<Vec<JsonB>, String> {
+    let mut results = Vec::new();
+    for entry in list_files_recursive_async(&op, path, None).await? {
+        let Some(entry_type) = entry_content_type_async(&op, &entry).await? else {
+            continue;
+        };
+        if !content_type_matches(content_type, &entry_type) {
+            continue;
+        }
+        let mut entry_info = listed_entry_info_async(&op, &entry).await?;
+        entry_info.insert("content_type".to_string(), Value::String(entry_type));
+        results.push(JsonB(Value::Object(entry_info)));
+    }
+    Ok(results)
+}
+
+#[pg_extern]
+fn pg_opendal_list_by_content_type(
+    service: &str,
+    path: &str,
+    content_type: &str,
+    config: JsonB,
+) -> Result<SetOfIterator<'static, JsonB>, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let results = rt.block_on(do_list_by_content_type_async(op, &resolve_path(path), content_type))?;
+    Ok(SetOfIterator::new(results))
+}
+
+/// Files in `path` whose size is within `min_bytes..=max_bytes`, searching subdirectories
+/// when `recursive`. Sizes and entry fields come from the listing when the service
+/// includes them, saving a stat per entry.
+async fn do_list_by_size_range_async(
+    op: Operator,
+    path: &str,
+    min_bytes: u64,
+    max_bytes: u64,
+    start_after: Option<&str>,
+    recursive: bool,
+) -> Result<Vec<JsonB>, String> {
+    let mut lister = lister_after_async(&op, path, recursive, start_after).await?;
+
+    let mut results = Vec::new();
+    while let Some(entry) = lister.try_next().await
+        .map_err(|e| format!("Failed to list contents of '{}': {}", path, e))? {
+        if !entry.metadata().is_file() || !listed_after(&entry, start_after) {
+            continue;
+        }
+        let size = entry_content_length_async(&op, &entry).await?;
+        if (min_bytes..=max_bytes).contains(&size) {
+            results.push(JsonB(Value::Object(listed_entry_info_async(&op, &entry).await?)));
+        }
+    }
+    Ok(results)
+}
+
+fn list_by_size_range(
+    service: &str,
+    path: &str,
+    config: JsonB,
+    min_bytes: i64,
+    max_bytes: i64,
+    start_after: Option<&str>,
+    recursive: bool,
+) -> Result<SetOfIterator<'static, JsonB>, String> {
+    let min_bytes = u64::try_from(min_bytes).map_err(|_| format!("Invalid min_bytes: {}", min_bytes))?;
+    let max_bytes = u64::try_from(max_bytes).map_err(|_| format!("Invalid max_bytes: {}", max_bytes))?;
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let start_after = start_after.map(resolve_path);
+    warn_if_start_after_unsupported(&op, start_after.as_deref());
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let results = rt.block_on(do_list_by_size_range_async(
+        op,
+        &resolve_path(path),
+        min_bytes,
+        max_bytes,
+        start_after.as_deref(),
+        recursive,
+    ))?;
+    Ok(SetOfIterator::new(results))
+}
+
+#[pg_extern]
+fn pg_opendal_list_by_size_range(
+    service: &str,
+    path: &str,
+    config: JsonB,
+    min_bytes: default!(i64, 0),
+    max_bytes: default!(i64, 9223372036854775807),
+    start_after: default!(Option<&str>, "NULL"),
+) -> Result<SetOfIterator<'static, JsonB>, String> {
+    list_by_size_range(service, path, config, min_bytes, max_bytes, start_after, true)
+}
+
+/// `pg_opendal_list_by_size_range` for the files directly in `path`, with NULL for an
+/// open bound.
+#[pg_extern]
+fn pg_opendal_list_by_size(
+    service: &str,
+    path: &str,
+    min_bytes: Option<i64>,
+    max_bytes: Option<i64>,
+    config: JsonB,
+    start_after: default!(Option<&str>, "NULL"),
+) -> Result<SetOfIterator<'static, JsonB>, String> {
+    list_by_size_range(service, path, config, min_bytes.unwrap_or(0), max_bytes.unwrap_or(i64::MAX), start_after, false)
+}
+
+/// `(bucket_min, bucket_max, file_count, total_bytes)`
+type HistogramRow = (i64, i64, i64, i64);
+
+/// Groups file sizes into `buckets` ranges of logarithmically growing width, from the
+/// smallest size to the largest. Ranges narrower than a byte are merged into their
+/// neighbour, so small spans yield fewer rows. Empty ranges are kept.
+fn size_histogram(sizes: &[u64], buckets: usize) -> Vec<HistogramRow> {
+    let (Some(&min), Some(&max)) = (sizes.iter().min(), sizes.iter().max()) else {
+        return Vec::new();
+    };
+    let lo = min.max(1) as f64;
+    let ratio = (max.max(1) as f64 / lo).powf(1.0 / buckets as f64);
+    let mut starts = vec![min];
+    for i in 1..buckets {
+        let start = (lo * ratio.powi(i as i32)).ceil() as u64;
+        if starts.last().is_some_and(|&last| start > last) && start <= max {
+            starts.push(start);
+        }
+    }
+
+    let mut rows: Vec<HistogramRow> = starts
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| {
+            let end = starts.get(i + 1).map_or(max, |next| next - 1);
+            (start as i64, end as i64, 0, 0)
+        })
+        .collect();
+    for &size in sizes {
+        let row = &mut rows[starts.partition_point(|&start| start <= size) - 1];
+        row.2 += 1;
+        row.3 += size as i64;
+    }
+    rows
+}
+
+async fn do_size_histogram_async(op: Operator, path: &str, buckets: usize) -> Result<Vec<HistogramRow>, String> {
+    let mut sizes = Vec::new();
+    for entry in list_files_recursive_async(&op, path, None).await? {
+        sizes.push(entry_content_length_async(&op, &entry).await?);
+    }
+    Ok(size_histogram(&sizes, buckets))
+}
+
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn pg_opendal_size_histogram(
+    service: &str,
+    path: &str,
+    config: JsonB,
+    buckets: default!(i32, 10),
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(bucket_min, i64),
+            name!(bucket_max, i64),
+            name!(file_count, i64),
+            name!(total_bytes, i64),
+        ),
+    >,
+    String,
+> {
+    let buckets = usize::try_from(buckets)
+        .ok()
+        .filter(|&buckets| buckets > 0)
+        .ok_or_else(|| format!("Invalid buckets: {}", buckets))?;
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let rows = rt.block_on(do_size_histogram_async(op, &resolve_path(path), buckets))?;
+    Ok(TableIterator::new(rows))
+}
+
+/// A listed file ordered by modification time, then path. Files without a modification
+/// time sort first, as if oldest.
+struct RecentEntry {
+    modified: Option<chrono::DateTime<chrono::Utc>>,
+    entry: opendal::Entry,
+}
+
+impl RecentEntry {
+    fn key(&self) -> (Option<chrono::DateTime<chrono::Utc>>, &str) {
+        (self.modified, self.entry.path())
+    }
+}
+
+impl PartialEq for RecentEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for RecentEntry {}
+
+impl PartialOrd for RecentEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RecentEntry {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// The `limit` most recently modified files under `path`, newest first. Only `limit`
+/// entries are held at a time, in a min-heap whose top is the oldest one kept.
+async fn do_list_recent_async(op: Operator, path: &str, limit: usize) -> Result<Vec<JsonB>, String> {
+    let mut lister = lister_after_async(&op, path, true, None).await?;
+
+    let mut newest = std::collections::BinaryHeap::with_capacity(limit + 1);
+    while let Some(entry) = lister.try_next().await
+        .map_err(|e| format!("Failed to list contents of '{}': {}", path, e))? {
+        if !entry.metadata().is_file() {
+            continue;
+        }
+        let modified = entry_last_modified_async(&op, &entry).await?;
+        newest.push(std::cmp::Reverse(RecentEntry { modified, entry }));
+        if newest.len() > limit {
+            newest.pop();
+        }
+    }
+
+    let mut results = Vec::with_capacity(newest.len());
+    // Ascending order of the reversed entries is newest first.
+    for std::cmp::Reverse(recent) in newest.into_sorted_vec() {
+        results.push(JsonB(Value::Object(listed_entry_info_async(&op, &recent.entry).await?)));
+    }
+    Ok(results)
+}
+
+#[pg_extern]
+fn pg_opendal_list_recent(
+    service: &str,
+    path: &str,
+    config: JsonB,
+    limit: default!(i32, 10),
+) -> Result<SetOfIterator<'static, JsonB>, String> {
+    let limit = usize::try_from(limit)
+        .ok()
+        .filter(|&limit| limit > 0)
+        .ok_or_else(|| format!("Invalid limit: {}", limit))?;
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let results = rt.block_on(do_list_recent_async(op, &resolve_path(path), limit))?;
+    Ok(SetOfIterator::new(results))
+}
+
+/// The files under `path` last modified before `cutoff`, in listing order. Files whose
+/// service reports no modification time are left out.
+async fn do_list_older_than_async(
+    op: Operator,
+    path: &str,
+    cutoff: chrono::DateTime<chrono::Utc>,
+) -> Result<Vec<JsonB>, String> {
+    let mut lister = lister_after_async(&op, path, true, None).await?;
+    let mut results = Vec::new();
+    while let Some(entry) = lister.try_next().await
+        .map_err(|e| format!("Failed to list contents of '{}': {}", path, e))? {
+        if !entry.metadata().is_file() {
+            continue;
+        }
+        if entry_last_modified_async(&op, &entry).await?.is_some_and(|modified| modified < cutoff) {
+            results.push(JsonB(Value::Object(listed_entry_info_async(&op, &entry).await?)));
+        }
+    }
+    Ok(results)
+}
+
+#[pg_extern]
+fn pg_opendal_list_older_than(
+    service: &str,
+    path: &str,
+    cutoff: TimestampWithTimeZone,
+    config: JsonB,
+) -> Result<SetOfIterator<'static, JsonB>, String> {
+    let cutoff = postgres_micros_to_utc(pg_sys::TimestampTz::from(cutoff));
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let results = rt.block_on(do_list_older_than_async(op, &resolve_path(path), cutoff))?;
+    Ok(SetOfIterator::new(results))
+}
+
+/// Compiles a glob matched against file names, or against full paths when the
+/// pattern contains a `/`, like `find -name` / `find -path`.
+fn compile_path_glob(pattern: &str) -> Result<impl Fn(&opendal::Entry) -> bool, String> {
+    let matcher = globset::Glob::new(pattern)
+        .map_err(|e| format!("Invalid pattern '{}': {}", pattern, e))?
+        .compile_matcher();
+    let match_path = pattern.contains('/');
+    Ok(move |entry: &opendal::Entry| {
+        if match_path {
+            matcher.is_match(entry.path())
+        } else {
+            matcher.is_match(entry.name())
+        }
+    })
+}
+
+#[pg_extern]
+fn pg_opendal_delete_matching(
+    service: &str,
+    path: &str,
+    pattern: &str,
+    config: JsonB,
+    dry_run: default!(bool, true),
+) -> Result<TableIterator<'static, (name!(path, String), name!(deleted, bool))>, String> {
+    let is_match = compile_path_glob(pattern)?;
+    let path = resolve_path(path);
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let matches: Vec<String> = rt
+        .block_on(list_files_recursive_async(&op, &path, None))?
+        .into_iter()
+        .filter(|entry| is_match(entry))
+        .map(|entry| entry.path().to_string())
+        .collect();
+
+    if dry_run {
+        pgrx::warning!(
+            "{} files under '{}' match '{}' (dry run, nothing deleted)",
+            matches.len(), path, pattern
+        );
+        return Ok(TableIterator::new(matches.into_iter().map(|p| (p, false))));
+    }
+
+    pgrx::warning!("Deleting {} files under '{}' matching '{}'", matches.len(), path, pattern);
+    let mut rows = Vec::with_capacity(matches.len());
+    for file in matches {
+        let deleted = match rt.block_on(op.delete(&file)) {
+            Ok(()) => true,
+            Err(e) => {
+                pgrx::warning!("Failed to delete '{}': {}", file, e);
+                false
+            }
+        };
+        rows.push((file, deleted));
+    }
+    Ok(TableIterator::new(rows))
+}
+
+/// Deletes everything under `prefix`, treated as a directory, returning the number of
+/// files deleted.
+async fn do_delete_prefix_async(op: &Operator, prefix: &str) -> Result<i64, String> {
+    if prefix.trim_matches('/').is_empty() {
+        return Err("Refusing to delete everything: prefix must not be empty or '/'".to_string());
+    }
+    let dir = if prefix.ends_with('/') { prefix.to_string() } else { format!("{}/", prefix) };
+
+    let mut lister = op.lister_with(&dir).recursive(true).await
+        .map_err(|e| format!("Failed to get lister for '{}': {}", dir, e))?;
+    let mut paths = Vec::new();
+    let mut files = 0;
+    while let Some(entry) = lister.try_next().await
+        .map_err(|e| format!("Failed to list contents of '{}': {}", dir, e))? {
+        if entry.metadata().is_file() {
+            files += 1;
+        }
+        paths.push(entry.path().to_string());
+    }
+
+    // Remove the deepest entries first, so directories are empty by the time they go.
+    paths.sort_by(|a, b| b.cmp(a));
+    op.delete_iter(paths)
+        .await
+        .map(|_| files)
+        .map_err(|e| format!("Failed to delete under '{}': {}", dir, e))
+}
+
+#[pg_extern]
+fn pg_opendal_delete_prefix(service: &str, prefix: &str, config: JsonB) -> Result<i64, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_delete_prefix_async(&op, &resolve_path(prefix)))
+}
+
+/// The longest prefix of `a` and `b` made of whole path components: it ends in `/`, or is
+/// the whole of both, so `data/a.csv` and `data/a.csv.gz` share only `data/`.
+fn common_path_prefix<'a>(a: &'a str, b: &str) -> &'a str {
+    let mut len = a.bytes().zip(b.bytes()).take_while(|(x, y)| x == y).count();
+    if len == a.len() && (len == b.len() || a.ends_with('/')) {
+        return a;
+    }
+    // Equal bytes can end partway through a multi-byte character.
+    while !a.is_char_boundary(len) {
+        len -= 1;
+    }
+    a[..len].rfind('/').map_or("", |slash| &a[..=slash])
+}
+
+/// The longest path prefix shared by every file under `path`. Only the prefix found so
+/// far is kept while listing, so memory doesn't grow with the number of files.
+async fn do_common_prefix_async(op: &Operator, path: &str) -> Result<String, String> {
+    let mut lister = lister_after_async(op, path, true, None).await?;
+    let mut prefix: Option<String> = None;
+    while let Some(entry) = lister.try_next().await
+        .map_err(|e| format!("Failed to list contents of '{}': {}", path, e))? {
+        if !entry.metadata().is_file() {
+            continue;
+        }
+        prefix = Some(match prefix {
+            Some(prefix) => common_path_prefix(&prefix, entry.path()).to_string(),
+            None => entry.path().to_string(),
+        });
+        if prefix.as_deref() == Some("") {
+            break;
+        }
+    }
+    Ok(prefix.unwrap_or_default())
+}
+
+#[pg_extern]
+fn pg_opendal_common_prefix(service: &str, path: &str, config: JsonB) -> Result<String, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_common_prefix_async(&op, &resolve_path(path)))
+}
+
+async fn do_delete_prefix_batch_async(
+    op: Operator,
+    prefixes: Vec<String>,
+) -> Result<Vec<(String, i64)>, String> {
+    let mut tasks = tokio::task::JoinSet::new();
+    for (i, prefix) in prefixes.into_iter().enumerate() {
+        let op = op.clone();
+        tasks.spawn(async move {
+            let deleted = do_delete_prefix_async(&op, &prefix).await;
+            (i, prefix, deleted)
+        });
+    }
+
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((i, prefix, Ok(deleted))) => rows.push((i, (prefix, deleted))),
+            Ok((_, _, Err(e))) => errors.push(e),
+            Err(e) => errors.push(format!("Delete task failed: {}", e)),
+        }
+    }
+    // Every prefix has run to completion by now, so report all failures together.
+    if !errors.is_empty() {
+        return Err(errors.join("; "));
+    }
+    rows.sort_by_key(|(i, _)| *i);
+    Ok(rows.into_iter().map(|(_, row)| row).collect())
+}
+
+#[pg_extern]
+fn pg_opendal_delete_prefix_batch(
+    service: &str,
+    prefixes: Vec<String>,
+    config: JsonB,
+) -> Result<TableIterator<'static, (name!(prefix, String), name!(objects_deleted, i64))>, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let rows = rt.block_on(do_delete_prefix_batch_async(op, resolve_paths(prefixes)))?;
+    Ok(TableIterator::new(rows))
+}
+
+/// Keys returned per list request by S3-style APIs, used to estimate list request counts.
+const LIST_PAGE_SIZE: u64 = 1000;
+
+async fn do_estimate_async(op: Operator, path: &str, operation: &str) -> Result<JsonB, String> {
+    // A directory covers every file below it; anything else is a single object.
+    let (object_count, total_bytes) = if path.is_empty() || path.ends_with('/') {
+        let files = list_files_recursive_async(&op, path, None).await?;
+        let mut total = 0;
+        for entry in &files {
+            total += entry_content_length_async(&op, entry).await?;
+        }
+        (files.len() as u64, total)
+    } else {
+        let metadata = op.stat(path).await
+            .map_err(|e| format!("Failed to get stat for '{}': {}", path, e))?;
+        (1, metadata.content_length())
+    };
+
+    let list_requests = object_count.div_ceil(LIST_PAGE_SIZE).max(1);
+    let (requests, egress_bytes) = match operation {
+        "read" => (object_count, total_bytes),
+        "list" => (list_requests, 0),
+        "delete" => {
+            let per_request = op.info().full_capability().delete_max_size.unwrap_or(1).max(1) as u64;
+            (object_count.div_ceil(per_request), 0)
+        }
+        other => {
+            return Err(format!(
+                "Invalid operation '{}', expected one of 'read', 'list' or 'delete'",
+                other
+            ))
+        }
+    };
+
+    let mut estimate = serde_json::Map::new();
+    estimate.insert("operation".to_string(), Value::String(operation.to_string()));
+    estimate.insert("object_count".to_string(), Value::Number(object_count.into()));
+    estimate.insert("total_bytes".to_string(), Value::Number(total_bytes.into()));
+    estimate.insert("estimated_requests".to_string(), Value::Number(requests.into()));
+    estimate.insert("estimated_egress_bytes".to_string(), Value::Number(egress_bytes.into()));
+    Ok(JsonB(Value::Object(estimate)))
+}
+
+#[pg_extern]
+fn pg_opendal_estimate(
+    service: &str,
+    path: &str,
+    operation: &str,
+    config: JsonB,
+) -> Result<JsonB, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    rt.block_on(do_estimate_async(op, &resolve_path(path), operation))
+}
+
+#[pg_extern]
+fn pg_opendal_capability(service: &str, config: JsonB) -> Result<JsonB, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let capability = op.info().full_capability();
+    let mut cap_info = serde_json::Map::new();
+
+    cap_info.insert("read".to_string(), Value::Bool(capability.read));
+    cap_info.insert("write".to_string(), Value::Bool(capability.write));
+    cap_info.insert("list".to_string(), Value::Bool(capability.list));
+    cap_info.insert("stat".to_string(), Value::Bool(capability.stat));
+    cap_info.insert("delete".to_string(), Value::Bool(capability.delete));
+    cap_info.insert("copy".to_string(), Value::Bool(capability.copy));
+    cap_info.insert("rename".to_string(), Value::Bool(capability.rename));
+    cap_info.insert("create_dir".to_string(), Value::Bool(capability.create_dir));
+
+    Ok(JsonB(Value::Object(cap_info)))
+}
+
+/// The PostgreSQL major version this build targets, taken from the pgNN cargo feature.
+fn compiled_pg_version() -> Option<&'static str> {
+    if cfg!(feature = "pg13") {
+        Some("13")
+    } else if cfg!(feature = "pg14") {
+        Some("14")
+    } else if cfg!(feature = "pg15") {
+        Some("15")
+    } else if cfg!(feature = "pg16") {
+        Some("16")
+    } else if cfg!(feature = "pg17") {
+        Some("17")
+    } else {
+        None
+    }
+}
+
+#[pg_extern]
+fn pg_opendal_version() -> JsonB {
+    let mut schemes: Vec<String> = Scheme::enabled().iter().map(|s| s.to_string()).collect();
+    schemes.sort();
+
+    let mut version_info = serde_json::Map::new();
+    version_info.insert(
+        "extension_version".to_string(),
+        Value::String(env!("CARGO_PKG_VERSION").to_string()),
+    );
+    version_info.insert(
+        "opendal_version".to_string(),
+        Value::String(opendal::raw::VERSION.to_string()),
+    );
+    version_info.insert(
+        "pg_version".to_string(),
+        compiled_pg_version().map_or(Value::Null, |v| Value::String(v.to_string())),
+    );
+    version_info.insert(
+        "services".to_string(),
+        Value::Array(schemes.into_iter().map(Value::String).collect()),
+    );
+    JsonB(Value::Object(version_info))
+}
+
+/// One step of a round-trip self test. Failures are recorded, never raised.
+struct SelftestStep {
+    name: &'static str,
+    result: Result<(), String>,
+    elapsed_ms: f64,
+}
+
+/// Writes a random UUID to `path`, reads and verifies it, optionally stats it, then
+/// deletes it, timing each step. Every step runs even if an earlier one failed, so
+/// the scratch object is cleaned up whenever possible.
+async fn round_trip_async(op: &Operator, path: &str, with_stat: bool) -> Vec<SelftestStep> {
+    let expected = uuid::Uuid::new_v4().to_string();
+    let mut steps = Vec::new();
+    let mut timed = |name, started: std::time::Instant, result| {
+        steps.push(SelftestStep {
+            name,
+            result,
+            elapsed_ms: started.elapsed().as_secs_f64() * 1000.0,
+        })
+    };
+
+    let started = std::time::Instant::now();
+    let result = op.write(path, expected.clone()).await.map(|_| ());
+    timed("write", started, result.map_err(|e| e.to_string()));
+
+    let started = std::time::Instant::now();
+    let read = op.read(path).await.map_err(|e| e.to_string());
+    let verify = match &read {
+        Ok(data) if data.to_vec() == expected.as_bytes() => Ok(()),
+        Ok(_) => Err("content read back differs from content written".to_string()),
+        Err(_) => Err("nothing read back".to_string()),
+    };
+    timed("read", started, read.map(|_| ()));
+    timed("verify", std::time::Instant::now(), verify);
+
+    if with_stat {
+        let started = std::time::Instant::now();
+        let result = match op.stat(path).await {
+            Ok(metadata) if metadata.content_length() == expected.len() as u64 => Ok(()),
+            Ok(metadata) => Err(format!(
+                "stat reports {} bytes, expected {}",
+                metadata.content_length(),
+                expected.len()
+            )),
+            Err(e) => Err(e.to_string()),
+        };
+        timed("stat", started, result);
+    }
+
+    let started = std::time::Instant::now();
+    let result = op.delete(path).await.map_err(|e| e.to_string());
+    timed("delete", started, result);
+
+    steps
+}
+
+fn step_ok(steps: &[SelftestStep], name: &str) -> bool {
+    steps.iter().any(|step| step.name == name && step.result.is_ok())
+}
+
+/// Outcome of the write / read / delete smoke test. Failures are recorded per step,
+/// never raised.
+struct WriteReadCycle {
+    write_ok: bool,
+    read_ok: bool,
+    content_matches: bool,
+    delete_ok: bool,
+    latency_ms: f64,
+}
+
+async fn write_read_cycle_async(op: &Operator, path: &str) -> WriteReadCycle {
+    let steps = round_trip_async(op, path, false).await;
+    WriteReadCycle {
+        write_ok: step_ok(&steps, "write"),
+        read_ok: step_ok(&steps, "read"),
+        content_matches: step_ok(&steps, "verify"),
+        delete_ok: step_ok(&steps, "delete"),
+        latency_ms: steps.iter().map(|step| step.elapsed_ms).sum(),
+    }
+}
+
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn pg_opendal_test_write_read_cycle(
+    service: &str,
+    path: &str,
+    config: JsonB,
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(write_ok, bool),
+            name!(read_ok, bool),
+            name!(content_matches, bool),
+            name!(delete_ok, bool),
+            name!(latency_ms, f64),
+        ),
+    >,
+    String,
+> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    let cycle = rt.block_on(write_read_cycle_async(&op, &resolve_path(path)));
+    Ok(TableIterator::once((
+        cycle.write_ok,
+        cycle.read_ok,
+        cycle.content_matches,
+        cycle.delete_ok,
+        cycle.latency_ms,
+    )))
+}
+
+async fn do_selftest_async(op: Operator, prefix: &str) -> JsonB {
+    let path = format!("{}pg_opendal_selftest_{}", prefix, uuid::Uuid::new_v4());
+    let steps = round_trip_async(&op, &path, true).await;
+
+    let mut step_info = serde_json::Map::new();
+    for step in &steps {
+        let mut info = serde_json::Map::new();
+        info.insert("ok".to_string(), Value::Bool(step.result.is_ok()));
+        info.insert("ms".to_string(), serde_json::json!(step.elapsed_ms));
+        if let Err(e) = &step.result {
+            info.insert("error".to_string(), Value::String(e.clone()));
+        }
+        step_info.insert(step.name.to_string(), Value::Object(info));
+    }
+
+    let mut result = serde_json::Map::new();
+    result.insert("ok".to_string(), Value::Bool(steps.iter().all(|s| s.result.is_ok())));
+    result.insert("path".to_string(), Value::String(path));
+    result.insert(
+        "total_ms".to_string(),
+        serde_json::json!(steps.iter().map(|step| step.elapsed_ms).sum::<f64>()),
+    );
+    result.insert("steps".to_string(), Value::Object(step_info));
+    JsonB(Value::Object(result))
+}
+
+#[pg_extern]
+fn pg_opendal_selftest(
+    service: &str,
+    config: JsonB,
+    prefix: default!(&str, "''"),
+) -> Result<JsonB, String> {
+    let config_map = jsonb_to_hashmap(config.0)
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    let op = create_operator(service, config_map)
+        .map_err(|e| format!("Failed to create operator: {}", e))?;
+
+    let rt = Runtime::new().map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
+    Ok(rt.block_on(do_selftest_async(op, &resolve_path(prefix))))
+}
+
+/// Config keys a service can't be built without.
+fn required_config_keys(scheme: Scheme) -> &'static [&'static str] {
+    match scheme {
+        Scheme::S3 => &["bucket"],
+        Scheme::Fs => &["root"],
+        _ => &[],
+    }
+}
+
+/// Checks a service config locally, without building an operator or touching the network.
+/// Returns every problem found rather than stopping at the first one.
+fn validate_config(service: &str, config: &Value) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    let scheme = Scheme::from_str(service).ok().filter(|s| Scheme::enabled().contains(s));
+    if scheme.is_none() {
+        errors.push(format!("Unsupported service type '{}'", service));
+    }
+
+    let Some(obj) = config.as_object() else {
+        errors.push("Config must be a JSON object".to_string());
+        return errors;
+    };
+    let mut map = HashMap::new();
+    for (k, v) in obj {
+        match v {
+            Value::String(s) => {
+                map.insert(k.clone(), s.clone());
+            }
+            _ => errors.push(format!("Config value for '{}' must be a string", k)),
+        }
+    }
+
+    let Some(scheme) = scheme else {
+        return errors;
+    };
+
+    if let Err(e) = take_resilience_config(&mut map) {
+        errors.push(e.to_string());
+    }
+    if let Err(e) = take_http_pool_config(&mut map) {
+        errors.push(e.to_string());
+    }
+    if let Err(e) = take_user_agent_config(&mut map) {
+        errors.push(e.to_string());
+    }
+    if let Err(e) = take_multipart_threshold_config(&mut map) {
+        errors.push(e.to_string());
+    }
+    if let Err(e) = take_checksum_sidecar_config(&mut map) {
+        errors.push(e.to_string());
+    }
+    if let Err(e) = take_provider_config(service, &mut map) {
+        errors.push(e.to_string());
+    }
+    if let Err(e) = take_request_payer_config(service, &mut map) {
+        errors.push(e.to_string());
+    }
+    for key in required_config_keys(scheme) {
+        if map.get(*key).map(String::as_str).unwrap_or("").is_empty() {
+            errors.push(format!("Missing required config key '{}' for service '{}'", key, service));
+        }
+    }
+
+    // Deserializing into the service's config struct catches values that can't be
+    // coerced, e.g. a boolean key set to "yes".
+    if scheme == Scheme::S3 {
+        if let Err(e) = take_sse_config(&mut map.clone()) {
+            errors.push(format!("Invalid encryption config: {}", e));
+        }
+    }
+    let parsed = match scheme {
+        Scheme::S3 => opendal::services::S3Config::from_iter(map.clone()).map(|_| ()),
+        Scheme::Fs => opendal::services::FsConfig::from_iter(map.clone()).map(|_| ()),
+        Scheme::Memory => opendal::services::MemoryConfig::from_iter(map.clone()).map(|_| ()),
+        _ => Ok(()),
+    };
+    if let Err(e) = parsed {
+        errors.push(format!("Invalid config: {}", e));
+    }
+
+    if let Some(endpoint) = map.get("endpoint") {
+        if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+            errors.push(format!("Endpoint '{}' must start with http:// or https://", endpoint));
+        }
+    }
+    if map.contains_key("access_key_id") != map.contains_key("secret_access_key") {
+        errors.push("access_key_id and secret_access_key must be set together".to_string());
+    }
+    if scheme == Scheme::Fs {
+        if let Some(root) = map.get("root").filter(|r| !r.is_empty()) {
+            if !root.starts_with('/') {
+                errors.push(format!("Root '{}' must be an absolute path", root));
+            }
+        }
+    }
+
+    errors
+}
+
+#[pg_extern]
+fn pg_opendal_validate_config(service: &str, config: JsonB) -> JsonB {
+    let errors = validate_config(service, &config.0);
+
+    let mut result = serde_json::Map::new();
+    result.insert("valid".to_string(), Value::Bool(errors.is_empty()));
+    result.insert(
+        "errors".to_string(),
+        Value::Array(errors.into_iter().map(Value::String).collect()),
+    );
+    JsonB(Value::Object(result))
+}
+
+fn jsonb_to_hashmap(value: Value) -> Result<HashMap<String, String>> {
+    let mut map = HashMap::new();
+    if let Value::Object(obj) = value {
+        for (k, v) in obj {
+            if let Value::String(s) = v {
+                map.insert(k, s);
+            } else {
+                return Err(anyhow::anyhow!("Config values must be strings"));
+            }
+        }
+        Ok(map)
+    } else {
+        Err(anyhow::anyhow!("Config must be a JSON object"))
+    }
+}
+
+/// Merges config objects left to right, later values winning. A `null` value removes
+/// the key, so an override can unset something from a base config.
+fn merge_configs(configs: Vec<Option<Value>>) -> Result<Value, String> {
+    let mut merged = serde_json::Map::new();
+    for (i, config) in configs.into_iter().enumerate() {
+        match config {
+            None => {}
+            Some(Value::Object(config)) => {
+                for (key, value) in config {
+                    if value.is_null() {
+                        merged.remove(&key);
+                    } else {
+                        merged.insert(key, value);
+                    }
+                }
+            }
+            Some(_) => return Err(format!("Config {} must be a JSON object", i + 1)),
+        }
+    }
+    Ok(Value::Object(merged))
+}
+
+/// A config value as text: strings as they are, other values as JSON. Missing and
+/// `null` values are `None`.
+fn config_value(config: &Value, key: &str) -> Option<String> {
+    match config.get(key)? {
+        Value::Null => None,
+        Value::String(value) => Some(value.clone()),
+        value => Some(value.to_string()),
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn pg_opendal_config_merge(configs: Vec<Option<JsonB>>) -> Result<JsonB, String> {
+    merge_configs(configs.into_iter().map(|config| config.map(|config| config.0)).collect()).map(JsonB)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn pg_opendal_config_get(config: JsonB, key: &str, default_value: default!(Option<&str>, "NULL")) -> Option<String> {
+    config_value(&config.0, key).or_else(|| default_value.map(str::to_string))
+}
+
+/// Server-side encryption requested through the `sse`, `sse_kms_key_id` and
+/// `sse_customer_key` config keys.
+#[derive(Debug, PartialEq)]
+enum SseMode {
+    /// SSE-S3: keys managed by S3.
+    S3,
+    /// SSE-KMS, with the AWS managed key when no key id is given.
+    Kms(Option<String>),
+    /// SSE-C with a 32 byte customer-provided key.
+    Customer(String),
+}
+
+/// Removes the SSE keys from `config` and checks that they form a valid combination.
+fn take_sse_config(config: &mut HashMap<String, String>) -> Result<Option<SseMode>> {
+    let sse = config.remove("sse");
+    let kms_key_id = config.remove("sse_kms_key_id");
+    let customer_key = config.remove("sse_customer_key");
+
+    if kms_key_id.is_some() && customer_key.is_some() {
+        return Err(anyhow::anyhow!(
+            "sse_kms_key_id and sse_customer_key are mutually exclusive"
+        ));
+    }
+
+    let mode = match sse.as_deref() {
+        None => match (kms_key_id, customer_key) {
+            (None, None) => return Ok(None),
+            (Some(id), None) => SseMode::Kms(Some(id)),
+            (None, Some(key)) => SseMode::Customer(key),
+            (Some(_), Some(_)) => unreachable!(),
+        },
+        Some("s3") => {
+            if kms_key_id.is_some() || customer_key.is_some() {
+                return Err(anyhow::anyhow!(
+                    "sse 's3' can't be combined with sse_kms_key_id or sse_customer_key"
+                ));
+            }
+            SseMode::S3
+        }
+        Some("kms") => {
+            if customer_key.is_some() {
+                return Err(anyhow::anyhow!("sse 'kms' can't be combined with sse_customer_key"));
+            }
+            SseMode::Kms(kms_key_id)
+        }
+        Some("c") => {
+            if kms_key_id.is_some() {
+                return Err(anyhow::anyhow!("sse 'c' can't be combined with sse_kms_key_id"));
+            }
+            SseMode::Customer(customer_key.ok_or_else(|| {
+                anyhow::anyhow!("sse 'c' requires sse_customer_key")
+            })?)
+        }
+        Some(other) => {
+            return Err(anyhow::anyhow!(
+                "Invalid sse '{}', expected one of 's3', 'kms' or 'c'",
+                other
+            ))
+        }
+    };
+
+    if let SseMode::Customer(key) = &mode {
+        if key.len() != 32 {
+            return Err(anyhow::anyhow!("sse_customer_key must be exactly 32 bytes"));
+        }
+    }
+    Ok(Some(mode))
+}
+
+/// Endpoint and default region of an S3-compatible provider. `{account_id}` and `{region}`
+/// in the endpoint are filled in from the config.
+struct ProviderPreset {
+    name: &'static str,
+    endpoint: &'static str,
+    default_region: Option<&'static str>,
+}
+
+const PROVIDER_PRESETS: &[ProviderPreset] = &[
+    ProviderPreset {
+        name: "r2",
+        endpoint: "https://{account_id}.r2.cloudflarestorage.com",
+        default_region: Some("auto"),
+    },
+    ProviderPreset {
+        name: "gcs",
+        endpoint: "https://storage.googleapis.com",
+        default_region: Some("auto"),
+    },
+    ProviderPreset {
+        name: "b2",
+        endpoint: "https://s3.{region}.backblazeb2.com",
+        default_region: None,
+    },
+    ProviderPreset {
+        name: "wasabi",
+        endpoint: "https://s3.{region}.wasabisys.com",
+        default_region: Some("us-east-1"),
+    },
+];
+
+/// Removes the `provider` and `account_id` keys from `config`, filling in the provider's
+/// `endpoint` and `region` where the config doesn't set them explicitly.
+fn take_provider_config(service: &str, config: &mut HashMap<String, String>) -> Result<()> {
+    let provider = config.remove("provider");
+    let account_id = config.remove("account_id").filter(|id| !id.is_empty());
+    let Some(provider) = provider else {
+        if account_id.is_some() {
+            return Err(anyhow::anyhow!("account_id requires provider"));
+        }
+        return Ok(());
+    };
+    if !matches!(Scheme::from_str(service), Ok(Scheme::S3)) {
+        return Err(anyhow::anyhow!("provider is only supported by the s3 service"));
+    }
+    let preset = PROVIDER_PRESETS
+        .iter()
+        .find(|preset| preset.name == provider.to_ascii_lowercase())
+        .ok_or_else(|| {
+            let names: Vec<&str> = PROVIDER_PRESETS.iter().map(|preset| preset.name).collect();
+            anyhow::anyhow!("Unknown provider '{}', expected one of: {}", provider, names.join(", "))
+        })?;
+
+    let is_unset = |config: &HashMap<String, String>, key: &str| config.get(key).is_none_or(|v| v.is_empty());
+    if is_unset(config, "region") {
+        if let Some(region) = preset.default_region {
+            config.insert("region".to_string(), region.to_string());
+        }
+    }
+    if is_unset(config, "endpoint") {
+        let mut endpoint = preset.endpoint.to_string();
+        if endpoint.contains("{account_id}") {
+            let account_id = account_id
+                .ok_or_else(|| anyhow::anyhow!("Provider '{}' requires account_id", preset.name))?;
+            endpoint = endpoint.replace("{account_id}", &account_id);
+        }
+        if endpoint.contains("{region}") {
+            let region = config
+                .get("region")
+                .filter(|region| !region.is_empty())
+                .ok_or_else(|| anyhow::anyhow!("Provider '{}' requires region", preset.name))?;
+            endpoint = endpoint.replace("{region}", region);
+        }
+        config.insert("endpoint".to_string(), endpoint);
+    }
+    Ok(())
+}
+
+/// Turns the `requester_pays` (`true`/`false`) and `request_payer` (`requester`) keys
+/// into OpenDAL's `enable_request_payer`, which sends `x-amz-request-payer: requester`
+/// with every request, so the caller's account is billed instead of the bucket owner's.
+fn take_request_payer_config(service: &str, config: &mut HashMap<String, String>) -> Result<()> {
+    let requester_pays = config
+        .remove("requester_pays")
+        .map(|v| {
+            v.parse::<bool>()
+                .map_err(|_| anyhow::anyhow!("Invalid requester_pays '{}', expected 'true' or 'false'", v))
+        })
+        .transpose()?;
+    let request_payer = config
+        .remove("request_payer")
+        .map(|v| match v.to_ascii_lowercase().as_str() {
+            "requester" => Ok(()),
+            _ => Err(anyhow::anyhow!("Invalid request_payer '{}', expected 'requester'", v)),
+        })
+        .transpose()?;
+    if requester_pays.is_none() && request_payer.is_none() {
+        return Ok(());
+    }
+    if !matches!(Scheme::from_str(service), Ok(Scheme::S3)) {
+        return Err(anyhow::anyhow!("requester_pays is only supported by the s3 service"));
+    }
+    if requester_pays == Some(false) && request_payer.is_some() {
+        return Err(anyhow::anyhow!("requester_pays 'false' contradicts request_payer 'requester'"));
+    }
+    if requester_pays == Some(true) || request_payer.is_some() {
+        config.insert("enable_request_payer".to_string(), "true".to_string());
+    }
+    Ok(())
+}
+
+/// Removes the extension-level `pg_opendal_timeout_secs` and `pg_opendal_retry_attempts`
+/// keys from `config`, returning the timeout and retry count they request.
+fn take_resilience_config(
+    config: &mut HashMap<String, String>,
+) -> Result<(Option<std::time::Duration>, Option<usize>)> {
+    let timeout = config
+        .remove("pg_opendal_timeout_secs")
+        .map(|v| {
+            v.parse::<u64>()
+                .ok()
+                .filter(|&secs| secs > 0)
+                .map(std::time::Duration::from_secs)
+                .ok_or_else(|| anyhow::anyhow!("Invalid pg_opendal_timeout_secs '{}'", v))
+        })
+        .transpose()?;
+    let retries = config
+        .remove("pg_opendal_retry_attempts")
+        .map(|v| {
+            v.parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("Invalid pg_opendal_retry_attempts '{}'", v))
+        })
+        .transpose()?;
+    Ok((timeout, retries))
+}
+
+/// Takes the extension-level `pg_opendal_pool_max_idle_per_host` key out of `config`.
+fn take_http_pool_config(config: &mut HashMap<String, String>) -> Result<Option<usize>> {
+    config
+        .remove("pg_opendal_pool_max_idle_per_host")
+        .map(|v| {
+            v.parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("Invalid pg_opendal_pool_max_idle_per_host '{}'", v))
+        })
+        .transpose()
+}
+
+/// Hash algorithms a checksum sidecar can use.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ChecksumAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl ChecksumAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "sha256",
+            ChecksumAlgorithm::Sha512 => "sha512",
+        }
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            "sha512" => Ok(ChecksumAlgorithm::Sha512),
+            other => Err(format!(
+                "Invalid pg_opendal_checksum_sidecar_algorithm '{}', expected one of 'sha256' or 'sha512'",
+                other
+            )),
+        }
+    }
+}
+
+/// A hash computed over content as it streams by, for checksum sidecars and the
+/// functions that compare content.
+enum ChecksumHasher {
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+}
+
+impl ChecksumHasher {
+    fn new(algorithm: ChecksumAlgorithm) -> Self {
+        use sha2::Digest;
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => ChecksumHasher::Sha256(sha2::Sha256::new()),
+            ChecksumAlgorithm::Sha512 => ChecksumHasher::Sha512(sha2::Sha512::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        use sha2::Digest;
+        match self {
+            ChecksumHasher::Sha256(hasher) => hasher.update(data),
+            ChecksumHasher::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finalize_hex(self) -> String {
+        use sha2::Digest;
+        match self {
+            ChecksumHasher::Sha256(hasher) => hex::encode(hasher.finalize()),
+            ChecksumHasher::Sha512(hasher) => hex::encode(hasher.finalize()),
+        }
+    }
+
+    fn digest_hex(algorithm: ChecksumAlgorithm, data: &[u8]) -> String {
+        let mut hasher = ChecksumHasher::new(algorithm);
+        hasher.update(data);
+        hasher.finalize_hex()
+    }
+}
+
+/// A file written next to each written file, holding the hex digest of its content.
+#[derive(Clone, Debug, PartialEq)]
+struct ChecksumSidecar {
+    algorithm: ChecksumAlgorithm,
+    suffix: String,
+}
+
+/// Takes the `pg_opendal_write_checksum_sidecar` (`true`/`false`),
+/// `pg_opendal_checksum_sidecar_algorithm` (default `sha256`) and `pg_opendal_checksum_sidecar_suffix` (default `.` and the algorithm) keys
+/// out of `config`. Returns the sidecar to write, if enabled.
+fn take_checksum_sidecar_config(config: &mut HashMap<String, String>) -> Result<Option<ChecksumSidecar>> {
+    let enabled = config
+        .remove("pg_opendal_write_checksum_sidecar")
+        .map(|v| {
+            v.parse::<bool>()
+                .map_err(|_| anyhow::anyhow!("Invalid pg_opendal_write_checksum_sidecar '{}', expected 'true' or 'false'", v))
+        })
+        .transpose()?
+        .unwrap_or(false);
+    let algorithm = config
+        .remove("pg_opendal_checksum_sidecar_algorithm")
+        .map(|v| v.parse::<ChecksumAlgorithm>().map_err(|e| anyhow::anyhow!(e)))
+        .transpose()?
+        .unwrap_or(ChecksumAlgorithm::Sha256);
+    let suffix = config.remove("pg_opendal_checksum_sidecar_suffix");
+    if suffix.as_deref().is_some_and(|suffix| suffix.is_empty() || suffix.contains('/')) {
+        return Err(anyhow::anyhow!(
+            "Invalid pg_opendal_checksum_sidecar_suffix '{}', expected a non-empty suffix without '/'",
+            suffix.unwrap_or_default()
+        ));
+    }
+    if !enabled {
+        return Ok(None);
+    }
+    let suffix = suffix.unwrap_or_else(|| format!(".{}", algorithm.name()));
+    Ok(Some(ChecksumSidecar { algorithm, suffix }))
+}
+
+/// Writes the checksum sidecar of `path`, after `path` itself was written.
+async fn write_checksum_sidecar_async(
+    op: &Operator,
+    sidecar: &ChecksumSidecar,
+    path: &str,
+    digest: &str,
+) -> Result<(), String> {
+    let sidecar_path = format!("{}{}", path, sidecar.suffix);
+    op.write(&sidecar_path, digest.to_string())
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Wrote '{}', but failed to write its checksum to '{}': {}", path, sidecar_path, e))
+}
+
+/// Takes the `pg_opendal_multipart_threshold` key out of `config`: the size in bytes above which
+/// streamed writes switch from a single PUT to a multipart upload. Streamed writes already
+/// go up in a single PUT when they fit in one `WRITE_CHUNK_SIZE` part, so a smaller
+/// threshold is refused.
+fn take_multipart_threshold_config(config: &mut HashMap<String, String>) -> Result<Option<usize>> {
+    config
+        .remove("pg_opendal_multipart_threshold")
+        .map(|v| {
+            let threshold = v
+                .parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("Invalid pg_opendal_multipart_threshold '{}'", v))?;
+            if threshold < WRITE_CHUNK_SIZE {
+                return Err(anyhow::anyhow!(
+                    "pg_opendal_multipart_threshold {} is below the part size of {} bytes",
+                    threshold,
+                    WRITE_CHUNK_SIZE
+                ));
+            }
+            Ok(threshold)
+        })
+        .transpose()
+}
+
+/// Checks that writes of up to `threshold` bytes fit in the single request `op`'s
+/// service accepts.
+fn check_multipart_threshold(op: &Operator, threshold: usize) -> Result<()> {
+    let cap = op.info().full_capability();
+    let single_max = cap.write_total_max_size.or(cap.write_multi_max_size);
+    match single_max {
+        Some(max) if cap.write_can_multi && threshold > max => Err(anyhow::anyhow!(
+            "pg_opendal_multipart_threshold {} exceeds the {} byte limit of a single write to '{}'",
+            threshold,
+            max,
+            op.info().scheme()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Holds back the parts a writer is given until they add up to more than `threshold`
+/// bytes. A writer closed before then sends everything as one part, which the service
+/// writes in a single request; past it, the held parts are sent on as they came and a
+/// multipart upload starts.
+struct MultipartThresholdLayer {
+    threshold: usize,
+}
+
+impl<A: opendal::raw::Access> opendal::raw::Layer<A> for MultipartThresholdLayer {
+    type LayeredAccess = MultipartThresholdAccessor<A>;
+
+    fn layer(&self, inner: A) -> Self::LayeredAccess {
+        MultipartThresholdAccessor { inner, threshold: self.threshold }
+    }
+}
+
+#[derive(Debug)]
+struct MultipartThresholdAccessor<A> {
+    inner: A,
+    threshold: usize,
+}
+
+impl<A: opendal::raw::Access> opendal::raw::LayeredAccess for MultipartThresholdAccessor<A> {
+    type Inner = A;
+    type Reader = A::Reader;
+    type Writer = MultipartThresholdWriter<A::Writer>;
+    type Lister = A::Lister;
+    type Deleter = A::Deleter;
+    type BlockingReader = A::BlockingReader;
+    type BlockingWriter = A::BlockingWriter;
+    type BlockingLister = A::BlockingLister;
+    type BlockingDeleter = A::BlockingDeleter;
+
+    fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    async fn read(&self, path: &str, args: opendal::raw::OpRead) -> opendal::Result<(opendal::raw::RpRead, A::Reader)> {
+        self.inner.read(path, args).await
+    }
+
+    async fn write(
+        &self,
+        path: &str,
+        args: opendal::raw::OpWrite,
+    ) -> opendal::Result<(opendal::raw::RpWrite, Self::Writer)> {
+        let (rp, writer) = self.inner.write(path, args).await?;
+        let writer = MultipartThresholdWriter { inner: writer, threshold: self.threshold, held: Vec::new(), passed: false };
+        Ok((rp, writer))
+    }
+
+    async fn delete(&self) -> opendal::Result<(opendal::raw::RpDelete, A::Deleter)> {
+        self.inner.delete().await
+    }
+
+    async fn list(&self, path: &str, args: opendal::raw::OpList) -> opendal::Result<(opendal::raw::RpList, A::Lister)> {
+        self.inner.list(path, args).await
+    }
+
+    fn blocking_read(
+        &self,
+        path: &str,
+        args: opendal::raw::OpRead,
+    ) -> opendal::Result<(opendal::raw::RpRead, A::BlockingReader)> {
+        self.inner.blocking_read(path, args)
+    }
+
+    fn blocking_write(
+        &self,
+        path: &str,
+        args: opendal::raw::OpWrite,
+    ) -> opendal::Result<(opendal::raw::RpWrite, A::BlockingWriter)> {
+        self.inner.blocking_write(path, args)
+    }
+
+    fn blocking_delete(&self) -> opendal::Result<(opendal::raw::RpDelete, A::BlockingDeleter)> {
+        self.inner.blocking_delete()
+    }
+
+    fn blocking_list(
+        &self,
+        path: &str,
+        args: opendal::raw::OpList,
+    ) -> opendal::Result<(opendal::raw::RpList, A::BlockingLister)> {
+        self.inner.blocking_list(path, args)
+    }
+}
+
+struct MultipartThresholdWriter<W> {
+    inner: W,
+    threshold: usize,
+    held: Vec<opendal::Buffer>,
+    passed: bool,
+}
+
+impl<W: opendal::raw::oio::Write> opendal::raw::oio::Write for MultipartThresholdWriter<W> {
+    async fn write(&mut self, bs: opendal::Buffer) -> opendal::Result<()> {
+        if self.passed {
+            return self.inner.write(bs).await;
+        }
+        self.held.push(bs);
+        if self.held.iter().map(opendal::Buffer::len).sum::<usize>() > self.threshold {
+            self.passed = true;
+            for bs in std::mem::take(&mut self.held) {
+                self.inner.write(bs).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> opendal::Result<opendal::Metadata> {
+        if !self.held.is_empty() {
+            let bs: opendal::Buffer = std::mem::take(&mut self.held).into_iter().flatten().collect();
+            self.inner.write(bs).await?;
+        }
+        self.inner.close().await
+    }
+
+    async fn abort(&mut self) -> opendal::Result<()> {
+        self.held.clear();
+        self.inner.abort().await
+    }
+}
+
+/// The User-Agent sent to HTTP services when the config doesn't set `pg_opendal_user_agent`.
+const DEFAULT_USER_AGENT: &str = concat!("pg_opendal/", env!("CARGO_PKG_VERSION"));
+
+/// Takes the `pg_opendal_user_agent` key out of `config`, defaulting to `DEFAULT_USER_AGENT`.
+fn take_user_agent_config(config: &mut HashMap<String, String>) -> Result<String> {
+    match config.remove("pg_opendal_user_agent") {
+        None => Ok(DEFAULT_USER_AGENT.to_string()),
+        Some(user_agent) if user_agent.is_empty() || reqwest::header::HeaderValue::from_str(&user_agent).is_err() => {
+            Err(anyhow::anyhow!("Invalid pg_opendal_user_agent '{}'", user_agent))
+        }
+        Some(user_agent) => Ok(user_agent),
+    }
+}
+
+type HttpClientKey = (String, Option<usize>);
+
+/// HTTP clients built so far, by user agent and idle pool size. A `reqwest::Client` is a
+/// handle to a shared connection pool, so reusing one keeps connections (and TLS setup)
+/// across calls instead of starting over for every operator.
+static HTTP_CLIENTS: std::sync::LazyLock<std::sync::Mutex<HashMap<HttpClientKey, reqwest::Client>>> =
+    std::sync::LazyLock::new(Default::default);
+
+/// An HTTP client sending `user_agent`, and keeping at most `max_idle` idle connections
+/// open per host when given.
+fn reqwest_client(user_agent: &str, max_idle: Option<usize>) -> Result<reqwest::Client> {
+    let mut clients = HTTP_CLIENTS.lock().unwrap_or_else(|e| e.into_inner());
+    let key = (user_agent.to_string(), max_idle);
+    if let Some(client) = clients.get(&key) {
+        return Ok(client.clone());
+    }
+    let mut builder = reqwest::Client::builder().user_agent(user_agent);
+    if let Some(max_idle) = max_idle {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    let client = builder
+        .build()
+        .map_err(|e| anyhow::anyhow!("Failed to build HTTP client: {}", e))?;
+    clients.insert(key, client.clone());
+    Ok(client)
+}
+
+/// `reqwest_client` wrapped for use by an operator.
+fn http_client(user_agent: &str, max_idle: Option<usize>) -> Result<opendal::raw::HttpClient> {
+    reqwest_client(user_agent, max_idle).map(opendal::raw::HttpClient::with)
+}
+
+/// Sends the HTTP requests this extension makes itself, outside an operator, with the
+/// same client, timeout and retries an operator built from the config would use.
+struct HttpTransport {
+    client: reqwest::Client,
+    timeout: Option<std::time::Duration>,
+    retries: usize,
+}
+
+impl HttpTransport {
+    fn from_config(config: &HashMap<String, String>) -> Result<Self, String> {
+        let mut config = config.clone();
+        let invalid = |e: anyhow::Error| format!("Failed to parse config: {}", e);
+        let (timeout, retries) = take_resilience_config(&mut config).map_err(invalid)?;
+        let max_idle = take_http_pool_config(&mut config).map_err(invalid)?;
+        let user_agent = take_user_agent_config(&mut config).map_err(invalid)?;
+        let client = reqwest_client(&user_agent, max_idle).map_err(|e| e.to_string())?;
+        Ok(HttpTransport { client, timeout, retries: retries.unwrap_or(0) })
+    }
+
+    /// Waits for `future`, giving up after the configured timeout.
+    async fn within_timeout<T>(&self, future: impl std::future::Future<Output = reqwest::Result<T>>) -> Result<T, String> {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, future)
+                .await
+                .map_err(|_| format!("timed out after {} seconds", timeout.as_secs()))?
+                .map_err(|e| e.to_string()),
+            None => future.await.map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Sends `request` and waits for the response headers. Failed attempts, server errors
+    /// and throttling are retried with exponential backoff, as long as the body can be
+    /// sent again.
+    async fn send(&self, request: reqwest::Request) -> Result<reqwest::Response, String> {
+        let mut request = request;
+        let mut attempt = 0;
+        loop {
+            let retry = request.try_clone().filter(|_| attempt < self.retries);
+            let result = self.within_timeout(self.client.execute(request)).await;
+            let retryable = result.as_ref().map_or(true, |response| {
+                response.status().is_server_error() || response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS
+            });
+            match retry {
+                Some(retry) if retryable => {
+                    tokio::time::sleep(std::time::Duration::from_secs(1 << attempt.min(6))).await;
+                    request = retry;
+                    attempt += 1;
+                }
+                _ => return result,
+            }
+        }
+    }
+}
+
+/// Wraps `op` so each attempt times out after `timeout`, and failed attempts are retried
+/// up to `retries` times. The retry layer goes outermost so every retry gets a fresh timeout.
+fn with_resilience_layers(
+    op: Operator,
+    timeout: Option<std::time::Duration>,
+    retries: Option<usize>,
+    notify: impl opendal::layers::RetryInterceptor,
+) -> Operator {
+    let op = match timeout {
+        Some(timeout) => op.layer(
+            opendal::layers::TimeoutLayer::new()
+                .with_timeout(timeout)
+                .with_io_timeout(timeout),
+        ),
+        None => op,
+    };
+    match retries {
+        Some(retries) => op.layer(
+            opendal::layers::RetryLayer::new()
+                .with_max_times(retries)
+                .with_jitter()
+                .with_notify(notify),
+        ),
+        None => op,
+    }
+}
+
+fn create_operator(service: &str, config: HashMap<String, String>) -> Result<Operator> {
+    let mut config = config;
+    take_provider_config(service, &mut config)?;
+    take_request_payer_config(service, &mut config)?;
+    let (timeout, retries) = take_resilience_config(&mut config)?;
+    let pool_max_idle = take_http_pool_config(&mut config)?;
+    let user_agent = take_user_agent_config(&mut config)?;
+    let multipart_threshold = take_multipart_threshold_config(&mut config)?;
+    // Functions that write sidecars take these keys out first, so an enabled sidecar here
+    // would be silently skipped.
+    if take_checksum_sidecar_config(&mut config)?.is_some() {
+        return Err(anyhow::anyhow!(
+            "pg_opendal_write_checksum_sidecar is only supported by pg_opendal_write, \
+             pg_opendal_write_bytea and pg_opendal_writer_open"
+        ));
+    }
+    let op = build_operator(service, config)?;
+    let client = http_client(&user_agent, pool_max_idle)?;
+    op.update_http_client(|_| client);
+    let op = match multipart_threshold {
+        Some(threshold) => {
+            check_multipart_threshold(&op, threshold)?;
+            op.layer(MultipartThresholdLayer { threshold })
+        }
+        None => op,
+    };
+    Ok(with_resilience_layers(op, timeout, retries, |_: &opendal::Error, _| {}))
+}
+
+fn build_operator(service: &str, config: HashMap<String, String>) -> Result<Operator> {
+    let scheme = Scheme::from_str(service)
+        .map_err(|e| anyhow::anyhow!("Invalid service type '{}': {}", service, e))?;
+
+    let mut config = config;
+    if scheme == Scheme::S3 {
+        if let Some(sse) = take_sse_config(&mut config)? {
+            let builder = opendal::services::S3Config::from_iter(config)?.into_builder();
+            let builder = match sse {
+                SseMode::S3 => builder.server_side_encryption_with_s3_key(),
+                SseMode::Kms(None) => builder.server_side_encryption_with_aws_managed_kms_key(),
+                SseMode::Kms(Some(id)) => {
+                    builder.server_side_encryption_with_customer_managed_kms_key(&id)
+                }
+                SseMode::Customer(key) => {
+                    builder.server_side_encryption_with_customer_key("AES256", key.as_bytes())
+                }
+            };
+            return Ok(Operator::new(builder)?.finish());
+        }
+    }
+    opendal::Operator::via_iter(scheme, config).map_err(|e| anyhow::anyhow!(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_conversion() {
+        let json = serde_json::json!({ "bucket": "my-bucket" });
+        let map = jsonb_to_hashmap(json).unwrap();
+        assert_eq!(map.get("bucket"), Some(&"my-bucket".to_string()));
+    }
+
+    fn memory_operator() -> Operator {
+        create_operator("memory", HashMap::new()).unwrap()
+    }
+
+    #[test]
+    fn test_write_bytea_known_and_unknown_length() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+
+        let written = rt
+            .block_on(do_write_bytea_async(op.clone(), "known", b"hello", Some(5)))
+            .unwrap();
+        assert_eq!(written, 5);
+        let written = rt
+            .block_on(do_write_bytea_async(op.clone(), "unknown", b"world!", None))
+            .unwrap();
+        assert_eq!(written, 6);
+        assert_eq!(rt.block_on(op.read("unknown")).unwrap().to_vec(), b"world!");
+
+        let err = rt
+            .block_on(do_write_bytea_async(op, "mismatch", b"hello", Some(4)))
+            .unwrap_err();
+        assert!(err.contains("Content length mismatch"));
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let ok = serde_json::json!({ "bucket": "my-bucket", "region": "us-east-1" });
+        assert!(validate_config("s3", &ok).is_empty());
+
+        let bad = serde_json::json!({
+            "enable_versioning": "yes",
+            "endpoint": "minio:9000",
+            "access_key_id": "key",
+            "region": 1
+        });
+        let errors = validate_config("s3", &bad);
+        assert_eq!(errors.len(), 5, "{:?}", errors);
+
+        assert!(!validate_config("fs", &serde_json::json!({ "root": "data" })).is_empty());
+        assert!(!validate_config("nope", &serde_json::json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_list_recursive_depth() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        for path in ["data/a.txt", "data/2024/b.txt", "data/2024/01/c.txt"] {
+            rt.block_on(op.write(path, "x")).unwrap();
+        }
+
+        let depths = |max_depth| {
+            let mut found: Vec<(String, i64)> = rt
+                .block_on(do_list_recursive_depth_async(op.clone(), "data/", max_depth, &PathFilter::default()))
+                .unwrap()
+                .into_iter()
+                .map(|e| {
+                    (
+                        e.0["path"].as_str().unwrap().to_string(),
+                        e.0["depth"].as_i64().unwrap(),
+                    )
+                })
+                .collect();
+            found.sort();
+            found
+        };
+
+        assert_eq!(
+            depths(1),
+            vec![("data/2024/".to_string(), 1), ("data/a.txt".to_string(), 1)]
+        );
+        assert_eq!(depths(2).len(), 4);
+        assert!(depths(3).contains(&("data/2024/01/c.txt".to_string(), 3)));
+    }
+
+    #[test]
+    fn test_list_path_filter() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        for path in ["logs/a.csv", "logs/b_tmp.csv", "logs/c.json", "logs/old/d.csv"] {
+            rt.block_on(op.write(path, "x")).unwrap();
+        }
+
+        let list = |filter: PathFilter| {
+            let mut paths: Vec<String> = rt
+                .block_on(do_list_recursive_depth_async(op.clone(), "logs/", 2, &filter))
+                .unwrap()
+                .into_iter()
+                .map(|e| e.0["path"].as_str().unwrap().to_string())
+                .collect();
+            paths.sort();
+            paths
+        };
+
+        assert_eq!(
+            list(PathFilter::new(Some(r"\.csv$"), Some("_tmp")).unwrap()),
+            vec!["logs/a.csv", "logs/old/d.csv"]
+        );
+        assert_eq!(list(PathFilter::new(None, Some("/old/")).unwrap()).len(), 3);
+        assert_eq!(
+            rt.block_on(do_list_async(op.clone(), "logs/", None, &PathFilter::new(Some("json"), None).unwrap()))
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let err = PathFilter::new(Some("("), None).err().unwrap();
+        assert!(err.starts_with("Invalid include_regex '('"), "{}", err);
+        assert!(PathFilter::new(None, Some("[")).is_err());
+    }
+
+    #[test]
+    fn test_trailing_slash_semantics() {
+        let rt = Runtime::new().unwrap();
+        let root = std::env::temp_dir().join(format!("pg_opendal_trailing_slash_{}", std::process::id()));
+        let config = HashMap::from([("root".to_string(), root.to_string_lossy().to_string())]);
+        for op in [memory_operator(), create_operator("fs", config).unwrap()] {
+            // create_dir accepts either form.
+            assert!(rt.block_on(do_create_dir_async(op.clone(), "plain")).unwrap());
+            assert!(rt.block_on(do_create_dir_async(op.clone(), "slashed/")).unwrap());
+            rt.block_on(op.write("plain/a.txt", "a")).unwrap();
+            rt.block_on(op.write("slashed/b.txt", "b")).unwrap();
+
+            // stat and exists find a directory with or without the slash.
+            for path in ["plain", "plain/", "slashed", "slashed/"] {
+                assert!(rt.block_on(stat_either_async(&op, path)).unwrap().is_dir(), "{}", path);
+                assert!(rt.block_on(do_exists_async(op.clone(), path)).unwrap(), "{}", path);
+            }
+            assert!(rt.block_on(stat_either_async(&op, "plain/a.txt")).unwrap().is_file());
+            assert!(!rt.block_on(do_exists_async(op.clone(), "missing")).unwrap());
+            assert!(!rt.block_on(do_exists_async(op.clone(), "missing/")).unwrap());
+
+            // list looks inside a directory either way; a file or unknown name is left alone.
+            for path in ["plain", "plain/"] {
+                assert_eq!(rt.block_on(list_path_async(&op, path)).unwrap(), "plain/");
+                let paths: Vec<String> = rt
+                    .block_on(do_list_async(op.clone(), path, None, &PathFilter::default()))
+                    .unwrap()
+                    .into_iter()
+                    .map(|entry| entry.0["path"].as_str().unwrap().to_string())
+                    .filter(|p| p != "plain/")
+                    .collect();
+                assert_eq!(paths, vec!["plain/a.txt"], "{}", path);
+            }
+            assert_eq!(rt.block_on(list_path_async(&op, "plain/a.txt")).unwrap(), "plain/a.txt");
+            assert_eq!(rt.block_on(list_path_async(&op, "pla")).unwrap(), "pla");
+            assert_eq!(rt.block_on(list_path_async(&op, "")).unwrap(), "");
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_copy_if_newer() {
+        let rt = Runtime::new().unwrap();
+        let root = std::env::temp_dir().join(format!("pg_opendal_copy_if_newer_{}", std::process::id()));
+        let config = HashMap::from([("root".to_string(), root.to_string_lossy().to_string())]);
+        let op = create_operator("fs", config).unwrap();
+        let pause = || std::thread::sleep(std::time::Duration::from_millis(20));
+
+        rt.block_on(op.write("src.txt", "v1")).unwrap();
+        // Missing target: copied.
+        assert!(rt.block_on(do_copy_async(op.clone(), "src.txt", "dst.txt", true)).unwrap());
+
+        pause();
+        rt.block_on(op.write("dst.txt", "newer")).unwrap();
+        // Target newer than source: skipped.
+        assert!(!rt.block_on(do_copy_async(op.clone(), "src.txt", "dst.txt", true)).unwrap());
+        assert_eq!(rt.block_on(op.read("dst.txt")).unwrap().to_vec(), b"newer");
+
+        pause();
+        rt.block_on(op.write("src.txt", "v2")).unwrap();
+        // Source modified again: copied.
+        assert!(rt.block_on(do_copy_async(op.clone(), "src.txt", "dst.txt", true)).unwrap());
+        assert_eq!(rt.block_on(op.read("dst.txt")).unwrap().to_vec(), b"v2");
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_line_stream() {
+        let op = memory_operator();
+        let rt = Runtime::new().unwrap();
+        rt.block_on(op.write("lines.txt", "one\r\ntwo\n\nthree")).unwrap();
+
+        let lines: Vec<Vec<u8>> = LineStream::open(rt, op.clone(), "lines.txt")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(lines, vec![b"one".to_vec(), b"two".to_vec(), vec![], b"three".to_vec()]);
+
+        // A line spanning several chunks, ended by a `\r\n` across the last boundary.
+        let long = "y".repeat(3 * READ_CHUNK_SIZE - 1);
+        let rt = Runtime::new().unwrap();
+        rt.block_on(op.write("long.txt", format!("{}\r\nz", long))).unwrap();
+        let lines: Vec<Vec<u8>> = LineStream::open(rt, op, "long.txt")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(lines, vec![long.into_bytes(), b"z".to_vec()]);
+    }
+
+    #[test]
+    fn test_line_stream_across_objects() {
+        let op = memory_operator();
+        let rt = Runtime::new().unwrap();
+        rt.block_on(op.write("part-00000", "a\nb")).unwrap();
+        rt.block_on(op.write("part-00001", "c\nd\n")).unwrap();
+
+        let paths = vec!["part-00000".to_string(), "part-00001".to_string()];
+        let lines: Vec<Vec<u8>> = LineStream::open_many(rt, op.clone(), paths)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(lines, vec![b"a".to_vec(), b"bc".to_vec(), b"d".to_vec()]);
+
+        let rt = Runtime::new().unwrap();
+        assert!(LineStream::open(rt, op, "missing").is_err());
+    }
+
+    #[test]
+    fn test_record_stream() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        // The second delimiter straddles the first chunk boundary.
+        let first = "x".repeat(READ_CHUNK_SIZE - 6);
+        let content = format!("a|b||{}||c||||||", first);
+        rt.block_on(op.write("table.txt", content)).unwrap();
+
+        let records = |path: &str, delimiter: &str| {
+            let stream = RecordStream::open(Runtime::new().unwrap(), op.clone(), path, delimiter.as_bytes()).unwrap();
+            drop_trailing_empty(stream).map(|r| String::from_utf8(r.unwrap()).unwrap()).collect::<Vec<_>>()
+        };
+        assert_eq!(records("table.txt", "||"), vec!["a|b".to_string(), first.clone(), "c".to_string()]);
+
+        // A record spanning several chunks, ended by a delimiter across the last boundary.
+        let long = "y".repeat(3 * READ_CHUNK_SIZE - 1);
+        rt.block_on(op.write("long.txt", format!("{}||z", long))).unwrap();
+        assert_eq!(records("long.txt", "||"), vec![long, "z".to_string()]);
+
+        rt.block_on(op.write("blanks.txt", "a\n\nb\n\n\n")).unwrap();
+        assert_eq!(records("blanks.txt", "\n"), vec!["a", "", "b"]);
+        rt.block_on(op.write("empty.txt", "")).unwrap();
+        assert!(records("empty.txt", "\n").is_empty());
+        assert!(RecordStream::open(Runtime::new().unwrap(), op.clone(), "table.txt", b"").is_err());
+    }
+
+    #[test]
+    fn test_config_merge() {
+        let base = serde_json::json!({ "bucket": "logs", "region": "us-east-1", "endpoint": "http://minio:9000" });
+        let creds = serde_json::json!({ "access_key_id": "key", "secret_access_key": "secret", "region": "eu-west-1" });
+        let no_endpoint = serde_json::json!({ "endpoint": null });
+        let merged = merge_configs(vec![Some(base), None, Some(creds), Some(no_endpoint)]).unwrap();
+        assert_eq!(
+            merged,
+            serde_json::json!({
+                "bucket": "logs",
+                "region": "eu-west-1",
+                "access_key_id": "key",
+                "secret_access_key": "secret",
+            })
+        );
+        assert_eq!(merge_configs(vec![]).unwrap(), serde_json::json!({}));
+        assert!(merge_configs(vec![Some(serde_json::json!(["bucket"]))]).is_err());
+
+        let config = serde_json::json!({ "bucket": "logs", "pg_opendal_retry_attempts": 3, "root": null });
+        assert_eq!(config_value(&config, "bucket").as_deref(), Some("logs"));
+        assert_eq!(config_value(&config, "pg_opendal_retry_attempts").as_deref(), Some("3"));
+        assert_eq!(config_value(&config, "root"), None);
+        assert_eq!(config_value(&config, "region"), None);
+    }
+
+    #[test]
+    fn test_tree_hash() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        for root in ["left/", "right/"] {
+            rt.block_on(op.write(&format!("{}a.txt", root), "one")).unwrap();
+            rt.block_on(op.write(&format!("{}sub/b.txt", root), "two")).unwrap();
+        }
+        rt.block_on(op.write("changed/a.txt", "one")).unwrap();
+        rt.block_on(op.write("changed/sub/b.txt", "three")).unwrap();
+        rt.block_on(op.write("renamed/a.txt", "one")).unwrap();
+        rt.block_on(op.write("renamed/sub/c.txt", "two")).unwrap();
+
+        let hash = |prefix: &str| rt.block_on(do_tree_hash_async(op.clone(), prefix)).unwrap();
+        assert_eq!(hash("left/"), hash("right"));
+        assert_ne!(hash("left/"), hash("changed/"));
+        assert_ne!(hash("left/"), hash("renamed/"));
+        assert_eq!(hash("missing/"), merkle_root(Vec::new()));
+
+        // Listing order doesn't matter, and an odd leaf is carried up unchanged.
+        let leaves = |names: &[&str]| names.iter().map(|n| (n.to_string(), "size:1".to_string())).collect::<Vec<_>>();
+        assert_eq!(merkle_root(leaves(&["a", "b", "c"])), merkle_root(leaves(&["c", "a", "b"])));
+        assert_ne!(merkle_root(leaves(&["a", "b", "c"])), merkle_root(leaves(&["a", "b"])));
+    }
+
+    #[test]
+    fn test_list_recent() {
+        let rt = Runtime::new().unwrap();
+        let root = std::env::temp_dir().join(format!("pg_opendal_list_recent_{}", std::process::id()));
+        let config = HashMap::from([("root".to_string(), root.to_string_lossy().to_string())]);
+        let op = create_operator("fs", config).unwrap();
+        for name in ["logs/a.log", "logs/2024/b.log", "logs/c.log", "logs/2024/d.log"] {
+            rt.block_on(op.write(name, name)).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+
+        let recent = |limit: usize| -> Vec<String> {
+            rt.block_on(do_list_recent_async(op.clone(), "logs/", limit))
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.0["path"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(recent(2), vec!["logs/2024/d.log", "logs/c.log"]);
+        assert_eq!(recent(10), vec!["logs/2024/d.log", "logs/c.log", "logs/2024/b.log", "logs/a.log"]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_list_older_than() {
+        let rt = Runtime::new().unwrap();
+        let root = std::env::temp_dir().join(format!("pg_opendal_list_older_than_{}", std::process::id()));
+        let config = HashMap::from([("root".to_string(), root.to_string_lossy().to_string())]);
+        let op = create_operator("fs", config).unwrap();
+        for name in ["logs/old.log", "logs/2024/old.log"] {
+            rt.block_on(op.write(name, name)).unwrap();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let cutoff = chrono::Utc::now();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        rt.block_on(op.write("logs/new.log", "new")).unwrap();
+
+        let mut stale: Vec<String> = rt
+            .block_on(do_list_older_than_async(op.clone(), "logs/", cutoff))
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.0["path"].as_str().unwrap().to_string())
+            .collect();
+        stale.sort();
+        assert_eq!(stale, vec!["logs/2024/old.log", "logs/old.log"]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_write_lines_append() {
+        let rt = Runtime::new().unwrap();
+        let lines = |items: &[&str]| items.iter().map(|s| Some(s.to_string())).collect::<Vec<_>>();
+        assert_eq!(join_lines(&lines(&["a", "b"]), "\r\n").unwrap(), b"a\r\nb\r\n");
+        assert_eq!(join_lines(&[], "\n").unwrap(), b"");
+        assert!(join_lines(&lines(&["a"]), ";").is_err());
+        assert!(join_lines(&[Some("a".to_string()), None], "\n").is_err());
+
+        let root = std::env::temp_dir().join(format!("pg_opendal_append_{}", std::process::id()));
+        let fs_config = HashMap::from([("root".to_string(), root.to_string_lossy().to_string())]);
+        // Memory can't append natively, so it takes the read-and-rewrite path.
+        for op in [memory_operator(), create_operator("fs", fs_config).unwrap()] {
+            rt.block_on(do_append_async(op.clone(), "log.txt", b"one\n")).unwrap();
+            rt.block_on(do_append_async(op.clone(), "log.txt", b"two\n")).unwrap();
+            assert_eq!(rt.block_on(op.read("log.txt")).unwrap().to_vec(), b"one\ntwo\n");
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_read_resilient() {
+        use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+        let failures = std::sync::Arc::new(AtomicUsize::new(1));
+        let remaining = failures.clone();
+        let (endpoint, requests) = mock_http_server(move |head| {
+            if head.starts_with("get /b/slow.csv ") {
+                std::thread::sleep(std::time::Duration::from_secs(3));
+                (200, "late".to_string())
+            } else if remaining.fetch_update(SeqCst, SeqCst, |n| n.checked_sub(1)).is_ok() {
+                (503, "<Error><Code>SlowDown</Code></Error>".to_string())
+            } else {
+                (200, "id,total\n1,42\n".to_string())
+            }
+        });
+        // The arguments win over the config-level settings, which would forbid retries.
+        let config = JsonB(serde_json::json!({
+            "bucket": "b",
+            "region": "us-east-1",
+            "endpoint": endpoint,
+            "access_key_id": "access",
+            "secret_access_key": "secret",
+            "pg_opendal_retry_attempts": "0",
+        }));
+        let read = |path: &str, timeout_secs, max_retries| {
+            pg_opendal_read_resilient("s3", path, JsonB(config.0.clone()), timeout_secs, max_retries)
+        };
+
+        assert_eq!(read("data.csv", 30, 3).unwrap(), "id,total\n1,42\n");
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        failures.store(1, SeqCst);
+        assert!(read("data.csv", 30, 0).is_err());
+        assert_eq!(read("data.csv", 30, 0).unwrap(), "id,total\n1,42\n");
+
+        assert_eq!(read("data.csv", 0, 3).unwrap_err(), "Invalid timeout_secs: 0");
+        assert_eq!(read("data.csv", 30, -1).unwrap_err(), "Invalid max_retries: -1");
+
+        let start = std::time::Instant::now();
+        assert!(read("slow.csv", 1, 0).is_err());
+        assert!(start.elapsed() < std::time::Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let rt = Runtime::new().unwrap();
+        let root = std::env::temp_dir().join(format!("pg_opendal_atomic_{}", std::process::id()));
+        let config = HashMap::from([("root".to_string(), root.to_string_lossy().to_string())]);
+        let op = create_operator("fs", config).unwrap();
+
+        rt.block_on(op.write("state.json", "old")).unwrap();
+        assert!(rt.block_on(do_write_atomic_async(op.clone(), "state.json", b"new", ".tmp")).unwrap());
+        assert_eq!(rt.block_on(op.read("state.json")).unwrap().to_vec(), b"new");
+        assert!(!rt.block_on(op.exists("state.json.tmp")).unwrap());
+
+        assert!(rt.block_on(do_write_atomic_async(op.clone(), "nested/state.json", b"a", ".partial")).unwrap());
+        assert_eq!(rt.block_on(op.read("nested/state.json")).unwrap().to_vec(), b"a");
+        assert!(!rt.block_on(op.exists("nested/state.json.partial")).unwrap());
+
+        assert_eq!(
+            rt.block_on(do_write_atomic_async(op.clone(), "state.json", b"x", "")).unwrap_err(),
+            "tmp_suffix must not be empty"
+        );
+        assert_eq!(rt.block_on(op.read("state.json")).unwrap().to_vec(), b"new");
+        std::fs::remove_dir_all(root).unwrap();
+
+        // Memory can't rename, so nothing is written rather than a non-atomic overwrite.
+        let op = memory_operator();
+        let err = rt.block_on(do_write_atomic_async(op.clone(), "state.json", b"new", ".tmp")).unwrap_err();
+        assert_eq!(err, "Service 'memory' does not support rename, which atomic writes require");
+        assert!(!rt.block_on(op.exists("state.json.tmp")).unwrap());
+    }
+
+    type RecordedRequests = std::sync::Arc<std::sync::Mutex<Vec<String>>>;
+
+    /// Starts an HTTP server on localhost answering each request with `respond`, which gets
+    /// the lowercased request line and headers. Returns its base URL and the recorded heads.
+    fn mock_http_server(respond: impl Fn(&str) -> (u16, String) + Send + 'static) -> (String, RecordedRequests) {
+        mock_http_server_with_headers(move |head| {
+            let (status, body) = respond(head);
+            (status, Vec::new(), body)
+        })
+    }
+
+    /// Like `mock_http_server`, with extra response headers from `respond`.
+    fn mock_http_server_with_headers(
+        respond: impl Fn(&str) -> (u16, Vec<(String, String)>, String) + Send + 'static,
+    ) -> (String, RecordedRequests) {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let requests = RecordedRequests::default();
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut head = Vec::new();
+                let mut byte = [0u8];
+                while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+                    head.push(byte[0]);
+                }
+                let head = String::from_utf8_lossy(&head).to_ascii_lowercase();
+                let content_length = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map_or(0, |len| len.trim().parse::<usize>().unwrap());
+                stream.read_exact(&mut vec![0; content_length]).unwrap();
+                let (status, headers, body) = respond(&head);
+                // HEAD responses advertise the body's length without sending it.
+                let sent = if head.starts_with("head ") { "" } else { body.as_str() };
+                let headers: String = headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+                let response = format!(
+                    "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nETag: \"mock\"\r\n{}Connection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    headers,
+                    sent
+                );
+                recorded.lock().unwrap().push(head);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (endpoint, requests)
+    }
+
+    #[test]
+    fn test_ingest_url() {
+        let (endpoint, requests) = mock_http_server_with_headers(|head| {
+            if head.starts_with("get /data/report%20q1.csv ") || head.starts_with("get /signed.csv?x-amz-signature=abc ") {
+                (200, Vec::new(), "id,total\n1,42\n".to_string())
+            } else if head.starts_with("get /moved.csv ") {
+                (302, vec![("Location".to_string(), "/data/report%20q1.csv".to_string())], String::new())
+            } else {
+                (404, Vec::new(), "missing".to_string())
+            }
+        });
+        let op = memory_operator();
+        let rt = Runtime::new().unwrap();
+
+        let url = format!("{}/data/report%20q1.csv", endpoint);
+        assert_eq!(rt.block_on(do_ingest_url_async(&url, &op, "raw/q1.csv")).unwrap(), 14);
+        assert_eq!(rt.block_on(op.read("raw/q1.csv")).unwrap().to_vec(), b"id,total\n1,42\n");
+        assert!(requests.lock().unwrap()[0].contains(&format!("user-agent: {}", DEFAULT_USER_AGENT)));
+
+        let url = format!("{}/data/gone.csv", endpoint);
+        assert_eq!(
+            rt.block_on(do_ingest_url_async(&url, &op, "raw/gone.csv")).unwrap_err(),
+            format!("Failed to download '{}': HTTP 404 Not Found", url)
+        );
+        assert!(!rt.block_on(op.exists("raw/gone.csv")).unwrap());
+
+        // Query strings are kept, as presigned URLs need them, and redirects are followed.
+        let url = format!("{}/signed.csv?X-Amz-Signature=abc", endpoint);
+        assert_eq!(rt.block_on(do_ingest_url_async(&url, &op, "raw/signed.csv")).unwrap(), 14);
+        let url = format!("{}/moved.csv", endpoint);
+        assert_eq!(rt.block_on(do_ingest_url_async(&url, &op, "raw/moved.csv")).unwrap(), 14);
+        assert_eq!(rt.block_on(op.read("raw/moved.csv")).unwrap().to_vec(), b"id,total\n1,42\n");
+
+        for url in ["ftp://example.com/a.csv", "https://example.com/data/"] {
+            assert!(rt.block_on(do_ingest_url_async(url, &op, "raw/x.csv")).unwrap_err().starts_with("Invalid URL"));
+        }
+    }
+
+    #[test]
+    fn test_multipart_threshold() {
+        let (endpoint, requests) = mock_http_server(|head| {
+            if head.starts_with("post ") && head.contains("?uploads ") {
+                (200, "<InitiateMultipartUploadResult><Bucket>b</Bucket><Key>big.bin</Key><UploadId>u1</UploadId></InitiateMultipartUploadResult>".to_string())
+            } else if head.starts_with("post ") {
+                (200, "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>".to_string())
+            } else {
+                (200, String::new())
+            }
+        });
+        let threshold = WRITE_CHUNK_SIZE + 4 * 1024 * 1024;
+        let config = |threshold: usize| {
+            HashMap::from([
+                ("bucket".to_string(), "b".to_string()),
+                ("region".to_string(), "us-east-1".to_string()),
+                ("endpoint".to_string(), endpoint.clone()),
+                ("access_key_id".to_string(), "access".to_string()),
+                ("secret_access_key".to_string(), "secret".to_string()),
+                ("pg_opendal_multipart_threshold".to_string(), threshold.to_string()),
+            ])
+        };
+        let op = create_operator("s3", config(threshold)).unwrap();
+        let rt = Runtime::new().unwrap();
+        let upload = |size: usize| {
+            requests.lock().unwrap().clear();
+            rt.block_on(async {
+                let mut writer = open_writer_async(&op, "big.bin").await.unwrap();
+                for chunk in vec![0u8; size].chunks(1024 * 1024) {
+                    writer.write(chunk.to_vec()).await.unwrap();
+                }
+                writer.close().await.unwrap();
+            });
+            let requests = requests.lock().unwrap();
+            requests.iter().map(|head| head.split(' ').next().unwrap().to_string()).collect::<Vec<_>>()
+        };
+
+        // The threshold doesn't change the part size, and nothing about the operator's
+        // reported capability.
+        assert_eq!(upload(threshold), ["put"]);
+        assert_eq!(upload(threshold + 1), ["post", "put", "put", "post"]);
+        assert_eq!(op.info().full_capability().write_multi_min_size, Some(5 * 1024 * 1024));
+
+        // Thresholds that can't be honored are refused rather than adjusted.
+        assert!(create_operator("s3", config(WRITE_CHUNK_SIZE - 1)).is_err());
+        assert!(create_operator("s3", config(6 * 1024 * 1024 * 1024)).is_err());
+        let mut memory = HashMap::from([("pg_opendal_multipart_threshold".to_string(), (6usize << 30).to_string())]);
+        assert!(create_operator("memory", memory.clone()).is_ok());
+        memory.insert("pg_opendal_multipart_threshold".to_string(), "0".to_string());
+        assert!(take_multipart_threshold_config(&mut memory).is_err());
+    }
+
+    #[test]
+    fn test_write_idempotent() {
+        let raced = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let racer = raced.clone();
+        let (endpoint, requests) = mock_http_server_with_headers(move |head| {
+            use std::sync::atomic::Ordering::SeqCst;
+
+            let meta = ("x-amz-meta-pg-opendal-idempotency-key".to_string(), "job-42".to_string());
+            if head.starts_with("head /b/done.txt ") || (head.starts_with("head /b/raced.txt ") && racer.load(SeqCst)) {
+                (200, vec![meta], "done".to_string())
+            } else if head.starts_with("put /b/raced.txt ") {
+                // Another session with the same key created the file after our check.
+                racer.store(true, SeqCst);
+                (412, Vec::new(), "<Error><Code>PreconditionFailed</Code></Error>".to_string())
+            } else if head.starts_with("head ") {
+                (404, Vec::new(), String::new())
+            } else {
+                (200, Vec::new(), String::new())
+            }
+        });
+        let op = create_operator(
+            "s3",
+            HashMap::from([
+                ("bucket".to_string(), "b".to_string()),
+                ("region".to_string(), "us-east-1".to_string()),
+                ("endpoint".to_string(), endpoint),
+                ("access_key_id".to_string(), "access".to_string()),
+                ("secret_access_key".to_string(), "secret".to_string()),
+            ]),
+        )
+        .unwrap();
+        assert!(supports_idempotency_key(&op));
+        let rt = Runtime::new().unwrap();
+        let write = |path, key| {
+            requests.lock().unwrap().clear();
+            let written = rt.block_on(do_write_idempotent_async(&op, path, b"done", key)).unwrap();
+            (written, requests.lock().unwrap().clone())
+        };
+
+        let (written, heads) = write("done.txt", "job-42");
+        assert!(!written);
+        assert_eq!(heads.len(), 1);
+
+        // Writes are conditional on the file being as checked: still absent, or unchanged.
+        for (path, key, condition) in [("done.txt", "job-43", "if-match: \"mock\""), ("new.txt", "job-42", "if-none-match: *")] {
+            let (written, heads) = write(path, key);
+            assert!(written);
+            assert_eq!(heads.len(), 2);
+            assert!(heads[1].starts_with("put "));
+            assert!(heads[1].contains(&format!("x-amz-meta-pg-opendal-idempotency-key: {}", key)));
+            assert!(heads[1].contains(condition), "{}", heads[1]);
+        }
+
+        // Losing the race to a write with the same key counts as already written.
+        let (written, heads) = write("raced.txt", "job-42");
+        assert!(!written);
+        assert_eq!(heads.len(), 3);
+        assert!(raced.load(std::sync::atomic::Ordering::SeqCst));
+
+        assert!(!supports_idempotency_key(&memory_operator()));
+    }
+
+    #[test]
+    fn test_requester_pays() {
+        let (endpoint, requests) = mock_http_server(|_| (200, "hello".to_string()));
+
+        let config = |extra: &[(&str, &str)]| {
+            let mut config = HashMap::from([
+                ("bucket".to_string(), "public-data".to_string()),
+                ("region".to_string(), "us-east-1".to_string()),
+                ("endpoint".to_string(), endpoint.clone()),
+                ("access_key_id".to_string(), "access".to_string()),
+                ("secret_access_key".to_string(), "secret".to_string()),
+            ]);
+            config.extend(extra.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+            config
+        };
+        let rt = Runtime::new().unwrap();
+        for (extra, expected) in [
+            (&[("requester_pays", "true")][..], true),
+            (&[("request_payer", "requester")][..], true),
+            (&[("requester_pays", "false")][..], false),
+        ] {
+            requests.lock().unwrap().clear();
+            let op = create_operator("s3", config(extra)).unwrap();
+            assert_eq!(rt.block_on(op.read("dataset.csv")).unwrap().to_vec(), b"hello");
+            let requests = requests.lock().unwrap();
+            assert!(!requests.is_empty());
+            assert!(requests.iter().all(|head| head.contains("x-amz-request-payer: requester") == expected));
+        }
+
+        let request = s3_signed_request(
+            &config(&[("enable_request_payer", "true")]),
+            "Testing",
+            reqwest::Method::GET,
+            "",
+            &[],
+            Vec::new(),
+            chrono::Utc::now(),
+        )
+        .unwrap();
+        assert_eq!(request.headers()["x-amz-request-payer"], "requester");
+        assert!(request.headers()["authorization"].to_str().unwrap().contains("x-amz-request-payer"));
+
+        assert!(create_operator("s3", config(&[("requester_pays", "yes")])).is_err());
+        assert!(create_operator("s3", config(&[("requester_pays", "false"), ("request_payer", "requester")])).is_err());
+        assert!(create_operator("fs", HashMap::from([("requester_pays".to_string(), "true".to_string())])).is_err());
+    }
+
+    #[test]
+    fn test_provider_presets() {
+        let expand = |service: &str, pairs: &[(&str, &str)]| {
+            let mut config: HashMap<String, String> =
+                pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            take_provider_config(service, &mut config).map(|_| {
+                (config.get("endpoint").cloned(), config.get("region").cloned(), config.len())
+            })
+        };
+
+        let (endpoint, region, len) =
+            expand("s3", &[("provider", "r2"), ("account_id", "abc123"), ("bucket", "b")]).unwrap();
+        assert_eq!(endpoint.as_deref(), Some("https://abc123.r2.cloudflarestorage.com"));
+        assert_eq!(region.as_deref(), Some("auto"));
+        assert_eq!(len, 3);
+
+        let (endpoint, region, _) = expand("s3", &[("provider", "b2"), ("region", "us-west-004")]).unwrap();
+        assert_eq!(endpoint.as_deref(), Some("https://s3.us-west-004.backblazeb2.com"));
+        assert_eq!(region.as_deref(), Some("us-west-004"));
+
+        // Explicit settings win over the preset.
+        let (endpoint, region, _) = expand(
+            "s3",
+            &[("provider", "wasabi"), ("endpoint", "http://localhost:9000"), ("region", "eu-central-1")],
+        )
+        .unwrap();
+        assert_eq!(endpoint.as_deref(), Some("http://localhost:9000"));
+        assert_eq!(region.as_deref(), Some("eu-central-1"));
+
+        assert!(expand("s3", &[("provider", "r2")]).is_err());
+        assert!(expand("s3", &[("provider", "b2")]).is_err());
+        assert!(expand("s3", &[("provider", "nope")]).is_err());
+        assert!(expand("fs", &[("provider", "r2"), ("account_id", "abc123")]).is_err());
+        assert!(expand("s3", &[("account_id", "abc123")]).is_err());
+        assert_eq!(expand("s3", &[("bucket", "b")]).unwrap(), (None, None, 1));
+    }
+
+    #[test]
+    fn test_bytea_compression() {
+        let data = "hello compression ".repeat(100).into_bytes();
+
+        let gzipped = gzip_compress(&data, 6).unwrap();
+        assert!(gzipped.len() < data.len());
+        assert_eq!(gzip_decompress(&gzipped).unwrap(), data);
+        let mut two_members = gzip_compress(b"ab", 0).unwrap();
+        two_members.extend(gzip_compress(b"cd", 9).unwrap());
+        assert_eq!(gzip_decompress(&two_members).unwrap(), b"abcd");
+        assert!(gzip_compress(&data, 10).is_err());
+        assert!(gzip_decompress(b"not gzip").is_err());
+
+        let zstded = zstd_compress(&data, 3).unwrap();
+        assert!(zstded.len() < data.len());
+        assert_eq!(zstd_decompress(&zstded).unwrap(), data);
+        assert_eq!(zstd_decompress(&zstd_compress(b"", 19).unwrap()).unwrap(), b"");
+        assert!(zstd_compress(&data, 100).is_err());
+        assert!(zstd_decompress(&gzipped).is_err());
+    }
+
+    #[test]
+    fn test_compression_detect() {
+        let data = "hello compression ".repeat(100).into_bytes();
+        for algorithm in [Codec::Gzip, Codec::Zstd, Codec::Lz4, Codec::Brotli] {
+            let compressed = algorithm.compress(&data).unwrap();
+            assert!(compressed.len() < data.len(), "{:?}", algorithm);
+            let path = format!("out{}", algorithm.extension());
+            assert_eq!(Codec::detect(&compressed, &path), algorithm);
+            assert_eq!(algorithm.decompress(&compressed).unwrap(), data, "{:?}", algorithm);
+        }
+
+        // Brotli has no magic number, so it is only recognized by extension.
+        let brotli = Codec::Brotli.compress(&data).unwrap();
+        assert_eq!(Codec::detect(&brotli, "out.bin"), Codec::None);
+        assert_eq!(Codec::detect(&data, "out.txt"), Codec::None);
+        assert!("ZSTD".parse::<Codec>().is_ok());
+        assert!("snappy".parse::<Codec>().is_err());
+    }
+
+    #[test]
+    fn test_conditional_fetch() {
+        assert!(etag_matches("\"a\", W/\"b\"", "\"b\""));
+        assert!(etag_matches("*", "\"c\""));
+        assert!(!etag_matches("\"a\"", "\"b\""));
+
+        let rt = Runtime::new().unwrap();
+        let root = std::env::temp_dir().join(format!("pg_opendal_conditional_{}", std::process::id()));
+        let config = HashMap::from([("root".to_string(), root.to_string_lossy().to_string())]);
+        let op = create_operator("fs", config).unwrap();
+        rt.block_on(op.write("page.html", "<p>hi</p>")).unwrap();
+
+        let fetch = |etag, since| rt.block_on(do_conditional_fetch_async(&op, "page.html", etag, since)).unwrap();
+        let fresh = fetch(None, None);
+        assert_eq!(fresh["status"], 200);
+        assert_eq!(fresh["content"], "PHA+aGk8L3A+");
+        let last_modified: chrono::DateTime<chrono::Utc> =
+            fresh["last_modified"].as_str().unwrap().parse().unwrap();
+
+        assert_eq!(fetch(None, Some(last_modified))["status"], 304);
+        assert_eq!(fetch(None, Some(last_modified - chrono::Duration::seconds(1)))["status"], 200);
+        if let Some(etag) = fresh["etag"].as_str() {
+            assert_eq!(fetch(Some(etag), None)["status"], 304);
+            // If-None-Match takes precedence over If-Modified-Since.
+            assert_eq!(fetch(Some("\"other\""), Some(last_modified))["status"], 200);
+        }
+        assert!(rt.block_on(do_conditional_fetch_async(&op, "missing.html", None, None)).is_err());
+        std::fs::remove_dir_all(root).unwrap();
+
+        // S3 evaluates the validators on the GET itself and answers 304 without a body.
+        let (endpoint, requests) = mock_http_server(|head| {
+            if head.starts_with("get ") && head.contains("if-none-match: \"mock\"") {
+                (304, String::new())
+            } else {
+                (200, "<p>hi</p>".to_string())
+            }
+        });
+        let config = HashMap::from([
+            ("bucket".to_string(), "site".to_string()),
+            ("region".to_string(), "us-east-1".to_string()),
+            ("endpoint".to_string(), endpoint),
+            ("access_key_id".to_string(), "access".to_string()),
+            ("secret_access_key".to_string(), "secret".to_string()),
+        ]);
+        let op = create_operator("s3", config).unwrap();
+        let fetch = |etag| rt.block_on(do_conditional_fetch_async(&op, "page.html", etag, None)).unwrap();
+        assert_eq!(fetch(Some("\"mock\"")), serde_json::json!({ "status": 304 }));
+        {
+            let heads = requests.lock().unwrap();
+            assert_eq!(heads.len(), 1);
+            assert!(heads[0].starts_with("get /site/page.html "));
+        }
+        let fresh = fetch(Some("\"old\""));
+        assert_eq!(fresh["status"], 200);
+        assert_eq!(fresh["etag"], "\"mock\"");
+        assert_eq!(fresh["content"], "PHA+aGk8L3A+");
+        assert!(requests.lock().unwrap()[1].contains("if-none-match: \"old\""));
+    }
+
+    #[test]
+    fn test_list_by_content_type() {
+        assert!(content_type_matches("image/*", "image/png"));
+        assert!(content_type_matches("text/csv", "Text/CSV; charset=utf-8"));
+        assert!(content_type_matches("*/*", "application/json"));
+        assert!(!content_type_matches("image/*", "application/octet-stream"));
+        assert!(!content_type_matches("image/png", "image/jpeg"));
+
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        for (path, content_type) in [
+            ("media/a.png", Some("image/png")),
+            ("media/sub/b.jpg", Some("image/jpeg")),
+            ("media/c.pdf", Some("application/pdf")),
+            ("media/d.bin", None),
+        ] {
+            match content_type {
+                Some(content_type) => rt.block_on(async { op.write_with(path, "x").content_type(content_type).await }).unwrap(),
+                None => rt.block_on(op.write(path, "x")).unwrap(),
+            };
+        }
+
+        let mut images: Vec<(String, String)> = rt
+            .block_on(do_list_by_content_type_async(op.clone(), "media/", "image/*"))
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.0["path"].as_str().unwrap().to_string(), e.0["content_type"].as_str().unwrap().to_string()))
+            .collect();
+        images.sort();
+        assert_eq!(
+            images,
+            vec![
+                ("media/a.png".to_string(), "image/png".to_string()),
+                ("media/sub/b.jpg".to_string(), "image/jpeg".to_string()),
+            ]
+        );
+        assert_eq!(rt.block_on(do_list_by_content_type_async(op, "media/", "*/*")).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_run_transfer_job() {
+        let rt = Runtime::new().unwrap();
+        let root = std::env::temp_dir().join(format!("pg_opendal_transfer_job_{}", std::process::id()));
+        let config = HashMap::from([("root".to_string(), root.to_string_lossy().to_string())]);
+        let op = create_operator("fs", config.clone()).unwrap();
+        for path in ["src/a.csv", "src/nested/b.csv", "srcfile.txt"] {
+            rt.block_on(op.write(path, "data")).unwrap();
+        }
+
+        let job = TransferJob {
+            src_service: "fs".to_string(),
+            src_path: "src".to_string(),
+            src_config: config.clone(),
+            dst_service: "fs".to_string(),
+            dst_path: "dst/".to_string(),
+            dst_config: config,
+            concurrency: 4,
+        };
+        // Jobs reach the worker through their jsonb params, which leave out the configs.
+        let params = serde_json::to_value(&job).unwrap();
+        assert!(params.get("src_config").is_none() && params.get("dst_config").is_none());
+        let job = TransferJob {
+            src_config: job.src_config.clone(),
+            dst_config: job.dst_config.clone(),
+            ..serde_json::from_value(params).unwrap()
+        };
+
+        let mut reports = Vec::new();
+        let result = run_transfer_job(&job, |done, total| reports.push((done, total))).unwrap();
+        assert_eq!(result["files_copied"], 2);
+        assert_eq!(result["bytes_copied"], 8);
+        assert_eq!(result["failed"], serde_json::json!([]));
+        assert_eq!(reports.last(), Some(&(2, 2)));
+        assert_eq!(rt.block_on(op.read("dst/nested/b.csv")).unwrap().to_vec(), b"data");
+        assert!(!rt.block_on(op.exists("dst/file.txt")).unwrap());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_generate_inventory() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        rt.block_on(async { op.write_with("bucket/a.json", "{}").content_type("application/json").await })
+            .unwrap();
+        for i in 0..INVENTORY_BATCH_ROWS {
+            rt.block_on(op.write(&format!("bucket/logs/{:04}.log", i), "line\n")).unwrap();
+        }
+
+        let mut batches = Vec::new();
+        let total = generate_inventory(&rt, &op, "bucket", 8, |rows| {
+            batches.push(rows);
+            Ok(())
+        })
+        .unwrap();
+        let rows: Vec<&Value> = batches.iter().flatten().collect();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(total as usize, rows.len());
+
+        let row = |path: &str| *rows.iter().find(|row| row["path"] == path).unwrap();
+        assert_eq!(row("bucket/a.json")["content_type"], "application/json");
+        assert_eq!(row("bucket/a.json")["is_file"], true);
+        assert_eq!(row("bucket/logs/0007.log")["content_length"], 5);
+        assert!(rows.iter().all(|row| row["path"] != "bucket/"));
+
+        let failed = generate_inventory(&rt, &op, "bucket/", 8, |_| Err("table is gone".to_string()));
+        assert_eq!(failed.unwrap_err(), "table is gone");
+    }
+
+    #[test]
+    fn test_read_csv_jsonb() {
+        let rows = |input: &str, options: Value| -> Result<Vec<Result<Value, String>>, String> {
+            let options = parse_csv_options(Some(options))?;
+            CsvRows::new(input.as_bytes(), options)?.collect()
+        };
+
+        let parsed = rows("id,name,active,score\n1,\"Smith, J\",true,\n007,x,no,2.5\n", Value::Null).unwrap();
+        assert_eq!(parsed[0], Ok(serde_json::json!({ "id": 1, "name": "Smith, J", "active": true, "score": null })));
+        // Inference leaves anything that isn't JSON syntax as a string.
+        assert_eq!(parsed[1], Ok(serde_json::json!({ "id": "007", "name": "x", "active": "no", "score": 2.5 })));
+
+        let options = serde_json::json!({
+            "columns": ["id", "flag"],
+            "delimiter": ";",
+            "null": "NA",
+            "types": { "id": "text", "flag": "boolean" },
+        });
+        let parsed = rows("1;yes\n2;NA\n3\n4;maybe\n", options).unwrap();
+        assert_eq!(parsed[0], Ok(serde_json::json!({ "id": "1", "flag": true })));
+        assert_eq!(parsed[1], Ok(serde_json::json!({ "id": "2", "flag": null })));
+        assert_eq!(parsed[2], Err("Line 3: found 1 fields, expected 2".to_string()));
+        assert_eq!(parsed[3], Err("Line 4, column 'flag': 'maybe' is not a boolean".to_string()));
+
+        let options = serde_json::json!({ "columns": ["n"], "header": true, "types": { "n": "integer" } });
+        assert_eq!(rows("n\n42\n", options).unwrap(), vec![Ok(serde_json::json!({ "n": 42 }))]);
+
+        assert!(rows("a,a\n", Value::Null).is_err());
+        assert!(rows("", Value::Null).is_err());
+        assert!(rows("a\n", serde_json::json!({ "types": { "b": "text" } })).is_err());
+        assert!(rows("a\n", serde_json::json!({ "types": { "a": "date" } })).is_err());
+        assert!(rows("a\n", serde_json::json!({ "delimiter": ",," })).is_err());
+        assert!(rows("a\n", serde_json::json!({ "quote": "'" })).is_err());
+    }
+
+    /// Encodes an event stream message with string headers, as S3 Select sends them.
+    fn event_message(headers: &[(&str, &str)], payload: &[u8]) -> Vec<u8> {
+        let mut encoded_headers = Vec::new();
+        for (name, value) in headers {
+            encoded_headers.push(name.len() as u8);
+            encoded_headers.extend_from_slice(name.as_bytes());
+            encoded_headers.push(7);
+            encoded_headers.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            encoded_headers.extend_from_slice(value.as_bytes());
+        }
+        let total_len = 16 + encoded_headers.len() + payload.len();
+        let mut message = Vec::new();
+        message.extend_from_slice(&(total_len as u32).to_be_bytes());
+        message.extend_from_slice(&(encoded_headers.len() as u32).to_be_bytes());
+        message.extend_from_slice(&crc32fast::hash(&message).to_be_bytes());
+        message.extend_from_slice(&encoded_headers);
+        message.extend_from_slice(payload);
+        message.extend_from_slice(&crc32fast::hash(&message).to_be_bytes());
+        message
+    }
+
+    #[test]
+    fn test_s3_select_event_stream() {
+        let records = event_message(&[(":message-type", "event"), (":event-type", "Records")], b"1,a\n");
+        let (message, len) = decode_event_message(&records).unwrap().unwrap();
+        assert_eq!(len, records.len());
+        assert_eq!(message.headers[":event-type"], "Records");
+        assert_eq!(message.payload, b"1,a\n");
+
+        // A partial message waits for more data; a corrupted one is an error.
+        assert!(decode_event_message(&records[..records.len() - 1]).unwrap().is_none());
+        let mut corrupt = records.clone();
+        corrupt[20] ^= 1;
+        assert!(decode_event_message(&corrupt).is_err());
+
+        assert_eq!(sigv4_encode_key("dir/a b+c(1).csv"), "dir/a%20b%2Bc%281%29.csv");
+        assert_eq!(
+            s3_select_input("CSV", "logs/day.csv.gz").unwrap(),
+            "<CompressionType>GZIP</CompressionType><CSV/>"
+        );
+        assert_eq!(s3_select_input("parquet", "t.parquet").unwrap(), "<Parquet/>");
+        assert!(s3_select_input("xml", "t.xml").is_err());
+
+        let config = HashMap::from([
+            ("bucket".to_string(), "b".to_string()),
+            ("region".to_string(), "us-east-1".to_string()),
+            ("root".to_string(), "/data".to_string()),
+            ("access_key_id".to_string(), "AKID".to_string()),
+            ("secret_access_key".to_string(), "secret".to_string()),
+        ]);
+        let request =
+            s3_select_request(&config, "a b.csv", "SELECT * FROM S3Object s WHERE s._1 < 'x'", "csv", chrono::Utc::now())
+                .unwrap();
+        assert_eq!(request.url().as_str(), "https://s3.us-east-1.amazonaws.com/b/data/a%20b.csv?select=&select-type=2");
+        assert!(request.headers()["authorization"].to_str().unwrap().starts_with("AWS4-HMAC-SHA256 Credential=AKID/"));
+        let body = std::str::from_utf8(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert!(body.contains("<Expression>SELECT * FROM S3Object s WHERE s._1 &lt; &apos;x&apos;</Expression>"));
+    }
+
+    #[test]
+    fn test_tier() {
+        assert_eq!(postgres_micros_to_utc(0).to_rfc3339(), "2000-01-01T00:00:00+00:00");
+        assert_eq!(postgres_micros_to_utc(i64::MAX), chrono::DateTime::<chrono::Utc>::MAX_UTC);
+        assert_eq!(postgres_micros_to_utc(i64::MIN), chrono::DateTime::<chrono::Utc>::MIN_UTC);
+
+        let rt = Runtime::new().unwrap();
+        let root = std::env::temp_dir().join(format!("pg_opendal_tier_{}", std::process::id()));
+        let config = HashMap::from([("root".to_string(), root.to_string_lossy().to_string())]);
+        let op = create_operator("fs", config).unwrap();
+        for name in ["hot/a.log", "hot/2024/b.log"] {
+            rt.block_on(op.write(name, name)).unwrap();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let cutoff = chrono::Utc::now();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        rt.block_on(op.write("hot/c.log", "c")).unwrap();
+
+        assert_eq!(tier(&rt, &op, "hot", "cold/", cutoff, 2).unwrap(), 2);
+        assert_eq!(rt.block_on(op.read("cold/2024/b.log")).unwrap().to_vec(), b"hot/2024/b.log");
+        assert!(!rt.block_on(op.exists("hot/a.log")).unwrap());
+        assert!(rt.block_on(op.exists("hot/c.log")).unwrap());
+        assert_eq!(tier(&rt, &op, "hot/", "cold/", cutoff, 2).unwrap(), 0);
+        assert!(tier(&rt, &op, "hot/", "hot/old/", cutoff, 2).is_err());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_multipart_listing_responses() {
+        let uploads: ListMultipartUploadsResult = parse_s3_xml(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <ListMultipartUploadsResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+                <Bucket>b</Bucket>
+                <NextKeyMarker>data/b&amp;c.bin</NextKeyMarker>
+                <NextUploadIdMarker>u2</NextUploadIdMarker>
+                <IsTruncated>true</IsTruncated>
+                <Upload><Key>data/a.bin</Key><UploadId>u1</UploadId><Initiated>2024-06-01T10:00:00.000Z</Initiated></Upload>
+                <Upload><Key>data/b&amp;c.bin</Key><UploadId>u2</UploadId><Initiated>2024-06-02T10:00:00.000Z</Initiated></Upload>
+            </ListMultipartUploadsResult>"#,
+            "list",
+        )
+        .unwrap();
+        assert!(uploads.is_truncated);
+        assert_eq!(uploads.next_key_marker.as_deref(), Some("data/b&c.bin"));
+        assert_eq!(uploads.uploads.len(), 2);
+        assert_eq!(uploads.uploads[1].upload_id, "u2");
+
+        let empty: ListMultipartUploadsResult =
+            parse_s3_xml("<ListMultipartUploadsResult><IsTruncated>false</IsTruncated></ListMultipartUploadsResult>", "list")
+                .unwrap();
+        assert!(empty.uploads.is_empty());
+
+        let parts: ListPartsResult = parse_s3_xml(
+            "<ListPartsResult><IsTruncated>false</IsTruncated>\
+             <Part><PartNumber>1</PartNumber><Size>5242880</Size></Part>\
+             <Part><PartNumber>2</PartNumber><Size>100</Size></Part></ListPartsResult>",
+            "parts",
+        )
+        .unwrap();
+        assert_eq!(parts.parts.iter().map(|part| part.size).sum::<u64>(), 5242980);
+
+        assert_eq!(sigv4_encode_query("data/a b"), "data%2Fa%20b");
+    }
+
+    #[test]
+    fn test_bucket_policy() {
+        let config = HashMap::from([
+            ("bucket".to_string(), "b".to_string()),
+            ("region".to_string(), "us-east-1".to_string()),
+            ("root".to_string(), "/data".to_string()),
+            ("access_key_id".to_string(), "AKID".to_string()),
+            ("secret_access_key".to_string(), "secret".to_string()),
+        ]);
+        let request =
+            s3_bucket_policy_request(&config, reqwest::Method::PUT, b"{}".to_vec(), chrono::Utc::now()).unwrap();
+        assert_eq!(request.method(), reqwest::Method::PUT);
+        assert_eq!(request.url().as_str(), "https://s3.us-east-1.amazonaws.com/b/?policy=");
+
+        let policy = r#"{"Version":"2012-10-17","Statement":[{"Effect":"Allow","Principal":"*","Action":"s3:GetObject"}]}"#;
+        let parsed = parse_bucket_policy_response(reqwest::StatusCode::OK, policy).unwrap().unwrap();
+        assert_eq!(parsed["Statement"][0]["Action"], "s3:GetObject");
+
+        let no_policy = "<Error><Code>NoSuchBucketPolicy</Code><Message>The bucket policy does not exist</Message></Error>";
+        assert_eq!(parse_bucket_policy_response(reqwest::StatusCode::NOT_FOUND, no_policy).unwrap(), None);
+        let no_bucket = "<Error><Code>NoSuchBucket</Code><Message>The specified bucket does not exist</Message></Error>";
+        let err = parse_bucket_policy_response(reqwest::StatusCode::NOT_FOUND, no_bucket).unwrap_err();
+        assert!(err.contains("The specified bucket does not exist"));
+
+        let rt = Runtime::new().unwrap();
+        assert!(rt.block_on(do_set_bucket_policy_async(&config, &serde_json::json!(["not", "a", "policy"]))).is_err());
+        assert!(s3_direct_config("fs", JsonB(serde_json::json!({})), "bucket policies").is_err());
+    }
+
+    #[test]
+    fn test_list_buckets() {
+        let (endpoint, requests) = mock_http_server(|head| {
+            let page = if head.contains("continuation-token=next") {
+                "<Bucket><Name>logs</Name><CreationDate>2024-02-01T00:00:00.000Z</CreationDate></Bucket>"
+            } else {
+                "<Bucket><Name>data</Name><CreationDate>2024-01-01T12:00:00.000Z</CreationDate>\
+                 <BucketRegion>eu-west-1</BucketRegion></Bucket>"
+            };
+            let token = if head.contains("continuation-token") { "" } else { "<ContinuationToken>next</ContinuationToken>" };
+            let body = format!(
+                "<ListAllMyBucketsResult><Owner><ID>o</ID></Owner><Buckets>{}</Buckets>{}</ListAllMyBucketsResult>",
+                page, token
+            );
+            (200, body)
+        });
+        // No bucket is needed to list them.
+        let config = HashMap::from([
+            ("region".to_string(), "us-east-1".to_string()),
+            ("endpoint".to_string(), endpoint),
+            ("access_key_id".to_string(), "AKID".to_string()),
+            ("secret_access_key".to_string(), "secret".to_string()),
+        ]);
+        let rt = Runtime::new().unwrap();
+        let rows = rt.block_on(do_list_buckets_async(&config)).unwrap();
+        let created = |t: &str| chrono::DateTime::parse_from_rfc3339(t).ok().map(|t| t.to_utc());
+        assert_eq!(
+            rows,
+            vec![
+                ("data".to_string(), created("2024-01-01T12:00:00Z"), Some("eu-west-1".to_string())),
+                ("logs".to_string(), created("2024-02-01T00:00:00Z"), None),
+            ]
+        );
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].starts_with("get / "));
+        assert!(requests[0].contains("authorization: aws4-hmac-sha256 credential=akid/"));
+        assert!(requests[1].starts_with("get /?continuation-token=next "));
+        assert!(s3_direct_config("fs", JsonB(serde_json::json!({})), "listing buckets").is_err());
+    }
+
+    #[test]
+    fn test_abort_incomplete_uploads() {
+        let (endpoint, requests) = mock_http_server(|head| {
+            if head.starts_with("get ") {
+                let listing = "<ListMultipartUploadsResult><IsTruncated>false</IsTruncated>\
+                    <Upload><Key>data/tmp/a.bin</Key><UploadId>u1</UploadId><Initiated>2024-06-01T10:00:00.000Z</Initiated></Upload>\
+                    <Upload><Key>data/tmp/b.bin</Key><UploadId>u2</UploadId><Initiated>2024-06-01T10:00:00.000Z</Initiated></Upload>\
+                    </ListMultipartUploadsResult>";
+                (200, listing.to_string())
+            } else if head.contains("uploadid=u2") {
+                (404, "<Error><Code>NoSuchUpload</Code></Error>".to_string())
+            } else {
+                (204, String::new())
+            }
+        });
+        let config = HashMap::from([
+            ("bucket".to_string(), "b".to_string()),
+            ("region".to_string(), "us-east-1".to_string()),
+            ("root".to_string(), "/data".to_string()),
+            ("endpoint".to_string(), endpoint),
+            ("access_key_id".to_string(), "AKID".to_string()),
+            ("secret_access_key".to_string(), "secret".to_string()),
+        ]);
+        let rt = Runtime::new().unwrap();
+
+        // u2 finished before it could be aborted, so only u1 counts.
+        assert_eq!(abort_incomplete_uploads(&rt, &config, "tmp/").unwrap(), 1);
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].starts_with("get /b/?prefix=data%2ftmp%2f&uploads= "));
+        assert!(requests[1].starts_with("delete /b/data/tmp/a.bin?uploadid=u1 "));
+        assert!(requests[2].starts_with("delete /b/data/tmp/b.bin?uploadid=u2 "));
+        assert!(s3_direct_config("fs", JsonB(serde_json::json!({})), "aborting multipart uploads").is_err());
+    }
+
+    #[test]
+    fn test_try_read_distinguishes_empty_from_missing() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        rt.block_on(op.write("markers/empty", "")).unwrap();
+        rt.block_on(op.write("markers/full", "done")).unwrap();
+
+        assert_eq!(rt.block_on(do_try_read_async(op.clone(), "markers/missing")).unwrap(), None);
+        assert_eq!(rt.block_on(do_try_read_async(op.clone(), "markers/empty")).unwrap(), Some(String::new()));
+        assert_eq!(rt.block_on(do_try_read_async(op.clone(), "markers/full")).unwrap(), Some("done".to_string()));
+        assert!(rt.block_on(do_try_read_async(op, "markers/")).is_err());
+    }
+
+    #[test]
+    fn test_clone_object() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        rt.block_on(op.write("src.txt", "payload")).unwrap();
+
+        // Memory can't copy natively, so both modes stream the object.
+        for preserve_metadata in [true, false] {
+            rt.block_on(do_clone_object_async(&op, "src.txt", "dst.txt", preserve_metadata, false)).unwrap();
+            assert_eq!(rt.block_on(op.read("dst.txt")).unwrap().to_vec(), b"payload");
+        }
+        assert!(rt.block_on(do_clone_object_async(&op, "src.txt", "tagged.txt", true, true)).is_err());
+        assert!(!rt.block_on(op.exists("tagged.txt")).unwrap());
+        assert!(rt.block_on(do_clone_object_async(&op, "missing.txt", "dst2.txt", true, false)).is_err());
+        assert!(rt.block_on(do_clone_object_async(&op, "missing.txt", "dst2.txt", false, false)).is_err());
+    }
+
+    #[test]
+    fn test_index() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        for path in ["site/b.txt", "site/<a>&\"x\".html", "site/sub/c.txt", "site/javascript:alert(1)"] {
+            rt.block_on(op.write(path, "x")).unwrap();
+        }
+
+        let entries = rt.block_on(index_entries_async(&op, "site", None)).unwrap();
+        let names: Vec<&str> = entries.iter().map(|info| info["name"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["sub/", "<a>&\"x\".html", "b.txt", "javascript:alert(1)"]);
+        assert_eq!(entries[1]["href"], "%3Ca%3E%26%22x%22.html");
+
+        let html = render_html_index("site", &entries);
+        assert!(html.contains("<title>Index of /site</title>"));
+        assert!(html.contains("<a href=\"sub/\">sub/</a></td><td>-</td>"));
+        assert!(html.contains(">&lt;a&gt;&amp;&quot;x&quot;.html</a></td><td>1</td>"));
+        assert!(html.contains("<a href=\"javascript%3Aalert%281%29\">"));
+        assert!(rt.block_on(index_entries_async(&op, "site", Some(std::time::Duration::from_secs(60)))).is_err());
+    }
+
+    #[test]
+    fn test_read_ranges() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        rt.block_on(op.write("app.log", "l1\nl2\nl3\nl4\nl5\n")).unwrap();
+        rt.block_on(op.write("utf8.txt", "h\u{e9}llo")).unwrap();
+
+        let range = |path: &str, start, end| rt.block_on(do_read_range_text_async(op.clone(), path, start, end));
+        assert_eq!(range("app.log", 3, 8).unwrap(), "l2\nl3");
+        assert_eq!(range("app.log", 12, 100).unwrap(), "l5\n");
+        assert_eq!(range("app.log", 100, 200).unwrap(), "");
+        assert!(range("app.log", 5, 4).is_err());
+        assert!(range("utf8.txt", 0, 2).is_err());
+        assert_eq!(range("utf8.txt", 1, 3).unwrap(), "\u{e9}");
+
+        let lines = |start, count| -> Result<Vec<String>, String> {
+            let stream = LineStream::open(Runtime::new().unwrap(), op.clone(), "app.log")?;
+            Ok(lines_range(stream, start, count)?
+                .map(|(_, line)| String::from_utf8(line.unwrap()).unwrap())
+                .collect())
+        };
+        assert_eq!(lines(2, 3).unwrap(), vec!["l2", "l3", "l4"]);
+        assert_eq!(lines(4, 10).unwrap(), vec!["l4", "l5"]);
+        assert!(lines(9, 1).unwrap().is_empty());
+        assert!(lines(1, 0).unwrap().is_empty());
+        assert!(lines(0, 1).is_err());
+        assert!(lines(1, -1).is_err());
+
+        // Nothing past the window is pulled from the stream.
+        let stream = (1..=2)
+            .map(|i| Ok(format!("l{}", i).into_bytes()))
+            .chain(std::iter::from_fn(|| panic!("read past the requested lines")));
+        assert_eq!(lines_range(stream, 2, 1).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_between_markers() {
+        let hosts = "127.0.0.1 localhost\n# BEGIN app\n10.0.0.1 db\n# END app\n# BEGIN other\n";
+        assert_eq!(
+            between_markers(hosts, "# BEGIN app\n", "# END app").unwrap(),
+            Some("10.0.0.1 db\n")
+        );
+        assert_eq!(between_markers(hosts, "# BEGIN missing", "# END missing").unwrap(), None);
+        assert_eq!(
+            between_markers(hosts, "# BEGIN other", "# END other").unwrap_err(),
+            "Found '# BEGIN other' but no '# END other' after it"
+        );
+        // The end marker is only looked for after the start marker.
+        assert_eq!(between_markers("]x[y]", "[", "]").unwrap(), Some("y"));
+        assert!(between_markers(hosts, "", "# END app").is_err());
+    }
+
+    #[test]
+    fn test_read_utf8_modes() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        rt.block_on(op.write("mixed.txt", b"ok\xff\xfe caf\xc3\xa9 \xe2\x82".to_vec())).unwrap();
+
+        let read = |mode: &str| rt.block_on(do_read_async(op.clone(), "mixed.txt", mode.parse().unwrap()));
+        assert!(read("strict").is_err());
+        assert_eq!(read("lossy").unwrap(), "ok\u{fffd}\u{fffd} caf\u{e9} \u{fffd}");
+        assert_eq!(read("SKIP").unwrap(), "ok caf\u{e9} ");
+        assert!("replace".parse::<Utf8Mode>().is_err());
+    }
+
+    #[test]
+    fn test_read_json_pointer() {
+        let doc = r#"{"name": "app", "a/b": {"m~n": [10, {"deep": true}, 30]}, "list": [], "name": "dup"}"#;
+        let read = |pointer: &str| read_json_pointer(doc.as_bytes(), pointer);
+        assert_eq!(read("/name").unwrap(), Some(serde_json::json!("app")));
+        assert_eq!(read("/a~1b/m~0n/1").unwrap(), Some(serde_json::json!({"deep": true})));
+        assert_eq!(read("/a~1b/m~0n/2").unwrap(), Some(serde_json::json!(30)));
+        assert_eq!(read("").unwrap().unwrap()["list"], serde_json::json!([]));
+        assert_eq!(read("/missing").unwrap(), None);
+        assert_eq!(read("/a~1b/m~0n/3").unwrap(), None);
+        assert_eq!(read("/a~1b/m~0n/01").unwrap(), None);
+        assert_eq!(read("/name/x").unwrap(), None);
+        assert!(read("name").is_err());
+        assert!(read("/a~2").is_err());
+
+        // Reading stops at the value, so trailing damage goes unnoticed; before it, it doesn't.
+        assert_eq!(read_json_pointer(&b"{\"a\": 1, \"b\": [oops"[..], "/a").unwrap(), Some(serde_json::json!(1)));
+        assert!(read_json_pointer(&b"{\"a\": 1, \"b\": [oops"[..], "/b/0").is_err());
+        assert!(read_json_pointer(&b"{\"a\": 1} trailing"[..], "/z").is_err());
+
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        rt.block_on(op.write("config.json", doc)).unwrap();
+        let input = ChunkReader::new(ChunkStream::open(Runtime::new().unwrap(), op, vec!["config.json".to_string()]));
+        assert_eq!(read_json_pointer(input, "/a~1b/m~0n/0").unwrap(), Some(serde_json::json!(10)));
+    }
+
+    #[test]
+    fn test_render_template() {
+        let params = serde_json::json!({"host": "db.internal", "port": 5432, "ssl": true, "note": null, "cost": "$1 {{host}}"});
+        let (rendered, missing) =
+            render_template("host={{host}}\nport={{ port }}\nssl={{ssl}}\nnote={{note}}\ncost={{cost}}\n", &params).unwrap();
+        assert_eq!(rendered, "host=db.internal\nport=5432\nssl=true\nnote=\ncost=$1 {{host}}\n");
+        assert!(missing.is_empty());
+
+        let (rendered, missing) = render_template("{{user}}@{{host}} as {{user}} in {{env}} {{ }} {{a b}}", &params).unwrap();
+        assert_eq!(rendered, "{{user}}@db.internal as {{user}} in {{env}} {{ }} {{a b}}");
+        assert_eq!(missing, vec!["user", "env"]);
+
+        assert!(render_template("{{host}}", &serde_json::json!(["host"])).is_err());
+    }
+
+    #[test]
+    fn test_stat_if_exists() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        rt.block_on(op.write("dir/file.txt", "abc")).unwrap();
+
+        let stat = rt.block_on(do_stat_if_exists_async(op.clone(), "dir/file.txt")).unwrap().unwrap();
+        assert_eq!(stat.0["content_length"], 3);
+        assert_eq!(stat.0["is_file"], true);
+        assert!(rt.block_on(do_stat_if_exists_async(op.clone(), "dir/missing.txt")).unwrap().is_none());
+        assert!(rt.block_on(do_stat_async(op, "dir/missing.txt")).is_err());
+    }
+
+    #[test]
+    fn test_stat_batch() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        let paths: Vec<String> = (0..50).map(|i| format!("files/{:02}.txt", i)).collect();
+        for path in paths.iter().step_by(2) {
+            rt.block_on(op.write(path, path.clone())).unwrap();
+        }
+
+        let rows = stat_batch(&rt, &op, paths.clone(), 4);
+        assert_eq!(rows.iter().map(|row| row.0.clone()).collect::<Vec<_>>(), paths);
+        for (i, (_, metadata, error)) in rows.iter().enumerate() {
+            assert!(error.is_none());
+            match metadata {
+                Some(metadata) => {
+                    assert_eq!(i % 2, 0);
+                    assert_eq!(metadata.0["content_length"], 12);
+                }
+                None => assert_eq!(i % 2, 1),
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_sequence_naming() {
+        let bucket = |name: &str| config_fingerprint(&HashMap::from([("bucket".to_string(), name.to_string())]));
+        let name = write_sequence_name("s3", &bucket("a"), "logs/batch_");
+        assert_eq!(name, write_sequence_name("s3", &bucket("a"), "logs/batch_"));
+        assert_ne!(name, write_sequence_name("fs", &bucket("a"), "logs/batch_"));
+        assert_ne!(name, write_sequence_name("s3", &bucket("b"), "logs/batch_"));
+        assert_ne!(name, write_sequence_name("s3", &bucket("a"), "logs/batch"));
+        assert!(name.starts_with("pg_opendal_seq_") && name.len() == "pg_opendal_seq_".len() + 16);
+
+        assert_eq!(write_sequence_path("logs/batch_", 42, 6).unwrap(), "logs/batch_000042.txt");
+        assert_eq!(write_sequence_path("logs/batch_", 1234567, 6).unwrap(), "logs/batch_1234567.txt");
+        assert_eq!(write_sequence_path("n", 7, 0).unwrap(), "n7.txt");
+        assert!(write_sequence_path("n", 7, -1).is_err());
+        assert!(write_sequence_path("n", 7, 100).is_err());
+    }
+
+    #[test]
+    fn test_copy_cross_service_batch() {
+        let rt = Runtime::new().unwrap();
+        let (src, dst) = (memory_operator(), memory_operator());
+        let mut copies = Vec::new();
+        for i in 0..25 {
+            rt.block_on(src.write(&format!("lake/{:02}.parquet", i), vec![b'x'; i])).unwrap();
+            copies.push((format!("lake/{:02}.parquet", i), format!("migrated/{:02}.parquet", i)));
+        }
+        copies.insert(3, ("lake/missing.parquet".to_string(), "migrated/missing.parquet".to_string()));
+
+        let mut reported = Vec::new();
+        let copies = batch_copies(&src, &dst, copies);
+        let rows = copy_cross_service_batch(&rt, copies, 4, |done, total| reported.push((done, total)));
+        assert_eq!(rows.len(), 26);
+        assert_eq!(rows[2], ("lake/02.parquet".to_string(), "migrated/02.parquet".to_string(), 2, true, None));
+        assert!(!rows[3].3 && rows[3].4.is_some());
+        assert_eq!(rows.iter().filter(|row| row.3).count(), 25);
+        assert_eq!(rt.block_on(dst.read("migrated/24.parquet")).unwrap().len(), 24);
+        assert_eq!(reported.iter().map(|(done, _)| *done).collect::<Vec<_>>(), vec![3, 6, 9, 12, 15, 18, 21, 24, 26]);
+
+        assert_eq!(
+            parse_copy_pairs(serde_json::json!([{"src": "a", "dst": "b"}])).unwrap(),
+            vec![("a".to_string(), "b".to_string())]
+        );
+        assert!(parse_copy_pairs(serde_json::json!([{"src": "a"}])).is_err());
+        assert!(copy_cross_service_batch(&rt, Vec::new(), 4, |_, _| panic!()).is_empty());
+
+        // An operator that failed to build fails only the copies that use it.
+        let copies = vec![
+            BatchCopy { src_op: Ok(src.clone()), src: "lake/01.parquet".into(), dst_op: Ok(dst.clone()), dst: "one".into() },
+            BatchCopy { src_op: Err("no such service".into()), src: "a".into(), dst_op: Ok(dst.clone()), dst: "b".into() },
+        ];
+        let rows = copy_cross_service_batch(&rt, copies, 4, |_, _| {});
+        assert!(rows[0].3);
+        assert_eq!(rows[1], ("a".to_string(), "b".to_string(), 0, false, Some("no such service".to_string())));
+    }
+
+    #[test]
+    fn test_copy_batch_across_services() {
+        let rt = Runtime::new().unwrap();
+        let dir = |side: &str| std::env::temp_dir().join(format!("pg_opendal_batch_{}_{}", side, std::process::id()));
+        let (src_root, dst_root) = (dir("src"), dir("dst"));
+        let config = |root: &std::path::Path| HashMap::from([("root".to_string(), root.to_string_lossy().to_string())]);
+        let src = create_operator("fs", config(&src_root)).unwrap();
+        rt.block_on(src.write("exports/a.csv", "a,1\n")).unwrap();
+        rt.block_on(src.write("exports/b.csv", "b,22\n")).unwrap();
+
+        let operations = serde_json::json!([
+            {"service_src": "fs", "src": "exports/a.csv", "service_dst": "fs", "dst": "imports/a.csv"},
+            {"service_src": "fs", "src": "exports/b.csv", "service_dst": "fs", "dst": "imports/b.csv"},
+            {"service_src": "fs", "src": "exports/a.csv", "service_dst": "nosuch", "dst": "imports/c.csv"},
+        ]);
+        let copies = parse_cross_service_copies(operations).unwrap();
+        let copies = cross_service_copies(copies, &config(&src_root), &config(&dst_root));
+        let rows = copy_cross_service_batch(&rt, copies, 2, |_, _| {});
+        assert_eq!(rows[0], ("exports/a.csv".to_string(), "imports/a.csv".to_string(), 4, true, None));
+        assert_eq!(rows[1], ("exports/b.csv".to_string(), "imports/b.csv".to_string(), 5, true, None));
+        assert!(!rows[2].3 && rows[2].4.as_ref().unwrap().starts_with("Failed to create operator: "));
+
+        // Each side uses its own config, so the copies land under the destination root.
+        let dst = create_operator("fs", config(&dst_root)).unwrap();
+        assert_eq!(rt.block_on(dst.read("imports/b.csv")).unwrap().to_vec(), b"b,22\n");
+        assert!(!rt.block_on(src.exists("imports/a.csv")).unwrap());
+        assert!(!rt.block_on(dst.exists("imports/c.csv")).unwrap());
+
+        assert!(parse_cross_service_copies(serde_json::json!({"src": "a"})).is_err());
+        assert_eq!(
+            parse_cross_service_copies(serde_json::json!([{"service_src": "fs", "src": "a", "dst": "b"}])).err(),
+            Some("Operation 0 is missing string field 'service_dst'".to_string())
+        );
+        std::fs::remove_dir_all(src_root).unwrap();
+        std::fs::remove_dir_all(dst_root).unwrap();
+    }
+
+    #[test]
+    fn test_estimate() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        for (path, size) in [("data/a.csv", 10), ("data/b.csv", 20), ("data/2024/c.csv", 30), ("other.csv", 5)] {
+            rt.block_on(op.write(path, vec![b'x'; size])).unwrap();
+        }
+        let estimate = |path: &str, operation: &str| rt.block_on(do_estimate_async(op.clone(), path, operation));
+
+        let read = estimate("data/", "read").unwrap().0;
+        assert_eq!(read["object_count"], 3);
+        assert_eq!(read["total_bytes"], 60);
+        assert_eq!(read["estimated_requests"], 3);
+        assert_eq!(read["estimated_egress_bytes"], 60);
+
+        let list = estimate("data/", "list").unwrap().0;
+        assert_eq!((list["estimated_requests"].as_u64(), list["estimated_egress_bytes"].as_u64()), (Some(1), Some(0)));
+        let delete = estimate("data/", "delete").unwrap().0;
+        assert_eq!(delete["estimated_egress_bytes"], 0);
+        assert!(delete["estimated_requests"].as_u64().unwrap() <= 3);
+
+        let single = estimate("other.csv", "read").unwrap().0;
+        assert_eq!((single["object_count"].as_u64(), single["total_bytes"].as_u64()), (Some(1), Some(5)));
+        assert!(estimate("missing.csv", "read").is_err());
+        assert_eq!(
+            estimate("data/", "copy").unwrap_err(),
+            "Invalid operation 'copy', expected one of 'read', 'list' or 'delete'"
+        );
+    }
+
+    #[test]
+    fn test_write_parted() {
+        assert_eq!(split_at_lines("a,1\nb,2\nc,3\n", 8).unwrap(), vec!["a,1\nb,2\n", "c,3\n"]);
+        assert_eq!(split_at_lines("a,1\nb,2\nc,3", 4).unwrap(), vec!["a,1\n", "b,2\n", "c,3"]);
+        assert_eq!(split_at_lines("a,1\nb,2\n", 100).unwrap(), vec!["a,1\nb,2\n"]);
+        assert!(split_at_lines("", 10).unwrap().is_empty());
+        assert!(split_at_lines("short\nmuch too long\n", 8).is_err());
+
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        let rows = rt.block_on(do_write_parted_async(&op, "out/file.{part}.csv", "a,1\nb,2\nc,3\n", 8)).unwrap();
+        assert_eq!(rows, vec![("out/file.0000.csv".to_string(), 8), ("out/file.0001.csv".to_string(), 4)]);
+        assert_eq!(rt.block_on(op.read("out/file.0001.csv")).unwrap().to_vec(), b"c,3\n");
+        assert!(rt.block_on(do_write_parted_async(&op, "out/file.csv", "a\n", 8)).is_err());
+    }
+
+    #[test]
+    fn test_mirror_to_local() {
+        let root = std::env::temp_dir().join(format!("pg_opendal_mirror_{}", std::process::id()));
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        rt.block_on(op.write("backup/a.sql", "a")).unwrap();
+        rt.block_on(op.write("backup/2024/b.sql", "bb")).unwrap();
+        rt.block_on(op.write("backup/../escape", "x")).unwrap();
+        rt.block_on(op.write("other/c.sql", "c")).unwrap();
+
+        // Memory reports no modification times, so nothing ever counts as current. A key
+        // climbing out of the prefix fails rather than being written outside the root.
+        let (copied, skipped, failures) = mirror_to_local(&rt, &op, "backup", &root).unwrap();
+        assert_eq!((copied, skipped), (2, 0));
+        assert_eq!(failures, vec![format!("Refusing to mirror '../escape' outside of '{}'", root.display())]);
+        assert_eq!(std::fs::read(root.join("2024/b.sql")).unwrap(), b"bb");
+        assert!(!root.join("other").exists());
+
+        assert!(mirror_local_path(&root, "../escape").is_err());
+        assert!(mirror_local_path(&root, "/etc/passwd").is_err());
+        let local = root.join("a.sql");
+        let now = chrono::Utc::now();
+        assert!(local_copy_is_current(&local, 1, Some(now - chrono::Duration::hours(1))));
+        assert!(!local_copy_is_current(&local, 2, Some(now - chrono::Duration::hours(1))));
+        assert!(!local_copy_is_current(&local, 1, Some(now + chrono::Duration::hours(1))));
+        assert!(!local_copy_is_current(&local, 1, None));
+        assert!(!local_copy_is_current(&root.join("missing"), 1, Some(now)));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_count_lines() {
+        let op = memory_operator();
+        let rt = Runtime::new().unwrap();
+        rt.block_on(op.write("terminated.log", "a\nb\n")).unwrap();
+        rt.block_on(op.write("unterminated.log", "a\nb\nc")).unwrap();
+        rt.block_on(op.write("empty.log", "")).unwrap();
+        let big: String = (0..100_000).map(|i| format!("{}\n", i)).collect();
+        rt.block_on(op.write("big.log", big)).unwrap();
+
+        let count = |path: &str| {
+            let rt = Runtime::new().unwrap();
+            count_lines(&mut ChunkStream::open(rt, op.clone(), vec![path.to_string()])).unwrap()
+        };
+        assert_eq!(count("terminated.log"), 2);
+        assert_eq!(count("unterminated.log"), 3);
+        assert_eq!(count("empty.log"), 0);
+        assert_eq!(count("big.log"), 100_000);
+    }
+
+    #[test]
+    fn test_config_fingerprint() {
+        let config = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+        };
+        let base = config_fingerprint(&config(&[("bucket", "a"), ("region", "us-east-1"), ("secret_access_key", "old")]));
+        let rotated = config_fingerprint(&config(&[("region", "us-east-1"), ("bucket", "a"), ("secret_access_key", "new")]));
+        let other_bucket = config_fingerprint(&config(&[("bucket", "b"), ("region", "us-east-1"), ("secret_access_key", "old")]));
+        assert_eq!(base, rotated);
+        assert_ne!(base, other_bucket);
+        assert_ne!(config_fingerprint(&config(&[("a", "b=c")])), config_fingerprint(&config(&[("a=b", "c")])));
+    }
+
+    #[test]
+    fn test_fetch_if_changed() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        rt.block_on(op.write("page.html", "<p>hi</p>")).unwrap();
+
+        let (content, _) = rt.block_on(fetch_if_changed_async(&op, "page.html", None)).unwrap().unwrap();
+        assert_eq!(content, "<p>hi</p>");
+        // A service without ETags can't prove the cached copy is current, so it re-reads.
+        let refetched = rt.block_on(fetch_if_changed_async(&op, "page.html", Some("\"stale\""))).unwrap();
+        assert!(refetched.is_some());
+        assert!(rt.block_on(fetch_if_changed_async(&op, "missing.html", None)).is_err());
+    }
+
+    #[test]
+    fn test_digest_object() {
+        let op = memory_operator();
+        let rt = Runtime::new().unwrap();
+        rt.block_on(op.write("empty.bin", "")).unwrap();
+        rt.block_on(op.write("abc.txt", "abc")).unwrap();
+        let big = vec![b'a'; 3 * READ_CHUNK_SIZE + 7];
+        rt.block_on(op.write("big.bin", big.clone())).unwrap();
+
+        let hash = |path: &str| rt.block_on(digest_object_async(&op, path, ChecksumAlgorithm::Sha256)).unwrap();
+        assert_eq!(
+            hash("empty.bin"),
+            ("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string(), 0)
+        );
+        assert_eq!(
+            hash("abc.txt"),
+            ("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string(), 3)
+        );
+        use sha2::Digest;
+        assert_eq!(hash("big.bin"), (hex::encode(sha2::Sha256::digest(&big)), big.len() as u64));
+    }
+
+    #[test]
+    fn test_read_tar_member() {
+        let long_name = format!("deep/{}/b.bin", "x".repeat(150));
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut append = |path: &str, entry_type: tar::EntryType, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, data).unwrap();
+        };
+        append("./a.txt", tar::EntryType::Regular, b"hello");
+        append("dir/", tar::EntryType::Directory, b"");
+        append(&long_name, tar::EntryType::Regular, &[7u8; 2000]);
+        let archive = builder.into_inner().unwrap();
+
+        let mut gzipped = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        std::io::Write::write_all(&mut gzipped, &archive).unwrap();
+        let gzipped = gzipped.finish().unwrap();
+
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        rt.block_on(op.write("data.tar", archive)).unwrap();
+        rt.block_on(op.write("data.tar.gz", gzipped)).unwrap();
+
+        for path in ["data.tar", "data.tar.gz"] {
+            let read = |member: &str| read_tar_member(Runtime::new().unwrap(), op.clone(), path, member);
+            assert_eq!(read("a.txt").unwrap(), Some(b"hello".to_vec()), "{}", path);
+            assert_eq!(read(&long_name).unwrap(), Some(vec![7u8; 2000]), "{}", path);
+            assert_eq!(read("missing.txt").unwrap(), None, "{}", path);
+            assert!(read("dir/").is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_size_histogram() {
+        assert!(size_histogram(&[], 10).is_empty());
+        assert_eq!(size_histogram(&[0, 0], 10), vec![(0, 0, 2, 0)]);
+
+        let sizes = [1, 5, 10, 99, 100, 1000, 5000, 10_000];
+        let rows = size_histogram(&sizes, 4);
+        assert_eq!(
+            rows,
+            vec![(1, 9, 2, 6), (10, 99, 2, 109), (100, 999, 1, 100), (1000, 10_000, 3, 16_000)]
+        );
+
+        // A narrow span can't be split into as many ranges as asked for.
+        let rows = size_histogram(&[3, 4, 4], 10);
+        assert_eq!(rows, vec![(3, 3, 1, 3), (4, 4, 2, 8)]);
+        assert_eq!(rows.iter().map(|row| row.2).sum::<i64>(), 3);
+    }
+
+    #[test]
+    fn test_list_archive() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in [("a.txt", &b"alpha"[..]), ("sub/b.txt", &b"bravo!"[..])] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, data).unwrap();
+        }
+        let tarball = builder.into_inner().unwrap();
+        let mut gzipped = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        std::io::Write::write_all(&mut gzipped, &tarball).unwrap();
+        let gzipped = gzipped.finish().unwrap();
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        for (name, data) in [("a.txt", &b"alpha"[..]), ("sub/b.txt", &b"bravo!"[..])] {
+            zip.start_file(name, options).unwrap();
+            std::io::Write::write_all(&mut zip, data).unwrap();
+        }
+        let zipped = zip.finish().unwrap().into_inner();
+
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        rt.block_on(op.write("bundle.tar", tarball.clone())).unwrap();
+        rt.block_on(op.write("bundle.tgz", gzipped)).unwrap();
+        rt.block_on(op.write("bundle", zipped.clone())).unwrap();
+        rt.block_on(op.write("notes.txt", "just text")).unwrap();
+
+        let list = |path: &str| list_archive(Runtime::new().unwrap(), op.clone(), path);
+        for (members, archive) in [(list("bundle.tar").unwrap(), &tarball), (list("bundle").unwrap(), &zipped)] {
+            let names: Vec<_> = members.iter().map(|(name, size, _)| (name.as_str(), *size)).collect();
+            assert_eq!(names, vec![("a.txt", 5), ("sub/b.txt", 6)]);
+            // Offsets point at each member's data within the stored archive.
+            let (_, size, offset) = &members[1];
+            let offset = offset.unwrap() as usize;
+            assert_eq!(&archive[offset..offset + *size as usize], b"bravo!");
+        }
+
+        let members = list("bundle.tgz").unwrap();
+        assert_eq!(members, vec![("a.txt".to_string(), 5, None), ("sub/b.txt".to_string(), 6, None)]);
+        assert!(list("notes.txt").is_err());
+    }
+
+    #[test]
+    fn test_list_duplicates() {
+        let rows = duplicate_groups(
+            vec![
+                (10, "e1".to_string(), "b/copy.bin".to_string()),
+                (10, "e1".to_string(), "a/orig.bin".to_string()),
+                (10, "e2".to_string(), "a/other.bin".to_string()),
+                (100, "e3".to_string(), "x".to_string()),
+                (100, "e3".to_string(), "y".to_string()),
+                (100, "e3".to_string(), "z".to_string()),
+            ],
+            true,
+        );
+        assert_eq!(
+            rows,
+            vec![
+                (Some("e3".to_string()), vec!["x".to_string(), "y".to_string(), "z".to_string()], 200),
+                (Some("e1".to_string()), vec!["a/orig.bin".to_string(), "b/copy.bin".to_string()], 10),
+            ]
+        );
+
+        // Memory reports no ETags, so same-sized files are told apart by their content.
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        rt.block_on(op.write("dup/a.txt", "same")).unwrap();
+        rt.block_on(op.write("dup/b.txt", "diff")).unwrap();
+        rt.block_on(op.write("dup/c/a.txt", "same")).unwrap();
+        rt.block_on(op.write("dup/empty1", "")).unwrap();
+        rt.block_on(op.write("dup/empty2", "")).unwrap();
+        let rows = rt.block_on(do_list_duplicates_async(op, "dup/")).unwrap();
+        assert_eq!(rows, vec![(None, vec!["dup/a.txt".to_string(), "dup/c/a.txt".to_string()], 4)]);
+    }
+
+    #[test]
+    fn test_dedup() {
+        assert_eq!(s3_etag_md5("\"D41D8CD98F00B204E9800998ECF8427E\"").as_deref(), Some("d41d8cd98f00b204e9800998ecf8427e"));
+        assert_eq!(s3_etag_md5("\"d41d8cd98f00b204e9800998ecf8427e-3\""), None);
+        assert_eq!(s3_etag_md5("W/\"abc\""), None);
+
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        for (path, content) in [("up/b/copy", "same"), ("up/a/orig", "same"), ("up/c", "same"), ("up/d", "diff"), ("up/e", ""), ("up/f", "")] {
+            rt.block_on(op.write(path, content)).unwrap();
+        }
+        let hash = format!("sha256:{}", hex::encode(<sha2::Sha256 as sha2::Digest>::digest(b"same")));
+        let expected = vec![
+            ("up/a/orig".to_string(), "up/b/copy".to_string(), hash.clone()),
+            ("up/a/orig".to_string(), "up/c".to_string(), hash),
+        ];
+
+        assert_eq!(rt.block_on(do_dedup_async(&op, "up/", true)).unwrap(), expected);
+        assert!(rt.block_on(op.exists("up/c")).unwrap());
+        assert_eq!(rt.block_on(do_dedup_async(&op, "up/", false)).unwrap(), expected);
+        assert!(!rt.block_on(op.exists("up/b/copy")).unwrap());
+        assert!(!rt.block_on(op.exists("up/c")).unwrap());
+        for path in ["up/a/orig", "up/d", "up/e", "up/f"] {
+            assert!(rt.block_on(op.exists(path)).unwrap());
+        }
+        assert!(rt.block_on(do_dedup_async(&op, "up/", false)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_move_recursive() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        for i in 0..MOVE_PROGRESS_INTERVAL + 5 {
+            rt.block_on(op.write(&format!("from/batch/{:03}.csv", i), i.to_string())).unwrap();
+        }
+        rt.block_on(op.write("from/top.txt", "top")).unwrap();
+        rt.block_on(op.write("fromage.txt", "not moved")).unwrap();
+
+        let mut reports = Vec::new();
+        let moved = move_recursive(&rt, &op, "from", "to/", |done, total| reports.push((done, total))).unwrap();
+        assert_eq!(moved, MOVE_PROGRESS_INTERVAL as i64 + 6);
+        assert_eq!(reports, vec![(MOVE_PROGRESS_INTERVAL, MOVE_PROGRESS_INTERVAL + 6)]);
+        assert_eq!(rt.block_on(op.read("to/top.txt")).unwrap().to_vec(), b"top");
+        assert_eq!(rt.block_on(op.read("to/batch/007.csv")).unwrap().to_vec(), b"7");
+        assert!(rt.block_on(list_files_recursive_async(&op, "from/", None)).unwrap().is_empty());
+        assert!(rt.block_on(op.exists("fromage.txt")).unwrap());
+
+        assert!(move_recursive(&rt, &op, "to", "to/sub", |_, _| {}).is_err());
+        assert!(move_recursive(&rt, &op, "/", "elsewhere", |_, _| {}).is_err());
+    }
+
+    #[test]
+    fn test_rename_plan() {
+        let paths: Vec<String> = ["logs/2024-01-05.csv", "logs/2024-02-01.csv", "logs/readme.txt"]
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        let date = regex::Regex::new(r"^logs/(?<y>\d{4})-(?<m>\d{2})-\d{2}\.csv$").unwrap();
+        assert_eq!(
+            rename_plan(&paths, &date, "logs/${y}/${m}.csv").unwrap(),
+            vec![
+                ("logs/2024-01-05.csv".to_string(), "logs/2024/01.csv".to_string()),
+                ("logs/2024-02-01.csv".to_string(), "logs/2024/02.csv".to_string()),
+            ]
+        );
+
+        let err = rename_plan(&paths, &date, "logs/${y}.csv").unwrap_err();
+        assert!(err.contains("would both be renamed to 'logs/2024.csv'"), "{}", err);
+        let readme = regex::Regex::new(r"readme\.txt$").unwrap();
+        assert!(rename_plan(&paths, &readme, "2024-01-05.csv").is_err());
+        assert!(rename_plan(&paths, &readme, "readme.txt").unwrap().is_empty());
+
+        // A destination that is itself renamed away would still be overwritten first.
+        let chained: Vec<String> = vec!["a.txt".into(), "a.txt.bak".into()];
+        let txt = regex::Regex::new(r"\.txt").unwrap();
+        assert!(rename_plan(&chained, &txt, ".txt.bak").unwrap_err().contains("already exists"));
+
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        for path in &paths {
+            rt.block_on(op.write(path, path.clone())).unwrap();
+        }
+        let plan = rename_plan(&paths, &date, "logs/${y}/${m}.csv").unwrap();
+        rt.block_on(check_rename_destinations_async(&op, &plan, &paths)).unwrap();
+        // A destination outside the listed prefix isn't overwritten either.
+        let whole = regex::Regex::new(r"^logs/readme\.txt$").unwrap();
+        let outside = rename_plan(&paths, &whole, "docs/readme.txt").unwrap();
+        rt.block_on(op.write("docs/readme.txt", "keep")).unwrap();
+        let err = rt.block_on(check_rename_destinations_async(&op, &outside, &paths)).unwrap_err();
+        assert!(err.contains("'docs/readme.txt', which already exists"), "{}", err);
+        let err = move_files(&rt, &op, &outside, |_, _| {}).unwrap_err();
+        assert!(err.contains("the destination already exists"), "{}", err);
+        assert_eq!(rt.block_on(op.read("docs/readme.txt")).unwrap().to_vec(), b"keep");
+        assert_eq!(move_files(&rt, &op, &plan, |_, _| {}).unwrap(), 2);
+        assert_eq!(rt.block_on(op.read("logs/2024/02.csv")).unwrap().to_vec(), b"logs/2024-02-01.csv");
+        assert!(!rt.block_on(op.exists("logs/2024-01-05.csv")).unwrap());
+    }
+
+    #[test]
+    fn test_fixed_chunks() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        let content: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        rt.block_on(op.write("big.bin", content.clone())).unwrap();
+
+        let chunks: Vec<Vec<u8>> = FixedChunks::open(rt, op.clone(), "big.bin", 70_000)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let sizes: Vec<usize> = chunks.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![70_000, 70_000, 60_000]);
+        assert_eq!(chunks.concat(), content);
+
+        assert!(FixedChunks::open(Runtime::new().unwrap(), op, "missing.bin", 10).is_err());
+    }
+
+    #[test]
+    fn test_map_json_fields() {
+        let columns = vec![
+            TargetColumn { name: "id".to_string(), required: true },
+            TargetColumn { name: "message".to_string(), required: false },
+        ];
+        let object = |value: Value| value.as_object().unwrap().clone();
+
+        let row = map_json_fields(object(serde_json::json!({"id": 1, "level": "info"})), None, &columns);
+        assert_eq!(Value::Object(row.unwrap()), serde_json::json!({"id": 1}));
+
+        let mapping = HashMap::from([
+            ("msg".to_string(), "message".to_string()),
+            ("request_id".to_string(), "id".to_string()),
+        ]);
+        let row = map_json_fields(
+            object(serde_json::json!({"request_id": 7, "msg": "hi", "id": 99})),
+            Some(&mapping),
+            &columns,
+        );
+        assert_eq!(Value::Object(row.unwrap()), serde_json::json!({"id": 7, "message": "hi"}));
+
+        let missing = map_json_fields(object(serde_json::json!({"id": null, "message": "x"})), None, &columns);
+        assert_eq!(missing.unwrap_err(), vec!["id"]);
+    }
+
+    #[test]
+    fn test_encode_export_row() {
+        let row = vec![Some(b"a,b".to_vec()), None, Some(b"".to_vec()), Some(b"x\ty\\".to_vec())];
+        let encoded = |format| {
+            let mut out = Vec::new();
+            encode_export_row(format, &row, &mut out);
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(encoded(ExportFormat::Csv), "\"a,b\",,\"\",x\ty\\\n");
+        assert_eq!(encoded(ExportFormat::CopyText), "a,b\t\\N\t\tx\\ty\\\\\n");
+
+        let mut out = export_header(ExportFormat::CopyBinary, &[]);
+        encode_export_row(ExportFormat::CopyBinary, &[Some(vec![0, 0, 0, 7]), None], &mut out);
+        assert_eq!(&out[..11], COPY_BINARY_SIGNATURE);
+        assert_eq!(&out[19..], [0, 2, 0, 0, 0, 4, 0, 0, 0, 7, 255, 255, 255, 255]);
+
+        let header = export_header(ExportFormat::Csv, &["id".to_string(), "name".to_string()]);
+        assert_eq!(header, b"id,name\n");
+        assert!("parquet".parse::<ExportFormat>().is_err());
+
+        assert_eq!(encoded(ExportFormat::CopyCsv), encoded(ExportFormat::Csv));
+        assert!(export_header(ExportFormat::CopyCsv, &["id".to_string()]).is_empty());
+        assert_eq!(ExportFormat::from_copy_format("BINARY"), Ok(ExportFormat::CopyBinary));
+        assert!(ExportFormat::from_copy_format("jsonl").is_err());
+    }
+
+    #[test]
+    fn test_recompress() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        let content: Vec<u8> = (0..300_000).flat_map(|i: u32| format!("line {}\n", i % 97).into_bytes()).collect();
+        rt.block_on(op.write("raw.log", content.clone())).unwrap();
+
+        let convert = |src, dst, codec| {
+            let (from, to) = parse_codec_conversion(codec).unwrap();
+            rt.block_on(recompress_async(&op, src, &op, dst, from, to)).unwrap()
+        };
+        let gzip_len = convert("raw.log", "raw.log.gz", "gzip");
+        assert!(gzip_len < content.len() as u64);
+        convert("raw.log.gz", "raw.log.zst", "gzip->zstd");
+        let plain_len = convert("raw.log.zst", "roundtrip.log", "zstd->none");
+
+        assert_eq!(plain_len, content.len() as u64);
+        assert_eq!(rt.block_on(op.read("roundtrip.log")).unwrap().to_vec(), content);
+
+        convert("raw.log", "raw.log.lz4", "lz4");
+        convert("raw.log.lz4", "raw.log.br", "lz4->brotli");
+        convert("raw.log.br", "roundtrip2.log", "brotli->none");
+        assert_eq!(rt.block_on(op.read("roundtrip2.log")).unwrap().to_vec(), content);
+        assert!(parse_codec_conversion("gzip->snappy").is_err());
+    }
+
+    #[test]
+    fn test_transcode_truncated() {
+        let content: Vec<u8> = (0..200_000u32).flat_map(|i| (i % 5000).to_le_bytes()).collect();
+        let transcode = |from, to, input: &[u8]| -> std::io::Result<Vec<u8>> {
+            let mut transcoder = Transcoder::new(from, to)?;
+            let mut output = Vec::new();
+            for chunk in input.chunks(1000) {
+                output.extend(transcoder.transcode(opendal::Buffer::from(chunk.to_vec()))?);
+            }
+            output.extend(transcoder.finish()?);
+            Ok(output)
+        };
+        for codec in [Codec::Gzip, Codec::Zstd, Codec::Lz4, Codec::Brotli] {
+            let compressed = transcode(Codec::None, codec, &content).unwrap();
+            assert_eq!(transcode(codec, Codec::None, &compressed).unwrap(), content, "{:?}", codec);
+            for cut in [compressed.len() / 2, compressed.len() - 1] {
+                assert!(transcode(codec, Codec::None, &compressed[..cut]).is_err(), "{:?} cut at {}", codec, cut);
+            }
+        }
+
+        // lz4 is decoded a block at a time, so a stream ending between blocks must fail
+        // too, while back-to-back frames are all decoded.
+        let compressed = transcode(Codec::None, Codec::Lz4, &content).unwrap();
+        let (header, blocks) = lz4_unit(&compressed, Lz4Position::FrameStart).unwrap().unwrap();
+        let (block, _) = lz4_unit(&compressed[header..], blocks).unwrap().unwrap();
+        assert!(header + block < compressed.len());
+        let mut transcoder = Transcoder::new(Codec::Lz4, Codec::None).unwrap();
+        transcoder.transcode(opendal::Buffer::from(compressed[..header + block].to_vec())).unwrap();
+        assert_eq!(transcoder.finish().unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+
+        let frames = [compressed.clone(), compressed].concat();
+        assert_eq!(transcode(Codec::Lz4, Codec::None, &frames).unwrap(), [content.clone(), content].concat());
+    }
+
+    #[test]
+    fn test_decode_text_auto() {
+        let utf8 = decode_text_auto("naïve café".as_bytes());
+        assert_eq!((utf8.text.as_str(), utf8.encoding, utf8.lossy), ("naïve café", "UTF-8", false));
+
+        let bom = decode_text_auto(b"\xff\xfeh\0i\0");
+        assert_eq!((bom.text.as_str(), bom.encoding), ("hi", "UTF-16LE"));
+
+        let (latin1, _, _) = encoding_rs::WINDOWS_1252
+            .encode("Le cœur a ses raisons que la raison ne connaît point, déjà été.");
+        let legacy = decode_text_auto(&latin1);
+        assert_eq!(legacy.encoding, "windows-1252");
+        assert!(legacy.text.contains("connaît"));
+    }
+
+    #[test]
+    fn test_slice_fixed_width() {
+        assert_eq!(
+            slice_fixed_width("AB  123 x", &[4, 4, 1], true),
+            vec![Some("AB".to_string()), Some("123".to_string()), Some("x".to_string())]
+        );
+        assert_eq!(
+            slice_fixed_width("AB  12", &[4, 4, 1], false),
+            vec![Some("AB  ".to_string()), Some("12".to_string()), None]
+        );
+    }
+
+    #[test]
+    fn test_list_files_recursive_glob() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        for path in ["out/part-0.csv", "out/_SUCCESS", "out/tmp/part-1.csv.tmp"] {
+            rt.block_on(op.write(path, "x")).unwrap();
+        }
+        let files = rt.block_on(list_files_recursive_async(&op, "out/", None)).unwrap();
+        assert_eq!(files.len(), 3);
+
+        let matching = |pattern| {
+            let is_match = compile_path_glob(pattern).unwrap();
+            files.iter().filter(|e| is_match(e)).count()
+        };
+        assert_eq!(matching("*.tmp"), 1);
+        assert_eq!(matching("_*"), 1);
+        assert_eq!(matching("out/*.csv"), 1);
+    }
+
+    #[test]
+    fn test_read_end_bytes() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        rt.block_on(op.write("data.parquet", "PAR1....PAR1")).unwrap();
+
+        let read = |end, n, strict| {
+            rt.block_on(do_read_end_bytes_async(op.clone(), "data.parquet", end, n, strict))
+        };
+        assert_eq!(read(ReadEnd::First, 4, true).unwrap(), b"PAR1");
+        assert_eq!(read(ReadEnd::Last, 6, true).unwrap(), b"..PAR1");
+        assert_eq!(read(ReadEnd::Last, 100, false).unwrap(), b"PAR1....PAR1");
+        assert!(read(ReadEnd::First, 100, true).is_err());
+        assert!(read(ReadEnd::First, 0, true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_end_lines() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        let long = "x".repeat(1000);
+        let log = format!("first\r\n{}\nmiddle\n\n{}\nlast", long, long);
+        rt.block_on(op.write("app.log", log)).unwrap();
+        rt.block_on(op.write("app2.log", "a\nb\n")).unwrap();
+        rt.block_on(op.write("empty.log", "")).unwrap();
+
+        let read = |path, end, n| rt.block_on(do_read_end_lines_async(op.clone(), path, end, n)).unwrap();
+        assert_eq!(read("app.log", ReadEnd::First, 1), ["first"]);
+        assert_eq!(read("app.log", ReadEnd::First, 3), ["first", long.as_str(), "middle"]);
+        assert_eq!(read("app.log", ReadEnd::Last, 1), ["last"]);
+        assert_eq!(read("app.log", ReadEnd::Last, 3), ["", long.as_str(), "last"]);
+        assert_eq!(read("app.log", ReadEnd::Last, 100).len(), 6);
+        assert_eq!(read("app2.log", ReadEnd::Last, 1), ["b"]);
+        assert_eq!(read("app2.log", ReadEnd::First, 5), ["a", "b"]);
+        assert!(read("empty.log", ReadEnd::Last, 5).is_empty());
+        assert!(read("app.log", ReadEnd::First, 0).is_empty());
+    }
+
+    #[test]
+    fn test_list_start_after() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        for path in ["scan/a.csv", "scan/b.csv", "scan/c.csv", "scan/sub/d.csv"] {
+            rt.block_on(op.write(path, "x")).unwrap();
+        }
+
+        let listed = rt.block_on(do_list_async(op.clone(), "scan/", Some("scan/b.csv"), &PathFilter::default())).unwrap();
+        let mut paths: Vec<&str> = listed.iter().map(|e| e.0["path"].as_str().unwrap()).collect();
+        paths.sort();
+        assert_eq!(paths, vec!["scan/c.csv", "scan/sub/"]);
+
+        let files = rt
+            .block_on(list_files_recursive_async(&op, "scan/", Some("scan/c.csv")))
+            .unwrap();
+        let paths: Vec<&str> = files.iter().map(|e| e.path()).collect();
+        assert_eq!(paths, vec!["scan/sub/d.csv"]);
+    }
+
+    #[test]
+    fn test_list_by_size() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        for (path, size) in [("logs/empty.log", 0), ("logs/small.log", 5), ("logs/big.log", 100), ("logs/old/a.log", 7)] {
+            rt.block_on(op.write(path, vec![b'x'; size])).unwrap();
+        }
+        let list = |min: Option<u64>, max: Option<u64>, recursive| {
+            let mut names: Vec<String> = rt
+                .block_on(do_list_by_size_range_async(
+                    op.clone(),
+                    "logs/",
+                    min.unwrap_or(0),
+                    max.unwrap_or(u64::MAX),
+                    None,
+                    recursive,
+                ))
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.0["name"].as_str().unwrap().to_string())
+                .collect();
+            names.sort();
+            names
+        };
+        let names = |min, max| list(min, max, false);
+        assert_eq!(names(None, Some(0)), vec!["empty.log"]);
+        assert_eq!(names(Some(50), None), vec!["big.log"]);
+        assert_eq!(names(Some(1), Some(100)), vec!["big.log", "small.log"]);
+        assert_eq!(names(None, None).len(), 3);
+
+        // The recursive form finds nested files too, with both bounds inclusive.
+        assert_eq!(list(Some(5), Some(7), true), vec!["a.log", "small.log"]);
+        assert_eq!(list(Some(6), Some(6), true), Vec::<String>::new());
+        assert_eq!(list(None, None, true).len(), 4);
+
+        // Entries carry their full path and size, and resume after `start_after`.
+        let page = |start_after| {
+            rt.block_on(do_list_by_size_range_async(op.clone(), "logs/", 1, u64::MAX, start_after, true))
+                .unwrap()
+                .into_iter()
+                .map(|entry| (entry.0["path"].as_str().unwrap().to_string(), entry.0["content_length"].as_u64().unwrap()))
+                .collect::<Vec<_>>()
+        };
+        let mut all = page(None);
+        all.sort();
+        assert_eq!(
+            all,
+            vec![("logs/big.log".to_string(), 100), ("logs/old/a.log".to_string(), 7), ("logs/small.log".to_string(), 5)]
+        );
+        let mut rest = page(Some("logs/big.log"));
+        rest.sort();
+        assert_eq!(rest, all[1..].to_vec());
+        assert!(page(Some("logs/small.log")).is_empty());
+    }
+
+    #[test]
+    fn test_diff_etags() {
+        let known = serde_json::json!({"a.csv": "1", "b.csv": "2", "c.csv": "3"});
+        let current = vec![
+            ("b.csv".to_string(), "2".to_string()),
+            ("a.csv".to_string(), "9".to_string()),
+            ("d.csv".to_string(), "4".to_string()),
+        ];
+        let rows = diff_etags(known.as_object().unwrap(), current).unwrap();
+        let rows: Vec<(&str, &str, Option<&str>)> = rows
+            .iter()
+            .map(|(p, c, e)| (p.as_str(), c.as_str(), e.as_deref()))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("a.csv", "modified", Some("9")),
+                ("d.csv", "added", Some("4")),
+                ("c.csv", "deleted", None),
+            ]
+        );
+
+        let bad = serde_json::json!({"a.csv": 1});
+        assert!(diff_etags(bad.as_object().unwrap(), Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_increment() {
+        assert_eq!(parse_counter("c", b"41\n").unwrap(), 41);
+        assert_eq!(parse_counter("c", b"-3").unwrap(), -3);
+        assert!(parse_counter("c", b"forty-one").is_err());
+
+        // The memory service has no conditional writes to build a counter on.
+        let rt = Runtime::new().unwrap();
+        assert!(rt.block_on(do_increment_async(memory_operator(), "c", 1)).is_err());
+    }
+
+    #[test]
+    fn test_common_prefix() {
+        assert_eq!(common_path_prefix("data/2024/a.csv", "data/2024/b.csv"), "data/2024/");
+        assert_eq!(common_path_prefix("data/a.csv", "data/a.csv.gz"), "data/");
+        assert_eq!(common_path_prefix("data/", "data/a.csv"), "data/");
+        assert_eq!(common_path_prefix("data/a.csv", "data/a.csv"), "data/a.csv");
+        assert_eq!(common_path_prefix("logs/a", "data/a"), "");
+        assert_eq!(common_path_prefix("data/é.csv", "data/è.csv"), "data/");
+        assert_eq!(common_path_prefix("données/é/a", "données/é/b"), "données/é/");
+
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        for path in ["bucket/tmp/x", "bucket/lake/v1/sales/2024/a.parquet", "bucket/lake/v1/sales/2025/b.parquet"] {
+            rt.block_on(op.write(path, "x")).unwrap();
+        }
+        let prefix = |path| rt.block_on(do_common_prefix_async(&op, path)).unwrap();
+        assert_eq!(prefix("bucket/lake/"), "bucket/lake/v1/sales/");
+        assert_eq!(prefix("bucket/lake/v1/sales/2024/"), "bucket/lake/v1/sales/2024/a.parquet");
+        assert_eq!(prefix("bucket/"), "bucket/");
+        assert_eq!(prefix("missing/"), "");
+    }
+
+    #[test]
+    fn test_delete_prefix_batch() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        for path in ["data/2020/a", "data/2020/01/b", "data/2021/c", "data/2022/d"] {
+            rt.block_on(op.write(path, "x")).unwrap();
+        }
+
+        let rows = rt
+            .block_on(do_delete_prefix_batch_async(
+                op.clone(),
+                vec!["data/2020/".to_string(), "data/2021".to_string()],
+            ))
+            .unwrap();
+        assert_eq!(rows, vec![("data/2020/".to_string(), 2), ("data/2021".to_string(), 1)]);
+
+        let remaining = rt.block_on(list_files_recursive_async(&op, "data/", None)).unwrap();
+        let remaining: Vec<&str> = remaining.iter().map(|e| e.path()).collect();
+        assert_eq!(remaining, vec!["data/2022/d"]);
+        assert!(rt.block_on(do_delete_prefix_async(&op, "/")).is_err());
+    }
+
+    #[test]
+    fn test_writer_handles() {
+        let op = memory_operator();
+
+        let handle = writer_open(op.clone(), "streamed.txt", None).unwrap();
+        assert_eq!(writer_write(&handle, b"hello ").unwrap(), 6);
+        assert_eq!(writer_write(&handle, b"world").unwrap(), 11);
+        let commit = writer_commit(&handle).unwrap();
+        assert_eq!(commit["content_length"], 11);
+        assert!(writer_write(&handle, b"late").is_err());
+
+        let rt = Runtime::new().unwrap();
+        assert_eq!(rt.block_on(op.read("streamed.txt")).unwrap().to_vec(), b"hello world");
+
+        let handle = writer_open(op.clone(), "aborted.txt", None).unwrap();
+        writer_write(&handle, b"partial").unwrap();
+        assert!(writer_abort(&handle).unwrap());
+        assert!(!writer_abort(&handle).unwrap());
+        assert!(writer_commit(&handle).is_err());
+        assert!(!rt.block_on(op.exists("aborted.txt")).unwrap());
+
+        // What a failed transaction does with the writers it leaves open.
+        let handle = writer_open(op.clone(), "abandoned.txt", None).unwrap();
+        writer_write(&handle, b"partial").unwrap();
+        abort_writers([take_open_writer(&handle).unwrap()]);
+        assert!(writer_write(&handle, b"more").is_err());
+        assert!(!rt.block_on(op.exists("abandoned.txt")).unwrap());
+
+        // A multipart upload that fails to complete is aborted rather than left behind.
+        let (endpoint, requests) = mock_http_server(|head| {
+            if head.starts_with("post ") && head.contains("?uploads ") {
+                (200, "<InitiateMultipartUploadResult><Bucket>b</Bucket><Key>big.bin</Key><UploadId>u1</UploadId></InitiateMultipartUploadResult>".to_string())
+            } else if head.starts_with("post ") {
+                (400, "<Error><Code>InvalidPart</Code></Error>".to_string())
+            } else {
+                (200, String::new())
+            }
+        });
+        let config = HashMap::from([
+            ("bucket".to_string(), "b".to_string()),
+            ("region".to_string(), "us-east-1".to_string()),
+            ("endpoint".to_string(), endpoint),
+            ("access_key_id".to_string(), "access".to_string()),
+            ("secret_access_key".to_string(), "secret".to_string()),
+        ]);
+        let handle = writer_open(create_operator("s3", config).unwrap(), "big.bin", None).unwrap();
+        writer_write(&handle, &vec![b'x'; WRITE_CHUNK_SIZE + 1]).unwrap();
+        assert!(writer_commit(&handle).unwrap_err().contains("upload aborted"));
+        assert!(requests.lock().unwrap().iter().any(|head| head.starts_with("delete /b/big.bin?uploadid=u1 ")));
+        assert!(!writer_abort(&handle).unwrap());
+    }
+
+    #[test]
+    fn test_checksum_sidecar() {
+        let config = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+        assert_eq!(take_checksum_sidecar_config(&mut config(&[("pg_opendal_checksum_sidecar_suffix", ".sum")])).unwrap(), None);
+        let mut enabled = config(&[("pg_opendal_write_checksum_sidecar", "true"), ("bucket", "b")]);
+        assert_eq!(
+            take_checksum_sidecar_config(&mut enabled).unwrap(),
+            Some(ChecksumSidecar { algorithm: ChecksumAlgorithm::Sha256, suffix: ".sha256".to_string() })
+        );
+        assert_eq!(enabled, config(&[("bucket", "b")]));
+        let mut sha512 = config(&[("pg_opendal_write_checksum_sidecar", "true"), ("pg_opendal_checksum_sidecar_algorithm", "SHA512")]);
+        assert_eq!(take_checksum_sidecar_config(&mut sha512).unwrap().unwrap().suffix, ".sha512");
+        assert!(take_checksum_sidecar_config(&mut config(&[("pg_opendal_checksum_sidecar_algorithm", "md4")])).is_err());
+        assert!(take_checksum_sidecar_config(&mut config(&[("pg_opendal_checksum_sidecar_suffix", "/sum")])).is_err());
+        // Functions that don't write sidecars refuse the setting rather than ignore it.
+        assert!(create_operator("memory", config(&[("pg_opendal_write_checksum_sidecar", "true")])).is_err());
+        assert!(create_operator("memory", config(&[("pg_opendal_write_checksum_sidecar", "false")])).is_ok());
+
+        let op = memory_operator();
+        let sidecar = ChecksumSidecar { algorithm: ChecksumAlgorithm::Sha256, suffix: ".sum".to_string() };
+        let handle = writer_open(op.clone(), "data.csv", Some(sidecar)).unwrap();
+        writer_write(&handle, b"hello ").unwrap();
+        writer_write(&handle, b"world").unwrap();
+        let commit = writer_commit(&handle).unwrap();
+        let expected = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        assert_eq!(commit["checksum"], expected);
+        let rt = Runtime::new().unwrap();
+        assert_eq!(rt.block_on(op.read("data.csv.sum")).unwrap().to_vec(), expected.as_bytes());
+        assert_eq!(ChecksumHasher::digest_hex(ChecksumAlgorithm::Sha256, b"hello world"), expected);
+    }
+
+    #[test]
+    fn test_presign_s3() {
+        let op = create_operator(
+            "s3",
+            HashMap::from([
+                ("bucket".to_string(), "my-bucket".to_string()),
+                ("region".to_string(), "us-east-1".to_string()),
+                ("access_key_id".to_string(), "access".to_string()),
+                ("secret_access_key".to_string(), "secret".to_string()),
+            ]),
+        )
+        .unwrap();
+        let rt = Runtime::new().unwrap();
+        let expire = presign_expiry(60).unwrap();
+
+        let none = PresignOverrides::default();
+        let presigned = rt.block_on(presign_async(&op, "a.txt", "write", expire, &none)).unwrap();
+        let (url, method, _) = presigned_request_parts(&presigned);
+        assert_eq!(method, "PUT");
+        assert!(url.contains("a.txt") && url.contains("X-Amz-Signature"));
+
+        assert!(rt.block_on(presign_async(&op, "a.txt", "list", expire, &none)).is_err());
+        assert!(rt.block_on(presign_async(&memory_operator(), "a.txt", "read", expire, &none)).is_err());
+        assert!(presign_expiry(0).is_err());
+
+        let overrides = parse_presign_options(Some(serde_json::json!({
+            "response-content-disposition": "attachment; filename=\"report.pdf\"",
+            "response-content-type": "application/pdf",
+        })))
+        .unwrap();
+        let presigned = rt.block_on(presign_async(&op, "a.txt", "read", expire, &overrides)).unwrap();
+        let (url, method, _) = presigned_request_parts(&presigned);
+        assert_eq!(method, "GET");
+        assert!(url.contains("response-content-disposition=attachment") && url.contains("response-content-type="));
+        assert!(rt.block_on(presign_async(&op, "a.txt", "write", expire, &overrides)).is_err());
+        assert!(parse_presign_options(Some(serde_json::json!({ "response-expires": "0" }))).is_err());
+        assert!(parse_presign_options(Some(serde_json::json!({ "response-content-type": 1 }))).is_err());
+    }
+
+    #[test]
+    fn test_write_and_presign() {
+        let rt = Runtime::new().unwrap();
+        let expire = presign_expiry(300).unwrap();
+
+        // Memory can't presign: the write stands, and only the URL fails.
+        let op = memory_operator();
+        let err = rt.block_on(do_write_and_presign_async(&op, "share/a.png", b"png", expire)).unwrap_err();
+        assert!(err.starts_with("Wrote 'share/a.png', but"));
+        assert_eq!(rt.block_on(op.read("share/a.png")).unwrap().to_vec(), b"png");
+    }
+
+    #[test]
+    fn test_object_url() {
+        let config = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+        let s3 = config(&[("bucket", "my-bucket"), ("region", "eu-west-1")]);
+        assert_eq!(
+            object_url("s3", &s3, "reports/q1 2024.csv").unwrap(),
+            "https://my-bucket.s3.eu-west-1.amazonaws.com/reports/q1%202024.csv"
+        );
+        let rooted = config(&[("bucket", "my-bucket"), ("region", "eu-west-1"), ("root", "/site/")]);
+        assert_eq!(
+            object_url("s3", &rooted, "/index.html").unwrap(),
+            "https://my-bucket.s3.eu-west-1.amazonaws.com/site/index.html"
+        );
+        assert_eq!(
+            object_url("gcs", &config(&[("bucket", "assets")]), "img/logo.png").unwrap(),
+            "https://storage.googleapis.com/assets/img/logo.png"
+        );
+        assert_eq!(
+            object_url("azblob", &config(&[("account_name", "acme"), ("container", "public")]), "a.txt").unwrap(),
+            "https://acme.blob.core.windows.net/public/a.txt"
+        );
+        let cdn = config(&[("bucket", "my-bucket"), ("custom_domain", "cdn.example.com/")]);
+        assert_eq!(object_url("s3", &cdn, "img/logo.png").unwrap(), "https://cdn.example.com/img/logo.png");
+
+        assert_eq!(
+            object_url("s3", &config(&[("bucket", "my-bucket")]), "a.txt").unwrap_err(),
+            "Object URLs for service 's3' require 'region' in config"
+        );
+        assert!(object_url("memory", &HashMap::new(), "a.txt").unwrap_err().contains("not supported"));
+    }
+
+    #[test]
+    fn test_presign_info() {
+        let s3 = parse_presign_info(
+            "https://my-bucket.s3.us-east-1.amazonaws.com/a%20b.txt?X-Amz-Algorithm=AWS4-HMAC-SHA256\
+             &X-Amz-Credential=AKID%2F20240601%2Fus-east-1%2Fs3%2Faws4_request&X-Amz-Date=20240601T120000Z\
+             &X-Amz-Expires=3600&X-Amz-SignedHeaders=content-type%3Bhost&X-Amz-Signature=abc123",
+        )
+        .unwrap();
+        assert_eq!(s3.base_url, "https://my-bucket.s3.us-east-1.amazonaws.com/a%20b.txt");
+        assert_eq!(s3.expires_at.unwrap().to_rfc3339(), "2024-06-01T13:00:00+00:00");
+        assert_eq!((s3.signature.as_str(), s3.service), ("abc123", "s3"));
+        assert_eq!(s3.headers, vec!["content-type"]);
+
+        let gcs = parse_presign_info(
+            "https://storage.googleapis.com/b/o?x-goog-date=20240601T120000Z&x-goog-expires=60\
+             &x-goog-signedheaders=host&x-goog-signature=def",
+        )
+        .unwrap();
+        assert_eq!(gcs.service, "gcs");
+        assert_eq!(gcs.expires_at.unwrap().to_rfc3339(), "2024-06-01T12:01:00+00:00");
+        assert!(gcs.headers.is_empty());
+
+        let azure = parse_presign_info(
+            "https://acct.blob.core.windows.net/c/f.txt?sv=2022-11-02&se=2024-06-02T00%3A00%3A00Z&sp=r&sig=xyz%3D",
+        )
+        .unwrap();
+        assert_eq!((azure.signature.as_str(), azure.service), ("xyz=", "azure"));
+        assert_eq!(azure.expires_at.unwrap().to_rfc3339(), "2024-06-02T00:00:00+00:00");
+
+        assert!(parse_presign_info("https://example.com/file?token=1").is_err());
+        assert!(parse_presign_info("not a url").is_err());
+    }
+
+    #[test]
+    fn test_presign_post() {
+        // Signing key example from the AWS Signature Version 4 documentation.
+        let key = sigv4_signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam");
+        assert_eq!(hex::encode(key), "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+
+        let config = HashMap::from([
+            ("bucket".to_string(), "uploads".to_string()),
+            ("region".to_string(), "eu-west-1".to_string()),
+            ("root".to_string(), "/incoming".to_string()),
+            ("access_key_id".to_string(), "AKIDEXAMPLE".to_string()),
+            ("secret_access_key".to_string(), "secret".to_string()),
+        ]);
+        let now = chrono::DateTime::parse_from_rfc3339("2026-01-02T03:04:05Z").unwrap().to_utc();
+        let limit = serde_json::json!(["content-length-range", 0, 1048576]);
+        let form = s3_post_policy(&config, "photo.jpg", presign_expiry(600).unwrap(), vec![limit.clone()], now).unwrap();
+
+        assert_eq!(form["url"], "https://s3.eu-west-1.amazonaws.com/uploads");
+        let fields = &form["fields"];
+        assert_eq!(fields["key"], "incoming/photo.jpg");
+        assert_eq!(fields["x-amz-credential"], "AKIDEXAMPLE/20260102/eu-west-1/s3/aws4_request");
+        assert_eq!(fields["x-amz-date"], "20260102T030405Z");
+        assert_eq!(fields["x-amz-signature"].as_str().unwrap().len(), 64);
+
+        let policy = base64::Engine::decode(
+            &base64::engine::general_purpose::STANDARD,
+            fields["policy"].as_str().unwrap(),
+        )
+        .unwrap();
+        let policy: Value = serde_json::from_slice(&policy).unwrap();
+        assert_eq!(policy["expiration"], "2026-01-02T03:14:05.000Z");
+        let conditions = policy["conditions"].as_array().unwrap();
+        assert!(conditions.contains(&serde_json::json!({ "bucket": "uploads" })));
+        assert!(conditions.contains(&serde_json::json!({ "key": "incoming/photo.jpg" })));
+        assert_eq!(conditions.last(), Some(&limit));
+
+        let mut anonymous = config.clone();
+        anonymous.remove("secret_access_key");
+        assert!(s3_post_policy(&anonymous, "photo.jpg", presign_expiry(600).unwrap(), vec![], now).is_err());
+    }
+
+    #[test]
+    fn test_write_read_cycle() {
+        let rt = Runtime::new().unwrap();
+        let op = memory_operator();
+        let cycle = rt.block_on(write_read_cycle_async(&op, "smoke.txt"));
+        assert!(cycle.write_ok && cycle.read_ok && cycle.content_matches && cycle.delete_ok);
+        assert!(!rt.block_on(op.exists("smoke.txt")).unwrap());
+
+        let report = rt.block_on(do_selftest_async(op.clone(), "checks/")).0;
+        assert_eq!(report["ok"], true, "{}", report);
+        assert!(report["path"].as_str().unwrap().starts_with("checks/pg_opendal_selftest_"));
+        assert_eq!(report["steps"]["stat"]["ok"], true);
+        assert!(rt.block_on(list_files_recursive_async(&op, "", None)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dir_prefixes() {
+        assert_eq!(dir_prefixes("a/b/c"), vec!["a/", "a/b/", "a/b/c/"]);
+        assert_eq!(dir_prefixes("/tmp/x/"), vec!["/tmp/", "/tmp/x/"]);
+        assert!(dir_prefixes("").is_empty());
+    }
+
+    #[test]
+    fn test_detect_content_type() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(detect_content_type(png), "image/png");
+        assert_eq!(detect_content_type(b"%PDF-1.7\n"), "application/pdf");
+        assert_eq!(detect_content_type(b"plain text"), "application/octet-stream");
+        assert_eq!(detect_content_type(b""), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_apply_path_prefix() {
+        assert_eq!(apply_path_prefix("", "a/b.txt"), "a/b.txt");
+        assert_eq!(apply_path_prefix("tenant", "a/b.txt"), "tenant/a/b.txt");
+        assert_eq!(apply_path_prefix("tenant/", "a/b.txt"), "tenant/a/b.txt");
+        assert_eq!(apply_path_prefix("tenant", "/a/b.txt"), "/a/b.txt");
+        assert_eq!(apply_path_prefix("tenant", ""), "tenant/");
+        // Paths returned by listings already carry the prefix and aren't prefixed twice.
+        assert_eq!(apply_path_prefix("tenant", "tenant/a/b.txt"), "tenant/a/b.txt");
+        assert_eq!(apply_path_prefix("tenant/", "tenant/"), "tenant/");
+        assert_eq!(apply_path_prefix("tenant", "tenant"), "tenant");
+        assert_eq!(apply_path_prefix("tenant", "tenants/a.txt"), "tenant/tenants/a.txt");
+    }
+
+    #[test]
+    fn test_user_agent() {
+        let (endpoint, requests) = mock_http_server(|_| (200, "hello".to_string()));
+        let config = |user_agent: Option<&str>| {
+            let mut config = HashMap::from([
+                ("bucket".to_string(), "b".to_string()),
+                ("region".to_string(), "us-east-1".to_string()),
+                ("endpoint".to_string(), endpoint.clone()),
+                ("access_key_id".to_string(), "access".to_string()),
+                ("secret_access_key".to_string(), "secret".to_string()),
+            ]);
+            if let Some(user_agent) = user_agent {
+                config.insert("pg_opendal_user_agent".to_string(), user_agent.to_string());
+            }
+            config
+        };
+        let rt = Runtime::new().unwrap();
+        for (user_agent, expected) in [
+            (None, format!("user-agent: pg_opendal/{}\r\n", env!("CARGO_PKG_VERSION"))),
+            (Some("etl-job/2.1 (team-data)"), "user-agent: etl-job/2.1 (team-data)\r\n".to_string()),
+        ] {
+            requests.lock().unwrap().clear();
+            let op = create_operator("s3", config(user_agent)).unwrap();
+            rt.block_on(op.read("a.txt")).unwrap();
+            let requests = requests.lock().unwrap();
+            assert!(!requests.is_empty());
+            assert!(requests.iter().all(|head| head.contains(&expected)));
+        }
+
+        assert!(create_operator("s3", config(Some(""))).is_err());
+        assert!(create_operator("s3", config(Some("bad\nagent"))).is_err());
+        requests.lock().unwrap().clear();
+        let config = config(Some("etl-job"));
+        let request =
+            s3_signed_request(&config, "Testing", reqwest::Method::GET, "", &[], Vec::new(), chrono::Utc::now()).unwrap();
+        rt.block_on(s3_send_async(&config, request, "test")).unwrap();
+        assert!(requests.lock().unwrap()[0].contains("user-agent: etl-job\r\n"));
+    }
+
+    #[test]
+    fn test_http_transport_retries() {
+        let attempts = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = attempts.clone();
+        let (endpoint, _) = mock_http_server(move |_| {
+            match counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 => (503, "<Error><Code>SlowDown</Code></Error>".to_string()),
+                _ => (200, "ok".to_string()),
+            }
+        });
+        let config = |retries: &str| {
+            HashMap::from([
+                ("bucket".to_string(), "b".to_string()),
+                ("region".to_string(), "us-east-1".to_string()),
+                ("endpoint".to_string(), endpoint.clone()),
+                ("access_key_id".to_string(), "access".to_string()),
+                ("secret_access_key".to_string(), "secret".to_string()),
+                ("pg_opendal_retry_attempts".to_string(), retries.to_string()),
+            ])
+        };
+        let rt = Runtime::new().unwrap();
+        let send = |config: &HashMap<String, String>| {
+            let request =
+                s3_signed_request(config, "Testing", reqwest::Method::GET, "", &[], Vec::new(), chrono::Utc::now()).unwrap();
+            rt.block_on(s3_send_async(config, request, "test"))
+        };
+
+        assert_eq!(send(&config("1")).unwrap(), "ok");
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        attempts.store(0, std::sync::atomic::Ordering::SeqCst);
+        assert!(send(&config("0")).unwrap_err().contains("HTTP 503: SlowDown"));
+        assert!(HttpTransport::from_config(&config("-1")).is_err());
+    }
+
+    #[test]
+    fn test_take_http_pool_config() {
+        let mut config = HashMap::from([
+            ("bucket".to_string(), "b".to_string()),
+            ("pg_opendal_pool_max_idle_per_host".to_string(), "4".to_string()),
+        ]);
+        assert_eq!(take_http_pool_config(&mut config).unwrap(), Some(4));
+        assert!(!config.contains_key("pg_opendal_pool_max_idle_per_host"));
+        assert_eq!(take_http_pool_config(&mut config).unwrap(), None);
+
+        config.insert("pg_opendal_pool_max_idle_per_host".to_string(), "-1".to_string());
+        assert!(take_http_pool_config(&mut config).is_err());
+
+        let config = HashMap::from([
+            ("bucket".to_string(), "b".to_string()),
+            ("region".to_string(), "us-east-1".to_string()),
+            ("pg_opendal_pool_max_idle_per_host".to_string(), "0".to_string()),
+        ]);
+        assert!(create_operator("s3", config).is_ok());
+    }
+
+    #[test]
+    fn test_take_sse_config() {
+        let sse = |pairs: &[(&str, &str)]| {
+            let mut config: HashMap<String, String> = pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            config.insert("bucket".to_string(), "b".to_string());
+            let mode = take_sse_config(&mut config);
+            assert_eq!(config.len(), 1, "SSE keys must be removed from the config");
+            mode
+        };
+
+        assert_eq!(sse(&[]).unwrap(), None);
+        assert_eq!(sse(&[("sse", "s3")]).unwrap(), Some(SseMode::S3));
+        assert_eq!(sse(&[("sse", "kms")]).unwrap(), Some(SseMode::Kms(None)));
+        assert_eq!(
+            sse(&[("sse_kms_key_id", "key-id")]).unwrap(),
+            Some(SseMode::Kms(Some("key-id".to_string())))
+        );
+        let key = "0123456789abcdef0123456789abcdef";
+        assert_eq!(
+            sse(&[("sse", "c"), ("sse_customer_key", key)]).unwrap(),
+            Some(SseMode::Customer(key.to_string()))
+        );
+
+        assert!(sse(&[("sse_kms_key_id", "id"), ("sse_customer_key", key)]).is_err());
+        assert!(sse(&[("sse", "s3"), ("sse_kms_key_id", "id")]).is_err());
+        assert!(sse(&[("sse", "c")]).is_err());
+        assert!(sse(&[("sse", "c"), ("sse_customer_key", "short")]).is_err());
+        assert!(sse(&[("sse", "aes")]).is_err());
+
+        let op = create_operator(
+            "s3",
+            HashMap::from([
+                ("bucket".to_string(), "b".to_string()),
+                ("region".to_string(), "us-east-1".to_string()),
+                ("sse".to_string(), "kms".to_string()),
+                ("sse_kms_key_id".to_string(), "key-id".to_string()),
+            ]),
+        );
+        assert!(op.is_ok());
+
+        // The chosen scheme reaches S3 as headers on the upload.
+        let (endpoint, requests) = mock_http_server(|_| (200, String::new()));
+        let rt = Runtime::new().unwrap();
+        let write_with = |pairs: &[(&str, &str)]| {
+            let mut config = HashMap::from([
+                ("bucket".to_string(), "b".to_string()),
+                ("region".to_string(), "us-east-1".to_string()),
+                ("endpoint".to_string(), endpoint.clone()),
+                ("access_key_id".to_string(), "access".to_string()),
+                ("secret_access_key".to_string(), "secret".to_string()),
+            ]);
+            config.extend(pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+            requests.lock().unwrap().clear();
+            let op = create_operator("s3", config).unwrap();
+            rt.block_on(op.write("secret.csv", "a,1\n")).unwrap();
+            let heads = requests.lock().unwrap();
+            assert_eq!(heads.len(), 1);
+            assert!(heads[0].starts_with("put /b/secret.csv "));
+            heads[0].clone()
+        };
+
+        let head = write_with(&[("sse", "kms"), ("sse_kms_key_id", "key-id")]);
+        assert!(head.contains("x-amz-server-side-encryption: aws:kms\r\n"));
+        assert!(head.contains("x-amz-server-side-encryption-aws-kms-key-id: key-id\r\n"));
+        let head = write_with(&[("sse", "kms")]);
+        assert!(head.contains("x-amz-server-side-encryption: aws:kms\r\n"));
+        assert!(!head.contains("x-amz-server-side-encryption-aws-kms-key-id"));
+        let head = write_with(&[("sse", "s3")]);
+        assert!(head.contains("x-amz-server-side-encryption: aes256\r\n"));
+        let head = write_with(&[("sse", "c"), ("sse_customer_key", key)]);
+        assert!(head.contains("x-amz-server-side-encryption-customer-algorithm: aes256\r\n"));
+        assert!(head.contains("x-amz-server-side-encryption-customer-key: "));
+        assert!(!write_with(&[]).contains("x-amz-server-side-encryption"));
     }
 }